@@ -0,0 +1,235 @@
+//! Benchmarks of the time it takes N watchers to readjust to a bump: from the bump until
+//! no watcher is outdated anymore, for N = 1, 10, 100 and 1000.
+//!
+//! - `state_machine` drives the `Sysgenid` state machine directly, acking every watcher
+//!   right after the bump: the cost of the bookkeeping alone.
+//! - `session_bus` runs the service on a private `dbus-daemon` session bus, with a
+//!   client connection per watcher acking the `NewGeneration` signal, until the
+//!   overseer gets `SystemReady`. Skipped when `dbus-daemon` isn't installed.
+//!
+//! The time of a run is the bump-to-drain latency of N watchers, its throughput the acks
+//! processed per second, the inverse of the per-watcher latency.
+
+#[path = "../tests/common/mod.rs"]
+mod common;
+
+use std::io::{BufRead, BufReader};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use common::TempDir;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use dbus::blocking::Connection;
+use dbus::channel::Channel;
+use dbus::message::MatchRule;
+use dbus::Message;
+use sysgenid_dbus::Sysgenid;
+
+const SYSGENID_INTERFACE: &str = "com.RFC.sysgenid";
+const SYSGENID_PATH: &str = "/com/RFC/sysgenid";
+const TIMEOUT: Duration = Duration::from_secs(10);
+const WATCHER_COUNTS: [usize; 4] = [1, 10, 100, 1000];
+
+/// Child process killed when dropped.
+struct Process(Child);
+
+impl Drop for Process {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+/// Watcher ids of `n` watchers, as the bus would name their connections.
+fn watcher_ids(n: usize) -> Vec<String> {
+    (0..n).map(|i| format!(":1.{}", i + 100)).collect()
+}
+
+fn state_machine(c: &mut Criterion) {
+    let dir = TempDir::new();
+    let config = dir.config();
+    let mut group = c.benchmark_group("state_machine");
+    for &n in WATCHER_COUNTS.iter() {
+        let ids = watcher_ids(n);
+        let mut sysgenid = Sysgenid::from_config(&config).unwrap();
+        for id in ids.iter() {
+            sysgenid
+                .register_watcher(id, None, || true, || None, |_| ())
+                .unwrap();
+        }
+        group.throughput(Throughput::Elements(n as u64));
+        group.bench_function(BenchmarkId::from_parameter(n), |b| {
+            b.iter(|| {
+                let (counter, outdated) = sysgenid.bump_generation(0, "bench", |_| ()).unwrap();
+                for id in outdated.iter() {
+                    sysgenid
+                        .ack_watcher_gen_counter(id, counter, None, || true, || None, |_| ())
+                        .unwrap();
+                }
+                assert!(sysgenid.is_ready());
+            })
+        });
+    }
+    group.finish();
+}
+
+/// The service running on a private session bus, with `n` watchers registered.
+struct LiveBus {
+    overseer: Connection,
+    watchers: Vec<Connection>,
+    _service: Process,
+    _bus: Process,
+    _dir: TempDir,
+}
+
+impl LiveBus {
+    /// Returns `None` if `dbus-daemon` isn't available.
+    fn start(n: usize) -> Option<Self> {
+        let dir = TempDir::new();
+        let bus = Command::new("dbus-daemon")
+            .args(["--session", "--nofork", "--print-address"])
+            .arg(format!(
+                "--address=unix:path={}",
+                dir.0.join("bus").display()
+            ))
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn();
+        let mut bus = match bus {
+            Ok(bus) => Process(bus),
+            Err(e) => {
+                eprintln!("skipping session_bus, could not run dbus-daemon: {}", e);
+                return None;
+            }
+        };
+        // The bus prints its address once it accepts connections.
+        let mut address = String::new();
+        BufReader::new(bus.0.stdout.as_mut().unwrap())
+            .read_line(&mut address)
+            .unwrap();
+        let address = address.trim_end().to_owned();
+
+        let service = Command::new(env!("CARGO_BIN_EXE_sysgenid-dbus"))
+            .arg("--bus-address")
+            .arg(&address)
+            .arg("--state-file")
+            .arg(dir.0.join("state"))
+            .arg("--counter-file")
+            .arg(dir.0.join("counter"))
+            .args(["--log-level", "error"])
+            .env_remove("DBUS_SESSION_BUS_ADDRESS")
+            .stdout(Stdio::null())
+            .spawn()
+            .unwrap();
+        let service = Process(service);
+
+        let connect = || {
+            let mut channel = Channel::open_private(&address).unwrap();
+            channel.register().unwrap();
+            Connection::from(channel)
+        };
+        let overseer = connect();
+        let deadline = Instant::now() + TIMEOUT;
+        while call::<(u32,), _>(&overseer, "GetSysGenCounter", ()).is_err() {
+            assert!(Instant::now() < deadline, "service did not come up");
+            thread::sleep(Duration::from_millis(50));
+        }
+        add_match(&overseer, "SystemReady");
+
+        let watchers: Vec<Connection> = (0..n)
+            .map(|_| {
+                let watcher = connect();
+                add_match(&watcher, "NewGeneration");
+                call::<(u32,), _>(&watcher, "RegisterWatcher", ()).unwrap();
+                watcher
+            })
+            .collect();
+        Some(LiveBus {
+            overseer,
+            watchers,
+            _service: service,
+            _bus: bus,
+            _dir: dir,
+        })
+    }
+
+    /// Bumps the generation and has every watcher ack it, returning once the overseer
+    /// gets the `SystemReady` of the new generation.
+    fn bump_and_drain(&self) {
+        let (counter, _): (u32, Vec<String>) =
+            call(&self.overseer, "TriggerSysGenUpdate", (0u32, "bench")).unwrap();
+        // Acks are pipelined: their replies are left for `pop_signal()` to skip.
+        for watcher in self.watchers.iter() {
+            let new_counter: u32 = pop_signal(watcher, "NewGeneration").read1().unwrap();
+            assert_eq!(new_counter, counter);
+            let ack = Message::new_method_call(
+                SYSGENID_INTERFACE,
+                SYSGENID_PATH,
+                SYSGENID_INTERFACE,
+                "AckWatcherCounter",
+            )
+            .unwrap()
+            .append1(counter);
+            watcher.channel().send(ack).unwrap();
+        }
+        loop {
+            let (_adjust_duration_ms, for_counter): (u64, u32) =
+                pop_signal(&self.overseer, "SystemReady").read2().unwrap();
+            if for_counter == counter {
+                break;
+            }
+        }
+    }
+}
+
+fn call<R: dbus::arg::ReadAll, A: dbus::arg::AppendAll>(
+    conn: &Connection,
+    method: &str,
+    args: A,
+) -> Result<R, dbus::Error> {
+    conn.with_proxy(SYSGENID_INTERFACE, SYSGENID_PATH, TIMEOUT)
+        .method_call(SYSGENID_INTERFACE, method, args)
+}
+
+/// Has the bus route the sysgenid `signal` to `conn`, left queued for `pop_signal()`.
+fn add_match(conn: &Connection, signal: &'static str) {
+    let rule = MatchRule::new_signal(SYSGENID_INTERFACE, signal);
+    conn.add_match_no_cb(&rule.match_str()).unwrap();
+}
+
+/// Pops the messages received by `conn` up to the next sysgenid `signal`.
+fn pop_signal(conn: &Connection, signal: &str) -> Message {
+    let deadline = Instant::now() + TIMEOUT;
+    loop {
+        let msg = conn
+            .channel()
+            .blocking_pop_message(TIMEOUT)
+            .unwrap()
+            .expect("no signal received");
+        if msg.member().as_deref() == Some(signal) {
+            return msg;
+        }
+        assert!(Instant::now() < deadline, "no signal received");
+    }
+}
+
+fn session_bus(c: &mut Criterion) {
+    let mut group = c.benchmark_group("session_bus");
+    // Every run round-trips through the bus at least twice per watcher.
+    group.sample_size(10);
+    for &n in WATCHER_COUNTS.iter() {
+        let live_bus = match LiveBus::start(n) {
+            Some(live_bus) => live_bus,
+            None => return,
+        };
+        group.throughput(Throughput::Elements(n as u64));
+        group.bench_function(BenchmarkId::from_parameter(n), |b| {
+            b.iter(|| live_bus.bump_and_drain())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, state_machine, session_bus);
+criterion_main!(benches);
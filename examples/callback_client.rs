@@ -0,0 +1,48 @@
+use std::error::Error;
+use std::time::Duration;
+
+use dbus::blocking::Connection;
+use dbus_crossroads::Crossroads;
+
+// This example shows a _tracked_ client relying on push acks. Instead of listening
+// for the `NewGeneration` signal and calling `AckWatcherCounter` back, it registers
+// an object implementing the `com.RFC.sysgenid.Callback` interface through
+// `RegisterCallbackWatcher`. On every generation change the service calls its
+// `OnNewGeneration` method, and a successful return acks the new counter.
+
+const SYSGENID_INTERFACE: &str = "com.RFC.sysgenid";
+const SYGENID_PATH: &str = "/com/RFC/sysgenid";
+const CALLBACK_INTERFACE: &str = "com.RFC.sysgenid.Callback";
+const CALLBACK_PATH: &str = "/callback";
+
+pub fn main() -> Result<(), Box<dyn Error>> {
+    let c = Connection::new_session()?;
+
+    let mut cr = Crossroads::new();
+    let iface_token = cr.register(CALLBACK_INTERFACE, |b| {
+        b.method(
+            "OnNewGeneration",
+            ("sysgen_counter",),
+            (),
+            |_, _: &mut (), (counter,): (u32,)| {
+                // IRL adjust to the new generation here, the service considers it done
+                // once we return.
+                println!("CallbackClient: adjusting to generation {}", counter);
+                Ok(())
+            },
+        );
+    });
+    cr.insert(CALLBACK_PATH, &[iface_token], ());
+
+    let proxy = c.with_proxy(SYSGENID_INTERFACE, SYGENID_PATH, Duration::from_secs(2));
+    let (counter,): (u32,) = proxy.method_call(
+        SYSGENID_INTERFACE,
+        "RegisterCallbackWatcher",
+        (dbus::Path::from(CALLBACK_PATH),),
+    )?;
+    println!("CallbackClient: tracked at generation {}", counter);
+
+    // Serve the callbacks forever.
+    cr.serve(&c)?;
+    Ok(())
+}
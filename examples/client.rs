@@ -2,7 +2,8 @@ use std::time::Duration;
 
 use dbus::message::MatchRule;
 use dbus::nonblock;
-use dbus::nonblock::SyncConnection;
+use dbus::nonblock::stdintf::org_freedesktop_dbus::Properties;
+use dbus::nonblock::{MsgMatch, SyncConnection};
 use dbus_tokio::connection;
 use std::sync::{Arc, Mutex};
 use tokio;
@@ -12,15 +13,31 @@ use uuid::Uuid;
 /// while also listening for SysGenID events. On receipt of a system generation
 /// change signal, it will adjust to new generation, acknowledge it back to the
 /// server and continue work.
+///
+/// Usage: `client [MAX_ADJUST_JITTER_MS]`.
 
 const SYSGENID_INTERFACE: &str = "com.RFC.sysgenid";
 const SYGENID_PATH: &str = "/com/RFC/sysgenid";
+const STALE_COUNTER_ERROR: &str = "com.RFC.sysgenid.Error.StaleCounter";
+// Errors worth retrying the call on: it timed out, or the service is (re)starting.
+const RETRYABLE_ERRORS: [&str; 4] = [
+    "org.freedesktop.DBus.Error.NoReply",
+    "org.freedesktop.DBus.Error.Timeout",
+    "org.freedesktop.DBus.Error.ServiceUnknown",
+    "org.freedesktop.DBus.Error.NameHasNoOwner",
+];
+const MAX_CALL_ATTEMPTS: u32 = 5;
+const INITIAL_RETRY_BACKOFF: Duration = Duration::from_millis(100);
+// Default upper bound of the random delay before adjusting to a new generation.
+const DEFAULT_MAX_ADJUST_JITTER: Duration = Duration::from_millis(500);
 
 pub struct Application {
     // Internal unique data that we want to change on each system generation bump.
     uuid: Uuid,
     // Flag that shows when running dirty (with old generation data).
     dirty_uniqueness: bool,
+    // System generation the internal data was last adjusted to.
+    generation: u32,
 
     // Connection to SysGenID DBus server.
     conn: Arc<SyncConnection>,
@@ -28,6 +45,8 @@ pub struct Application {
     // Tracked clients are expected to explicitly acknowledge back to the server
     // when they have adjusted to a new generation.
     tracking_enabled: bool,
+    // Upper bound of the random delay before adjusting to a new generation.
+    max_adjust_jitter: Duration,
 }
 
 impl Application {
@@ -40,14 +59,28 @@ impl Application {
         // This would be a simple main loop.
         loop {
             interval.tick().await;
-            let mut app = app_lock.lock().unwrap();
-            if app.dirty_uniqueness {
-                app.adjust_to_new_generation().await;
-                println!("Client: adjusted, continuing workload...");
+            // Without the lock held, so signals keep being handled meanwhile.
+            let jitter = app_lock.lock().unwrap().adjust_jitter();
+            if let Some(jitter) = jitter {
+                println!("Client: waiting {:?} before adjusting...", jitter);
+                tokio::time::sleep(jitter).await;
+            }
+            let dirty_uniqueness = app_lock.lock().unwrap().dirty_uniqueness;
+            if dirty_uniqueness {
+                match Self::adjust_to_new_generation(&app_lock).await {
+                    Ok(()) => println!("Client: adjusted, continuing workload..."),
+                    // The service could not be reached even after retrying, or failed
+                    // the call. Keep working meanwhile, but stay dirty so that the next
+                    // round adjusts again.
+                    Err(e) => {
+                        println!("Client: failed to adjust, will try again: {}", e);
+                        app_lock.lock().unwrap().dirty_uniqueness = true;
+                    }
+                }
             }
             // In a real app this would be main loop event handler.
             // In this example, we just call this every 2 seconds.
-            app.do_app_specific_work();
+            app_lock.lock().unwrap().do_app_specific_work();
         }
     }
 
@@ -58,82 +91,232 @@ impl Application {
         );
     }
 
-    pub fn new_generation_handler(&mut self) {
-        println!("Client: got NewGeneration signal! Marking dirty...");
+    pub fn new_generation_handler(&mut self, reason: &str) {
+        println!(
+            "Client: got NewGeneration signal (reason '{}')! Marking dirty...",
+            reason
+        );
         self.dirty_uniqueness = true;
     }
 
-    async fn adjust_to_new_generation(&mut self) {
-        let proxy = nonblock::Proxy::new(
-            SYSGENID_INTERFACE,
-            SYGENID_PATH,
-            Duration::from_secs(2),
-            self.conn.clone(),
-        );
+    /// Random delay to wait before adjusting to a new generation, if one is pending.
+    ///
+    /// A generation change reaches the whole fleet at once. Were every watcher to
+    /// immediately call `GetSysGenCounter` then `AckWatcherCounter`, the service
+    /// would get hit by all of them at the same time. Waiting a random delay first
+    /// spreads that load, at the cost of a slower overall adjustment.
+    fn adjust_jitter(&self) -> Option<Duration> {
+        if !self.dirty_uniqueness {
+            return None;
+        }
+        let max_ms = self.max_adjust_jitter.as_millis();
+        let jitter_ms = Uuid::new_v4().as_u128() % (max_ms + 1);
+        Some(Duration::from_millis(jitter_ms as u64))
+    }
 
-        println!("Client: getting new generation (using DBus method GetSysGenCounter)...");
-        let (counter,): (u32,) = proxy
-            .method_call(SYSGENID_INTERFACE, "GetSysGenCounter", ())
-            .await
-            .unwrap();
-        println!("Client: got new gen counter: {}", counter);
+    pub fn set_max_adjust_jitter(&mut self, max_adjust_jitter: Duration) {
+        self.max_adjust_jitter = max_adjust_jitter;
+    }
 
+    fn adjust(&mut self, counter: u32) {
         println!("Client: adjusting to new environment...");
         self.uuid = Uuid::new_v4();
         self.dirty_uniqueness = false;
+        self.generation = counter;
         println!(
             "Client: adjusted to new environment: new UUID: {}",
             self.uuid
         );
+    }
+
+    /// Adjusts to the current generation and acks it if tracked. The app lock is only
+    /// held in between calls, so signals keep being handled while waiting on replies.
+    async fn adjust_to_new_generation(app_lock: &Arc<Mutex<Self>>) -> Result<(), dbus::Error> {
+        let (conn, tracking_enabled) = {
+            let app = app_lock.lock().unwrap();
+            (app.conn.clone(), app.tracking_enabled)
+        };
 
-        if self.tracking_enabled {
+        println!("Client: getting new generation (using DBus method GetSysGenCounter)...");
+        let mut counter = get_sys_gen_counter(&conn).await?;
+        println!("Client: got new gen counter: {}", counter);
+
+        loop {
+            app_lock.lock().unwrap().adjust(counter);
+            if !tracking_enabled {
+                return Ok(());
+            }
             println!(
                 "Client: acknowledging adjustment complete (using DBus method AckWatcherCounter)..."
             );
-            let (counter,): (u32,) = proxy
-                .method_call(SYSGENID_INTERFACE, "AckWatcherCounter", (counter,))
-                .await
-                .unwrap();
-            println!("Client: acknowledged new counter: {}", counter);
+            match ack_counter(&conn, counter).await {
+                Ok(counter) => {
+                    println!("Client: acknowledged new counter: {}", counter);
+                    return Ok(());
+                }
+                // The generation changed again while we were adjusting. Error messages
+                // are meant for humans only, so read the current counter back, then
+                // readjust and ack that one instead.
+                Err(e) if e.name() == Some(STALE_COUNTER_ERROR) => {
+                    counter = get_sys_gen_counter(&conn).await?;
+                    println!("Client: generation moved on to {} meanwhile", counter);
+                }
+                // Anything else is up to the caller.
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Called when the SysGenID service (re)appears on the bus, e.g. after a restart,
+    /// which loses track of all watchers.
+    pub async fn service_appeared_handler(app_lock: Arc<Mutex<Self>>) {
+        let (conn, generation, tracking_enabled) = {
+            let app = app_lock.lock().unwrap();
+            (app.conn.clone(), app.generation, app.tracking_enabled)
+        };
+        println!("Client: SysGenID service appeared on the bus");
+        let res = match get_sys_gen_counter(&conn).await {
+            // A generation change happened while the service was gone, readjust and ack.
+            Ok(counter) if counter != generation => {
+                app_lock
+                    .lock()
+                    .unwrap()
+                    .new_generation_handler("missed while service was gone");
+                Ok(())
+            }
+            Ok(counter) if tracking_enabled => {
+                println!("Client: registering again with the service...");
+                ack_counter(&conn, counter).await.map(|_| ())
+            }
+            Ok(_) => Ok(()),
+            Err(e) => Err(e),
+        };
+        // Not knowing where the new instance stands, readjust and ack to be safe.
+        if let Err(e) = res {
+            println!("Client: failed to check in with the service: {}", e);
+            app_lock
+                .lock()
+                .unwrap()
+                .new_generation_handler("service check-in failed");
         }
     }
 
-    pub fn new(conn: Arc<SyncConnection>, tracking_enabled: bool) -> Self {
+    pub fn new(conn: Arc<SyncConnection>, tracking_enabled: bool, generation: u32) -> Self {
         Application {
             uuid: Uuid::new_v4(),
             dirty_uniqueness: false,
+            generation,
             conn,
             tracking_enabled,
+            max_adjust_jitter: DEFAULT_MAX_ADJUST_JITTER,
+        }
+    }
+}
+
+/// Calls `method` of the service, retrying with exponential backoff while calls time
+/// out or the service is not on the bus, e.g. overloaded or restarting. Other errors
+/// are returned right away, for the caller to handle.
+async fn call_service<R, A>(
+    conn: &Arc<SyncConnection>,
+    method: &str,
+    args: A,
+) -> Result<R, dbus::Error>
+where
+    R: dbus::arg::ReadAll + 'static,
+    A: dbus::arg::AppendAll + Clone,
+{
+    let proxy = nonblock::Proxy::new(
+        SYSGENID_INTERFACE,
+        SYGENID_PATH,
+        Duration::from_secs(2),
+        conn.clone(),
+    );
+    let mut backoff = INITIAL_RETRY_BACKOFF;
+    for _ in 1..MAX_CALL_ATTEMPTS {
+        match proxy
+            .method_call(SYSGENID_INTERFACE, method, args.clone())
+            .await
+        {
+            Err(e) if RETRYABLE_ERRORS.iter().any(|name| e.name() == Some(*name)) => {
+                println!(
+                    "Client: {} failed, retrying in {:?}: {}",
+                    method, backoff, e
+                );
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+            }
+            res => return res,
         }
     }
+    // Out of retries, whatever happens now is final.
+    proxy.method_call(SYSGENID_INTERFACE, method, args).await
 }
 
-pub fn new_untracked_app(conn: Arc<SyncConnection>) -> Application {
-    Application::new(conn, false)
+async fn get_sys_gen_counter(conn: &Arc<SyncConnection>) -> Result<u32, dbus::Error> {
+    let (counter,): (u32,) = call_service(conn, "GetSysGenCounter", ()).await?;
+    Ok(counter)
 }
 
-pub async fn new_tracked_app(conn: Arc<SyncConnection>) -> Application {
-    // Ping SysGenID service so it starts tracking this client.
+/// Acks `counter`, returning the counter acked.
+async fn ack_counter(conn: &Arc<SyncConnection>, counter: u32) -> Result<u32, dbus::Error> {
+    let (counter,): (u32,) = call_service(conn, "AckWatcherCounter", (counter,)).await?;
+    Ok(counter)
+}
+
+pub async fn new_untracked_app(conn: Arc<SyncConnection>) -> Result<Application, dbus::Error> {
+    let counter = get_sys_gen_counter(&conn).await?;
+    Ok(Application::new(conn, false, counter))
+}
+
+pub async fn new_tracked_app(conn: Arc<SyncConnection>) -> Result<Application, dbus::Error> {
     let proxy = nonblock::Proxy::new(
         SYSGENID_INTERFACE,
         SYGENID_PATH,
         Duration::from_secs(2),
         conn.clone(),
     );
-    let (counter,): (u32,) = proxy
-        .method_call(SYSGENID_INTERFACE, "GetSysGenCounter", ())
-        .await
-        .unwrap();
-    let (_,): (u32,) = proxy
-        .method_call(SYSGENID_INTERFACE, "AckWatcherCounter", (counter,))
+    // Services predating the `Features` property have none of the optional features.
+    let features: Vec<String> = proxy
+        .get(SYSGENID_INTERFACE, "Features")
         .await
-        .unwrap();
+        .unwrap_or_default();
+    // Ping SysGenID service so it starts tracking this client, at the generation
+    // it returns, which is the one we start off in.
+    let counter = if features.iter().any(|feature| feature == "register-watcher") {
+        let (counter,): (u32,) = call_service(&conn, "RegisterWatcher", ()).await?;
+        counter
+    } else {
+        // Older services start tracking on the first ack instead.
+        let counter = get_sys_gen_counter(&conn).await?;
+        ack_counter(&conn, counter).await?
+    };
+
+    Ok(Application::new(conn, true, counter))
+}
 
-    Application::new(conn, true)
+// To receive D-Bus signals we need to add a match that defines which signals should be
+// forwarded to our application.
+async fn add_new_generation_match(
+    conn: &Arc<SyncConnection>,
+    app: Arc<Mutex<Application>>,
+) -> Result<MsgMatch, dbus::Error> {
+    let mr = MatchRule::new_signal(SYSGENID_INTERFACE, "NewGeneration");
+    Ok(conn
+        .add_match(mr)
+        .await?
+        .cb(move |_, (_counter, reason): (u32, String)| {
+            app.lock().unwrap().new_generation_handler(&reason);
+            true
+        }))
 }
 
 #[tokio::main]
 pub async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let max_adjust_jitter = match std::env::args().nth(1) {
+        Some(millis) => Duration::from_millis(millis.parse()?),
+        None => DEFAULT_MAX_ADJUST_JITTER,
+    };
+
     // Connect to the D-Bus session bus (this is blocking, unfortunately).
     let (resource, conn) = connection::new_session_sync()?;
 
@@ -145,14 +328,51 @@ pub async fn main() -> Result<(), Box<dyn std::error::Error>> {
     });
 
     // Create `Application` client with tracking enabled.
-    let app = Arc::new(Mutex::new(new_tracked_app(conn.clone()).await));
+    // Without a service to start off from, there is nothing to do.
+    let mut app = new_tracked_app(conn.clone()).await?;
+    app.set_max_adjust_jitter(max_adjust_jitter);
+    let app = Arc::new(Mutex::new(app));
 
-    // To receive D-Bus signals we need to add a match that defines which signals should be forwarded
-    // to our application.
+    let incoming_signal = Arc::new(Mutex::new(Some(
+        add_new_generation_match(&conn, app.clone()).await?,
+    )));
+
+    // Follow the service going away and coming back, e.g. when restarted. The service
+    // forgets about its watchers when restarted, so register again and re-establish
+    // the NewGeneration match.
     let app2 = app.clone();
-    let mr = MatchRule::new_signal(SYSGENID_INTERFACE, "NewGeneration");
-    let incoming_signal = conn.add_match(mr).await?.cb(move |_, (_counter,): (u32,)| {
-        app2.lock().unwrap().new_generation_handler();
+    let conn2 = conn.clone();
+    let incoming_signal2 = incoming_signal.clone();
+    let mr = MatchRule::new_signal("org.freedesktop.DBus", "NameOwnerChanged")
+        .with_sender("org.freedesktop.DBus");
+    let owner_signal = conn.add_match(mr).await?.cb(
+        move |_, (name, _old_owner, new_owner): (String, String, String)| {
+            if name == SYSGENID_INTERFACE && !new_owner.is_empty() {
+                let app = app2.clone();
+                let conn = conn2.clone();
+                let incoming_signal = incoming_signal2.clone();
+                tokio::spawn(async move {
+                    let old_match = incoming_signal.lock().unwrap().take();
+                    if let Some(old_match) = old_match {
+                        if let Err(e) = conn.remove_match(old_match.token()).await {
+                            println!("Client: failed to remove NewGeneration match: {}", e);
+                        }
+                    }
+                    match add_new_generation_match(&conn, app.clone()).await {
+                        Ok(new_match) => *incoming_signal.lock().unwrap() = Some(new_match),
+                        // Generation changes would go unnoticed, don't carry on blindly.
+                        Err(e) => panic!("Client: failed to match NewGeneration: {}", e),
+                    }
+                    Application::service_appeared_handler(app).await;
+                });
+            }
+            true
+        },
+    );
+
+    let mr = MatchRule::new_signal(SYSGENID_INTERFACE, "ServiceShuttingDown");
+    let shutdown_signal = conn.add_match(mr).await?.cb(move |_, (): ()| {
+        println!("Client: SysGenID service is shutting down, generation changes won't be seen");
         true
     });
 
@@ -160,7 +380,12 @@ pub async fn main() -> Result<(), Box<dyn std::error::Error>> {
     Application::main_loop(app).await;
 
     // Needed here to ensure the "incoming_signal" object is not dropped too early
-    conn.remove_match(incoming_signal.token()).await?;
+    let incoming_signal = incoming_signal.lock().unwrap().take();
+    if let Some(incoming_signal) = incoming_signal {
+        conn.remove_match(incoming_signal.token()).await?;
+    }
+    conn.remove_match(shutdown_signal.token()).await?;
+    conn.remove_match(owner_signal.token()).await?;
 
     unreachable!()
 }
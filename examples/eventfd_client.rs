@@ -0,0 +1,103 @@
+use std::error::Error;
+use std::os::unix::io::AsRawFd;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use dbus::arg::OwnedFd;
+use dbus::message::MatchRule;
+use dbus::nonblock;
+use dbus_tokio::connection;
+use tokio::io::unix::AsyncFd;
+
+// This example shows a latency sensitive _tracked_ client, such as a crypto RNG
+// reseeder, that learns about generation changes through the eventfd handed out by
+// `GetEventFd` instead of the `NewGeneration` signal. The service writes to the
+// eventfd while bumping the generation, before any signal goes out, so the client
+// wakes up without waiting for the bus to route a signal to it.
+//
+// On each wake-up it reads the new counter, acks it, and reports how much later the
+// `NewGeneration` signal arrived.
+
+const SYSGENID_INTERFACE: &str = "com.RFC.sysgenid";
+const SYGENID_PATH: &str = "/com/RFC/sysgenid";
+
+#[tokio::main]
+pub async fn main() -> Result<(), Box<dyn Error>> {
+    // Connect to the D-Bus session bus (this is blocking, unfortunately).
+    let (resource, conn) = connection::new_session_sync()?;
+
+    // The resource is a task that should be spawned onto a tokio compatible
+    // reactor ASAP. If the resource ever finishes, you lost connection to D-Bus.
+    tokio::spawn(async {
+        let err = resource.await;
+        panic!("Lost connection to D-Bus: {}", err);
+    });
+
+    let proxy = nonblock::Proxy::new(
+        SYSGENID_INTERFACE,
+        SYGENID_PATH,
+        Duration::from_secs(2),
+        conn.clone(),
+    );
+    let (counter,): (u32,) = proxy
+        .method_call(SYSGENID_INTERFACE, "RegisterWatcher", ())
+        .await?;
+    let (event_fd,): (OwnedFd,) = proxy
+        .method_call(SYSGENID_INTERFACE, "GetEventFd", ())
+        .await?;
+    println!("EventFdClient: tracked at generation {}", counter);
+
+    // Only used to compare the signal path against the eventfd.
+    let last_wakeup: Arc<Mutex<Option<Instant>>> = Arc::new(Mutex::new(None));
+    let last_wakeup2 = last_wakeup.clone();
+    let mr = MatchRule::new_signal(SYSGENID_INTERFACE, "NewGeneration");
+    // Keep the match alive for as long as we run.
+    let _incoming_signal =
+        conn.add_match(mr)
+            .await?
+            .cb(move |_, (counter, _reason): (u32, String)| {
+                if let Some(wakeup) = last_wakeup2.lock().unwrap().take() {
+                    println!(
+                        "EventFdClient: NewGeneration {} signal arrived {} us after the eventfd",
+                        counter,
+                        wakeup.elapsed().as_micros()
+                    );
+                }
+                true
+            });
+
+    // The eventfd is non-blocking, have tokio tell us when it is readable.
+    let event_fd = AsyncFd::new(event_fd)?;
+    loop {
+        let mut guard = event_fd.readable().await?;
+        *last_wakeup.lock().unwrap() = Some(Instant::now());
+        // Reading resets the eventfd, and returns the number of bumps since the
+        // previous read.
+        let mut bumps = 0u64;
+        // Safe because we read at most 8 bytes into a u64.
+        let len = unsafe {
+            libc::read(
+                guard.get_inner().as_raw_fd(),
+                &mut bumps as *mut u64 as *mut libc::c_void,
+                std::mem::size_of::<u64>(),
+            )
+        };
+        guard.clear_ready();
+        if len < 0 {
+            // Spurious wake-up, wait for the next one.
+            continue;
+        }
+
+        // IRL reseed right away, only then look up and ack the new counter.
+        let (counter,): (u32,) = proxy
+            .method_call(SYSGENID_INTERFACE, "GetSysGenCounter", ())
+            .await?;
+        println!(
+            "EventFdClient: woken up by {} bump(s), adjusting to generation {}",
+            bumps, counter
+        );
+        let (_,): (u32,) = proxy
+            .method_call(SYSGENID_INTERFACE, "AckWatcherCounter", (counter,))
+            .await?;
+    }
+}
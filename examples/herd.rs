@@ -0,0 +1,131 @@
+use std::error::Error;
+use std::sync::Arc;
+use std::time::Duration;
+
+use dbus::arg::{PropMap, RefArg};
+use dbus::message::MatchRule;
+use dbus::nonblock;
+use dbus::nonblock::{MsgMatch, SyncConnection};
+use dbus_tokio::connection;
+use tokio::sync::Notify;
+use uuid::Uuid;
+
+// This example simulates a fleet of tracked watchers all adjusting to the same
+// generation change, the _thundering herd_ hitting the SysGenID service. Every
+// simulated watcher has its own bus connection and waits a random delay, up to
+// MAX_ADJUST_JITTER_MS, before acking a new generation.
+//
+// It triggers a generation change, waits for `SystemReady`, then prints the
+// `peak_concurrent_acks` statistic of the service. Compare runs against fresh service
+// instances with and without jitter to see it spread the load, e.g. `herd 100 0` and
+// `herd 100 2000`.
+//
+// Usage: `herd [WATCHERS] [MAX_ADJUST_JITTER_MS]`.
+
+const SYSGENID_INTERFACE: &str = "com.RFC.sysgenid";
+const SYGENID_PATH: &str = "/com/RFC/sysgenid";
+const DEFAULT_WATCHERS: usize = 50;
+const DEFAULT_MAX_ADJUST_JITTER_MS: u64 = 1000;
+
+fn connect() -> Result<Arc<SyncConnection>, Box<dyn Error>> {
+    // Connect to the D-Bus session bus (this is blocking, unfortunately).
+    let (resource, conn) = connection::new_session_sync()?;
+    // The resource is a task that should be spawned onto a tokio compatible
+    // reactor ASAP. If the resource ever finishes, you lost connection to D-Bus.
+    tokio::spawn(async {
+        let err = resource.await;
+        panic!("Lost connection to D-Bus: {}", err);
+    });
+    Ok(conn)
+}
+
+fn proxy(conn: &Arc<SyncConnection>) -> nonblock::Proxy<'static, Arc<SyncConnection>> {
+    nonblock::Proxy::new(
+        SYSGENID_INTERFACE,
+        SYGENID_PATH,
+        Duration::from_secs(5),
+        conn.clone(),
+    )
+}
+
+// Registers a simulated watcher, acking new generations after a random delay.
+async fn spawn_watcher(max_jitter_ms: u64) -> Result<MsgMatch, Box<dyn Error>> {
+    let conn = connect()?;
+    let (_,): (u32,) = proxy(&conn)
+        .method_call(SYSGENID_INTERFACE, "RegisterWatcher", ())
+        .await?;
+    let conn2 = conn.clone();
+    let mr = MatchRule::new_signal(SYSGENID_INTERFACE, "NewGeneration");
+    Ok(conn
+        .add_match(mr)
+        .await?
+        .cb(move |_, (counter, _reason): (u32, String)| {
+            let conn = conn2.clone();
+            tokio::spawn(async move {
+                let jitter_ms = Uuid::new_v4().as_u128() % (u128::from(max_jitter_ms) + 1);
+                tokio::time::sleep(Duration::from_millis(jitter_ms as u64)).await;
+                let ack: Result<(u32,), dbus::Error> = proxy(&conn)
+                    .method_call(SYSGENID_INTERFACE, "AckWatcherCounter", (counter,))
+                    .await;
+                if let Err(e) = ack {
+                    println!("Herd: watcher failed to ack: {}", e);
+                }
+            });
+            true
+        }))
+}
+
+#[tokio::main]
+pub async fn main() -> Result<(), Box<dyn Error>> {
+    let mut args = std::env::args().skip(1);
+    let watchers = match args.next() {
+        Some(watchers) => watchers.parse()?,
+        None => DEFAULT_WATCHERS,
+    };
+    let max_jitter_ms = match args.next() {
+        Some(millis) => millis.parse()?,
+        None => DEFAULT_MAX_ADJUST_JITTER_MS,
+    };
+
+    // Keep the matches alive for as long as we run.
+    let mut herd = Vec::new();
+    for _ in 0..watchers {
+        herd.push(spawn_watcher(max_jitter_ms).await?);
+    }
+    println!(
+        "Herd: {} watchers registered, adjusting within {} ms",
+        watchers, max_jitter_ms
+    );
+
+    let conn = connect()?;
+    let system_ready = Arc::new(Notify::new());
+    let system_ready2 = system_ready.clone();
+    let mr = MatchRule::new_signal(SYSGENID_INTERFACE, "SystemReady");
+    let _ready_signal = conn
+        .add_match(mr)
+        .await?
+        .cb(move |_, (adjust_duration_ms,): (u64,)| {
+            println!(
+                "Herd: all watchers adjusted after {} ms",
+                adjust_duration_ms
+            );
+            system_ready2.notify_one();
+            true
+        });
+
+    let (counter,): (u32,) = proxy(&conn)
+        .method_call(SYSGENID_INTERFACE, "TriggerSysGenUpdate", (0u32, "herd"))
+        .await?;
+    println!("Herd: triggered generation {}", counter);
+    system_ready.notified().await;
+
+    let (stats,): (PropMap,) = proxy(&conn)
+        .method_call(SYSGENID_INTERFACE, "GetStats", ())
+        .await?;
+    let peak = stats
+        .get("peak_concurrent_acks")
+        .and_then(|peak| peak.0.as_u64())
+        .unwrap_or_default();
+    println!("Herd: peak concurrent acks: {}", peak);
+    Ok(())
+}
@@ -0,0 +1,197 @@
+use std::time::Duration;
+
+use dbus::message::MatchRule;
+use dbus::nonblock;
+use dbus::nonblock::{MsgMatch, SyncConnection};
+use dbus_tokio::connection;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+
+// This example shows an `Overseer`-type application coordinating several generation
+// domains, as hosted by a service started with e.g. `--domain net --domain storage`.
+// It reconfigures the system in stages, one domain after the other:
+// 1. quiesce the domain (turn off networking for example),
+// 2. bump the generation of the domain,
+// 3. wait for the watchers of the domain to readjust, i.e. for its `SystemReady`,
+// 4. un-quiesce the domain, and only then move on to the next one.
+//
+// Usage: `multi_overseer [DOMAIN]... [--timeout-secs SECS]`, domains default to
+// `net storage` and are staged in the order given.
+//
+// Ordering guarantees an overseer can rely on across domains:
+// - Domains are independent: each has its own counter, watchers and signals, emitted
+//   from its own object path. Bumping one never changes the state of another, and the
+//   service doesn't order their convergence, the overseer does by waiting on each.
+// - The bus delivers the messages of the service to us in the order it sent them. Once
+//   the reply to a bump arrives, every signal the service sent before it did too, so a
+//   `SystemReady` of an earlier stage can't show up in a later one. One of the same
+//   domain for an older generation can still be queued up though, hence the counter
+//   check.
+// - The watchers of a domain get its `NewGeneration` only once we bump it: they never
+//   readjust ahead of their stage, as long as they too match on the object path of
+//   their domain.
+
+const SYSGENID_INTERFACE: &str = "com.RFC.sysgenid";
+const SYGENID_PATH: &str = "/com/RFC/sysgenid";
+const DEFAULT_DOMAINS: [&str; 2] = ["net", "storage"];
+// Default for how long to wait for the watchers of a domain to readjust.
+const DEFAULT_WAIT_READY_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A generation domain, served at its own object path.
+struct Domain {
+    name: String,
+    path: String,
+    // Counters the domain reported ready at, fed by its `SystemReady` match.
+    ready: mpsc::UnboundedReceiver<u32>,
+    // Kept for the matches to be removed once done.
+    matches: Vec<MsgMatch>,
+}
+
+impl Domain {
+    // Signal matches are per domain: restricting them to the object path of the domain
+    // keeps the readiness of one from being mistaken for that of another.
+    pub async fn new(conn: &SyncConnection, name: &str) -> Result<Self, dbus::Error> {
+        let path = format!("{}/{}", SYGENID_PATH, name);
+        let (ready_tx, ready) = mpsc::unbounded_channel();
+
+        let mr = MatchRule::new_signal(SYSGENID_INTERFACE, "SystemReady").with_path(path.clone());
+        let system_ready = conn.add_match(mr).await?.cb(
+            move |_, (_adjust_duration_ms, for_counter): (u64, u32)| {
+                let _ = ready_tx.send(for_counter);
+                true
+            },
+        );
+
+        // Watcher groups of the domain converge one by one before the whole domain does.
+        let domain_name = name.to_owned();
+        let mr = MatchRule::new_signal(SYSGENID_INTERFACE, "GroupReady").with_path(path.clone());
+        let group_ready = conn.add_match(mr).await?.cb(move |_, (group,): (String,)| {
+            println!("MultiOverseer: [{}] group {} ready", domain_name, group);
+            true
+        });
+
+        Ok(Domain {
+            name: name.to_owned(),
+            path,
+            ready,
+            matches: vec![system_ready, group_ready],
+        })
+    }
+
+    pub fn quiesce(&self) {
+        // Do actual quiescing instead of simple print.
+        println!("MultiOverseer: [{}] do quiesce.", self.name);
+    }
+
+    // Bumps the generation of the domain and waits for its watchers to readjust to it,
+    // at most `timeout`. Returns whether they all did.
+    pub async fn bump_and_wait(
+        &mut self,
+        conn: Arc<SyncConnection>,
+        timeout: Duration,
+    ) -> Result<bool, dbus::Error> {
+        let proxy = nonblock::Proxy::new(
+            SYSGENID_INTERFACE,
+            self.path.clone(),
+            Duration::from_secs(2),
+            conn,
+        );
+        // The bump also tells exactly which watchers we have to wait for.
+        let (counter, outdated): (u32, Vec<String>) = proxy
+            .method_call(
+                SYSGENID_INTERFACE,
+                "TriggerSysGenUpdate",
+                (0u32, format!("staged reconfiguration of {}", self.name)),
+            )
+            .await?;
+        println!(
+            "MultiOverseer: [{}] new generation counter {}, waiting for {} watchers",
+            self.name,
+            counter,
+            outdated.len()
+        );
+
+        // Without outdated watchers the domain is ready right away, no signal follows. Not
+        // so with `--pre-bump-ms`: watchers are only outdated once the grace period ends.
+        if outdated.is_empty() {
+            return Ok(true);
+        }
+        let wait = async {
+            while let Some(for_counter) = self.ready.recv().await {
+                // Readiness for a generation older than ours is stale.
+                if for_counter >= counter {
+                    return true;
+                }
+            }
+            false
+        };
+        Ok(tokio::time::timeout(timeout, wait).await.unwrap_or(false))
+    }
+
+    pub fn unquiesce(&self) {
+        // Do actual unquiescing.
+        println!("MultiOverseer: [{}] do un-quiesce.", self.name);
+    }
+}
+
+#[tokio::main]
+pub async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let mut names = Vec::new();
+    let mut wait_ready_timeout = DEFAULT_WAIT_READY_TIMEOUT;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--timeout-secs" {
+            let secs = args.next().ok_or("'--timeout-secs' requires a value")?;
+            wait_ready_timeout = Duration::from_secs(secs.parse()?);
+        } else {
+            names.push(arg);
+        }
+    }
+    if names.is_empty() {
+        names = DEFAULT_DOMAINS
+            .iter()
+            .map(|name| name.to_string())
+            .collect();
+    }
+
+    // Connect to the D-Bus session bus (this is blocking, unfortunately).
+    let (resource, conn) = connection::new_session_sync()?;
+
+    // The resource is a task that should be spawned onto a tokio compatible
+    // reactor ASAP. If the resource ever finishes, you lost connection to D-Bus.
+    tokio::spawn(async {
+        let err = resource.await;
+        panic!("Lost connection to D-Bus: {}", err);
+    });
+
+    // Match the signals of every domain before bumping any of them.
+    let mut domains = Vec::new();
+    for name in names.iter() {
+        domains.push(Domain::new(&conn, name).await?);
+    }
+
+    for domain in domains.iter_mut() {
+        domain.quiesce();
+        if domain
+            .bump_and_wait(conn.clone(), wait_ready_timeout)
+            .await?
+        {
+            println!("MultiOverseer: [{}] all watchers readjusted.", domain.name);
+        } else {
+            println!(
+                "MultiOverseer: [{}] timed out waiting for watchers, moving on regardless.",
+                domain.name
+            );
+        }
+        domain.unquiesce();
+    }
+    println!("MultiOverseer: System ready!");
+
+    for domain in domains {
+        for msg_match in domain.matches {
+            conn.remove_match(msg_match.token()).await?;
+        }
+    }
+
+    Ok(())
+}
@@ -1,5 +1,6 @@
 use std::time::Duration;
 
+use dbus::arg::{PropMap, RefArg};
 use dbus::message::MatchRule;
 use dbus::nonblock;
 use dbus::nonblock::{MsgMatch, SyncConnection};
@@ -11,11 +12,16 @@ use tokio;
 /// IRL such an app would:
 /// 1. quiesce the system (turn off networking for example) before a snapshot happens,
 /// 2. bump sys gen id after system is loaded from snapshot,
-/// 3. wait for all consumer apps to readjust to the new environment (wait for SystemReady signal),
+/// 3. wait for all consumer apps to readjust to the new environment (steps 2 and 3 are a
+///    single `BumpAndWait` call, leaving no window for the readjustment to be missed),
 /// 4. un-quiesce system (rollback step 1) bringing it back to active state.
+///
+/// Usage: `overseer [WAIT_READY_TIMEOUT_SECS]`.
 
 const SYSGENID_INTERFACE: &str = "com.RFC.sysgenid";
 const SYGENID_PATH: &str = "/com/RFC/sysgenid";
+// Default for how long to wait for watchers to readjust to a new generation.
+const DEFAULT_WAIT_READY_TIMEOUT: Duration = Duration::from_secs(60);
 
 #[derive(PartialEq)]
 enum SystemState {
@@ -31,6 +37,8 @@ struct Overseer {
     system_state: SystemState,
     // Connection to SysGenID DBus server.
     conn: Arc<SyncConnection>,
+    // Lowest generation our bump can lead to, readiness for older ones is stale.
+    min_ready_counter: u32,
 }
 
 impl Overseer {
@@ -38,6 +46,7 @@ impl Overseer {
         Overseer {
             system_state: SystemState::Ready,
             conn,
+            min_ready_counter: 0,
         }
     }
 
@@ -48,10 +57,34 @@ impl Overseer {
         let ovs2 = ovs.clone();
         let mr = MatchRule::new_signal(SYSGENID_INTERFACE, "SystemReady");
         let conn = ovs.lock().unwrap().conn.clone();
-        conn.add_match(mr).await.unwrap().cb(move |_, (): ()| {
-            ovs2.lock().unwrap().system_adjusted_signal_handler();
-            true
-        })
+        conn.add_match(mr).await.unwrap().cb(
+            move |_, (adjust_duration_ms, for_counter): (u64, u32)| {
+                ovs2.lock()
+                    .unwrap()
+                    .system_adjusted_signal_handler(adjust_duration_ms, for_counter);
+                true
+            },
+        )
+    }
+
+    // Follow the outdated watchers count as they readjust, instead of polling for it.
+    pub async fn register_outdated_count_handler(ovs: Arc<Mutex<Self>>) -> MsgMatch {
+        let mr = MatchRule::new_signal("org.freedesktop.DBus.Properties", "PropertiesChanged")
+            .with_path(SYGENID_PATH);
+        let conn = ovs.lock().unwrap().conn.clone();
+        conn.add_match(mr).await.unwrap().cb(
+            move |_, (iface, changed, _): (String, PropMap, Vec<String>)| {
+                if iface == SYSGENID_INTERFACE {
+                    if let Some(count) = changed.get("OutdatedWatcherCount") {
+                        println!(
+                            "Overseer: {} outdated watchers left",
+                            count.as_u64().unwrap_or_default()
+                        );
+                    }
+                }
+                true
+            },
+        )
     }
 
     pub fn quiesce(&mut self) {
@@ -61,60 +94,71 @@ impl Overseer {
         self.system_state = SystemState::Quiesced;
     }
 
-    pub async fn bump_generation(&mut self) {
+    // Bumps the generation and waits for watchers to readjust to it, at most `timeout`.
+    pub async fn bump_and_wait(ovs: Arc<Mutex<Self>>, timeout: Duration) {
+        ovs.lock().unwrap().system_state = SystemState::Adjusting;
         let proxy = nonblock::Proxy::new(
             SYSGENID_INTERFACE,
             SYGENID_PATH,
-            Duration::from_secs(2),
-            self.conn.clone(),
+            // The service only replies once the wait is over.
+            timeout + Duration::from_secs(2),
+            ovs.lock().unwrap().conn.clone(),
         );
-        println!("Overseer: trigger new generation (min gen counter 0)!");
-        let (): () = proxy
-            .method_call(SYSGENID_INTERFACE, "TriggerSysGenUpdate", (0 as u32,))
+        // Any generation up to the current one is older than the one we are about to
+        // trigger, even if its SystemReady only arrives after our bump.
+        let (current,): (u32,) = proxy
+            .method_call(SYSGENID_INTERFACE, "GetSysGenCounter", ())
+            .await
+            .unwrap();
+        ovs.lock().unwrap().min_ready_counter = current + 1;
+        println!("Overseer: trigger new generation (min gen counter 0) and wait for watchers!");
+        let (counter, ready): (u32, bool) = proxy
+            .method_call(
+                SYSGENID_INTERFACE,
+                "BumpAndWait",
+                (0u32, "snapshot restore", timeout.as_millis() as u32),
+            )
             .await
             .unwrap();
+        println!("Overseer: new generation counter {}", counter);
+        if ready {
+            println!("Overseer: All watchers readjusted. Moving on.");
+        } else {
+            let names = ovs.lock().unwrap().get_outdated_watchers().await;
+            println!(
+                "Overseer: Timed out waiting for {}, moving on regardless.",
+                names.join(", ")
+            );
+        }
+        ovs.lock().unwrap().system_state = SystemState::Adjusted;
     }
 
-    pub async fn count_outdated_watchers(&self) -> u32 {
+    pub async fn get_outdated_watchers(&self) -> Vec<String> {
         let proxy = nonblock::Proxy::new(
             SYSGENID_INTERFACE,
             SYGENID_PATH,
             Duration::from_secs(2),
             self.conn.clone(),
         );
-        println!("Overseer: call 'CountOutdatedWatchers'");
-        let (count,): (u32,) = proxy
-            .method_call(SYSGENID_INTERFACE, "CountOutdatedWatchers", ())
+        let (watchers,): (Vec<String>,) = proxy
+            .method_call(SYSGENID_INTERFACE, "GetOutdatedWatchers", ())
             .await
             .unwrap();
-        println!("Overseer: 'CountOutdatedWatchers' method result {}", count);
-        count
+        watchers
     }
 
-    pub async fn wait_system_adjust(ovs: Arc<Mutex<Self>>) {
-        ovs.lock().unwrap().system_state = SystemState::Adjusting;
-
-        // Check if there are any outdated watchers to wait for.
-        let outdated_watchers = ovs.lock().unwrap().count_outdated_watchers().await;
-        if outdated_watchers != 0 {
+    fn system_adjusted_signal_handler(&mut self, adjust_duration_ms: u64, for_counter: u32) {
+        if for_counter < self.min_ready_counter {
             println!(
-                "Overseer: There are {} outdated watchers across the system. Waiting for them...",
-                outdated_watchers
+                "Overseer: Ignoring stale SystemReady for generation {}.",
+                for_counter
             );
-            // IRL this would be conditional variable or something other than polling.
-            let mut interval = tokio::time::interval(Duration::from_secs(1));
-            while ovs.lock().unwrap().system_state != SystemState::Adjusted {
-                interval.tick().await;
-            }
-        } else {
-            println!("Overseer: There are no outdated watchers across the system. Moving on.");
-            ovs.lock().unwrap().system_state = SystemState::Adjusted;
+            return;
         }
-    }
-
-    fn system_adjusted_signal_handler(&mut self) {
-        println!("Overseer: System is adjusted (got SystemReady DBus signal)!");
-        self.system_state = SystemState::Adjusted;
+        println!(
+            "Overseer: System is adjusted to generation {} (got SystemReady DBus signal) after {} ms!",
+            for_counter, adjust_duration_ms
+        );
     }
 
     pub fn unquiesce(&mut self) {
@@ -128,6 +172,11 @@ impl Overseer {
 
 #[tokio::main]
 pub async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let wait_ready_timeout = match std::env::args().nth(1) {
+        Some(secs) => Duration::from_secs(secs.parse()?),
+        None => DEFAULT_WAIT_READY_TIMEOUT,
+    };
+
     // Connect to the D-Bus session bus (this is blocking, unfortunately).
     let (resource, conn) = connection::new_session_sync()?;
 
@@ -142,14 +191,16 @@ pub async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let ovs = Arc::new(Mutex::new(Overseer::new(conn.clone())));
     // Register handler for SystemReady signal.
     let incoming_signal = Overseer::register_system_ready_handler(ovs.clone()).await;
+    // Register handler for outdated watchers count changes.
+    let count_changes = Overseer::register_outdated_count_handler(ovs.clone()).await;
 
     ovs.lock().unwrap().quiesce();
-    ovs.lock().unwrap().bump_generation().await;
-    Overseer::wait_system_adjust(ovs.clone()).await;
+    Overseer::bump_and_wait(ovs.clone(), wait_ready_timeout).await;
     ovs.lock().unwrap().unquiesce();
 
     // Needed here to ensure the "incoming_signal" object is not dropped too early
     conn.remove_match(incoming_signal.token()).await?;
+    conn.remove_match(count_changes.token()).await?;
 
     Ok(())
 }
@@ -0,0 +1,70 @@
+use std::time::Duration;
+
+use dbus::message::MatchRule;
+use dbus::nonblock;
+use dbus_tokio::connection;
+
+// This example shows a misbehaving _tracked_ client: it registers with the SysGenID
+// service by acking the current generation at startup, but then never readjusts nor
+// acks again.
+//
+// It is meant as a fixture for exercising the _outdated watcher_ failure mode. After
+// a generation bump, an operator running it next to the service would observe:
+// - `CountOutdatedWatchers` (and the `OutdatedWatcherCount` property) stuck at 1, and
+//   `GetOutdatedWatchers` listing this client's bus name,
+// - `examples/overseer` waiting on this client until its wait for `SystemReady` times out,
+//   since `SystemReady` is never sent,
+// - with `--ack-timeout-secs`, the service dropping this client from tracking once the
+//   timeout expires and sending `SystemReady`,
+// - `ForceSystemReady` dropping this client from tracking and sending `SystemReady`
+//   right away.
+
+const SYSGENID_INTERFACE: &str = "com.RFC.sysgenid";
+const SYGENID_PATH: &str = "/com/RFC/sysgenid";
+
+#[tokio::main]
+pub async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // Connect to the D-Bus session bus (this is blocking, unfortunately).
+    let (resource, conn) = connection::new_session_sync()?;
+
+    // The resource is a task that should be spawned onto a tokio compatible
+    // reactor ASAP. If the resource ever finishes, you lost connection to D-Bus.
+    tokio::spawn(async {
+        let err = resource.await;
+        panic!("Lost connection to D-Bus: {}", err);
+    });
+
+    // Get tracked by acking the current generation, just like a well-behaved client.
+    let proxy = nonblock::Proxy::new(
+        SYSGENID_INTERFACE,
+        SYGENID_PATH,
+        Duration::from_secs(2),
+        conn.clone(),
+    );
+    let (counter,): (u32,) = proxy
+        .method_call(SYSGENID_INTERFACE, "GetSysGenCounter", ())
+        .await?;
+    let (_,): (u32,) = proxy
+        .method_call(SYSGENID_INTERFACE, "AckWatcherCounter", (counter,))
+        .await?;
+    println!("StubbornClient: tracked at generation {}", counter);
+
+    // Listen for new generations, only to make a point of ignoring them.
+    let mr = MatchRule::new_signal(SYSGENID_INTERFACE, "NewGeneration");
+    // Keep the match alive for as long as we run.
+    let _incoming_signal =
+        conn.add_match(mr)
+            .await?
+            .cb(move |_, (counter, reason): (u32, String)| {
+                println!(
+                    "StubbornClient: got NewGeneration {} (reason '{}'), not acking it!",
+                    counter, reason
+                );
+                true
+            });
+
+    // Stay connected, and thus tracked, forever.
+    loop {
+        tokio::time::sleep(Duration::from_secs(3600)).await;
+    }
+}
@@ -8,7 +8,7 @@ cargo +stable build --examples
 # Kill old instances of SysGenID DBus service
 killall sysgenid-dbus
 # Start new instance of SysGenID DBus service
-cargo +stable run &
+cargo +stable run -- --session &
 
 # Give the service a chance to start
 sleep 1
@@ -0,0 +1,340 @@
+use dbus::strings::{BusName, Path};
+use log::LevelFilter;
+use std::path::PathBuf;
+use std::time::Duration;
+use sysgenid_dbus::{BusType, CorruptStatePolicy, SysgenidConfig};
+
+/// Client subcommand, run against an already running service.
+#[derive(Debug)]
+pub enum Command {
+    /// Print the current sys gen counter.
+    Get,
+    /// Trigger a generation bump.
+    Bump { min_gen: u32, reason: String },
+    /// Print the number of outdated watchers.
+    CountOutdated,
+    /// Ack the given counter.
+    Ack { counter: u32 },
+    /// Trigger a generation bump and wait for the system to converge.
+    Oneshot {
+        min_gen: u32,
+        reason: String,
+        timeout: Duration,
+    },
+    /// Check that the service responds.
+    Ping { timeout: Duration },
+}
+
+// Default for how long `oneshot` waits for the system to converge.
+const DEFAULT_ONESHOT_TIMEOUT: Duration = Duration::from_secs(60);
+// Default for how long `ping` waits for the service to respond.
+const DEFAULT_PING_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Command line arguments of the SysGenID DBus service.
+#[derive(Debug)]
+pub struct Args {
+    /// Service instance options.
+    pub config: SysgenidConfig,
+    /// Maximum level of the service logs.
+    pub log_level: LevelFilter,
+    /// Whether to print significant events to stdout as JSON lines.
+    pub json_events: bool,
+    /// Client subcommand to run instead of serving, if any.
+    pub command: Option<Command>,
+}
+
+impl Args {
+    /// Parses the service arguments, `args` must not include the program name.
+    pub fn parse<I: Iterator<Item = String>>(args: I) -> Result<Self, String> {
+        // Real system daemons run as root, default to the system bus for them.
+        let mut config = SysgenidConfig::default();
+        if unsafe { libc::geteuid() } == 0 {
+            config.bus = BusType::System;
+        }
+        let mut log_level = LevelFilter::Info;
+        let mut json_events = false;
+
+        // Options from the config file come first, so the command line overrides them.
+        let args: Vec<String> = args.collect();
+        if let Some(pos) = args.iter().position(|arg| arg == "--config") {
+            let path = args
+                .get(pos + 1)
+                .ok_or_else(|| "missing value for '--config'".to_owned())?;
+            config.load_file(path.as_ref()).map_err(|e| e.to_string())?;
+        }
+        let mut cli_domains = false;
+        let mut cli_track_deny = false;
+        let mut command = None;
+        // Options of the bump and oneshot subcommands.
+        let mut min_gen = None;
+        let mut reason = None;
+        let mut timeout = None;
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--config" => {
+                    option_value(&mut args, &arg)?;
+                }
+                "--system" => config.bus = BusType::System,
+                "--session" => config.bus = BusType::Session,
+                "--bus-address" => config.set_bus_address(option_value(&mut args, &arg)?)?,
+                "--allow-tcp" => config.allow_tcp = true,
+                "--bus-name" => config.bus_name = option_value(&mut args, &arg)?,
+                "--replace" | "--replace-existing" => config.replace = true,
+                "--allow-replacement" => config.allow_replacement = true,
+                "--do-not-queue" => config.do_not_queue = true,
+                "--object-path" => config.object_path = option_value(&mut args, &arg)?,
+                "--state-file" => config.state_file = option_value(&mut args, &arg)?.into(),
+                "--on-corrupt-state" => {
+                    config.on_corrupt_state =
+                        CorruptStatePolicy::parse(&option_value(&mut args, &arg)?)?
+                }
+                "--seed-from" => config.seed_from = Some(option_value(&mut args, &arg)?.into()),
+                "--counter-file" => config.counter_file = option_value(&mut args, &arg)?.into(),
+                "--counter-file-mode" => {
+                    config.set_counter_file_mode(&option_value(&mut args, &arg)?)?
+                }
+                "--domain" => {
+                    // Domains given on the command line replace those from the file.
+                    if !cli_domains {
+                        config.domains.clear();
+                        cli_domains = true;
+                    }
+                    config.add_domain(option_value(&mut args, &arg)?)?;
+                }
+                "--track-deny" => {
+                    // Same for the names excluded from tracking.
+                    if !cli_track_deny {
+                        config.track_deny.clear();
+                        cli_track_deny = true;
+                    }
+                    config.add_track_deny(option_value(&mut args, &arg)?)?;
+                }
+                "--enable-polkit" => config.enable_polkit = true,
+                "--polkit-action" => config.polkit_action = option_value(&mut args, &arg)?,
+                "--ack-timeout-secs" => {
+                    let secs = option_value(&mut args, &arg)?;
+                    let secs = secs
+                        .parse()
+                        .map_err(|_| format!("invalid ack timeout '{}'", secs))?;
+                    config.ack_timeout = Some(Duration::from_secs(secs));
+                }
+                "--slow-convergence-warn-secs" => {
+                    let secs = option_value(&mut args, &arg)?;
+                    let secs = secs
+                        .parse()
+                        .map_err(|_| format!("invalid slow convergence warning '{}'", secs))?;
+                    config.slow_convergence_warn = Some(Duration::from_secs(secs));
+                }
+                "--auto-bump-interval-secs" => {
+                    let secs = option_value(&mut args, &arg)?;
+                    let secs = secs
+                        .parse()
+                        .ok()
+                        .filter(|secs| *secs > 0)
+                        .ok_or_else(|| format!("invalid auto bump interval '{}'", secs))?;
+                    config.auto_bump_interval = Some(Duration::from_secs(secs));
+                }
+                "--max-bumps-per-sec" => {
+                    let max_bumps = option_value(&mut args, &arg)?;
+                    let max_bumps = max_bumps
+                        .parse()
+                        .ok()
+                        .filter(|max_bumps| *max_bumps > 0)
+                        .ok_or_else(|| format!("invalid max bumps per sec '{}'", max_bumps))?;
+                    config.max_bumps_per_sec = Some(max_bumps);
+                }
+                "--max-watchers" => {
+                    let max_watchers = option_value(&mut args, &arg)?;
+                    let max_watchers = max_watchers
+                        .parse()
+                        .map_err(|_| format!("invalid max watchers '{}'", max_watchers))?;
+                    config.max_watchers = Some(max_watchers);
+                }
+                "--heartbeat-timeout-secs" => {
+                    let secs = option_value(&mut args, &arg)?;
+                    let secs = secs
+                        .parse()
+                        .map_err(|_| format!("invalid heartbeat timeout '{}'", secs))?;
+                    config.heartbeat_timeout = Some(Duration::from_secs(secs));
+                }
+                "--reap-interval-secs" => {
+                    let secs = option_value(&mut args, &arg)?;
+                    let secs = secs
+                        .parse()
+                        .ok()
+                        .filter(|secs| *secs > 0)
+                        .ok_or_else(|| format!("invalid reap interval '{}'", secs))?;
+                    config.reap_interval = Duration::from_secs(secs);
+                }
+                "--bump-history" => {
+                    let len = option_value(&mut args, &arg)?;
+                    config.bump_history = len
+                        .parse()
+                        .map_err(|_| format!("invalid bump history length '{}'", len))?;
+                }
+                "--max-reason-len" => {
+                    let len = option_value(&mut args, &arg)?;
+                    config.max_reason_len = len
+                        .parse()
+                        .map_err(|_| format!("invalid maximum reason length '{}'", len))?;
+                }
+                "--coalesce-ms" => {
+                    let millis = option_value(&mut args, &arg)?;
+                    let millis = millis
+                        .parse()
+                        .map_err(|_| format!("invalid coalesce window '{}'", millis))?;
+                    config.coalesce_window = Some(Duration::from_millis(millis));
+                }
+                "--pre-bump-ms" => {
+                    let millis = option_value(&mut args, &arg)?;
+                    let millis = millis
+                        .parse()
+                        .map_err(|_| format!("invalid pre-bump grace period '{}'", millis))?;
+                    config.pre_bump_grace = Some(Duration::from_millis(millis));
+                }
+                "--metrics-addr" => {
+                    let addr = option_value(&mut args, &arg)?;
+                    let addr = addr
+                        .parse()
+                        .map_err(|_| format!("invalid metrics address '{}'", addr))?;
+                    config.metrics_addr = Some(addr);
+                }
+                #[cfg(target_os = "linux")]
+                "--chardev" => config.chardev = Some(option_value(&mut args, &arg)?.into()),
+                "--async" => config.async_server = true,
+                "--targeted-signals" => config.targeted_signals = true,
+                "--resolve-names" => config.resolve_names = true,
+                "--json-events" => json_events = true,
+                "--log-level" => {
+                    let level = option_value(&mut args, &arg)?;
+                    log_level = level
+                        .parse()
+                        .map_err(|_| format!("invalid log level '{}'", level))?;
+                }
+                "--min" => {
+                    let min = option_value(&mut args, &arg)?;
+                    let min = min
+                        .parse()
+                        .map_err(|_| format!("invalid min generation '{}'", min))?;
+                    min_gen = Some(min);
+                }
+                "--reason" => reason = Some(option_value(&mut args, &arg)?),
+                "--timeout-secs" => {
+                    let secs = option_value(&mut args, &arg)?;
+                    let secs = secs
+                        .parse()
+                        .map_err(|_| format!("invalid timeout '{}'", secs))?;
+                    timeout = Some(Duration::from_secs(secs));
+                }
+                "get" | "bump" | "count-outdated" | "ack" | "oneshot" | "ping"
+                    if command.is_none() =>
+                {
+                    command = Some(match arg.as_str() {
+                        "get" => Command::Get,
+                        "bump" => Command::Bump {
+                            min_gen: 0,
+                            reason: String::new(),
+                        },
+                        "oneshot" => Command::Oneshot {
+                            min_gen: 0,
+                            reason: String::new(),
+                            timeout: DEFAULT_ONESHOT_TIMEOUT,
+                        },
+                        "count-outdated" => Command::CountOutdated,
+                        "ping" => Command::Ping {
+                            timeout: DEFAULT_PING_TIMEOUT,
+                        },
+                        _ => {
+                            let counter = option_value(&mut args, &arg)?;
+                            let counter = counter
+                                .parse()
+                                .map_err(|_| format!("invalid counter '{}'", counter))?;
+                            Command::Ack { counter }
+                        }
+                    });
+                }
+                _ => return Err(format!("unknown argument '{}'", arg)),
+            }
+        }
+
+        config.use_user_dirs(&user_runtime_dir(), &user_state_dir());
+
+        let bus_name = &config.bus_name;
+        BusName::new(bus_name.as_str())
+            .map_err(|e| format!("invalid bus name '{}': {}", bus_name, e))?;
+        let object_path = &config.object_path;
+        Path::new(object_path.as_str())
+            .map_err(|e| format!("invalid object path '{}': {}", object_path, e))?;
+
+        match &mut command {
+            Some(Command::Bump {
+                min_gen: bump_min_gen,
+                reason: bump_reason,
+            }) if timeout.is_none() => {
+                *bump_min_gen = min_gen.unwrap_or(0);
+                *bump_reason = reason.unwrap_or_default();
+            }
+            Some(Command::Oneshot {
+                min_gen: bump_min_gen,
+                reason: bump_reason,
+                timeout: wait_timeout,
+            }) => {
+                *bump_min_gen = min_gen.unwrap_or(0);
+                *bump_reason = reason.unwrap_or_default();
+                *wait_timeout = timeout.unwrap_or(DEFAULT_ONESHOT_TIMEOUT);
+            }
+            Some(Command::Ping {
+                timeout: ping_timeout,
+            }) if min_gen.is_none() && reason.is_none() => {
+                *ping_timeout = timeout.unwrap_or(DEFAULT_PING_TIMEOUT);
+            }
+            _ if timeout.is_some() => {
+                return Err("'--timeout-secs' only applies to 'oneshot' and 'ping'".to_owned());
+            }
+            _ if min_gen.is_some() || reason.is_some() => {
+                return Err("'--min' and '--reason' only apply to 'bump' and 'oneshot'".to_owned());
+            }
+            _ => (),
+        }
+        // The kernel interface knows of a single generation.
+        if config.chardev.is_some() && !config.domains.is_empty() {
+            return Err("'--chardev' doesn't support '--domain'".to_owned());
+        }
+        if command.is_some() && config.domains.len() > 1 {
+            return Err("subcommands take at most one '--domain'".to_owned());
+        }
+
+        Ok(Args {
+            config,
+            log_level,
+            json_events,
+            command,
+        })
+    }
+}
+
+/// Runtime directory of the user, `$XDG_RUNTIME_DIR`, or one of their own under the
+/// temporary directory when unset.
+fn user_runtime_dir() -> PathBuf {
+    match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => std::env::temp_dir().join(format!("sysgenid-{}", unsafe { libc::geteuid() })),
+    }
+}
+
+/// State directory of the user, `$XDG_STATE_HOME` or `~/.local/state`, falling back to
+/// their runtime directory without a home directory.
+fn user_state_dir() -> PathBuf {
+    match (std::env::var_os("XDG_STATE_HOME"), std::env::var_os("HOME")) {
+        (Some(dir), _) if !dir.is_empty() => PathBuf::from(dir),
+        (_, Some(home)) if !home.is_empty() => PathBuf::from(home).join(".local/state"),
+        _ => user_runtime_dir(),
+    }
+}
+
+fn option_value<I: Iterator<Item = String>>(args: &mut I, option: &str) -> Result<String, String> {
+    args.next()
+        .ok_or_else(|| format!("missing value for '{}'", option))
+}
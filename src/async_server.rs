@@ -0,0 +1,185 @@
+use crate::{lock, name_owner_changed_match, name_ownership_matches, shutdown, Service};
+use dbus::channel::MatchingReceiver;
+use dbus::message::{MatchRule, MessageType};
+use dbus::nonblock::stdintf::org_freedesktop_dbus::RequestNameReply;
+use dbus::nonblock::SyncConnection;
+use dbus::Message;
+use dbus_crossroads::Crossroads;
+use dbus_tokio::connection;
+use log::{error, info, warn};
+use std::error::Error;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::sync::Notify;
+
+// Handling of an incoming message, queued for the dispatcher.
+type Job = Box<dyn FnOnce(&SyncConnection) + Send>;
+
+/// Hands the messages matching `mr` over to `handler`, through the dispatcher `jobs`
+/// queue rather than right on the reactor.
+fn receive<H>(c: &SyncConnection, mr: MatchRule<'static>, jobs: &UnboundedSender<Job>, handler: H)
+where
+    H: Fn(Message, &SyncConnection) + Send + Sync + 'static,
+{
+    let jobs = jobs.clone();
+    let handler = Arc::new(handler);
+    c.start_receive(
+        mr,
+        Box::new(move |msg: Message, _: &SyncConnection| {
+            let handler = handler.clone();
+            // Only fails once the dispatcher is gone, when shutting down.
+            let _ = jobs.send(Box::new(move |c: &SyncConnection| handler(msg, c)));
+            true
+        }),
+    );
+}
+
+/// Runs the `queued` jobs one at a time, in the order their messages arrived, on the
+/// blocking thread pool: handlers make blocking round trips to the bus daemon and to
+/// polkit, which would stall the reactor. Wakes up the serve loop after each one, as
+/// handled messages may have moved timers.
+async fn dispatch(
+    mut queued: UnboundedReceiver<Job>,
+    c: Arc<SyncConnection>,
+    handled: Arc<Notify>,
+) {
+    while let Some(job) = queued.recv().await {
+        let c = c.clone();
+        match tokio::task::spawn_blocking(move || job(&c)).await {
+            Ok(()) => (),
+            Err(e) if e.is_panic() => error!("message handler panicked"),
+            // The reactor is shutting down, the remaining messages go unhandled.
+            Err(_) => break,
+        }
+        handled.notify_one();
+    }
+}
+
+/// Serves clients from a tokio reactor until asked to shut down. Behaves just like
+/// the blocking server, with timers running as reactor tasks rather than between
+/// blocking waits for messages, so other background tasks can share the reactor.
+pub fn serve(service: Arc<Service>, cr: Crossroads) -> Result<(), Box<dyn Error>> {
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?
+        .block_on(serve_async(service, cr))
+}
+
+async fn serve_async(service: Arc<Service>, cr: Crossroads) -> Result<(), Box<dyn Error>> {
+    let config = &service.config;
+    // Start up a connection to the requested bus and request a name.
+    let (resource, c) = connection::from_channel::<SyncConnection>(config.open_bus()?)?;
+    // The resource drives the connection, it only ever finishes when the connection
+    // is lost.
+    let mut resource = tokio::spawn(resource);
+    info!(
+        "SysGenID DBus service connected to the {}",
+        config.describe_bus()
+    );
+
+    let handled = Arc::new(Notify::new());
+    let (jobs, queued) = mpsc::unbounded_channel();
+    tokio::spawn(dispatch(queued, c.clone(), handled.clone()));
+
+    // Follow the ownership of the bus name, from before asking for it.
+    for mr in name_ownership_matches()? {
+        let shared = service.clone();
+        receive(&c, mr, &jobs, move |msg, _| {
+            shared.handle_name_ownership(&msg)
+        });
+    }
+    // Wait in line behind another instance unless asked not to: serving without owning
+    // the name would leave clients talking to the other one.
+    let reply = c
+        .request_name(
+            config.bus_name.as_str(),
+            config.allow_replacement,
+            config.replace,
+            config.do_not_queue,
+        )
+        .await
+        .map_err(|e| format!("failed to acquire bus name {}: {}", config.bus_name, e))?;
+    match reply {
+        RequestNameReply::PrimaryOwner | RequestNameReply::AlreadyOwner => {
+            service.owns_name.store(true, Ordering::SeqCst);
+        }
+        RequestNameReply::InQueue if !config.do_not_queue => {
+            info!("waiting in line for bus name {}", config.bus_name);
+            while !service.owns_name() {
+                tokio::select! {
+                    res = &mut resource => {
+                        let err = match res {
+                            Ok(err) => err.to_string(),
+                            Err(err) => err.to_string(),
+                        };
+                        return Err(format!("lost connection to the bus: {}", err).into());
+                    }
+                    _ = handled.notified() => (),
+                }
+            }
+        }
+        RequestNameReply::Exists | RequestNameReply::InQueue => {
+            return Err(service.name_taken_error())
+        }
+    }
+
+    // Track connections on the bus to find out when any active client/watcher disconnects.
+    let (mr, match_strs) = name_owner_changed_match(config)?;
+    for match_str in match_strs.iter() {
+        c.add_match_no_cb(match_str).await?;
+    }
+    let shared = service.clone();
+    receive(&c, mr, &jobs, move |msg, c| {
+        shared.handle_name_owner_changed(&msg, c)
+    });
+
+    // Replies to watcher callbacks.
+    for msg_type in [MessageType::MethodReturn, MessageType::Error].iter() {
+        let mut mr = MatchRule::new();
+        mr.msg_type = Some(*msg_type);
+        let shared = service.clone();
+        receive(&c, mr, &jobs, move |msg, c| {
+            shared.handle_callback_reply(msg, c)
+        });
+    }
+
+    // Blocks on the bus daemon, from the serve loop rather than on the reactor.
+    service.start()?;
+    // Hand all incoming method calls over to crossroads, timing them.
+    let cr = Mutex::new(cr);
+    let shared = service.clone();
+    receive(&c, MatchRule::new_method_call(), &jobs, move |msg, c| {
+        let method = msg.member().map(|m| m.to_string()).unwrap_or_default();
+        let started = Instant::now();
+        // Crossroads only fails messages it can't dispatch, keep serving the others.
+        if lock(&cr).handle_message(msg, c).is_err() {
+            warn!("failed to handle {} call", method);
+        }
+        shared.serve_stats.record(&method, started.elapsed());
+    });
+
+    // Serve clients until asked to shut down, waking up in time for timers.
+    let mut next_reap = Instant::now() + config.reap_interval;
+    while !shutdown::requested() {
+        tokio::select! {
+            res = &mut resource => {
+                let err = match res {
+                    Ok(err) => err.to_string(),
+                    Err(err) => err.to_string(),
+                };
+                return Err(format!("lost connection to the bus: {}", err).into());
+            }
+            _ = handled.notified() => (),
+            _ = tokio::time::sleep(service.next_timeout()) => (),
+        }
+        service.process_timers(&*c, &mut next_reap);
+        service.call_watchers(&*c);
+    }
+
+    service.stop(&*c);
+    // Everything queued went out by the time the bus replies.
+    c.release_name(config.bus_name.as_str()).await?;
+    Ok(())
+}
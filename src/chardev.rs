@@ -0,0 +1,336 @@
+use std::convert::TryInto;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use log::{debug, error, info, warn};
+use sysgenid_dbus::{RemovalReason, Signal, Sysgenid, SysgenidError};
+
+use crate::lock;
+
+// Ioctls of the Linux SysGenID character device, `<linux/sysgenid.h>`. All of them take
+// their argument, if any, by value and return their result as the ioctl return value.
+const SYSGENID_IOCTL: u32 = 0xE4;
+const SYSGENID_SET_WATCHER_TRACKING: u32 = io(1);
+const SYSGENID_WAIT_WATCHERS: u32 = io(2);
+const SYSGENID_TRIGGER_GEN_UPDATE: u32 = io(3);
+const SYSGENID_GET_OUTDATED_WATCHERS: u32 = io(4);
+// Not in the kernel interface, which hands the counter out through read(): lets ioctl
+// based clients get it without a read buffer.
+const SYSGENID_GET_GENERATION: u32 = io(5);
+
+// CUSE protocol, `<linux/fuse.h>`.
+const CUSE_DEVICE: &str = "/dev/cuse";
+const FUSE_KERNEL_VERSION: u32 = 7;
+const FUSE_KERNEL_MINOR_VERSION: u32 = 31;
+const FUSE_OPEN: u32 = 14;
+const FUSE_READ: u32 = 15;
+const FUSE_WRITE: u32 = 16;
+const FUSE_RELEASE: u32 = 18;
+const FUSE_FLUSH: u32 = 25;
+const FUSE_INTERRUPT: u32 = 36;
+const FUSE_DESTROY: u32 = 38;
+const FUSE_IOCTL: u32 = 39;
+const CUSE_INIT: u32 = 4096;
+const FOPEN_DIRECT_IO: u32 = 1 << 0;
+const FOPEN_NONSEEKABLE: u32 = 1 << 2;
+const IN_HEADER_LEN: usize = 40;
+const OUT_HEADER_LEN: usize = 16;
+const MAX_WRITE: u32 = 4096;
+// Requests are read whole, their payload is at most MAX_WRITE bytes.
+const REQUEST_BUF_LEN: usize = 64 * 1024;
+
+// Watcher ids of open device files, which aren't bus names: never looked up on the bus.
+const CHARDEV_WATCHER_PREFIX: &str = "chardev:";
+
+const fn io(nr: u32) -> u32 {
+    (SYSGENID_IOCTL << 8) | nr
+}
+
+/// Whether `watcher_id` is the watcher of an open file of the chardev.
+pub fn is_chardev_watcher(watcher_id: &str) -> bool {
+    watcher_id.starts_with(CHARDEV_WATCHER_PREFIX)
+}
+
+/// Serves the SysGenID character device at `dev_path`, e.g. `/dev/sysgenid`, through
+/// CUSE, for applications written against the kernel driver.
+///
+/// Every open file is a watcher, tracked once enabled with
+/// `SYSGENID_SET_WATCHER_TRACKING` and acking by writing the counter it adjusted to;
+/// `read()` returns the counter as a native-endian u32. Signals the operations lead to
+/// are handed to `signal_fn`, to be sent out over the bus. Access is controlled by the
+/// permissions of the device node.
+pub fn spawn<F>(dev_path: &Path, sysgenid: Arc<Mutex<Sysgenid>>, signal_fn: F) -> io::Result<()>
+where
+    F: FnMut(Vec<Signal>) + Send + 'static,
+{
+    // CUSE creates the device under /dev, named after the rest of the path.
+    let dev_name = dev_path
+        .strip_prefix("/dev")
+        .ok()
+        .and_then(Path::to_str)
+        .filter(|name| !name.is_empty())
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("chardev {} is not under /dev", dev_path.display()),
+            )
+        })?
+        .to_owned();
+    let cuse = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(CUSE_DEVICE)
+        .map_err(|e| io::Error::new(e.kind(), format!("failed to open {}: {}", CUSE_DEVICE, e)))?;
+    let mut server = Server {
+        cuse,
+        dev_name,
+        sysgenid,
+        signal_fn,
+    };
+    thread::Builder::new()
+        .name("chardev".to_owned())
+        .spawn(move || {
+            if let Err(e) = server.serve() {
+                error!("chardev stopped: {}", e);
+            }
+        })?;
+    Ok(())
+}
+
+struct Server<F> {
+    cuse: File,
+    dev_name: String,
+    sysgenid: Arc<Mutex<Sysgenid>>,
+    signal_fn: F,
+}
+
+/// Header of a request of the kernel.
+struct Request<'a> {
+    opcode: u32,
+    unique: u64,
+    pid: u32,
+    body: &'a [u8],
+}
+
+impl<F: FnMut(Vec<Signal>)> Server<F> {
+    fn serve(&mut self) -> io::Result<()> {
+        let mut buf = vec![0u8; REQUEST_BUF_LEN];
+        loop {
+            let len = match self.cuse.read(&mut buf) {
+                Ok(len) => len,
+                // The request was interrupted before we got to read it.
+                Err(e) if e.raw_os_error() == Some(libc::ENOENT) => continue,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            if len < IN_HEADER_LEN {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "short CUSE request",
+                ));
+            }
+            let request = Request {
+                opcode: u32_at(&buf, 4),
+                unique: u64_at(&buf, 8),
+                pid: u32_at(&buf, 32),
+                body: &buf[IN_HEADER_LEN..len],
+            };
+            if request.opcode == FUSE_DESTROY {
+                info!("chardev {} destroyed", self.dev_name);
+                return Ok(());
+            }
+            let reply = self.handle(&request);
+            match reply {
+                // Interrupts are never replied to.
+                None => (),
+                Some(reply) => self.reply(request.unique, reply)?,
+            }
+        }
+    }
+
+    /// Handles `request`, returning the reply payload or a negated errno.
+    fn handle(&mut self, request: &Request) -> Option<Result<Vec<u8>, i32>> {
+        let body = request.body;
+        let reply = match request.opcode {
+            CUSE_INIT => self.init(body),
+            FUSE_OPEN => {
+                // The kernel hands out a fresh `unique` per request, reuse it as handle.
+                let mut out = request.unique.to_ne_bytes().to_vec();
+                out.extend_from_slice(&(FOPEN_DIRECT_IO | FOPEN_NONSEEKABLE).to_ne_bytes());
+                out.extend_from_slice(&0u32.to_ne_bytes());
+                Ok(out)
+            }
+            FUSE_READ => {
+                let size = u32_at(body, 16) as usize;
+                let counter = lock(&self.sysgenid).sysgen_counter().to_ne_bytes();
+                if size < counter.len() {
+                    Err(-libc::EINVAL)
+                } else {
+                    Ok(counter.to_vec())
+                }
+            }
+            FUSE_WRITE => {
+                let (fh, size) = (u64_at(body, 0), u32_at(body, 16));
+                self.ack(fh, request.pid, &body[40.min(body.len())..])
+                    .map(|()| [size.to_ne_bytes(), 0u32.to_ne_bytes()].concat())
+            }
+            FUSE_RELEASE => {
+                self.untrack(u64_at(body, 0), RemovalReason::Disconnected);
+                Ok(Vec::new())
+            }
+            FUSE_FLUSH => Ok(Vec::new()),
+            FUSE_IOCTL => {
+                let (fh, cmd, arg) = (u64_at(body, 0), u32_at(body, 12), u64_at(body, 16));
+                self.ioctl(fh, request.pid, cmd, arg).map(|result| {
+                    let mut out = result.to_ne_bytes().to_vec();
+                    // No flags, nor iovecs to retry with: arguments go by value.
+                    out.extend_from_slice(&[0u8; 12]);
+                    out
+                })
+            }
+            FUSE_INTERRUPT => return None,
+            opcode => {
+                debug!("unsupported CUSE request {}", opcode);
+                Err(-libc::ENOSYS)
+            }
+        };
+        Some(reply)
+    }
+
+    fn init(&self, body: &[u8]) -> Result<Vec<u8>, i32> {
+        let (major, minor) = (u32_at(body, 0), u32_at(body, 4));
+        if major != FUSE_KERNEL_VERSION {
+            error!("unsupported CUSE protocol version {}.{}", major, minor);
+            return Err(-libc::EPROTO);
+        }
+        let fields = [
+            FUSE_KERNEL_VERSION,
+            minor.min(FUSE_KERNEL_MINOR_VERSION),
+            0,
+            // Restricted ioctls: the kernel decodes their arguments itself.
+            0,
+            MAX_WRITE,
+            MAX_WRITE,
+            // Let the kernel pick the device numbers.
+            0,
+            0,
+        ];
+        let mut out: Vec<u8> = fields.iter().flat_map(|f| f.to_ne_bytes()).collect();
+        out.extend_from_slice(&[0u8; 40]);
+        out.extend_from_slice(format!("DEVNAME={}\0", self.dev_name).as_bytes());
+        info!("serving chardev /dev/{}", self.dev_name);
+        Ok(out)
+    }
+
+    fn ioctl(&mut self, fh: u64, pid: u32, cmd: u32, arg: u64) -> Result<i32, i32> {
+        let watcher_id = watcher_id(fh);
+        let mut signals = Vec::new();
+        let res = {
+            let mut sysgenid = lock(&self.sysgenid);
+            match cmd {
+                SYSGENID_GET_GENERATION => Ok(sysgenid.sysgen_counter() as i32),
+                SYSGENID_GET_OUTDATED_WATCHERS => Ok(sysgenid.outdated_count() as i32),
+                SYSGENID_TRIGGER_GEN_UPDATE => {
+                    let min_gen = arg.try_into().unwrap_or(u32::MAX);
+                    sysgenid
+                        .bump_generation(min_gen, "chardev", |signal| signals.push(signal))
+                        .map(|_| 0)
+                        .map_err(errno)
+                }
+                SYSGENID_SET_WATCHER_TRACKING if arg != 0 => sysgenid
+                    .register_watcher(
+                        &watcher_id,
+                        None,
+                        || true,
+                        || Some(pid),
+                        |signal| signals.push(signal),
+                    )
+                    .map(|_| 0)
+                    .map_err(errno),
+                SYSGENID_SET_WATCHER_TRACKING => {
+                    sysgenid.remove_watcher(&watcher_id, RemovalReason::Unregistered, |signal| {
+                        signals.push(signal)
+                    });
+                    Ok(0)
+                }
+                // Would block the only thread serving the device, use WaitSystemReady.
+                SYSGENID_WAIT_WATCHERS => Err(-libc::EOPNOTSUPP),
+                _ => Err(-libc::ENOTTY),
+            }
+        };
+        (self.signal_fn)(signals);
+        res
+    }
+
+    fn ack(&mut self, fh: u64, pid: u32, data: &[u8]) -> Result<(), i32> {
+        let counter = match data.try_into() {
+            Ok(bytes) => u32::from_ne_bytes(bytes),
+            Err(_) => return Err(-libc::EINVAL),
+        };
+        let mut signals = Vec::new();
+        let res = lock(&self.sysgenid).ack_watcher_gen_counter(
+            &watcher_id(fh),
+            counter,
+            None,
+            || true,
+            || Some(pid),
+            |signal| signals.push(signal),
+        );
+        (self.signal_fn)(signals);
+        res.map_err(errno)
+    }
+
+    fn untrack(&mut self, fh: u64, reason: RemovalReason) {
+        let mut signals = Vec::new();
+        lock(&self.sysgenid).remove_watcher(&watcher_id(fh), reason, |signal| signals.push(signal));
+        (self.signal_fn)(signals);
+    }
+
+    fn reply(&mut self, unique: u64, reply: Result<Vec<u8>, i32>) -> io::Result<()> {
+        let (error, payload) = match reply {
+            Ok(payload) => (0, payload),
+            Err(error) => (error, Vec::new()),
+        };
+        let mut msg = Vec::with_capacity(OUT_HEADER_LEN + payload.len());
+        msg.extend_from_slice(&((OUT_HEADER_LEN + payload.len()) as u32).to_ne_bytes());
+        msg.extend_from_slice(&error.to_ne_bytes());
+        msg.extend_from_slice(&unique.to_ne_bytes());
+        msg.extend_from_slice(&payload);
+        match self.cuse.write(&msg) {
+            // The request was interrupted meanwhile, nobody waits for the reply.
+            Err(e) if e.raw_os_error() == Some(libc::ENOENT) => Ok(()),
+            res => res.map(|_| ()),
+        }
+    }
+}
+
+fn watcher_id(fh: u64) -> String {
+    format!("{}{}", CHARDEV_WATCHER_PREFIX, fh)
+}
+
+/// Errno the kernel driver fails with for `err`.
+fn errno(err: SysgenidError) -> i32 {
+    warn!("chardev request failed: {}", err);
+    match err {
+        SysgenidError::PersistFailed(_) => -libc::EIO,
+        SysgenidError::RateLimited | SysgenidError::BumpsPaused => -libc::EAGAIN,
+        SysgenidError::TooManyWatchers(_) | SysgenidError::Draining => -libc::EBUSY,
+        SysgenidError::CounterExhausted => -libc::EOVERFLOW,
+        _ => -libc::EINVAL,
+    }
+}
+
+fn u32_at(buf: &[u8], offset: usize) -> u32 {
+    buf.get(offset..offset + 4)
+        .and_then(|bytes| bytes.try_into().ok())
+        .map_or(0, u32::from_ne_bytes)
+}
+
+fn u64_at(buf: &[u8], offset: usize) -> u64 {
+    buf.get(offset..offset + 8)
+        .and_then(|bytes| bytes.try_into().ok())
+        .map_or(0, u64::from_ne_bytes)
+}
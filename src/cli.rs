@@ -0,0 +1,81 @@
+use crate::args::Command;
+use dbus::blocking::Connection;
+use std::convert::TryFrom;
+use std::error::Error;
+use std::time::Duration;
+use sysgenid_dbus::{SysgenidConfig, SYGENID_INTERFACE};
+
+/// Runs the client `command` against the service instance described by `config`,
+/// printing the result to stdout.
+pub fn run(command: Command, config: &SysgenidConfig) -> Result<(), Box<dyn Error>> {
+    let conn = Connection::from(config.open_bus()?);
+    // A single domain at most, checked when parsing arguments.
+    let object_path = config.domain_configs().remove(0).object_path;
+    let proxy = conn.with_proxy(
+        config.bus_name.as_str(),
+        object_path.as_str(),
+        Duration::from_millis(5000),
+    );
+    let iface = SYGENID_INTERFACE;
+
+    if let Command::Oneshot {
+        min_gen,
+        reason,
+        timeout,
+    } = command
+    {
+        return oneshot(&conn, config, &object_path, min_gen, reason, timeout);
+    }
+    if let Command::Ping { timeout } = command {
+        let proxy = conn.with_proxy(config.bus_name.as_str(), object_path.as_str(), timeout);
+        let (status,): (String,) = proxy.method_call(iface, "Ping", ())?;
+        println!("{}", status);
+        return Ok(());
+    }
+
+    let (result,): (u32,) = match command {
+        Command::Get => proxy.method_call(iface, "GetSysGenCounter", ())?,
+        Command::Bump { min_gen, reason } => {
+            proxy.method_call(iface, "TriggerSysGenUpdate", (min_gen, reason))?
+        }
+        Command::CountOutdated => proxy.method_call(iface, "CountOutdatedWatchers", ())?,
+        Command::Ack { counter } => proxy.method_call(iface, "AckWatcherCounter", (counter,))?,
+        Command::Oneshot { .. } | Command::Ping { .. } => unreachable!(),
+    };
+    println!("{}", result);
+    Ok(())
+}
+
+/// Triggers a generation bump and waits up to `timeout` for the system to converge,
+/// printing the new counter. Fails if the system didn't converge in time.
+fn oneshot(
+    conn: &Connection,
+    config: &SysgenidConfig,
+    object_path: &str,
+    min_gen: u32,
+    reason: String,
+    timeout: Duration,
+) -> Result<(), Box<dyn Error>> {
+    // The service only replies once the wait is over.
+    let proxy = conn.with_proxy(
+        config.bus_name.as_str(),
+        object_path,
+        timeout + Duration::from_millis(5000),
+    );
+    let timeout_ms = u32::try_from(timeout.as_millis()).unwrap_or(u32::MAX);
+    let (counter, ready): (u32, bool) = proxy.method_call(
+        SYGENID_INTERFACE,
+        "BumpAndWait",
+        (min_gen, reason, timeout_ms),
+    )?;
+    println!("{}", counter);
+    if !ready {
+        return Err(format!(
+            "system did not converge to generation {} within {} s",
+            counter,
+            timeout.as_secs()
+        )
+        .into());
+    }
+    Ok(())
+}
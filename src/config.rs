@@ -0,0 +1,494 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use dbus::channel::Channel;
+use serde::Deserialize;
+
+use crate::config_file::{self, ConfigFile};
+use crate::mapped_counter::{DEFAULT_COUNTER_FILE, DEFAULT_COUNTER_FILE_MODE};
+use crate::polkit::DEFAULT_POLKIT_ACTION;
+use crate::state_file::DEFAULT_STATE_FILE;
+use crate::{
+    DEFAULT_BUMP_HISTORY, DEFAULT_MAX_REASON_LEN, DEFAULT_REAP_INTERVAL, SYGENID_INTERFACE,
+    SYGENID_PATH,
+};
+
+/// DBus bus the service connects to.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum BusType {
+    Session,
+    System,
+}
+
+impl fmt::Display for BusType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BusType::Session => write!(f, "session"),
+            BusType::System => write!(f, "system"),
+        }
+    }
+}
+
+/// What to do at startup when the state file can't be parsed, e.g. truncated by a crash.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum CorruptStatePolicy {
+    /// Refuse to start.
+    Fail,
+    /// Start over from counter 0, overwriting the state file.
+    Reset,
+}
+
+impl CorruptStatePolicy {
+    /// Parses a policy as named on the command line, `fail` or `reset`.
+    pub fn parse(policy: &str) -> Result<Self, String> {
+        match policy {
+            "fail" => Ok(CorruptStatePolicy::Fail),
+            "reset" => Ok(CorruptStatePolicy::Reset),
+            _ => Err(format!("invalid corrupt state policy '{}'", policy)),
+        }
+    }
+}
+
+/// Options of a SysGenID service instance.
+#[derive(Clone, Debug)]
+pub struct SysgenidConfig {
+    pub bus: BusType,
+    /// Address of the bus to connect to instead of the `bus` one, e.g. a private bus.
+    pub bus_address: Option<String>,
+    /// Whether `bus_address` may point at a bus over TCP.
+    pub allow_tcp: bool,
+    /// Well-known bus name to request.
+    pub bus_name: String,
+    /// Whether to take the bus name over from its current owner, if it allows it.
+    pub replace: bool,
+    /// Whether to let other connections take the bus name over from us.
+    pub allow_replacement: bool,
+    /// Whether to fail when the bus name is taken rather than waiting in line for it.
+    pub do_not_queue: bool,
+    /// Object path implementing the sysgenid interface.
+    pub object_path: String,
+    /// File the sys gen counter is persisted to across restarts.
+    pub state_file: PathBuf,
+    /// What to do when the state file is corrupt.
+    pub on_corrupt_state: CorruptStatePolicy,
+    /// External source the sys gen counter is seeded from and never falls behind of.
+    pub seed_from: Option<PathBuf>,
+    /// File the sys gen counter is exported to through shared memory.
+    pub counter_file: PathBuf,
+    /// Permissions of the counter file.
+    pub counter_file_mode: u32,
+    /// How long outdated watchers have to ack a new generation before being dropped.
+    pub ack_timeout: Option<Duration>,
+    /// How long outdated watchers can take before `ConvergenceStalled` is sent.
+    pub slow_convergence_warn: Option<Duration>,
+    /// Upper bound on the rate of generation bumps, unlimited if `None`.
+    pub max_bumps_per_sec: Option<u32>,
+    /// Window during which successive bumps are notified as a single one.
+    pub coalesce_window: Option<Duration>,
+    /// Grace period between announcing a bump with `PreBump` and performing it, bumps
+    /// are performed right away if `None`.
+    pub pre_bump_grace: Option<Duration>,
+    /// Period of the generation bumps the service triggers on its own, e.g. for soak
+    /// testing watchers, disabled if `None`.
+    pub auto_bump_interval: Option<Duration>,
+    /// Upper bound on the number of tracked watchers, unlimited if `None`.
+    pub max_watchers: Option<usize>,
+    /// How long heartbeating watchers can go silent before being reported as stale.
+    pub heartbeat_timeout: Option<Duration>,
+    /// How often to check that tracked watchers are still on the bus.
+    pub reap_interval: Duration,
+    /// Whether to send `NewGeneration` to each tracked watcher rather than broadcast it.
+    pub targeted_signals: bool,
+    /// Unique or well-known bus names whose acks are accepted without tracking them.
+    pub track_deny: Vec<String>,
+    /// Whether watcher signals and listings also carry the well-known names their
+    /// connection owns.
+    pub resolve_names: bool,
+    /// Number of recent generation bumps remembered for `GetBumpHistory`.
+    pub bump_history: usize,
+    /// Maximum length of bump reasons, in bytes.
+    pub max_reason_len: usize,
+    /// Whether privileged methods require polkit authorization.
+    pub enable_polkit: bool,
+    /// Polkit action id checked for privileged methods.
+    pub polkit_action: String,
+    /// Independent generation domains, each served at `<object_path>/<domain>`.
+    /// A single domain is served at `object_path` when empty.
+    pub domains: Vec<String>,
+    /// Address to serve Prometheus metrics on, disabled if `None`.
+    pub metrics_addr: Option<SocketAddr>,
+    /// Character device under /dev to emulate the SysGenID kernel driver at through
+    /// CUSE, disabled if `None`.
+    pub chardev: Option<PathBuf>,
+    /// Whether to serve from a tokio reactor rather than a blocking loop.
+    pub async_server: bool,
+}
+
+impl Default for SysgenidConfig {
+    fn default() -> Self {
+        SysgenidConfig {
+            bus: BusType::Session,
+            bus_address: None,
+            allow_tcp: false,
+            bus_name: SYGENID_INTERFACE.to_owned(),
+            replace: false,
+            allow_replacement: false,
+            do_not_queue: false,
+            object_path: SYGENID_PATH.to_owned(),
+            state_file: PathBuf::from(DEFAULT_STATE_FILE),
+            on_corrupt_state: CorruptStatePolicy::Reset,
+            seed_from: None,
+            counter_file: PathBuf::from(DEFAULT_COUNTER_FILE),
+            counter_file_mode: DEFAULT_COUNTER_FILE_MODE,
+            ack_timeout: None,
+            slow_convergence_warn: None,
+            max_bumps_per_sec: None,
+            coalesce_window: None,
+            pre_bump_grace: None,
+            auto_bump_interval: None,
+            max_watchers: None,
+            heartbeat_timeout: None,
+            reap_interval: DEFAULT_REAP_INTERVAL,
+            targeted_signals: false,
+            track_deny: Vec::new(),
+            resolve_names: false,
+            bump_history: DEFAULT_BUMP_HISTORY,
+            max_reason_len: DEFAULT_MAX_REASON_LEN,
+            enable_polkit: false,
+            polkit_action: DEFAULT_POLKIT_ACTION.to_owned(),
+            domains: Vec::new(),
+            metrics_addr: None,
+            chardev: None,
+            async_server: false,
+        }
+    }
+}
+
+impl SysgenidConfig {
+    /// Overrides the options set in the configuration file at `path`, a flat TOML table
+    /// whose keys are named after the corresponding command line options.
+    pub fn load_file(&mut self, path: &Path) -> io::Result<()> {
+        let invalid = |msg: String| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid config file {}: {}", path.display(), msg),
+            )
+        };
+        let text = fs::read_to_string(path).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("failed to read config file {}: {}", path.display(), e),
+            )
+        })?;
+        let file = config_file::parse(&text).map_err(invalid)?;
+        self.apply_file(file).map_err(invalid)
+    }
+
+    /// Sets the options of `file` which are set, validating them as on the command line.
+    fn apply_file(&mut self, file: ConfigFile) -> Result<(), String> {
+        if let Some(bus) = file.bus {
+            self.bus = bus;
+        }
+        if let Some(address) = file.bus_address {
+            self.set_bus_address(address)?;
+        }
+        set_if(&mut self.allow_tcp, file.allow_tcp);
+        set_if(&mut self.bus_name, file.bus_name);
+        set_if(&mut self.replace, file.replace);
+        set_if(&mut self.allow_replacement, file.allow_replacement);
+        set_if(&mut self.do_not_queue, file.do_not_queue);
+        set_if(&mut self.object_path, file.object_path);
+        set_if(&mut self.state_file, file.state_file);
+        set_if(&mut self.on_corrupt_state, file.on_corrupt_state);
+        if file.seed_from.is_some() {
+            self.seed_from = file.seed_from;
+        }
+        set_if(&mut self.counter_file, file.counter_file);
+        if let Some(mode) = file.counter_file_mode {
+            self.set_counter_file_mode(&mode)?;
+        }
+        if let Some(secs) = file.ack_timeout_secs {
+            self.ack_timeout = Some(Duration::from_secs(secs));
+        }
+        if let Some(secs) = file.slow_convergence_warn_secs {
+            self.slow_convergence_warn = Some(Duration::from_secs(secs));
+        }
+        if let Some(max_bumps) = file.max_bumps_per_sec {
+            if max_bumps == 0 {
+                return Err(format!("invalid max bumps per sec '{}'", max_bumps));
+            }
+            self.max_bumps_per_sec = Some(max_bumps);
+        }
+        if file.max_watchers.is_some() {
+            self.max_watchers = file.max_watchers;
+        }
+        if let Some(secs) = file.heartbeat_timeout_secs {
+            self.heartbeat_timeout = Some(Duration::from_secs(secs));
+        }
+        if let Some(secs) = file.reap_interval_secs {
+            if secs == 0 {
+                return Err(format!("invalid reap interval '{}'", secs));
+            }
+            self.reap_interval = Duration::from_secs(secs);
+        }
+        set_if(&mut self.targeted_signals, file.targeted_signals);
+        if let Some(names) = file.track_deny {
+            self.track_deny.clear();
+            for name in names {
+                self.add_track_deny(name)?;
+            }
+        }
+        set_if(&mut self.resolve_names, file.resolve_names);
+        set_if(&mut self.bump_history, file.bump_history);
+        set_if(&mut self.max_reason_len, file.max_reason_len);
+        if let Some(millis) = file.coalesce_ms {
+            self.coalesce_window = Some(Duration::from_millis(millis));
+        }
+        if let Some(millis) = file.pre_bump_ms {
+            self.pre_bump_grace = Some(Duration::from_millis(millis));
+        }
+        if let Some(secs) = file.auto_bump_interval_secs {
+            if secs == 0 {
+                return Err(format!("invalid auto bump interval '{}'", secs));
+            }
+            self.auto_bump_interval = Some(Duration::from_secs(secs));
+        }
+        set_if(&mut self.enable_polkit, file.enable_polkit);
+        set_if(&mut self.polkit_action, file.polkit_action);
+        if let Some(domains) = file.domains {
+            self.domains.clear();
+            for domain in domains {
+                self.add_domain(domain)?;
+            }
+        }
+        if file.metrics_addr.is_some() {
+            self.metrics_addr = file.metrics_addr;
+        }
+        if file.chardev.is_some() {
+            self.chardev = file.chardev;
+        }
+        set_if(&mut self.async_server, file.async_server);
+        Ok(())
+    }
+
+    /// Sets the address of the bus to connect to, a D-Bus server address such as
+    /// "unix:path=/run/test-bus".
+    pub fn set_bus_address(&mut self, address: String) -> Result<(), String> {
+        if !is_bus_address(&address) {
+            return Err(format!("invalid bus address '{}'", address));
+        }
+        self.bus_address = Some(address);
+        Ok(())
+    }
+
+    /// Describes the bus to connect to, for logs.
+    pub fn describe_bus(&self) -> String {
+        match &self.bus_address {
+            Some(address) => format!("bus at {}", address),
+            None => format!("{} bus", self.bus),
+        }
+    }
+
+    /// Opens a private connection to the bus to connect to, registered with the bus.
+    /// Bus addresses over TCP are refused unless `allow_tcp` is set.
+    pub fn open_bus(&self) -> Result<Channel, dbus::Error> {
+        match &self.bus_address {
+            Some(address) => {
+                // TCP transports are neither encrypted nor tied to local credentials,
+                // anyone reaching the port can talk to the bus.
+                if !self.allow_tcp && is_tcp_bus_address(address) {
+                    return Err(dbus::Error::new_custom(
+                        "org.freedesktop.DBus.Error.BadAddress",
+                        &format!(
+                            "refusing to connect to TCP bus at {} without --allow-tcp",
+                            address
+                        ),
+                    ));
+                }
+                let mut channel = Channel::open_private(address)?;
+                channel.register()?;
+                Ok(channel)
+            }
+            None => Channel::get_private(match self.bus {
+                BusType::Session => dbus::channel::BusType::Session,
+                BusType::System => dbus::channel::BusType::System,
+            }),
+        }
+    }
+
+    /// Moves the counter and state files under the per-user `runtime_dir` and
+    /// `state_dir` when left at their system-wide defaults, for instances on the session
+    /// bus: those usually run unprivileged, without write access to /run or /var/lib.
+    pub fn use_user_dirs(&mut self, runtime_dir: &Path, state_dir: &Path) {
+        if self.bus != BusType::Session {
+            return;
+        }
+        if self.state_file == Path::new(DEFAULT_STATE_FILE) {
+            self.state_file = state_dir.join("sysgenid").join("state");
+        }
+        if self.counter_file == Path::new(DEFAULT_COUNTER_FILE) {
+            self.counter_file = runtime_dir.join("sysgenid").join("counter");
+        }
+    }
+
+    /// Sets the counter file permissions from the octal `mode`, e.g. "0640".
+    pub fn set_counter_file_mode(&mut self, mode: &str) -> Result<(), String> {
+        self.counter_file_mode = u32::from_str_radix(mode, 8)
+            .ok()
+            .filter(|mode| *mode <= 0o7777)
+            .ok_or_else(|| format!("invalid counter file mode '{}'", mode))?;
+        Ok(())
+    }
+
+    /// Excludes the connection named `name` from watcher tracking, `name` being a valid
+    /// unique or well-known bus name.
+    pub fn add_track_deny(&mut self, name: String) -> Result<(), String> {
+        if dbus::strings::BusName::new(name.as_str()).is_err() {
+            return Err(format!("invalid bus name '{}'", name));
+        }
+        if !self.track_deny.contains(&name) {
+            self.track_deny.push(name);
+        }
+        Ok(())
+    }
+
+    /// Adds the generation domain `domain`, which must be a valid and unique name.
+    pub fn add_domain(&mut self, domain: String) -> Result<(), String> {
+        // Domains become object path elements.
+        if domain.is_empty()
+            || !domain
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_')
+        {
+            return Err(format!("invalid domain '{}'", domain));
+        }
+        if self.domains.contains(&domain) {
+            return Err(format!("duplicate domain '{}'", domain));
+        }
+        self.domains.push(domain);
+        Ok(())
+    }
+
+    /// Returns the configuration of each generation domain. Every domain gets its own
+    /// object path, state file and counter file, derived from the domain name.
+    pub fn domain_configs(&self) -> Vec<SysgenidConfig> {
+        if self.domains.is_empty() {
+            return vec![self.clone()];
+        }
+        self.domains
+            .iter()
+            .map(|domain| SysgenidConfig {
+                object_path: format!("{}/{}", self.object_path.trim_end_matches('/'), domain),
+                state_file: with_suffix(&self.state_file, domain),
+                counter_file: with_suffix(&self.counter_file, domain),
+                domains: Vec::new(),
+                ..self.clone()
+            })
+            .collect()
+    }
+
+    /// Capabilities of the sysgenid interface served under this configuration, as
+    /// listed by its `Features` property.
+    pub fn features(&self) -> Vec<String> {
+        let mut features = vec![
+            "bump-and-wait",
+            "bump-history",
+            "callback-watchers",
+            "convergence-histogram",
+            "drain-mode",
+            "epoch",
+            "event-fd",
+            "groups",
+            "heartbeats",
+            "missed-generations",
+            "notify-threshold",
+            "pause-bumps",
+            "ping",
+            "register-watcher",
+            "set-generation",
+            "sub-watchers",
+            "wait-system-ready",
+            "watcher-budgets",
+        ];
+        // Behaviors clients can notice, depending on the options.
+        if self.ack_timeout.is_some() {
+            features.push("ack-timeout");
+        }
+        if self.auto_bump_interval.is_some() {
+            features.push("auto-bump");
+        }
+        if cfg!(target_os = "linux") && self.chardev.is_some() {
+            features.push("chardev");
+        }
+        if self.coalesce_window.is_some() {
+            features.push("coalescing");
+        }
+        if self.slow_convergence_warn.is_some() {
+            features.push("convergence-stalled");
+        }
+        if self.enable_polkit {
+            features.push("polkit");
+        }
+        if self.pre_bump_grace.is_some() {
+            features.push("pre-bump");
+        }
+        if self.resolve_names {
+            features.push("resolve-names");
+        }
+        if self.targeted_signals {
+            features.push("targeted-signals");
+        }
+        features.into_iter().map(str::to_owned).collect()
+    }
+}
+
+/// Whether `address` has the shape of a D-Bus server address: semicolon separated
+/// `transport:key=value,...` entries. libdbus reports what it can't connect to.
+fn is_bus_address(address: &str) -> bool {
+    let entries: Vec<&str> = address.split(';').filter(|e| !e.is_empty()).collect();
+    !entries.is_empty() && entries.iter().all(|entry| is_bus_address_entry(entry))
+}
+
+fn is_bus_address_entry(entry: &str) -> bool {
+    let (transport, params) = match entry.split_once(':') {
+        Some(entry) => entry,
+        None => return false,
+    };
+    !transport.is_empty()
+        && params
+            .split(',')
+            .filter(|param| !param.is_empty())
+            .all(|param| match param.split_once('=') {
+                Some((key, _)) => !key.is_empty(),
+                None => false,
+            })
+}
+
+/// Whether any entry of the bus `address` uses a TCP transport.
+fn is_tcp_bus_address(address: &str) -> bool {
+    address
+        .split(';')
+        .filter_map(|entry| entry.split_once(':'))
+        .any(|(transport, _)| transport == "tcp" || transport == "nonce-tcp")
+}
+
+/// Overwrites `option` with `value`, if set.
+fn set_if<T>(option: &mut T, value: Option<T>) {
+    if let Some(value) = value {
+        *option = value;
+    }
+}
+
+/// Appends `.<suffix>` to the file name of `path`.
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(".");
+    path.push(suffix);
+    path.into()
+}
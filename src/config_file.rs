@@ -0,0 +1,54 @@
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use serde::Deserialize;
+
+use crate::config::{BusType, CorruptStatePolicy};
+
+/// Options set in a configuration file, a flat TOML table whose keys are named after the
+/// corresponding command line options. Unset options are `None`, unknown keys and
+/// tables are rejected.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigFile {
+    pub bus: Option<BusType>,
+    pub bus_address: Option<String>,
+    pub allow_tcp: Option<bool>,
+    pub bus_name: Option<String>,
+    pub replace: Option<bool>,
+    pub allow_replacement: Option<bool>,
+    pub do_not_queue: Option<bool>,
+    pub object_path: Option<String>,
+    pub state_file: Option<PathBuf>,
+    pub on_corrupt_state: Option<CorruptStatePolicy>,
+    pub seed_from: Option<PathBuf>,
+    pub counter_file: Option<PathBuf>,
+    /// Octal string, e.g. "0640".
+    pub counter_file_mode: Option<String>,
+    pub ack_timeout_secs: Option<u64>,
+    pub slow_convergence_warn_secs: Option<u64>,
+    pub max_bumps_per_sec: Option<u32>,
+    pub max_watchers: Option<usize>,
+    pub heartbeat_timeout_secs: Option<u64>,
+    pub reap_interval_secs: Option<u64>,
+    pub targeted_signals: Option<bool>,
+    pub track_deny: Option<Vec<String>>,
+    pub resolve_names: Option<bool>,
+    pub bump_history: Option<usize>,
+    pub max_reason_len: Option<usize>,
+    pub coalesce_ms: Option<u64>,
+    pub pre_bump_ms: Option<u64>,
+    pub auto_bump_interval_secs: Option<u64>,
+    pub enable_polkit: Option<bool>,
+    pub polkit_action: Option<String>,
+    pub domains: Option<Vec<String>>,
+    pub metrics_addr: Option<SocketAddr>,
+    pub chardev: Option<PathBuf>,
+    #[serde(rename = "async")]
+    pub async_server: Option<bool>,
+}
+
+/// Parses the configuration file `text`. Errors point at the offending line.
+pub fn parse(text: &str) -> Result<ConfigFile, String> {
+    toml::from_str(text).map_err(|e| e.to_string())
+}
@@ -0,0 +1,112 @@
+use std::fmt;
+use std::io;
+
+use dbus_crossroads::MethodErr;
+
+use crate::{
+    BUMPS_PAUSED_ERROR, COUNTER_EXHAUSTED_ERROR, DRAINING_ERROR, INVALID_COUNTER_ERROR,
+    INVALID_REASON_ERROR, INVALID_SUB_ID_ERROR, PERSIST_FAILED_ERROR, RATE_LIMITED_ERROR,
+    STALE_COUNTER_ERROR, TOO_MANY_WATCHERS_ERROR, UNKNOWN_WATCHER_ERROR, WATCHER_GONE_ERROR,
+    WATCHER_NOT_OUTDATED_ERROR,
+};
+
+/// Errors of the sysgenid interface methods.
+///
+/// Each variant maps to a stable D-Bus error name, see `name()`, which clients can match
+/// on; the error message is meant for humans only.
+#[derive(Debug)]
+pub enum SysgenidError {
+    /// An ack of a counter older than the current one.
+    StaleCounter { counter: u32, current: u32 },
+    /// An ack of a counter newer than the current one, which was never handed out.
+    InvalidCounter { counter: u32, current: u32 },
+    /// A bump with a reason which is too long or not printable.
+    InvalidReason(String),
+    /// A sub-watcher id which is empty, too long or not printable.
+    InvalidSubId(String),
+    /// A bump coming in faster than the configured rate limit.
+    RateLimited,
+    /// A bump while the counter is saturated at `u32::MAX`.
+    CounterExhausted,
+    /// A bump while bumps are paused.
+    BumpsPaused,
+    /// A generation that could not be persisted, and thus was not entered.
+    PersistFailed(io::Error),
+    /// A call about a watcher which is not tracked.
+    UnknownWatcher(String),
+    /// A call about the lag of a tracked watcher which is up to date.
+    WatcherNotOutdated(String),
+    /// A first ack of a watcher which already left the bus.
+    WatcherGone(String),
+    /// A first ack of a watcher while the configured watcher limit is reached.
+    TooManyWatchers(usize),
+    /// A first ack of a watcher while new watchers are turned away.
+    Draining,
+}
+
+impl SysgenidError {
+    /// D-Bus error name of the error.
+    pub fn name(&self) -> &'static str {
+        match self {
+            SysgenidError::StaleCounter { .. } => STALE_COUNTER_ERROR,
+            SysgenidError::InvalidCounter { .. } => INVALID_COUNTER_ERROR,
+            SysgenidError::InvalidReason(_) => INVALID_REASON_ERROR,
+            SysgenidError::InvalidSubId(_) => INVALID_SUB_ID_ERROR,
+            SysgenidError::RateLimited => RATE_LIMITED_ERROR,
+            SysgenidError::CounterExhausted => COUNTER_EXHAUSTED_ERROR,
+            SysgenidError::BumpsPaused => BUMPS_PAUSED_ERROR,
+            SysgenidError::PersistFailed(_) => PERSIST_FAILED_ERROR,
+            SysgenidError::UnknownWatcher(_) => UNKNOWN_WATCHER_ERROR,
+            SysgenidError::WatcherNotOutdated(_) => WATCHER_NOT_OUTDATED_ERROR,
+            SysgenidError::WatcherGone(_) => WATCHER_GONE_ERROR,
+            SysgenidError::TooManyWatchers(_) => TOO_MANY_WATCHERS_ERROR,
+            SysgenidError::Draining => DRAINING_ERROR,
+        }
+    }
+}
+
+impl fmt::Display for SysgenidError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SysgenidError::StaleCounter { counter, current } => write!(
+                f,
+                "watcher counter {} is stale, current counter is {}",
+                counter, current
+            ),
+            SysgenidError::InvalidCounter { counter, current } => write!(
+                f,
+                "watcher counter {} is invalid, current counter is {}",
+                counter, current
+            ),
+            SysgenidError::InvalidReason(problem) => write!(f, "invalid bump reason: {}", problem),
+            SysgenidError::InvalidSubId(problem) => {
+                write!(f, "invalid sub-watcher id: {}", problem)
+            }
+            SysgenidError::RateLimited => write!(f, "too many generation bumps, try again later"),
+            SysgenidError::CounterExhausted => write!(f, "generation counter exhausted"),
+            SysgenidError::BumpsPaused => write!(f, "generation bumps are paused"),
+            SysgenidError::PersistFailed(e) => write!(f, "failed to persist generation: {}", e),
+            SysgenidError::UnknownWatcher(watcher_id) => {
+                write!(f, "watcher {} is not tracked", watcher_id)
+            }
+            SysgenidError::WatcherNotOutdated(watcher_id) => {
+                write!(f, "watcher {} is not outdated", watcher_id)
+            }
+            SysgenidError::WatcherGone(watcher_id) => {
+                write!(f, "watcher {} left the bus", watcher_id)
+            }
+            SysgenidError::TooManyWatchers(max_watchers) => {
+                write!(f, "too many watchers, limit is {}", max_watchers)
+            }
+            SysgenidError::Draining => write!(f, "draining, not accepting new watchers"),
+        }
+    }
+}
+
+impl std::error::Error for SysgenidError {}
+
+impl From<SysgenidError> for MethodErr {
+    fn from(err: SysgenidError) -> Self {
+        MethodErr::from((err.name(), err.to_string()))
+    }
+}
@@ -0,0 +1,39 @@
+use std::fs::File;
+use std::io::{self, Write};
+use std::os::unix::io::FromRawFd;
+
+/// Linux eventfd notified on every generation bump.
+///
+/// Every notification adds 1 to the eventfd counter, making the fd readable. A
+/// reader gets the number of bumps since its previous read as a native-endian u64,
+/// which also resets the counter. The fd is non-blocking.
+pub struct EventFd {
+    file: File,
+}
+
+impl EventFd {
+    pub fn new() -> io::Result<Self> {
+        // Safe because we only check the result of the call.
+        let fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Safe because `fd` is a freshly created fd nobody else owns.
+        let file = unsafe { File::from_raw_fd(fd) };
+        Ok(EventFd { file })
+    }
+
+    /// Returns a new handle to the eventfd, e.g. to hand out to a reader.
+    pub fn try_clone(&self) -> io::Result<File> {
+        self.file.try_clone()
+    }
+
+    /// Makes the eventfd readable.
+    pub fn notify(&self) -> io::Result<()> {
+        match (&self.file).write(&1u64.to_ne_bytes()) {
+            // The counter is about to overflow, the fd is readable anyway.
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(()),
+            res => res.map(|_| ()),
+        }
+    }
+}
@@ -0,0 +1,87 @@
+use serde_json::{json, Value};
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use sysgenid_dbus::Signal;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Turns on printing events to stdout, one JSON object per line.
+pub fn enable() {
+    ENABLED.store(true, Ordering::SeqCst);
+}
+
+/// Prints the event matching `signal`, sent out by the generation domain at `path`, if
+/// it is a significant one.
+pub fn signal(path: &str, signal: &Signal) {
+    if !ENABLED.load(Ordering::SeqCst) {
+        return;
+    }
+    match signal {
+        Signal::NewGeneration {
+            counter,
+            reason,
+            epoch,
+            ..
+        } => emit(
+            "bump",
+            path,
+            json!({"counter": counter, "reason": reason, "epoch": epoch}),
+        ),
+        Signal::WatcherLost {
+            watcher_id,
+            was_outdated,
+            removal_reason,
+        } => emit(
+            "watcher_lost",
+            path,
+            json!({
+                "watcher": watcher_id,
+                "was_outdated": was_outdated,
+                "reason": removal_reason.as_str(),
+            }),
+        ),
+        Signal::SystemReady {
+            adjust_duration_ms,
+            for_counter,
+        } => emit(
+            "system_ready",
+            path,
+            json!({"adjust_duration_ms": adjust_duration_ms, "counter": for_counter}),
+        ),
+        Signal::PreBump { proposed_counter } => {
+            emit("pre_bump", path, json!({ "counter": proposed_counter }))
+        }
+        Signal::ConvergenceStalled {
+            counter,
+            outstanding_count,
+        } => emit(
+            "convergence_stalled",
+            path,
+            json!({"counter": counter, "outstanding_count": outstanding_count}),
+        ),
+        _ => (),
+    }
+}
+
+/// Prints the event of `watcher` acking `counter` in the generation domain at `path`.
+pub fn ack(path: &str, watcher: &str, counter: u32) {
+    if !ENABLED.load(Ordering::SeqCst) {
+        return;
+    }
+    emit("ack", path, json!({"watcher": watcher, "counter": counter}));
+}
+
+/// Prints the `event` line, `fields` being an object of its event specific members.
+fn emit(event: &str, path: &str, fields: Value) {
+    let ts = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64;
+    let mut line = json!({"event": event, "path": path, "ts": ts});
+    if let (Value::Object(line), Value::Object(fields)) = (&mut line, fields) {
+        line.extend(fields);
+    }
+    // Don't bring the service down when nobody reads the events anymore.
+    let _ = writeln!(io::stdout().lock(), "{}", line);
+}
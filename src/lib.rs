@@ -0,0 +1,1669 @@
+//! SysGenID system generation tracking.
+//!
+//! This crate holds the generation state machine behind the SysGenID DBus service.
+//! It has no knowledge of the bus connection itself: state changes that need to be
+//! broadcast are reported through caller provided `FnOnce(Signal)` closures, which
+//! lets the state machine be embedded in other services or driven directly.
+
+mod config;
+mod config_file;
+mod error;
+mod event_fd;
+mod mapped_counter;
+mod polkit;
+mod seed_source;
+mod state_file;
+
+pub use config::{BusType, CorruptStatePolicy, SysgenidConfig};
+pub use error::SysgenidError;
+pub use event_fd::EventFd;
+pub use mapped_counter::{MappedCounter, DEFAULT_COUNTER_FILE, DEFAULT_COUNTER_FILE_MODE};
+pub use polkit::{Polkit, DEFAULT_POLKIT_ACTION};
+pub use seed_source::SeedSource;
+pub use state_file::{StateFile, DEFAULT_STATE_FILE};
+
+use dbus::arg::{PropMap, Variant};
+use dbus::Message;
+use log::{debug, error, info, warn};
+use std::cmp::{max, Ordering};
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+pub const SYGENID_INTERFACE: &str = "com.RFC.sysgenid";
+pub const SYGENID_PATH: &str = "/com/RFC/sysgenid";
+
+// Signal names, shared between interface registration and emission.
+pub const NEW_GENERATION_SIGNAL: &str = "NewGeneration";
+pub const SYSTEM_READY_SIGNAL: &str = "SystemReady";
+pub const COUNTER_EXHAUSTED_SIGNAL: &str = "CounterExhausted";
+pub const WATCHER_REGISTERED_SIGNAL: &str = "WatcherRegistered";
+pub const WATCHER_LOST_SIGNAL: &str = "WatcherLost";
+pub const SERVICE_SHUTTING_DOWN_SIGNAL: &str = "ServiceShuttingDown";
+pub const GROUP_READY_SIGNAL: &str = "GroupReady";
+pub const PRE_BUMP_SIGNAL: &str = "PreBump";
+pub const CONVERGENCE_STALLED_SIGNAL: &str = "ConvergenceStalled";
+
+// Interface and method implemented by callback watchers.
+pub const CALLBACK_INTERFACE: &str = "com.RFC.sysgenid.Callback";
+pub const ON_NEW_GENERATION_METHOD: &str = "OnNewGeneration";
+
+// Property names.
+pub const SYSGEN_COUNTER_PROPERTY: &str = "SysGenCounter";
+pub const OUTDATED_WATCHER_COUNT_PROPERTY: &str = "OutdatedWatcherCount";
+pub const LAST_BUMP_TIMESTAMP_PROPERTY: &str = "LastBumpTimestamp";
+pub const VERSION_PROPERTY: &str = "Version";
+pub const FEATURES_PROPERTY: &str = "Features";
+pub const DRAINING_PROPERTY: &str = "Draining";
+pub const BUMPS_PAUSED_PROPERTY: &str = "BumpsPaused";
+
+// Error names.
+pub const STALE_COUNTER_ERROR: &str = "com.RFC.sysgenid.Error.StaleCounter";
+pub const INVALID_COUNTER_ERROR: &str = "com.RFC.sysgenid.Error.InvalidCounter";
+pub const RATE_LIMITED_ERROR: &str = "com.RFC.sysgenid.Error.RateLimited";
+pub const UNKNOWN_WATCHER_ERROR: &str = "com.RFC.sysgenid.Error.UnknownWatcher";
+pub const WATCHER_GONE_ERROR: &str = "com.RFC.sysgenid.Error.WatcherGone";
+pub const TOO_MANY_WATCHERS_ERROR: &str = "com.RFC.sysgenid.Error.TooManyWatchers";
+pub const COUNTER_EXHAUSTED_ERROR: &str = "com.RFC.sysgenid.Error.CounterExhausted";
+pub const PERSIST_FAILED_ERROR: &str = "com.RFC.sysgenid.Error.PersistFailed";
+pub const INVALID_REASON_ERROR: &str = "com.RFC.sysgenid.Error.InvalidReason";
+pub const WATCHER_NOT_OUTDATED_ERROR: &str = "com.RFC.sysgenid.Error.WatcherNotOutdated";
+pub const DRAINING_ERROR: &str = "com.RFC.sysgenid.Error.Draining";
+pub const BUMPS_PAUSED_ERROR: &str = "com.RFC.sysgenid.Error.BumpsPaused";
+pub const INVALID_SUB_ID_ERROR: &str = "com.RFC.sysgenid.Error.InvalidSubId";
+
+/// Number of recent generation bumps remembered by default.
+pub const DEFAULT_BUMP_HISTORY: usize = 32;
+
+/// Maximum length of a bump reason by default, in bytes.
+pub const DEFAULT_MAX_REASON_LEN: usize = 256;
+
+/// How often the service checks that tracked watchers are still on the bus by default.
+pub const DEFAULT_REAP_INTERVAL: Duration = Duration::from_secs(30);
+
+// Reason of the generation bumps the service triggers on its own.
+const AUTO_BUMP_REASON: &str = "auto";
+
+/// Maximum length of a sub-watcher id, in bytes.
+pub const MAX_SUB_ID_LEN: usize = 255;
+
+// Separates the bus name of a connection from the sub-watcher id in watcher ids. Never
+// part of a bus name.
+const SUB_WATCHER_SEPARATOR: char = '/';
+
+/// Upper bounds, in ms, of the convergence duration histogram buckets. Longer
+/// convergences land in a last, unbounded bucket.
+pub const CONVERGENCE_BUCKETS_MS: [u64; 11] =
+    [10, 50, 100, 250, 500, 1000, 2500, 5000, 10000, 30000, 60000];
+
+// Acks received within this long of the first one of a burst count as concurrent.
+const ACK_BURST_WINDOW: Duration = Duration::from_millis(100);
+
+/// Locks `mutex`, recovering it if a panic poisoned it while held: a single bad request
+/// must not take down every later one with it.
+pub fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|poisoned| {
+        error!("recovering state poisoned by an earlier panic");
+        poisoned.into_inner()
+    })
+}
+
+/// Checks `sender`, the sender of a method call, is the unique name of a connection
+/// (`:1.42`) and returns it. Watchers are tracked and callers authorized by it, which a
+/// missing or well-known name can't stand for: the bus should never let one through,
+/// don't trust it to.
+pub fn unique_sender(sender: Option<&str>) -> Result<&str, String> {
+    let sender = sender.ok_or_else(|| "could not identify sender".to_owned())?;
+    // Unique names have at least two elements, which libdbus doesn't check for `:`.
+    let is_unique = sender.starts_with(':') && sender.contains('.');
+    if !is_unique || dbus::strings::BusName::new(sender).is_err() {
+        return Err(format!("sender '{}' is not a unique bus name", sender));
+    }
+    Ok(sender)
+}
+
+/// Id of the sub-watcher `sub_id` of the connection `bus_name`, e.g. one of several
+/// plugins of a process, tracked independently of the connection's own watcher.
+pub fn sub_watcher_id(bus_name: &str, sub_id: &str) -> Result<String, SysgenidError> {
+    if sub_id.is_empty() {
+        return Err(SysgenidError::InvalidSubId("sub id is empty".to_owned()));
+    }
+    if sub_id.len() > MAX_SUB_ID_LEN {
+        return Err(SysgenidError::InvalidSubId(format!(
+            "sub id is {} bytes long, limit is {}",
+            sub_id.len(),
+            MAX_SUB_ID_LEN
+        )));
+    }
+    if sub_id.chars().any(char::is_control) {
+        return Err(SysgenidError::InvalidSubId(
+            "sub id contains control characters".to_owned(),
+        ));
+    }
+    Ok(format!("{}{}{}", bus_name, SUB_WATCHER_SEPARATOR, sub_id))
+}
+
+/// Bus name of the connection hosting the watcher `watcher_id`, which is the watcher id
+/// itself unless it is a sub-watcher.
+pub fn watcher_bus_name(watcher_id: &str) -> &str {
+    watcher_id
+        .split(SUB_WATCHER_SEPARATOR)
+        .next()
+        .unwrap_or(watcher_id)
+}
+
+/// Orders generation counters `a` and `b`, `Greater` meaning `a` is the newer one.
+///
+/// The sys gen counter saturates at `u32::MAX` rather than wrapping around, so plain
+/// numeric order is also generation order, up to and including the boundary. Every
+/// "is this generation newer" decision goes through here, the single place to revisit
+/// should the counter ever wrap or widen.
+pub fn generation_cmp(a: u32, b: u32) -> Ordering {
+    a.cmp(&b)
+}
+
+/// Why a watcher stopped being tracked.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RemovalReason {
+    /// The watcher left the bus, gracefully or not.
+    Disconnected,
+    /// The watcher opted out through `UnregisterWatcher`.
+    Unregistered,
+}
+
+impl RemovalReason {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            RemovalReason::Disconnected => "disconnected",
+            RemovalReason::Unregistered => "unregistered",
+        }
+    }
+}
+
+/// Signals emitted by the service on the sysgenid interface.
+pub enum Signal {
+    NewGeneration {
+        counter: u32,
+        reason: String,
+        /// Epoch the generation was entered at, see `Sysgenid::epoch()`.
+        epoch: u64,
+        /// Bus names to send the signal to, one message each, rather than broadcasting
+        /// it. Set in targeted signals mode only.
+        destinations: Option<Vec<String>>,
+    },
+    SystemReady {
+        adjust_duration_ms: u64,
+        /// Generation the system is ready at, letting listeners tell readiness for an
+        /// older generation apart from readiness for the one they care about.
+        for_counter: u32,
+    },
+    CounterExhausted {
+        counter: u32,
+    },
+    WatcherRegistered {
+        watcher_id: String,
+    },
+    WatcherLost {
+        watcher_id: String,
+        was_outdated: bool,
+        removal_reason: RemovalReason,
+    },
+    ServiceShuttingDown,
+    GroupReady {
+        group: String,
+    },
+    PreBump {
+        proposed_counter: u32,
+    },
+    ConvergenceStalled {
+        counter: u32,
+        outstanding_count: u32,
+    },
+    /// Changes of sysgenid interface properties, to be sent out as a single
+    /// `org.freedesktop.DBus.Properties.PropertiesChanged` signal.
+    PropertiesChanged {
+        changed: PropMap,
+    },
+}
+
+impl Signal {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Signal::NewGeneration { .. } => NEW_GENERATION_SIGNAL,
+            Signal::SystemReady { .. } => SYSTEM_READY_SIGNAL,
+            Signal::CounterExhausted { .. } => COUNTER_EXHAUSTED_SIGNAL,
+            Signal::WatcherRegistered { .. } => WATCHER_REGISTERED_SIGNAL,
+            Signal::WatcherLost { .. } => WATCHER_LOST_SIGNAL,
+            Signal::ServiceShuttingDown => SERVICE_SHUTTING_DOWN_SIGNAL,
+            Signal::GroupReady { .. } => GROUP_READY_SIGNAL,
+            Signal::PreBump { .. } => PRE_BUMP_SIGNAL,
+            Signal::ConvergenceStalled { .. } => CONVERGENCE_STALLED_SIGNAL,
+            Signal::PropertiesChanged { .. } => "PropertiesChanged",
+        }
+    }
+
+    /// Appends the signal arguments to `msg`, matching the registered signal signature.
+    /// `PropertiesChanged` has no arguments of its own, the interface name is up to
+    /// the caller.
+    pub fn append_args(&self, msg: &mut Message) {
+        match self {
+            Signal::NewGeneration {
+                counter,
+                reason,
+                epoch,
+                ..
+            } => msg.append_all((*counter, reason.as_str(), *epoch)),
+            Signal::SystemReady {
+                adjust_duration_ms,
+                for_counter,
+            } => msg.append_all((*adjust_duration_ms, *for_counter)),
+            Signal::CounterExhausted { counter } => msg.append_all((*counter,)),
+            Signal::WatcherRegistered { watcher_id } => msg.append_all((watcher_id.as_str(),)),
+            Signal::WatcherLost {
+                watcher_id,
+                was_outdated,
+                removal_reason,
+            } => msg.append_all((watcher_id.as_str(), *was_outdated, removal_reason.as_str())),
+            Signal::GroupReady { group } => msg.append_all((group.as_str(),)),
+            Signal::PreBump { proposed_counter } => msg.append_all((*proposed_counter,)),
+            Signal::ConvergenceStalled {
+                counter,
+                outstanding_count,
+            } => msg.append_all((*counter, *outstanding_count)),
+            Signal::ServiceShuttingDown | Signal::PropertiesChanged { .. } => (),
+        }
+    }
+}
+
+/// Call of the `OnNewGeneration` callback of a watcher, to be made by the caller. A
+/// successful return counts as the watcher acking `counter`.
+pub struct WatcherCallback {
+    pub bus_name: String,
+    pub path: dbus::Path<'static>,
+    pub counter: u32,
+}
+
+pub struct Watcher {
+    // Unix process id of the watcher, if the bus could tell.
+    pid: Option<u32>,
+    // When the watcher started being tracked.
+    registered: Instant,
+    // Latest generation acked by the watcher.
+    acked_generation: u32,
+    // Group the watcher declared itself part of, if any.
+    group: Option<String>,
+    // How long the watcher expects to take adjusting to a new generation, if it told.
+    expected_adjust: Option<Duration>,
+    // When the watcher got outdated, if it is.
+    outdated_since: Option<Instant>,
+    // Object path the watcher gets called back at on new generations, if any.
+    callback: Option<dbus::Path<'static>>,
+    // When the watcher last proved it is responsive, if it ever sent a heartbeat.
+    last_heartbeat: Option<Instant>,
+    // Generations entered since the watcher last caught up, or since they were taken.
+    missed_count: u32,
+    // Counter below which the watcher doesn't want targeted notifications, if any.
+    notify_threshold: Option<u32>,
+}
+
+impl Watcher {
+    fn new(pid: Option<u32>, acked_generation: u32) -> Self {
+        Watcher {
+            pid,
+            registered: Instant::now(),
+            acked_generation,
+            group: None,
+            expected_adjust: None,
+            outdated_since: None,
+            callback: None,
+            last_heartbeat: None,
+            missed_count: 0,
+            notify_threshold: None,
+        }
+    }
+
+    pub fn pid(&self) -> Option<u32> {
+        self.pid
+    }
+
+    pub fn registered(&self) -> Instant {
+        self.registered
+    }
+
+    pub fn acked_generation(&self) -> u32 {
+        self.acked_generation
+    }
+
+    pub fn group(&self) -> Option<&str> {
+        self.group.as_deref()
+    }
+
+    pub fn callback(&self) -> Option<&dbus::Path<'static>> {
+        self.callback.as_ref()
+    }
+
+    pub fn expected_adjust(&self) -> Option<Duration> {
+        self.expected_adjust
+    }
+
+    pub fn notify_threshold(&self) -> Option<u32> {
+        self.notify_threshold
+    }
+
+    /// Whether the watcher wants to be notified of generation `counter`.
+    fn wants_notification(&self, counter: u32) -> bool {
+        match self.notify_threshold {
+            Some(threshold) => generation_cmp(counter, threshold) != Ordering::Less,
+            None => true,
+        }
+    }
+
+    /// Number of generations entered while the watcher was tracked, since it last acked
+    /// the current one.
+    pub fn missed_count(&self) -> u32 {
+        self.missed_count
+    }
+
+    /// How long ago the watcher sent its latest heartbeat, if it ever did.
+    pub fn since_heartbeat(&self) -> Option<Duration> {
+        self.last_heartbeat.map(|last| last.elapsed())
+    }
+
+    /// How long the watcher has been outdated, since the generation change it has yet
+    /// to ack.
+    pub fn outdated_for(&self) -> Option<Duration> {
+        self.outdated_since.map(|since| since.elapsed())
+    }
+
+    /// Whether the watcher has been outdated for longer than it expected to take
+    /// adjusting. Never the case for watchers which didn't tell.
+    pub fn over_budget(&self) -> bool {
+        match (self.outdated_for(), self.expected_adjust) {
+            (Some(outdated_for), Some(expected_adjust)) => outdated_for > expected_adjust,
+            _ => false,
+        }
+    }
+}
+
+/// A generation bump announced with `PreBump`, entered once its grace period is over.
+struct PendingBump {
+    counter: u32,
+    reason: String,
+    deadline: Instant,
+}
+
+pub struct Sysgenid {
+    generation_counter: u32,
+    last_bump_reason: String,
+    mapped_counter: MappedCounter,
+    state_file: StateFile,
+    // External counter source the generation never falls behind of.
+    seed_source: Option<SeedSource>,
+    /// Bump notification eventfds, by the bus name they were handed out to.
+    event_fds: HashMap<String, EventFd>,
+    watchers: HashMap<String, Watcher>,
+    outdated_watchers: HashMap<String, Watcher>,
+    // How long watchers have to ack a new generation before being dropped.
+    ack_timeout: Option<Duration>,
+    // When the outdated watchers of the latest generation are due.
+    ack_deadline: Option<Instant>,
+    // How long outdated watchers can take before convergence is reported as stalled.
+    stall_timeout: Option<Duration>,
+    stall_deadline: Option<Instant>,
+    // Minimum time between two accepted bumps.
+    min_bump_interval: Option<Duration>,
+    // When the latest bump was accepted, the start of the current adjustment.
+    last_bump: Option<Instant>,
+    // Wall clock time of the latest bump, or of the service start before any bump.
+    last_bump_time: SystemTime,
+    // Window during which bump notifications are merged into one.
+    coalesce_window: Option<Duration>,
+    // When the pending coalesced notification is due.
+    coalesce_deadline: Option<Instant>,
+    // Whether the system became ready while the notification was held back.
+    system_ready_pending: bool,
+    // How long watchers are given to prepare between PreBump and the actual bump.
+    pre_bump_grace: Option<Duration>,
+    pending_bump: Option<PendingBump>,
+    // Period of the bumps triggered on a timer, and when the next one is due.
+    auto_bump_interval: Option<Duration>,
+    auto_bump_deadline: Option<Instant>,
+    // Upper bound on the number of tracked watchers.
+    max_watchers: Option<usize>,
+    // Whether new watchers are turned away, e.g. ahead of maintenance.
+    draining: bool,
+    // Whether generation bumps are refused, freezing the generation.
+    bumps_paused: bool,
+    // Upper bound on the length of bump reasons, in bytes.
+    max_reason_len: usize,
+    // How long heartbeating watchers can go silent before being reported as stale.
+    heartbeat_timeout: Option<Duration>,
+    // Whether NewGeneration is only sent to the outdated watchers.
+    targeted_signals: bool,
+    // Lifetime statistics.
+    started: Instant,
+    total_bumps: u64,
+    total_acks: u64,
+    total_disconnects: u64,
+    // Start and size of the latest burst of acks, and the largest burst seen.
+    ack_burst_start: Option<Instant>,
+    ack_burst: u32,
+    peak_concurrent_acks: u32,
+    // Watcher callbacks due, not handed out to the caller yet.
+    pending_callbacks: Vec<WatcherCallback>,
+    // Counter, reason and Unix timestamp of the most recent bumps, oldest first.
+    bump_history: VecDeque<(u32, String, u64)>,
+    bump_history_len: usize,
+    // Whether the latest notified generation is still waiting for the system to be ready.
+    converging: bool,
+    // Convergence durations, per CONVERGENCE_BUCKETS_MS bucket plus the unbounded one.
+    convergence_buckets: [u64; CONVERGENCE_BUCKETS_MS.len() + 1],
+    convergence_sum_ms: u64,
+    // Generations superseded by a newer one before the system was ready.
+    incomplete_convergences: u64,
+}
+
+impl Sysgenid {
+    /// Creates the service state, resuming from `initial_counter` if one was
+    /// persisted by a previous instance.
+    pub fn new(
+        initial_counter: Option<u32>,
+        mapped_counter: MappedCounter,
+        state_file: StateFile,
+        config: &SysgenidConfig,
+    ) -> Self {
+        let generation_counter = initial_counter.unwrap_or(0);
+        mapped_counter.store(generation_counter);
+        Sysgenid {
+            generation_counter,
+            last_bump_reason: String::new(),
+            mapped_counter,
+            state_file,
+            seed_source: config.seed_from.clone().map(SeedSource::new),
+            event_fds: HashMap::new(),
+            watchers: HashMap::new(),
+            outdated_watchers: HashMap::new(),
+            ack_timeout: config.ack_timeout,
+            ack_deadline: None,
+            stall_timeout: config.slow_convergence_warn,
+            stall_deadline: None,
+            min_bump_interval: config
+                .max_bumps_per_sec
+                .map(|max_bumps| Duration::from_secs(1) / max_bumps),
+            last_bump: None,
+            last_bump_time: SystemTime::now(),
+            coalesce_window: config.coalesce_window,
+            coalesce_deadline: None,
+            system_ready_pending: false,
+            pre_bump_grace: config.pre_bump_grace,
+            pending_bump: None,
+            auto_bump_interval: config.auto_bump_interval,
+            auto_bump_deadline: config
+                .auto_bump_interval
+                .map(|interval| Instant::now() + interval),
+            max_watchers: config.max_watchers,
+            draining: false,
+            bumps_paused: false,
+            max_reason_len: config.max_reason_len,
+            heartbeat_timeout: config.heartbeat_timeout,
+            targeted_signals: config.targeted_signals,
+            started: Instant::now(),
+            total_bumps: 0,
+            total_acks: 0,
+            total_disconnects: 0,
+            ack_burst_start: None,
+            ack_burst: 0,
+            peak_concurrent_acks: 0,
+            pending_callbacks: Vec::new(),
+            bump_history: VecDeque::with_capacity(config.bump_history),
+            bump_history_len: config.bump_history,
+            converging: false,
+            convergence_buckets: [0; CONVERGENCE_BUCKETS_MS.len() + 1],
+            convergence_sum_ms: 0,
+            incomplete_convergences: 0,
+        }
+    }
+
+    /// Creates the service state described by `config`: resumes from the persisted
+    /// counter, if any, or the seed source counter when higher, and maps the exported
+    /// counter file. A corrupt state file is handled according to `on_corrupt_state`.
+    pub fn from_config(config: &SysgenidConfig) -> io::Result<Self> {
+        let state_file = StateFile::new(config.state_file.clone());
+        let (mut initial_counter, corrupt) = match state_file.load() {
+            Ok(counter) => (counter, false),
+            Err(e)
+                if e.kind() == io::ErrorKind::InvalidData
+                    && config.on_corrupt_state == CorruptStatePolicy::Reset =>
+            {
+                warn!("{}, starting over from counter 0", e);
+                (Some(0), true)
+            }
+            Err(e) => return Err(e),
+        };
+        // Start off no earlier than the external counter source.
+        if let Some(seed_from) = &config.seed_from {
+            let seed = SeedSource::new(seed_from.clone()).read()?;
+            initial_counter = Some(initial_counter.map_or(seed, |counter| max(counter, seed)));
+        }
+        // Leave a valid state behind, for the next start not to trip over it again.
+        if let (true, Some(counter)) = (corrupt, initial_counter) {
+            state_file.store(counter)?;
+        }
+        let mapped_counter = MappedCounter::new(&config.counter_file, config.counter_file_mode)?;
+        Ok(Sysgenid::new(
+            initial_counter,
+            mapped_counter,
+            state_file,
+            config,
+        ))
+    }
+
+    pub fn sysgen_counter(&self) -> u32 {
+        self.generation_counter
+    }
+
+    pub fn last_bump_reason(&self) -> &str {
+        &self.last_bump_reason
+    }
+
+    /// Time of the latest generation bump, or of the service start if none happened
+    /// yet, in seconds since the Unix epoch.
+    pub fn last_bump_timestamp(&self) -> u64 {
+        self.last_bump_time
+            .duration_since(UNIX_EPOCH)
+            .map(|since_epoch| since_epoch.as_secs())
+            .unwrap_or_default()
+    }
+
+    /// Counter, reason and timestamp, in seconds since the Unix epoch, of the most
+    /// recent generation bumps, oldest first.
+    pub fn bump_history(&self) -> impl Iterator<Item = &(u32, String, u64)> {
+        self.bump_history.iter()
+    }
+
+    /// Returns a handle to the bump notification eventfd of `owner`, creating it on
+    /// first use. It lives until `owner` is removed through `remove_watcher()`.
+    pub fn event_fd(&mut self, owner: &str) -> io::Result<File> {
+        if let Some(event_fd) = self.event_fds.get(owner) {
+            return event_fd.try_clone();
+        }
+        let event_fd = EventFd::new()?;
+        let file = event_fd.try_clone()?;
+        debug!("created eventfd for {}", owner);
+        self.event_fds.insert(owner.to_owned(), event_fd);
+        Ok(file)
+    }
+
+    /// Persists the current counter to the state file.
+    pub fn persist(&self) -> io::Result<()> {
+        self.state_file.store(self.generation_counter)
+    }
+
+    /// Moves to a new generation, at least `min_gen` and the seed source counter, and
+    /// returns its counter along with the ids of the watchers it made outdated, sorted.
+    /// None are for an announced bump, its watchers are only outdated once it's entered.
+    pub fn bump_generation<F>(
+        &mut self,
+        min_gen: u32,
+        reason: &str,
+        mut signal_fn: F,
+    ) -> Result<(u32, Vec<String>), SysgenidError>
+    where
+        F: FnMut(Signal),
+    {
+        self.check_bumps_allowed()?;
+        // The reason is kept in the history and sent along with every notification,
+        // keep it bounded and safe to log.
+        if reason.len() > self.max_reason_len {
+            return Err(SysgenidError::InvalidReason(format!(
+                "reason is {} bytes long, limit is {}",
+                reason.len(),
+                self.max_reason_len
+            )));
+        }
+        if reason.chars().any(char::is_control) {
+            return Err(SysgenidError::InvalidReason(
+                "reason contains control characters".to_owned(),
+            ));
+        }
+        let now = Instant::now();
+        self.check_bump_rate(now)?;
+        // Never fall behind the external counter source.
+        let min_gen = match &self.seed_source {
+            Some(seed_source) => match seed_source.read() {
+                Ok(seed) => max(min_gen, seed),
+                Err(e) => {
+                    warn!("ignoring seed source: {}", e);
+                    min_gen
+                }
+            },
+            None => min_gen,
+        };
+        // The counter must never wrap around, as that would break monotonicity and
+        // make stale acks look valid. Saturate at u32::MAX and let everyone know.
+        let next_gen = match self.generation_counter.checked_add(1) {
+            Some(next_gen) => next_gen,
+            None => {
+                warn!("generation counter exhausted, refusing to bump");
+                signal_fn(Signal::CounterExhausted {
+                    counter: self.generation_counter,
+                });
+                return Err(SysgenidError::CounterExhausted);
+            }
+        };
+        let new_gen = max(min_gen, next_gen);
+        if let Some(grace) = self.pre_bump_grace {
+            let counter = self.announce_bump(new_gen, reason, now + grace, signal_fn);
+            return Ok((counter, Vec::new()));
+        }
+        // Jumping ahead skips counter values, make that visible.
+        if min_gen > next_gen {
+            info!(
+                "bump driven by min_gen {}, skipping from {}",
+                min_gen, self.generation_counter
+            );
+        } else {
+            info!("bump driven by natural increment, min_gen {}", min_gen);
+        }
+        self.enter_generation(new_gen, reason, now, signal_fn)
+    }
+
+    /// Moves to generation `counter` exactly, which must be greater than the current one
+    /// (and than an announced one), and returns it. Unlike `min_gen`, never picks another
+    /// counter: meant for mirroring the counter of an external authority.
+    pub fn set_generation<F>(&mut self, counter: u32, signal_fn: F) -> Result<u32, SysgenidError>
+    where
+        F: FnMut(Signal),
+    {
+        let current = match &self.pending_bump {
+            Some(pending) => pending.counter,
+            None => self.generation_counter,
+        };
+        self.check_bumps_allowed()?;
+        if counter <= current {
+            return Err(SysgenidError::InvalidCounter { counter, current });
+        }
+        let now = Instant::now();
+        self.check_bump_rate(now)?;
+        info!(
+            "setting generation counter from {} to {}",
+            self.generation_counter, counter
+        );
+        if let Some(grace) = self.pre_bump_grace {
+            return Ok(self.announce_bump(counter, "", now + grace, signal_fn));
+        }
+        self.enter_generation(counter, "", now, signal_fn)
+            .map(|(counter, _)| counter)
+    }
+
+    /// Refuses bumps while they are paused.
+    fn check_bumps_allowed(&self) -> Result<(), SysgenidError> {
+        if self.bumps_paused {
+            warn!("bump requested while bumps are paused, refusing");
+            return Err(SysgenidError::BumpsPaused);
+        }
+        Ok(())
+    }
+
+    /// Refuses bump storms, they would keep watchers from ever converging.
+    fn check_bump_rate(&self, now: Instant) -> Result<(), SysgenidError> {
+        if let (Some(interval), Some(last_bump)) = (self.min_bump_interval, self.last_bump) {
+            if now.duration_since(last_bump) < interval {
+                warn!("bump requested too soon after the previous one, refusing");
+                return Err(SysgenidError::RateLimited);
+            }
+        }
+        Ok(())
+    }
+
+    /// Announces the bump to `counter` with `PreBump`, giving watchers until `deadline`
+    /// to get ready before `flush_pending_bump()` enters the new generation, and returns
+    /// the announced counter. A bump requested while one is pending joins it, raising
+    /// its counter if needed; the latest reason wins.
+    fn announce_bump<F>(
+        &mut self,
+        counter: u32,
+        reason: &str,
+        deadline: Instant,
+        mut signal_fn: F,
+    ) -> u32
+    where
+        F: FnMut(Signal),
+    {
+        match &mut self.pending_bump {
+            Some(pending) => {
+                pending.reason = reason.to_owned();
+                if counter <= pending.counter {
+                    debug!("bump joins the one announced for {}", pending.counter);
+                    return pending.counter;
+                }
+                pending.counter = counter;
+            }
+            None => {
+                self.pending_bump = Some(PendingBump {
+                    counter,
+                    reason: reason.to_owned(),
+                    deadline,
+                })
+            }
+        }
+        info!("announcing bump to generation {}", counter);
+        signal_fn(Signal::PreBump {
+            proposed_counter: counter,
+        });
+        counter
+    }
+
+    /// Enters the generation announced with `PreBump` once its grace period is over.
+    pub fn flush_pending_bump<F>(&mut self, now: Instant, signal_fn: F)
+    where
+        F: FnMut(Signal),
+    {
+        match &self.pending_bump {
+            Some(pending) if now >= pending.deadline => self.commit_pending_bump(signal_fn),
+            _ => (),
+        }
+    }
+
+    /// Enters the generation announced with `PreBump` right away, if any. Watchers were
+    /// told about it, it must not get lost, e.g. when shutting down.
+    pub fn commit_pending_bump<F>(&mut self, signal_fn: F)
+    where
+        F: FnMut(Signal),
+    {
+        if let Some(pending) = self.pending_bump.take() {
+            let res =
+                self.enter_generation(pending.counter, &pending.reason, Instant::now(), signal_fn);
+            if let Err(e) = res {
+                error!(
+                    "failed to enter announced generation {}: {}",
+                    pending.counter, e
+                );
+            }
+        }
+    }
+
+    /// Sets the counter to `counter`, lower than the current one or not, and notifies it
+    /// like a generation bump. Breaks the counter monotonicity watchers rely on: only
+    /// meant for testing and recovery.
+    pub fn reset_counter<F>(&mut self, counter: u32, signal_fn: F) -> Result<u32, SysgenidError>
+    where
+        F: FnMut(Signal),
+    {
+        self.check_bumps_allowed()?;
+        warn!(
+            "resetting generation counter from {} to {}",
+            self.generation_counter, counter
+        );
+        self.enter_generation(counter, "counter reset", Instant::now(), signal_fn)
+            .map(|(counter, _)| counter)
+    }
+
+    /// Moves to generation `new_gen`, marking all tracked watchers outdated, and
+    /// returns its counter along with the ids of those watchers, sorted.
+    fn enter_generation<F>(
+        &mut self,
+        new_gen: u32,
+        reason: &str,
+        now: Instant,
+        signal_fn: F,
+    ) -> Result<(u32, Vec<String>), SysgenidError>
+    where
+        F: FnMut(Signal),
+    {
+        // Persist the new value first, never hand out a generation that could be
+        // reused after a service restart.
+        self.state_file.store(new_gen).map_err(|e| {
+            error!("failed to persist generation {}: {}", new_gen, e);
+            SysgenidError::PersistFailed(e)
+        })?;
+        // A notified generation the system didn't converge to is over, coalesced ones
+        // were never waited for.
+        if self.converging && self.coalesce_deadline.is_none() {
+            debug!(
+                "generation {} superseded before the system was ready",
+                self.generation_counter
+            );
+            self.incomplete_convergences += 1;
+        }
+        self.converging = true;
+        // Update generation counter.
+        self.generation_counter = new_gen;
+        self.last_bump = Some(now);
+        self.last_bump_time = SystemTime::now();
+        self.total_bumps += 1;
+        self.last_bump_reason = reason.to_owned();
+        if self.bump_history_len > 0 {
+            if self.bump_history.len() == self.bump_history_len {
+                self.bump_history.pop_front();
+            }
+            self.bump_history
+                .push_back((new_gen, reason.to_owned(), self.last_bump_timestamp()));
+        }
+        info!(
+            "generation bumped to {} (reason '{}')",
+            self.generation_counter, reason
+        );
+        // Publish new value to the memory mapped file and wake up eventfd readers
+        // before signalling anyone.
+        self.mapped_counter.store(self.generation_counter);
+        for (owner, event_fd) in &self.event_fds {
+            if let Err(e) = event_fd.notify() {
+                warn!("failed to notify eventfd of {}: {}", owner, e);
+            }
+        }
+        // Mark all tracked watchers as outdated. Those which already were miss one more.
+        for watcher in self.outdated_watchers.values_mut() {
+            watcher.missed_count = watcher.missed_count.saturating_add(1);
+        }
+        let mut newly_outdated = Vec::with_capacity(self.watchers.len());
+        for (watcher_id, mut watcher) in std::mem::take(&mut self.watchers) {
+            watcher.outdated_since = Some(now);
+            watcher.missed_count = watcher.missed_count.saturating_add(1);
+            newly_outdated.push(watcher_id.clone());
+            self.outdated_watchers.insert(watcher_id, watcher);
+        }
+        newly_outdated.sort();
+        match self.coalesce_window {
+            // Hold back notifications until the coalescing window closes, further
+            // bumps meanwhile are folded into the same notification.
+            Some(window) => {
+                if self.coalesce_deadline.is_none() {
+                    self.coalesce_deadline = Some(now + window);
+                }
+                debug!("new generation notification coalesced");
+            }
+            None => self.notify_new_generation(signal_fn),
+        }
+        debug!(
+            "watchers count {} ; outdated count {}",
+            self.watchers.len(),
+            self.outdated_watchers.len()
+        );
+        Ok((self.generation_counter, newly_outdated))
+    }
+
+    /// Signals the latest generation to watchers and starts waiting for their acks.
+    fn notify_new_generation<F>(&mut self, mut signal_fn: F)
+    where
+        F: FnMut(Signal),
+    {
+        // Watchers tracked at the latest generation already know about it, and coasting
+        // ones don't care until it reaches their threshold. Sub-watchers share the signal
+        // sent to their connection.
+        let counter = self.generation_counter;
+        let destinations = if self.targeted_signals {
+            let mut bus_names: Vec<String> = self
+                .outdated_watchers
+                .iter()
+                .filter(|(_, watcher)| watcher.wants_notification(counter))
+                .map(|(watcher_id, _)| watcher_bus_name(watcher_id).to_owned())
+                .collect();
+            bus_names.sort();
+            bus_names.dedup();
+            Some(bus_names)
+        } else {
+            None
+        };
+        signal_fn(Signal::NewGeneration {
+            counter: self.generation_counter,
+            reason: self.last_bump_reason.clone(),
+            epoch: self.epoch(),
+            destinations,
+        });
+        // Properties change at once, notify them as one transition.
+        let mut changed = PropMap::new();
+        changed.insert(
+            SYSGEN_COUNTER_PROPERTY.to_owned(),
+            Variant(Box::new(self.generation_counter)),
+        );
+        changed.insert(
+            LAST_BUMP_TIMESTAMP_PROPERTY.to_owned(),
+            Variant(Box::new(self.last_bump_timestamp())),
+        );
+        if !self.outdated_watchers.is_empty() {
+            changed.insert(
+                OUTDATED_WATCHER_COUNT_PROPERTY.to_owned(),
+                Variant(Box::new(self.outdated_watchers.len() as u32)),
+            );
+        }
+        signal_fn(Signal::PropertiesChanged { changed });
+        // Callbacks of a previous generation still pending are superseded.
+        let counter = self.generation_counter;
+        self.pending_callbacks = self
+            .outdated_watchers
+            .iter()
+            .filter_map(|(watcher_id, watcher)| {
+                watcher.callback.clone().map(|path| WatcherCallback {
+                    bus_name: watcher_id.clone(),
+                    path,
+                    counter,
+                })
+            })
+            .collect();
+        if self.outdated_watchers.is_empty() && std::mem::take(&mut self.system_ready_pending) {
+            // All outdated watchers went away while the notification was held back.
+            signal_fn(self.system_ready());
+        } else if self.outdated_watchers.is_empty() {
+            // No one to wait for, the system is ready right away.
+            self.record_convergence();
+        }
+        // (Re)arm the ack and stall timers for the new generation.
+        self.ack_deadline = self.ack_timeout.map(|timeout| Instant::now() + timeout);
+        self.stall_deadline = self.stall_timeout.map(|timeout| Instant::now() + timeout);
+    }
+
+    /// When the pending coalesced generation notification is due, if any.
+    pub fn coalesce_deadline(&self) -> Option<Instant> {
+        self.coalesce_deadline
+    }
+
+    /// Earliest of the ack, stall, coalescing, pre-bump and auto-bump deadlines, if any
+    /// is pending.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        let pre_bump = self.pending_bump.as_ref().map(|pending| pending.deadline);
+        [
+            self.ack_deadline,
+            self.stall_deadline,
+            self.coalesce_deadline,
+            pre_bump,
+            self.auto_bump_deadline,
+        ]
+        .iter()
+        .flatten()
+        .min()
+        .copied()
+    }
+
+    /// Sends out the coalesced generation notification once its window closed.
+    pub fn flush_coalesced<F>(&mut self, now: Instant, signal_fn: F)
+    where
+        F: FnMut(Signal),
+    {
+        match self.coalesce_deadline {
+            Some(deadline) if now >= deadline => self.coalesce_deadline = None,
+            _ => return,
+        }
+        info!("notifying coalesced generation {}", self.generation_counter);
+        self.notify_new_generation(signal_fn);
+    }
+
+    /// Time elapsed since the service started.
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Number of generation bumps since the service started.
+    pub fn total_bumps(&self) -> u64 {
+        self.total_bumps
+    }
+
+    /// Epoch of the current generation: increases with every generation entered, even
+    /// when the counter doesn't (`ResetCounter`), and is carried by `NewGeneration`.
+    /// Restarts from 0 along with the service.
+    pub fn epoch(&self) -> u64 {
+        self.total_bumps
+    }
+
+    /// Number of successful watcher acks since the service started.
+    pub fn total_acks(&self) -> u64 {
+        self.total_acks
+    }
+
+    /// Largest number of acks of outdated watchers received in a burst, i.e. within
+    /// 100 ms of each other, since the service started. Shows how hard watchers hit
+    /// the service at once after a generation change.
+    pub fn peak_concurrent_acks(&self) -> u32 {
+        self.peak_concurrent_acks
+    }
+
+    /// Number of tracked watchers that left the bus since the service started.
+    pub fn total_disconnects(&self) -> u64 {
+        self.total_disconnects
+    }
+
+    /// How long the system took to become ready after each generation bump since the
+    /// service started, as (bucket upper bound in ms, count) pairs following
+    /// `CONVERGENCE_BUCKETS_MS`. The last bucket, bounded by `u64::MAX`, holds the
+    /// convergences longer than all the others.
+    pub fn convergence_histogram(&self) -> Vec<(u64, u64)> {
+        CONVERGENCE_BUCKETS_MS
+            .iter()
+            .chain(std::iter::once(&u64::MAX))
+            .copied()
+            .zip(self.convergence_buckets.iter().copied())
+            .collect()
+    }
+
+    /// Sum of the convergence durations in `convergence_histogram()`, in ms.
+    pub fn convergence_sum_ms(&self) -> u64 {
+        self.convergence_sum_ms
+    }
+
+    /// Number of generations superseded by a newer one before the system became ready
+    /// since the service started. These are not part of `convergence_histogram()`.
+    pub fn incomplete_convergences(&self) -> u64 {
+        self.incomplete_convergences
+    }
+
+    /// Whether new watchers are turned away.
+    pub fn is_draining(&self) -> bool {
+        self.draining
+    }
+
+    /// Starts or stops turning new watchers away. Tracked watchers keep acking and
+    /// leaving as usual either way.
+    pub fn set_draining<F>(&mut self, draining: bool, mut signal_fn: F)
+    where
+        F: FnMut(Signal),
+    {
+        if draining == self.draining {
+            return;
+        }
+        info!(
+            "{} drain mode",
+            if draining { "entering" } else { "leaving" }
+        );
+        self.draining = draining;
+        let mut changed = PropMap::new();
+        changed.insert(DRAINING_PROPERTY.to_owned(), Variant(Box::new(draining)));
+        signal_fn(Signal::PropertiesChanged { changed });
+    }
+
+    /// Whether generation bumps are refused.
+    pub fn bumps_paused(&self) -> bool {
+        self.bumps_paused
+    }
+
+    /// Starts or stops refusing generation bumps, e.g. to freeze the generation during
+    /// maintenance. A bump already announced with `PreBump` still goes through.
+    pub fn set_bumps_paused<F>(&mut self, paused: bool, mut signal_fn: F)
+    where
+        F: FnMut(Signal),
+    {
+        if paused == self.bumps_paused {
+            return;
+        }
+        info!(
+            "{} generation bumps",
+            if paused { "pausing" } else { "resuming" }
+        );
+        self.bumps_paused = paused;
+        let mut changed = PropMap::new();
+        changed.insert(BUMPS_PAUSED_PROPERTY.to_owned(), Variant(Box::new(paused)));
+        signal_fn(Signal::PropertiesChanged { changed });
+    }
+
+    /// Number of tracked watchers, outdated ones included.
+    pub fn tracked_count(&self) -> usize {
+        self.watchers.len() + self.outdated_watchers.len()
+    }
+
+    /// Number of watchers a generation bump would mark outdated. Has no side effects.
+    pub fn preview_bump(&self) -> usize {
+        self.watchers.len()
+    }
+
+    /// Whether the system is ready at the latest generation: no watcher is outdated
+    /// and no announced bump is pending.
+    pub fn is_ready(&self) -> bool {
+        self.outdated_watchers.is_empty() && self.pending_bump.is_none()
+    }
+
+    pub fn outdated_count(&self) -> usize {
+        self.outdated_watchers.len()
+    }
+
+    pub fn outdated_watcher_ids(&self) -> Vec<String> {
+        self.outdated_watchers.keys().cloned().collect()
+    }
+
+    /// Outdated watchers, along with their bus names.
+    pub fn outdated_watchers(&self) -> impl Iterator<Item = (&str, &Watcher)> {
+        self.outdated_watchers
+            .iter()
+            .map(|(watcher_id, watcher)| (watcher_id.as_str(), watcher))
+    }
+
+    pub fn tracked_watcher_ids(&self) -> Vec<String> {
+        self.watchers.keys().cloned().collect()
+    }
+
+    /// Number of outdated watchers part of `group`.
+    pub fn outdated_count_in_group(&self, group: &str) -> usize {
+        self.outdated_watchers
+            .values()
+            .filter(|watcher| watcher.group() == Some(group))
+            .count()
+    }
+
+    /// Looks up a tracked watcher, also returning whether it is outdated.
+    pub fn find_watcher(&self, watcher_id: &str) -> Option<(&Watcher, bool)> {
+        self.watchers
+            .get(watcher_id)
+            .map(|w| (w, false))
+            .or_else(|| self.outdated_watchers.get(watcher_id).map(|w| (w, true)))
+    }
+
+    /// Returns whether the tracked watcher `watcher_id` is outdated, or an
+    /// `UnknownWatcher` error if it isn't tracked at all.
+    pub fn is_watcher_outdated(&self, watcher_id: &str) -> Result<bool, SysgenidError> {
+        self.find_watcher(watcher_id)
+            .map(|(_, outdated)| outdated)
+            .ok_or_else(|| SysgenidError::UnknownWatcher(watcher_id.to_owned()))
+    }
+
+    /// Returns how long the tracked watcher `watcher_id` has been outdated, a
+    /// `WatcherNotOutdated` error if it is up to date, or an `UnknownWatcher` error if it
+    /// isn't tracked at all.
+    pub fn watcher_lag(&self, watcher_id: &str) -> Result<Duration, SysgenidError> {
+        match self.find_watcher(watcher_id) {
+            Some((watcher, true)) => Ok(watcher.outdated_for().unwrap_or_default()),
+            Some((_, false)) => Err(SysgenidError::WatcherNotOutdated(watcher_id.to_owned())),
+            None => Err(SysgenidError::UnknownWatcher(watcher_id.to_owned())),
+        }
+    }
+
+    /// Watchers acking with a `group` join it, or keep their group otherwise.
+    /// `alive_fn` and `pid_fn` are only called for watchers not tracked yet, to find
+    /// out whether their connection is still around and their process id. `watcher_id`
+    /// is the bus name of the connection, or a `sub_watcher_id()` of it.
+    pub fn ack_watcher_gen_counter<A, P, F>(
+        &mut self,
+        watcher_id: &str,
+        watcher_counter: u32,
+        group: Option<&str>,
+        alive_fn: A,
+        pid_fn: P,
+        mut signal_fn: F,
+    ) -> Result<(), SysgenidError>
+    where
+        A: FnOnce() -> bool,
+        P: FnOnce() -> Option<u32>,
+        F: FnMut(Signal),
+    {
+        debug!("watcher {} ack val {}", watcher_id, watcher_counter);
+        let order = generation_cmp(watcher_counter, self.generation_counter);
+        if order == Ordering::Greater {
+            // Counters from the future are never handed out, this is either a buggy
+            // watcher or a replay.
+            warn!(
+                "watcher {} acked counter {} from the future, current counter is {}",
+                watcher_id, watcher_counter, self.generation_counter
+            );
+            Err(SysgenidError::InvalidCounter {
+                counter: watcher_counter,
+                current: self.generation_counter,
+            })
+        } else if order == Ordering::Less {
+            warn!(
+                "watcher {} acked invalid counter {}, current counter is {}",
+                watcher_id, watcher_counter, self.generation_counter
+            );
+            // Let the watcher know the current counter so it doesn't need to query it.
+            Err(SysgenidError::StaleCounter {
+                counter: watcher_counter,
+                current: self.generation_counter,
+            })
+        } else if let Some(watcher) = self.watchers.get_mut(watcher_id) {
+            // Up to date watchers acking again, e.g. after reconnecting, are a no-op
+            // success: nothing changes besides the group and nothing is signaled.
+            debug!(
+                "watcher {} already acked counter {}",
+                watcher_id, watcher_counter
+            );
+            if let Some(group) = group {
+                watcher.group = Some(group.to_owned());
+            }
+            Ok(())
+        } else {
+            // Outdated watchers keep their original record.
+            let mut watcher = match self.remove_outdated_watcher(watcher_id, &mut signal_fn) {
+                Some(watcher) => {
+                    self.record_adjustment_ack();
+                    watcher
+                }
+                None => {
+                    if self.draining {
+                        warn!("refusing watcher {} while draining", watcher_id);
+                        return Err(SysgenidError::Draining);
+                    }
+                    // Bound the memory misbehaving clients can make us use.
+                    if let Some(max_watchers) = self.max_watchers {
+                        if self.tracked_count() >= max_watchers {
+                            warn!(
+                                "refusing watcher {}, already tracking {} watchers",
+                                watcher_id, max_watchers
+                            );
+                            return Err(SysgenidError::TooManyWatchers(max_watchers));
+                        }
+                    }
+                    // The ack may race with the watcher leaving the bus, never start
+                    // tracking a connection which is already gone.
+                    if !alive_fn() {
+                        warn!("ignoring ack of watcher {} which left the bus", watcher_id);
+                        return Err(SysgenidError::WatcherGone(watcher_id.to_owned()));
+                    }
+                    info!("watcher {} registered", watcher_id);
+                    signal_fn(Signal::WatcherRegistered {
+                        watcher_id: watcher_id.to_owned(),
+                    });
+                    Watcher::new(pid_fn(), watcher_counter)
+                }
+            };
+            watcher.acked_generation = watcher_counter;
+            watcher.outdated_since = None;
+            watcher.missed_count = 0;
+            if let Some(group) = group {
+                watcher.group = Some(group.to_owned());
+            }
+            self.total_acks += 1;
+            self.watchers.insert(watcher_id.to_owned(), watcher);
+            debug!(
+                "watchers count {} ; outdated count {}",
+                self.watchers.len(),
+                self.outdated_watchers.len()
+            );
+            Ok(())
+        }
+    }
+
+    /// Tracks `watcher_id` at the current counter, returning it. Unlike reading the
+    /// counter and acking it in two calls, no bump can sneak in between. Watchers
+    /// registering with an `expected_adjust` time get flagged once they stay outdated
+    /// for longer, or keep their previous one otherwise.
+    pub fn register_watcher<A, P, F>(
+        &mut self,
+        watcher_id: &str,
+        expected_adjust: Option<Duration>,
+        alive_fn: A,
+        pid_fn: P,
+        signal_fn: F,
+    ) -> Result<u32, SysgenidError>
+    where
+        A: FnOnce() -> bool,
+        P: FnOnce() -> Option<u32>,
+        F: FnMut(Signal),
+    {
+        let counter = self.generation_counter;
+        self.ack_watcher_gen_counter(watcher_id, counter, None, alive_fn, pid_fn, signal_fn)?;
+        if let (Some(expected_adjust), Some(watcher)) =
+            (expected_adjust, self.watchers.get_mut(watcher_id))
+        {
+            watcher.expected_adjust = Some(expected_adjust);
+        }
+        Ok(counter)
+    }
+
+    /// Sets the object path `watcher_id` gets called back at on new generations, or
+    /// stops calling it back if `None`.
+    pub fn set_watcher_callback(
+        &mut self,
+        watcher_id: &str,
+        callback: Option<dbus::Path<'static>>,
+    ) -> Result<(), SysgenidError> {
+        self.tracked_watcher_mut(watcher_id)?.callback = callback;
+        Ok(())
+    }
+
+    /// Records that the tracked watcher `watcher_id` is still responsive.
+    pub fn heartbeat(&mut self, watcher_id: &str) -> Result<(), SysgenidError> {
+        self.tracked_watcher_mut(watcher_id)?.last_heartbeat = Some(Instant::now());
+        Ok(())
+    }
+
+    /// Watchers which sent heartbeats but went silent for longer than the heartbeat
+    /// timeout, along with how long ago they were last heard of. They stay tracked,
+    /// being connected to the bus; this only tells they may be wedged. Empty if no
+    /// heartbeat timeout is configured.
+    pub fn stale_watchers(&self) -> Vec<(&str, Duration)> {
+        let timeout = match self.heartbeat_timeout {
+            Some(timeout) => timeout,
+            None => return Vec::new(),
+        };
+        self.watchers
+            .iter()
+            .chain(self.outdated_watchers.iter())
+            .filter_map(|(watcher_id, watcher)| {
+                watcher
+                    .since_heartbeat()
+                    .filter(|since| *since > timeout)
+                    .map(|since| (watcher_id.as_str(), since))
+            })
+            .collect()
+    }
+
+    /// Returns the number of generations the tracked watcher `watcher_id` missed since it
+    /// last caught up, or since the previous call, and starts counting again from zero.
+    pub fn take_missed_generations(&mut self, watcher_id: &str) -> Result<u32, SysgenidError> {
+        let watcher = self.tracked_watcher_mut(watcher_id)?;
+        Ok(std::mem::take(&mut watcher.missed_count))
+    }
+
+    /// Only notifies the tracked watcher `watcher_id` of generations from `threshold` on,
+    /// in targeted signals mode. Broadcast notifications can't be held back.
+    pub fn set_notify_threshold(
+        &mut self,
+        watcher_id: &str,
+        threshold: u32,
+    ) -> Result<(), SysgenidError> {
+        self.tracked_watcher_mut(watcher_id)?.notify_threshold = Some(threshold);
+        Ok(())
+    }
+
+    fn tracked_watcher_mut(&mut self, watcher_id: &str) -> Result<&mut Watcher, SysgenidError> {
+        match self.watchers.get_mut(watcher_id) {
+            Some(watcher) => Ok(watcher),
+            None => self
+                .outdated_watchers
+                .get_mut(watcher_id)
+                .ok_or_else(|| SysgenidError::UnknownWatcher(watcher_id.to_owned())),
+        }
+    }
+
+    /// Hands out the watcher callbacks due since the previous call. The caller calls
+    /// them and acks on behalf of the watchers whose callback returned successfully.
+    pub fn take_watcher_callbacks(&mut self) -> Vec<WatcherCallback> {
+        std::mem::take(&mut self.pending_callbacks)
+    }
+
+    /// Stops tracking `watcher_id`, e.g. once it left the bus. Callers must only pass
+    /// bus names vouched for by the bus itself, or require authorization: this removes
+    /// any watcher, not just the caller's own. Returns whether the watcher was tracked.
+    pub fn remove_watcher<F>(
+        &mut self,
+        watcher_id: &str,
+        reason: RemovalReason,
+        mut signal_fn: F,
+    ) -> bool
+    where
+        F: FnMut(Signal),
+    {
+        debug!("remove watcher {} ({})", watcher_id, reason.as_str());
+        self.event_fds.remove(watcher_id);
+        self.pending_callbacks
+            .retain(|callback| callback.bus_name != watcher_id);
+        // Remove watcher from both tracking lists.
+        let was_outdated = self.outdated_watchers.contains_key(watcher_id);
+        let was_tracked = was_outdated || self.watchers.remove(watcher_id).is_some();
+        if was_tracked {
+            if reason == RemovalReason::Disconnected {
+                self.total_disconnects += 1;
+            }
+            // Let listeners know before the SystemReady this may lead to.
+            signal_fn(Signal::WatcherLost {
+                watcher_id: watcher_id.to_owned(),
+                was_outdated,
+                removal_reason: reason,
+            });
+            if was_outdated {
+                self.remove_outdated_watcher(watcher_id, &mut signal_fn);
+            }
+        }
+        debug!(
+            "watchers count {} ; outdated count {}",
+            self.watchers.len(),
+            self.outdated_watchers.len()
+        );
+        was_tracked
+    }
+
+    /// Stops tracking all the watchers of the connection `bus_name`, its own and its
+    /// sub-watchers, e.g. once it left the bus. Returns the number of removed watchers.
+    pub fn remove_connection<F>(
+        &mut self,
+        bus_name: &str,
+        reason: RemovalReason,
+        mut signal_fn: F,
+    ) -> u32
+    where
+        F: FnMut(Signal),
+    {
+        self.event_fds.remove(bus_name);
+        let watcher_ids: Vec<String> = self
+            .watchers
+            .keys()
+            .chain(self.outdated_watchers.keys())
+            .filter(|watcher_id| watcher_bus_name(watcher_id) == bus_name)
+            .cloned()
+            .collect();
+        for watcher_id in &watcher_ids {
+            self.remove_watcher(watcher_id, reason, &mut signal_fn);
+        }
+        watcher_ids.len() as u32
+    }
+
+    /// Stops tracking `watcher_id` on its own request.
+    pub fn unregister_watcher<F>(
+        &mut self,
+        watcher_id: &str,
+        signal_fn: F,
+    ) -> Result<(), SysgenidError>
+    where
+        F: FnMut(Signal),
+    {
+        if self.remove_watcher(watcher_id, RemovalReason::Unregistered, signal_fn) {
+            info!("watcher {} unregistered", watcher_id);
+            Ok(())
+        } else {
+            Err(SysgenidError::UnknownWatcher(watcher_id.to_owned()))
+        }
+    }
+
+    /// Drops all outdated watchers from tracking and unconditionally signals
+    /// that the system is ready. Returns the number of dropped watchers.
+    pub fn force_system_ready<F>(&mut self, mut signal_fn: F) -> u32
+    where
+        F: FnMut(Signal),
+    {
+        let dropped = self.outdated_watchers.len() as u32;
+        warn!(
+            "forcing system ready, dropping {} outdated watchers",
+            dropped
+        );
+        if dropped > 0 {
+            self.clear_outdated_watchers(&mut signal_fn);
+        }
+        signal_fn(self.system_ready());
+        dropped
+    }
+
+    /// Stops tracking all watchers, up-to-date and outdated, signalling that the system
+    /// is ready if any was outdated. Returns the number of dropped watchers.
+    pub fn reset_watchers<F>(&mut self, mut signal_fn: F) -> u32
+    where
+        F: FnMut(Signal),
+    {
+        let dropped = (self.watchers.len() + self.outdated_watchers.len()) as u32;
+        warn!("resetting watcher tracking, dropping {} watchers", dropped);
+        self.watchers.clear();
+        if !self.outdated_watchers.is_empty() {
+            self.clear_outdated_watchers(&mut signal_fn);
+            if self.coalesce_deadline.is_some() {
+                // Watchers haven't been told about the new generation yet.
+                self.system_ready_pending = true;
+            } else {
+                signal_fn(self.system_ready());
+            }
+        }
+        dropped
+    }
+
+    pub fn ack_deadline(&self) -> Option<Instant> {
+        self.ack_deadline
+    }
+
+    /// Drops the watchers that did not ack the latest generation within the ack
+    /// timeout, as if they had disconnected, and signals that the system is ready.
+    pub fn expire_outdated_watchers<F>(&mut self, now: Instant, mut signal_fn: F)
+    where
+        F: FnMut(Signal),
+    {
+        match self.ack_deadline {
+            Some(deadline) if now >= deadline => self.ack_deadline = None,
+            _ => return,
+        }
+        if self.outdated_watchers.is_empty() {
+            return;
+        }
+        warn!(
+            "ack timeout expired, dropping {} outdated watchers: {}",
+            self.outdated_watchers.len(),
+            self.outdated_watchers
+                .keys()
+                .cloned()
+                .collect::<Vec<String>>()
+                .join(", ")
+        );
+        self.clear_outdated_watchers(&mut signal_fn);
+        signal_fn(self.system_ready());
+    }
+
+    /// Bumps the generation with reason "auto" once the auto-bump interval elapsed, then
+    /// rearms the timer. Manual bumps in between don't delay it.
+    pub fn auto_bump<F>(&mut self, now: Instant, signal_fn: F)
+    where
+        F: FnMut(Signal),
+    {
+        match (self.auto_bump_deadline, self.auto_bump_interval) {
+            (Some(deadline), Some(interval)) if now >= deadline => {
+                self.auto_bump_deadline = Some(now + interval)
+            }
+            _ => return,
+        }
+        // Bumps refused otherwise, e.g. paused or rate limited, are skipped until the
+        // next period.
+        match self.bump_generation(0, AUTO_BUMP_REASON, signal_fn) {
+            Ok((counter, _)) => debug!("auto-bumped generation to {}", counter),
+            Err(e) => warn!("skipping auto-bump: {}", e),
+        }
+    }
+
+    /// Warns with `ConvergenceStalled` once the stall timeout of the latest generation
+    /// expired, if watchers are still outdated.
+    pub fn check_convergence_stalled<F>(&mut self, now: Instant, mut signal_fn: F)
+    where
+        F: FnMut(Signal),
+    {
+        match self.stall_deadline {
+            Some(deadline) if now >= deadline => self.stall_deadline = None,
+            _ => return,
+        }
+        if self.outdated_watchers.is_empty() {
+            return;
+        }
+        warn!(
+            "convergence to generation {} stalled, {} watchers still outdated",
+            self.generation_counter,
+            self.outdated_watchers.len()
+        );
+        signal_fn(Signal::ConvergenceStalled {
+            counter: self.generation_counter,
+            outstanding_count: self.outdated_watchers.len() as u32,
+        });
+    }
+
+    /// Drops all outdated watchers, signalling every group they leave ready.
+    fn clear_outdated_watchers<F>(&mut self, mut signal_fn: F)
+    where
+        F: FnMut(Signal),
+    {
+        let mut groups: Vec<String> = self
+            .outdated_watchers
+            .drain()
+            .filter_map(|(_, watcher)| watcher.group)
+            .collect();
+        groups.sort();
+        groups.dedup();
+        signal_fn(self.outdated_count_changed());
+        for group in groups {
+            info!("group {} is ready", group);
+            signal_fn(Signal::GroupReady { group });
+        }
+    }
+
+    /// Stops tracking `watcher_id` as outdated. Signals are only sent out if it was
+    /// actually outdated, `SystemReady` only if it was the last one: watchers which
+    /// were never outdated, e.g. acking the initial generation, never make the system
+    /// ready.
+    fn remove_outdated_watcher<F>(&mut self, watcher_id: &str, mut signal_fn: F) -> Option<Watcher>
+    where
+        F: FnMut(Signal),
+    {
+        debug!("remove outdated watcher {}", watcher_id);
+        let watcher = self.outdated_watchers.remove(watcher_id);
+        if let Some(watcher) = &watcher {
+            signal_fn(self.outdated_count_changed());
+            if let Some(group) = watcher.group() {
+                if self.outdated_count_in_group(group) == 0 {
+                    info!("just removed the last outdated watcher of group {}", group);
+                    signal_fn(Signal::GroupReady {
+                        group: group.to_owned(),
+                    });
+                }
+            }
+            if self.outdated_watchers.is_empty() {
+                if self.coalesce_deadline.is_some() {
+                    // Watchers haven't been told about the new generation yet.
+                    self.system_ready_pending = true;
+                } else {
+                    info!("just removed the last outdated watcher; system is ready");
+                    signal_fn(self.system_ready());
+                }
+            }
+        }
+        watcher
+    }
+
+    fn record_adjustment_ack(&mut self) {
+        let now = Instant::now();
+        match self.ack_burst_start {
+            Some(start) if now.duration_since(start) < ACK_BURST_WINDOW => self.ack_burst += 1,
+            _ => {
+                self.ack_burst_start = Some(now);
+                self.ack_burst = 1;
+            }
+        }
+        self.peak_concurrent_acks = max(self.peak_concurrent_acks, self.ack_burst);
+    }
+
+    /// `SystemReady` signal for the current generation, carrying how long watchers took
+    /// to adjust since the bump. Records the convergence of the generation, if pending.
+    fn system_ready(&mut self) -> Signal {
+        let adjust_duration = self.record_convergence();
+        Signal::SystemReady {
+            adjust_duration_ms: adjust_duration.as_millis() as u64,
+            for_counter: self.generation_counter,
+        }
+    }
+
+    /// Accounts for the system being ready at the current generation, if it wasn't
+    /// already, and returns how long it took since the bump.
+    fn record_convergence(&mut self) -> Duration {
+        let adjust_duration = self
+            .last_bump
+            .map(|bump| bump.elapsed())
+            .unwrap_or_default();
+        if std::mem::take(&mut self.converging) {
+            let adjust_ms = adjust_duration.as_millis() as u64;
+            let bucket = CONVERGENCE_BUCKETS_MS
+                .iter()
+                .position(|&bound| adjust_ms <= bound)
+                .unwrap_or(CONVERGENCE_BUCKETS_MS.len());
+            self.convergence_buckets[bucket] += 1;
+            self.convergence_sum_ms = self.convergence_sum_ms.saturating_add(adjust_ms);
+        }
+        adjust_duration
+    }
+
+    fn outdated_count_changed(&self) -> Signal {
+        let mut changed = PropMap::new();
+        changed.insert(
+            OUTDATED_WATCHER_COUNT_PROPERTY.to_owned(),
+            Variant(Box::new(self.outdated_watchers.len() as u32)),
+        );
+        Signal::PropertiesChanged { changed }
+    }
+}
@@ -1,114 +1,410 @@
+mod args;
+mod async_server;
+#[cfg(target_os = "linux")]
+mod chardev;
+mod cli;
+mod json_events;
+mod metrics;
+mod names;
+mod serve_stats;
+mod shutdown;
+
+use args::Args;
 use dbus::arg;
-use dbus::blocking::Connection;
+use dbus::blocking::stdintf::org_freedesktop_dbus::{
+    PropertiesPropertiesChanged, RequestNameReply,
+};
+use dbus::blocking::{Connection, SyncConnection};
+use dbus::channel::MatchingReceiver;
 use dbus::channel::Sender;
+use dbus::message::{MatchRule, MessageType, SignalArgs};
 use dbus::Message;
 use dbus_crossroads::{Context, Crossroads, MethodErr};
-use log::debug;
-use std::cmp::max;
+use log::{debug, error, info, warn};
+use sd_notify::NotifyState;
+use serve_stats::ServeStats;
+use std::cmp::min;
 use std::collections::HashMap;
 use std::error::Error;
+use std::os::unix::io::IntoRawFd;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use sysgenid_dbus::{
+    lock, sub_watcher_id, unique_sender, watcher_bus_name, Polkit, RemovalReason, Signal, Sysgenid,
+    SysgenidConfig, SysgenidError, BUMPS_PAUSED_PROPERTY, CALLBACK_INTERFACE,
+    CONVERGENCE_STALLED_SIGNAL, COUNTER_EXHAUSTED_SIGNAL, DRAINING_PROPERTY, FEATURES_PROPERTY,
+    GROUP_READY_SIGNAL, LAST_BUMP_TIMESTAMP_PROPERTY, NEW_GENERATION_SIGNAL,
+    ON_NEW_GENERATION_METHOD, OUTDATED_WATCHER_COUNT_PROPERTY, PRE_BUMP_SIGNAL,
+    SERVICE_SHUTTING_DOWN_SIGNAL, SYGENID_INTERFACE, SYSGEN_COUNTER_PROPERTY, SYSTEM_READY_SIGNAL,
+    VERSION_PROPERTY, WATCHER_LOST_SIGNAL, WATCHER_REGISTERED_SIGNAL,
+};
 
-const SYGENID_INTERFACE: &str = "com.RFC.sysgenid";
-const SYGENID_PATH: &str = "/com/RFC/sysgenid";
+// Upper bound for blocking on incoming messages, so timers are checked regularly.
+const MAX_PROCESS_WAIT: Duration = Duration::from_millis(1000);
+// Introspection annotation documenting methods and signals, as understood by binding
+// generators such as gdbus-codegen.
+const DOC_ANNOTATION: &str = "org.gtk.GDBus.DocString";
 
-// TODO: export read-only file for mapping sys gen counter.
+type LSysgenid = Arc<Mutex<Sysgenid>>;
+// Object path and state of every generation domain.
+type Domains = Arc<Vec<(dbus::Path<'static>, LSysgenid)>>;
 
-struct Watcher {}
+/// A `WaitSystemReady` or `BumpAndWait` call waiting for the outdated watchers of its
+/// domain to clear.
+struct PendingWait {
+    ctx: Context,
+    sysgenid: LSysgenid,
+    deadline: Instant,
+    // Generation bumped to by a `BumpAndWait` call, replied along with readiness.
+    counter: Option<u32>,
+}
 
-struct Sysgenid {
-    generation_counter: u32,
-    watchers: HashMap<String, Watcher>,
-    outdated_watchers: HashMap<String, Watcher>,
+/// Replies to the pending `WaitSystemReady` and `BumpAndWait` calls whose domain became
+/// ready or whose timeout expired. All of them are replied to if `all` is set.
+fn complete_pending_waits<S: Sender>(
+    conn: &S,
+    pending_waits: &Mutex<Vec<PendingWait>>,
+    now: Instant,
+    all: bool,
+) {
+    // Nothing is sent while holding the lock, calls queued meanwhile are handled next time.
+    let waits = std::mem::take(&mut *lock(pending_waits));
+    let mut still_pending = Vec::new();
+    for mut wait in waits {
+        let ready = lock(&wait.sysgenid).is_ready();
+        if !ready && !all && now < wait.deadline {
+            // Send out the signals of the bump of a `BumpAndWait` call right away.
+            let _ = wait.ctx.flush_messages(conn);
+            still_pending.push(wait);
+            continue;
+        }
+        match wait.counter {
+            Some(counter) => {
+                debug!("complete BumpAndWait, ready: {}", ready);
+                wait.ctx.reply(Ok((counter, ready)));
+            }
+            None => {
+                debug!("complete WaitSystemReady, ready: {}", ready);
+                wait.ctx.reply(Ok((ready,)));
+            }
+        }
+        let _ = wait.ctx.flush_messages(conn);
+    }
+    lock(pending_waits).extend(still_pending);
 }
 
-impl Sysgenid {
-    pub fn new() -> Self {
-        Sysgenid {
-            generation_counter: 0,
-            watchers: HashMap::new(),
-            outdated_watchers: HashMap::new(),
+/// Asks the bus for the unix process id of the connection owning `bus_name`.
+fn get_connection_pid(conn: &SyncConnection, bus_name: &str) -> Option<u32> {
+    let proxy = conn.with_proxy(
+        "org.freedesktop.DBus",
+        "/org/freedesktop/DBus",
+        Duration::from_millis(5000),
+    );
+    let res: Result<(u32,), dbus::Error> = proxy.method_call(
+        "org.freedesktop.DBus",
+        "GetConnectionUnixProcessID",
+        (bus_name,),
+    );
+    match res {
+        Ok((pid,)) => Some(pid),
+        Err(e) => {
+            debug!("could not get pid of {}: {}", bus_name, e);
+            None
         }
     }
+}
 
-    pub fn bump_generation<F>(&mut self, min_gen: u32, signal_fn: F)
-    where
-        F: FnOnce(&str, u32),
-    {
-        // Update generation counter.
-        self.generation_counter = max(min_gen, self.generation_counter + 1);
-        debug!("generation bumped to {}", self.generation_counter);
-        // TODO: update mapped value here
-        // Signal watchers new generation event.
-        signal_fn("NewGeneration", self.generation_counter);
-        // Mark all tracked watchers as outdated.
-        self.outdated_watchers
-            .extend(std::mem::take(&mut self.watchers));
-        debug!(
-            "watchers count {} ; outdated count {}",
-            self.watchers.len(),
-            self.outdated_watchers.len()
-        );
-    }
-
-    pub fn ack_watcher_gen_counter<F>(
-        &mut self,
-        watcher_id: &str,
-        watcher_counter: u32,
-        signal_fn: F,
-    ) -> Result<(), MethodErr>
-    where
-        F: FnOnce(&str),
-    {
-        debug!("watcher {} ack val {}", watcher_id, watcher_counter);
-        if watcher_counter != self.generation_counter {
-            debug!(
-                "invalid counter ack: {} != {}",
-                watcher_counter, self.generation_counter
-            );
-            Err(MethodErr::invalid_arg("watcher_counter"))
-        } else {
-            self.watchers.insert(watcher_id.to_owned(), Watcher {});
-            self.remove_outdated_watcher(watcher_id, signal_fn);
-            debug!(
-                "watchers count {} ; outdated count {}",
-                self.watchers.len(),
-                self.outdated_watchers.len()
+/// Asks the bus for the unique name of the connection owning `bus_name`.
+fn get_name_owner(conn: &SyncConnection, bus_name: &str) -> Option<String> {
+    let proxy = conn.with_proxy(
+        "org.freedesktop.DBus",
+        "/org/freedesktop/DBus",
+        Duration::from_millis(5000),
+    );
+    let res: Result<(String,), dbus::Error> =
+        proxy.method_call("org.freedesktop.DBus", "GetNameOwner", (bus_name,));
+    match res {
+        Ok((owner,)) => Some(owner),
+        Err(e) => {
+            debug!("could not get owner of {}: {}", bus_name, e);
+            None
+        }
+    }
+}
+
+/// Returns the well-known names on the bus along with the unique names owning them.
+fn list_name_owners(conn: &SyncConnection) -> Result<Vec<(String, String)>, dbus::Error> {
+    let proxy = conn.with_proxy(
+        "org.freedesktop.DBus",
+        "/org/freedesktop/DBus",
+        Duration::from_millis(5000),
+    );
+    let (names,): (Vec<String>,) = proxy.method_call("org.freedesktop.DBus", "ListNames", ())?;
+    // Names can lose their owner meanwhile, NameOwnerChanged tells about those.
+    Ok(names
+        .into_iter()
+        .filter(|name| !name.starts_with(':') && name != "org.freedesktop.DBus")
+        .filter_map(|name| get_name_owner(conn, &name).map(|owner| (name, owner)))
+        .collect())
+}
+
+/// Describes the connection owning `bus_name`, for error messages.
+fn describe_name_owner(conn: &SyncConnection, bus_name: &str) -> String {
+    match get_name_owner(conn, bus_name) {
+        Some(owner) => match get_connection_pid(conn, &owner) {
+            Some(pid) => format!("{} (pid {})", owner, pid),
+            None => owner,
+        },
+        None => "another connection".to_owned(),
+    }
+}
+
+/// Checks the caller of the current privileged method call with `polkit`, if enabled.
+fn authorize(polkit: &Option<Polkit>, ctx: &Context) -> Result<(), MethodErr> {
+    match polkit {
+        Some(polkit) => polkit.check_authorization(&sender_id(ctx)?),
+        None => Ok(()),
+    }
+}
+
+/// Builds the messages for `signal`, emitted by the sysgenid `iface` at `path`: a single
+/// broadcast one, unless the signal targets specific destinations.
+fn make_signals(
+    path: &dbus::Path,
+    iface: &dbus::strings::Interface,
+    signal: Signal,
+) -> Vec<Message> {
+    debug!("send signal: {}", signal.name());
+    json_events::signal(path, &signal);
+    let mut signal_msg = match signal {
+        Signal::PropertiesChanged { changed } => {
+            return vec![make_properties_changed(path, iface, changed)]
+        }
+        _ => dbus::Message::signal(path, iface, &signal.name().into()),
+    };
+    signal.append_args(&mut signal_msg);
+    // Friendlier names of watchers, for operators.
+    match &signal {
+        Signal::WatcherRegistered { watcher_id } | Signal::WatcherLost { watcher_id, .. } => {
+            signal_msg.append_all((names::well_known_names(watcher_bus_name(watcher_id)),))
+        }
+        _ => (),
+    }
+    match signal {
+        Signal::NewGeneration {
+            destinations: Some(destinations),
+            ..
+        } => {
+            let destinations = destinations.iter();
+            // Chardev watchers get notified through their open file.
+            #[cfg(target_os = "linux")]
+            let destinations =
+                destinations.filter(|destination| !chardev::is_chardev_watcher(destination));
+            destinations
+                .filter_map(|destination| {
+                    let mut msg = signal_msg.duplicate().ok()?;
+                    msg.set_destination(Some(destination.as_str().into()));
+                    Some(msg)
+                })
+                .collect()
+        }
+        _ => vec![signal_msg],
+    }
+}
+
+/// Unique bus name of the caller of the current method call. Every sender-identity
+/// sensitive operation goes through here.
+fn sender_id(ctx: &Context) -> Result<String, MethodErr> {
+    let sender = ctx.message().sender();
+    match unique_sender(sender.as_deref()) {
+        Ok(sender) => Ok(sender.to_owned()),
+        Err(e) => {
+            warn!("rejecting {}: {}", ctx.method(), e);
+            Err(MethodErr::failed(&e))
+        }
+    }
+}
+
+/// Acks `watcher_counter` on behalf of the sender of the current method call, or of its
+/// sub-watcher `sub_id`. Senders excluded by --track-deny only get the current counter.
+fn ack_watcher_counter(
+    ctx: &mut Context,
+    data: &mut LSysgenid,
+    query_conn: &SyncConnection,
+    watcher_counter: u32,
+    group: Option<&str>,
+    sub_id: Option<&str>,
+) -> Result<(u32,), MethodErr> {
+    let bus_name = sender_id(ctx)?;
+    if names::is_track_denied(&bus_name) {
+        debug!("not tracking {}, excluded by --track-deny", bus_name);
+        return Ok((lock(data).sysgen_counter(),));
+    }
+    let watcher_id = match sub_id {
+        Some(sub_id) => sub_watcher_id(&bus_name, sub_id)?,
+        None => bus_name.clone(),
+    };
+    let mut sysgenid = lock(data);
+    sysgenid.ack_watcher_gen_counter(
+        &watcher_id,
+        watcher_counter,
+        group,
+        || name_has_owner(query_conn, &bus_name),
+        || get_connection_pid(query_conn, &bus_name),
+        |signal| push_signal(ctx, signal),
+    )?;
+    json_events::ack(ctx.path(), &watcher_id, watcher_counter);
+    Ok((sysgenid.sysgen_counter(),))
+}
+
+/// Registers the sender of the current method call as watcher. Senders excluded by
+/// --track-deny only get the current counter.
+fn register_watcher(
+    ctx: &mut Context,
+    data: &mut LSysgenid,
+    query_conn: &SyncConnection,
+    expected_adjust: Option<Duration>,
+    callback: Option<dbus::Path<'static>>,
+) -> Result<(u32,), MethodErr> {
+    let watcher_id = sender_id(ctx)?;
+    if names::is_track_denied(&watcher_id) {
+        debug!("not tracking {}, excluded by --track-deny", watcher_id);
+        return Ok((lock(data).sysgen_counter(),));
+    }
+    let mut sysgenid = lock(data);
+    let counter = sysgenid.register_watcher(
+        &watcher_id,
+        expected_adjust,
+        || name_has_owner(query_conn, &watcher_id),
+        || get_connection_pid(query_conn, &watcher_id),
+        |signal| push_signal(ctx, signal),
+    )?;
+    // Under the same lock, so that no generation change goes without a callback.
+    if callback.is_some() {
+        sysgenid.set_watcher_callback(&watcher_id, callback)?;
+    }
+    json_events::ack(ctx.path(), &watcher_id, counter);
+    Ok((counter,))
+}
+
+/// Asks the bus whether `bus_name` still has an owner. Assumes it does if the bus
+/// can't tell.
+fn name_has_owner(conn: &SyncConnection, bus_name: &str) -> bool {
+    let proxy = conn.with_proxy(
+        "org.freedesktop.DBus",
+        "/org/freedesktop/DBus",
+        Duration::from_millis(5000),
+    );
+    let res: Result<(bool,), dbus::Error> =
+        proxy.method_call("org.freedesktop.DBus", "NameHasOwner", (bus_name,));
+    match res {
+        Ok((has_owner,)) => has_owner,
+        Err(e) => {
+            debug!("could not check owner of {}: {}", bus_name, e);
+            true
+        }
+    }
+}
+
+/// Stops tracking the watchers whose connection lost its bus name without us hearing
+/// about it, e.g. while the NameOwnerChanged match was not in place yet.
+fn reap_dead_watchers<S: Sender>(
+    conn: &S,
+    query_conn: &SyncConnection,
+    domains: &Domains,
+    iface: &dbus::strings::Interface,
+) {
+    for (path, sysgenid) in domains.iter() {
+        // Don't hold the lock across a bus round-trip per connection.
+        let bus_names = {
+            let sysgenid = lock(sysgenid);
+            let mut watcher_ids = sysgenid.tracked_watcher_ids();
+            watcher_ids.extend(sysgenid.outdated_watcher_ids());
+            // Chardev watchers aren't on the bus, closing their file is what removes them.
+            #[cfg(target_os = "linux")]
+            watcher_ids.retain(|watcher_id| !chardev::is_chardev_watcher(watcher_id));
+            let mut bus_names: Vec<String> = watcher_ids
+                .iter()
+                .map(|watcher_id| watcher_bus_name(watcher_id).to_owned())
+                .collect();
+            bus_names.sort();
+            bus_names.dedup();
+            bus_names
+        };
+        for bus_name in bus_names {
+            if name_has_owner(query_conn, &bus_name) {
+                continue;
+            }
+            warn!(
+                "tracked watcher {} of {} is gone from the bus, reaping it",
+                bus_name, path
             );
-            Ok(())
+            let mut signals = Vec::new();
+            lock(sysgenid).remove_connection(&bus_name, RemovalReason::Disconnected, |signal| {
+                signals.push(signal)
+            });
+            send_signals(conn, path, iface, signals);
         }
     }
+}
 
-    pub fn remove_watcher<F>(&mut self, watcher_id: &str, signal_fn: F)
-    where
-        F: FnOnce(&str),
-    {
-        debug!("remove watcher {}", watcher_id);
-        // Remove watcher from both tracking lists.
-        self.watchers.remove(watcher_id);
-        self.remove_outdated_watcher(watcher_id, signal_fn);
-        debug!(
-            "watchers count {} ; outdated count {}",
-            self.watchers.len(),
-            self.outdated_watchers.len()
-        );
-    }
-
-    fn remove_outdated_watcher<F>(&mut self, watcher_id: &str, signal_fn: F)
-    where
-        F: FnOnce(&str),
-    {
-        debug!("remove outdated watcher {}", watcher_id);
-        if self.outdated_watchers.remove(watcher_id).is_some() && self.outdated_watchers.is_empty()
-        {
-            debug!("just removed the last outdated watcher; system is ready");
-            signal_fn("SystemReady");
+/// Sends `signal` out on `conn`, outside of any method call.
+fn send_signal<S: Sender>(
+    conn: &S,
+    path: &dbus::Path,
+    iface: &dbus::strings::Interface,
+    signal: Signal,
+) {
+    for signal_msg in make_signals(path, iface, signal) {
+        if conn.send(signal_msg).is_err() {
+            warn!("failed to send signal of {}", path);
         }
     }
 }
 
-type LSysgenid = Arc<Mutex<Sysgenid>>;
+/// Sends `signals` out on `conn`, outside of any method call.
+///
+/// Signals raised while holding the lock of a domain are collected and only sent once
+/// it is released: sending may block, e.g. on a full outgoing queue, and would stall
+/// every method call waiting for the lock meanwhile.
+fn send_signals<S: Sender>(
+    conn: &S,
+    path: &dbus::Path,
+    iface: &dbus::strings::Interface,
+    signals: Vec<Signal>,
+) {
+    for signal in signals {
+        send_signal(conn, path, iface, signal);
+    }
+}
+
+/// Queues `signal` to be sent out along with the reply to the current method call.
+/// Crossroads sends them once the method handler returned, after its lock was released.
+fn push_signal(ctx: &mut Context, signal: Signal) {
+    let iface = ctx
+        .interface()
+        .expect("method call without interface")
+        .clone();
+    for signal_msg in make_signals(ctx.path(), &iface, signal) {
+        ctx.push_msg(signal_msg);
+    }
+}
+
+/// Builds an `org.freedesktop.DBus.Properties.PropertiesChanged` signal for the
+/// sysgenid `interface` at `path`.
+fn make_properties_changed(
+    path: &dbus::Path,
+    interface: &str,
+    changed_properties: arg::PropMap,
+) -> Message {
+    PropertiesPropertiesChanged {
+        interface_name: interface.to_owned(),
+        changed_properties,
+        invalidated_properties: Vec::new(),
+    }
+    .to_emit_message(path)
+}
 
 #[derive(Debug)]
 pub struct OrgFreedesktopDBusNameOwnerChanged {
@@ -140,54 +436,539 @@ impl dbus::message::SignalArgs for OrgFreedesktopDBusNameOwnerChanged {
     const INTERFACE: &'static str = "org.freedesktop.DBus";
 }
 
-fn main() -> Result<(), Box<dyn Error>> {
-    let sysgenid = Arc::new(Mutex::new(Sysgenid::new()));
+/// Service state shared by the blocking and the async server.
+struct Service {
+    config: SysgenidConfig,
+    domains: Domains,
+    iface: dbus::strings::Interface<'static>,
+    // Crossroads method handlers have no access to the serving connection, use a
+    // separate one for querying the bus daemon about watchers.
+    query_conn: Arc<SyncConnection>,
+    // WaitSystemReady calls are replied to from the serve loop, once they complete.
+    pending_waits: Arc<Mutex<Vec<PendingWait>>>,
+    // Watcher callbacks waiting for a reply, by call serial: domain index, watcher bus
+    // name and the counter it acks.
+    callbacks_in_flight: Mutex<HashMap<u32, (usize, String, u32)>>,
+    // Whether we are the primary owner of the bus name.
+    owns_name: AtomicBool,
+    serve_stats: Arc<ServeStats>,
+    // Signals of the chardev operations, sent out from the serve loop.
+    chardev_signals: Arc<Mutex<Vec<Signal>>>,
+    // Authorizes privileged method calls, if polkit is enabled.
+    polkit: Option<Polkit>,
+}
 
-    // Start up a connection to the session bus and request a name.
-    let c = Connection::new_session()?;
-    c.request_name(SYGENID_INTERFACE, false, true, false)?;
+impl Service {
+    /// Error for failing to get the bus name because another connection owns it.
+    fn name_taken_error(&self) -> Box<dyn Error> {
+        let config = &self.config;
+        let owner = describe_name_owner(&self.query_conn, &config.bus_name);
+        let hint = if config.replace {
+            "which does not allow replacement"
+        } else {
+            "(use --replace to take it over, or drop --do-not-queue to wait for it)"
+        };
+        format!(
+            "bus name {} is already owned by {} {}",
+            config.bus_name, owner, hint
+        )
+        .into()
+    }
 
-    // Create a new crossroads instance so that introspection and properties interfaces
-    // are added by default on object path additions.
-    let mut cr = Crossroads::new();
+    /// Whether we are the primary owner of the bus name.
+    fn owns_name(&self) -> bool {
+        self.owns_name.load(Ordering::SeqCst)
+    }
 
-    // Track connections on the bus to find out when any active client/watcher disconnects.
-    {
-        let proxy = c.with_proxy(
-            "org.freedesktop.DBus",
-            "/org/freedesktop/DBus",
-            Duration::from_millis(5000),
-        );
-        let s2 = sysgenid.clone();
-        let _id = proxy.match_signal(
-            move |h: OrgFreedesktopDBusNameOwnerChanged, c: &Connection, _: &Message| {
-                // When there's someone leaving the bus,
-                if h.arg0.eq(&h.arg1) {
-                    debug!("client {} leaving the bus", h.arg0);
-                    let mut sysgenid = s2.lock().unwrap();
-                    sysgenid.remove_watcher(&h.arg0, |name| {
-                        debug!("send signal: {}", name);
-                        let mut signal_msg = dbus::Message::signal(
-                            &SYGENID_PATH.into(),
-                            &SYGENID_INTERFACE.into(),
-                            &name.into(),
+    /// Keeps track of owning the bus name, as reported by the NameAcquired and NameLost
+    /// signal `msg`. Shuts the service down once another connection took the name over,
+    /// rather than serving on a name clients no longer reach us through.
+    fn handle_name_ownership(&self, msg: &Message) {
+        match msg.read1::<&str>() {
+            Ok(name) if name == self.config.bus_name => (),
+            _ => return,
+        }
+        let acquired = match msg.member().as_deref() {
+            Some("NameAcquired") => true,
+            Some("NameLost") => false,
+            _ => return,
+        };
+        let owned = self.owns_name.swap(acquired, Ordering::SeqCst);
+        if acquired && !owned {
+            info!("acquired bus name {}", self.config.bus_name);
+        } else if !acquired && owned {
+            warn!(
+                "lost bus name {} to another connection, shutting down",
+                self.config.bus_name
+            );
+            shutdown::request();
+        }
+    }
+
+    /// Stops tracking a client leaving the bus, as reported by the NameOwnerChanged
+    /// signal `msg`.
+    fn handle_name_owner_changed<S: Sender>(&self, msg: &Message, conn: &S) {
+        let h = match OrgFreedesktopDBusNameOwnerChanged::from_message(msg) {
+            Some(h) => h,
+            None => return,
+        };
+        debug!("handle NameOwnerChanged of {}", h.arg0);
+        // When there's someone leaving the bus,
+        if h.arg0.eq(&h.arg1) {
+            debug!("client {} leaving the bus", h.arg0);
+            lock(&self.callbacks_in_flight).retain(|_, (_, watcher_id, _)| *watcher_id != h.arg0);
+            for (path, sysgenid) in self.domains.iter() {
+                let mut signals = Vec::new();
+                {
+                    let mut sysgenid = lock(sysgenid);
+                    if let Some((_, outdated)) = sysgenid.find_watcher(&h.arg0) {
+                        info!(
+                            "tracked watcher {} of {} left the bus (outdated: {})",
+                            h.arg0, path, outdated
                         );
-                        signal_msg.append_all(());
-                        c.send(signal_msg).unwrap();
+                    }
+                    // Along with the sub-watchers it hosts, if any.
+                    sysgenid.remove_connection(&h.arg0, RemovalReason::Disconnected, |signal| {
+                        signals.push(signal)
                     });
                 }
+                send_signals(conn, path, &self.iface, signals);
+            }
+        }
+        // Only once the signals about a departure went out, for them to name it.
+        names::name_owner_changed(&h.arg0, &h.arg1, &h.arg2, || {
+            name_has_owner(&self.query_conn, &h.arg1)
+        });
+    }
+
+    /// Calls the callbacks of the watchers which registered one, for the latest
+    /// generation of their domain.
+    fn call_watchers<S: Sender>(&self, conn: &S) {
+        for (idx, (path, sysgenid)) in self.domains.iter().enumerate() {
+            let callbacks = lock(sysgenid).take_watcher_callbacks();
+            for callback in callbacks {
+                debug!("calling back watcher {}", callback.bus_name);
+                let msg = Message::method_call(
+                    &callback.bus_name.as_str().into(),
+                    &callback.path,
+                    &CALLBACK_INTERFACE.into(),
+                    &ON_NEW_GENERATION_METHOD.into(),
+                )
+                .append1(callback.counter);
+                match conn.send(msg) {
+                    Ok(serial) => {
+                        lock(&self.callbacks_in_flight)
+                            .insert(serial, (idx, callback.bus_name, callback.counter));
+                    }
+                    Err(()) => warn!(
+                        "failed to call back watcher {} of {}",
+                        callback.bus_name, path
+                    ),
+                }
+            }
+        }
+    }
+
+    /// Acks on behalf of the watcher whose callback returned successfully, as told by
+    /// the reply `msg`.
+    fn handle_callback_reply<S: Sender>(&self, mut msg: Message, conn: &S) {
+        let in_flight = msg
+            .get_reply_serial()
+            .and_then(|serial| lock(&self.callbacks_in_flight).remove(&serial));
+        let (idx, watcher_id, counter) = match in_flight {
+            Some(in_flight) => in_flight,
+            None => return,
+        };
+        let (path, sysgenid) = &self.domains[idx];
+        if let Err(e) = msg.as_result() {
+            warn!(
+                "callback of watcher {} of {} failed: {}",
+                watcher_id,
+                path,
+                e.name().unwrap_or("unknown error")
+            );
+            return;
+        }
+        let mut signals = Vec::new();
+        // Only ack for watchers still tracked, a reply never starts tracking anyone.
+        let res = lock(sysgenid).ack_watcher_gen_counter(
+            &watcher_id,
+            counter,
+            None,
+            || false,
+            || None,
+            |signal| signals.push(signal),
+        );
+        send_signals(conn, path, &self.iface, signals);
+        match res {
+            Ok(()) => json_events::ack(path, &watcher_id, counter),
+            Err(e) => debug!("ignoring callback reply of watcher {}: {}", watcher_id, e),
+        }
+    }
+
+    /// How long the serve loop can wait for incoming messages, so that it wakes up in
+    /// time for ack timeouts, coalesced notifications, auto-bumps and WaitSystemReady
+    /// timeouts.
+    fn next_timeout(&self) -> Duration {
+        let deadline = self
+            .domains
+            .iter()
+            .filter_map(|(_, sysgenid)| lock(sysgenid).next_deadline())
+            .chain(lock(&self.pending_waits).iter().map(|wait| wait.deadline))
+            .min();
+        deadline
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+            .map_or(MAX_PROCESS_WAIT, |until| min(until, MAX_PROCESS_WAIT))
+    }
+
+    /// Handles the timers that expired, rescheduling `next_reap` once it is due.
+    fn process_timers<S: Sender>(&self, conn: &S, next_reap: &mut Instant) {
+        for (path, sysgenid) in self.domains.iter() {
+            let mut signals = Vec::new();
+            {
+                // The chardev only serves the single domain.
+                signals.append(&mut lock(&self.chardev_signals));
+                let mut sysgenid = lock(sysgenid);
+                let now = Instant::now();
+                sysgenid.auto_bump(now, |signal| signals.push(signal));
+                sysgenid.flush_pending_bump(now, |signal| signals.push(signal));
+                sysgenid.flush_coalesced(now, |signal| signals.push(signal));
+                sysgenid.check_convergence_stalled(now, |signal| signals.push(signal));
+                sysgenid.expire_outdated_watchers(now, |signal| signals.push(signal));
+            }
+            send_signals(conn, path, &self.iface, signals);
+        }
+        if Instant::now() >= *next_reap {
+            reap_dead_watchers(conn, &self.query_conn, &self.domains, &self.iface);
+            *next_reap = Instant::now() + self.config.reap_interval;
+        }
+        complete_pending_waits(conn, &self.pending_waits, Instant::now(), false);
+    }
+
+    /// Starts the side services and lets systemd know we're up, once the bus name is
+    /// ours.
+    fn start(&self) -> Result<(), Box<dyn Error>> {
+        if let Some(addr) = self.config.metrics_addr {
+            metrics::spawn_server(addr, self.domains.clone(), self.serve_stats.clone())?;
+        }
+        #[cfg(not(target_os = "linux"))]
+        if self.config.chardev.is_some() {
+            return Err("the chardev is only supported on Linux".into());
+        }
+        #[cfg(target_os = "linux")]
+        if let Some(dev_path) = &self.config.chardev {
+            let (path, sysgenid) = &self.domains[0];
+            let chardev_signals = self.chardev_signals.clone();
+            let query_conn = self.query_conn.clone();
+            // Pinging ourselves wakes the serve loop up to send the signals out.
+            let mut wake_up =
+                Message::new_method_call(self.config.bus_name.as_str(), path, &self.iface, "Ping")?;
+            wake_up.set_no_reply(true);
+            chardev::spawn(dev_path, sysgenid.clone(), move |mut signals| {
+                if signals.is_empty() {
+                    return;
+                }
+                lock(&chardev_signals).append(&mut signals);
+                let sent = wake_up.duplicate().map(|msg| query_conn.send(msg));
+                if !matches!(sent, Ok(Ok(_))) {
+                    warn!("failed to wake up the serve loop for chardev signals");
+                }
+            })?;
+        }
+        if self.config.resolve_names {
+            names::enable(list_name_owners(&self.query_conn)?);
+        }
+        if !self.config.track_deny.is_empty() {
+            names::deny_tracking(&self.config.track_deny, |name| {
+                get_name_owner(&self.query_conn, name)
+            });
+        }
+        shutdown::install_handlers()?;
+        // Let systemd know dependent units can go ahead now that we're reachable.
+        if let Err(e) = sd_notify::notify(false, &[NotifyState::Ready]) {
+            warn!("failed to notify readiness to systemd: {}", e);
+        }
+        info!("SysGenID DBus service started");
+        Ok(())
+    }
+
+    /// Lets clients know we're going away, before the bus name is released.
+    fn stop<S: Sender>(&self, conn: &S) {
+        info!("SysGenID DBus service shutting down");
+        if let Err(e) = sd_notify::notify(false, &[NotifyState::Stopping]) {
+            warn!("failed to notify shutdown to systemd: {}", e);
+        }
+        for (path, sysgenid) in self.domains.iter() {
+            let mut signals = Vec::new();
+            lock(sysgenid).commit_pending_bump(|signal| signals.push(signal));
+            signals.push(Signal::ServiceShuttingDown);
+            send_signals(conn, path, &self.iface, signals);
+            // Every bump is persisted as it happens, this is just belt and braces.
+            if let Err(e) = lock(sysgenid).persist() {
+                error!("failed to persist generation of {}: {}", path, e);
+            }
+        }
+        complete_pending_waits(conn, &self.pending_waits, Instant::now(), true);
+    }
+}
+
+/// Match rule for the NameOwnerChanged signals of names losing their owner, of the
+/// well-known names excluded from tracking, or of any name changing owners when
+/// resolving names, along with the match strings to register it with the bus.
+fn name_owner_changed_match(
+    config: &SysgenidConfig,
+) -> Result<(MatchRule<'static>, Vec<String>), Box<dyn Error>> {
+    let bus_name = dbus::strings::BusName::new("org.freedesktop.DBus")?;
+    let bus_path = dbus::Path::new("/org/freedesktop/DBus")?;
+    // Any client can send us a crafted NameOwnerChanged signal directly, trying to get
+    // another client's watcher removed. Strictly require the bus itself as sender,
+    // the default sender check lets signals from unique names through.
+    let mr = OrgFreedesktopDBusNameOwnerChanged::match_rule(None, Some(&bus_path))
+        .with_strict_sender(bus_name)
+        .static_clone();
+    if config.resolve_names {
+        return Ok((mr.clone(), vec![mr.match_str()]));
+    }
+    // Only have the bus send names losing their owner (no new owner, `arg2=''`),
+    // instead of waking us up for every name change on the bus, unless resolving
+    // names. MatchRule can't express argument filters, so extend its match string.
+    let mut match_strs = vec![format!("{},arg2=''", mr.match_str())];
+    match_strs.extend(
+        config
+            .track_deny
+            .iter()
+            .filter(|name| !name.starts_with(':'))
+            .map(|name| format!("{},arg0='{}'", mr.match_str(), name)),
+    );
+    Ok((mr, match_strs))
+}
+
+/// Match rules for the signals of the bus about our names being acquired and lost.
+fn name_ownership_matches() -> Result<Vec<MatchRule<'static>>, Box<dyn Error>> {
+    let bus_name = dbus::strings::BusName::new("org.freedesktop.DBus")?;
+    // These are sent to us directly, make sure they come from the bus itself.
+    Ok(["NameAcquired", "NameLost"]
+        .iter()
+        .map(|member| {
+            MatchRule::new_signal("org.freedesktop.DBus", *member)
+                .with_strict_sender(bus_name.clone())
+                .static_clone()
+        })
+        .collect())
+}
+
+/// Serves clients from a blocking loop until asked to shut down.
+fn serve_blocking(service: Arc<Service>, mut cr: Crossroads) -> Result<(), Box<dyn Error>> {
+    let config = &service.config;
+    // Start up a connection to the requested bus and request a name.
+    let c = Connection::from(config.open_bus()?);
+    info!(
+        "SysGenID DBus service connected to the {}",
+        config.describe_bus()
+    );
+    // Follow the ownership of the bus name, from before asking for it.
+    for mr in name_ownership_matches()? {
+        let service2 = service.clone();
+        c.start_receive(
+            mr,
+            Box::new(move |msg: Message, _: &Connection| {
+                service2.handle_name_ownership(&msg);
                 true
-            },
+            }),
+        );
+    }
+    // Wait in line behind another instance unless asked not to: serving without owning
+    // the name would leave clients talking to the other one.
+    let reply = c
+        .request_name(
+            config.bus_name.as_str(),
+            config.allow_replacement,
+            config.replace,
+            config.do_not_queue,
+        )
+        .map_err(|e| format!("failed to acquire bus name {}: {}", config.bus_name, e))?;
+    match reply {
+        RequestNameReply::PrimaryOwner | RequestNameReply::AlreadyOwner => {
+            service.owns_name.store(true, Ordering::SeqCst);
+        }
+        RequestNameReply::InQueue if !config.do_not_queue => {
+            info!("waiting in line for bus name {}", config.bus_name);
+            while !service.owns_name() {
+                c.process(Duration::from_secs(60))?;
+            }
+        }
+        RequestNameReply::Exists | RequestNameReply::InQueue => {
+            return Err(service.name_taken_error())
+        }
+    }
+
+    // Track connections on the bus to find out when any active client/watcher disconnects.
+    let (mr, match_strs) = name_owner_changed_match(config)?;
+    for match_str in match_strs.iter() {
+        c.add_match_no_cb(match_str)?;
+    }
+    let service2 = service.clone();
+    c.start_receive(
+        mr,
+        Box::new(move |msg: Message, c: &Connection| {
+            service2.handle_name_owner_changed(&msg, c);
+            true
+        }),
+    );
+
+    // Replies to watcher callbacks.
+    for msg_type in [MessageType::MethodReturn, MessageType::Error].iter() {
+        let mut mr = MatchRule::new();
+        mr.msg_type = Some(*msg_type);
+        let service2 = service.clone();
+        c.start_receive(
+            mr,
+            Box::new(move |msg: Message, c: &Connection| {
+                service2.handle_callback_reply(msg, c);
+                true
+            }),
         );
     }
 
+    service.start()?;
+    // Hand all incoming method calls over to crossroads, timing them.
+    let service2 = service.clone();
+    c.start_receive(
+        MatchRule::new_method_call(),
+        Box::new(move |msg, conn| {
+            let method = msg.member().map(|m| m.to_string()).unwrap_or_default();
+            let started = Instant::now();
+            // Crossroads only fails messages it can't dispatch, keep serving the others.
+            if cr.handle_message(msg, conn).is_err() {
+                warn!("failed to handle {} call", method);
+            }
+            service2.serve_stats.record(&method, started.elapsed());
+            true
+        }),
+    );
+
+    // Serve clients until asked to shut down, waking up in time for timers.
+    let mut next_reap = Instant::now() + service.config.reap_interval;
+    while !shutdown::requested() {
+        c.process(service.next_timeout())?;
+        service.process_timers(&c, &mut next_reap);
+        service.call_watchers(&c);
+    }
+
+    service.stop(&c);
+    c.release_name(config.bus_name.as_str())?;
+    // Make sure everything queued goes out before the connection is closed.
+    c.channel().flush();
+    Ok(())
+}
+
+/// Builds the crossroads instance serving the sysgenid interface at the object path of
+/// every generation domain.
+fn build_crossroads(service: &Arc<Service>) -> Crossroads {
+    let config = &service.config;
+    let domains = &service.domains;
+    let features = config.features();
+
+    // Create a new crossroads instance so that introspection and properties interfaces
+    // are added by default on object path additions.
+    let mut cr = Crossroads::new();
+
     // Build the com.RFC.sysgenid interface.
     let iface_token = cr.register(SYGENID_INTERFACE, |b| {
         // This row is just for introspection: It advertises that we can send a
-        // NewSystemGeneration signal. We use the single-tuple to say that we have one single argument,
-        // named "gen_counter" of type "u32".
-        b.signal::<(u32,), _>("NewSystemGeneration", ("sysgen_counter",));
-        b.signal::<(), _>("SystemReady", ());
+        // NewGeneration signal. We use the tuple to say that we have three arguments,
+        // named "sysgen_counter" of type "u32", "reason" of type "String" and "epoch" of
+        // type "u64".
+        b.signal::<(u32, String, u64), _>(
+            NEW_GENERATION_SIGNAL,
+            ("sysgen_counter", "reason", "epoch"),
+        )
+        .annotate(
+            DOC_ANNOTATION,
+            "The generation changed to sysgen_counter for the given reason, at the given epoch. \
+             Tracked watchers are outdated until they ack it.",
+        );
+        b.signal::<(u64, u32), _>(SYSTEM_READY_SIGNAL, ("adjust_duration_ms", "for_counter"))
+            .annotate(
+                DOC_ANNOTATION,
+                "No tracked watcher is outdated anymore at generation for_counter, \
+                 adjust_duration_ms after it was entered.",
+            );
+        b.signal::<(u32,), _>(COUNTER_EXHAUSTED_SIGNAL, ("sysgen_counter",))
+            .annotate(
+                DOC_ANNOTATION,
+                "A generation change was refused, the counter is saturated at sysgen_counter.",
+            );
+        b.signal::<(String, Vec<String>), _>(
+            WATCHER_REGISTERED_SIGNAL,
+            ("watcher_id", "well_known_names"),
+        )
+        .annotate(
+            DOC_ANNOTATION,
+            "The watcher watcher_id, a unique bus name or <bus name>/<sub_id> for a \
+             sub-watcher, started being tracked.",
+        );
+        b.signal::<(String, bool, String, Vec<String>), _>(
+            WATCHER_LOST_SIGNAL,
+            (
+                "watcher_id",
+                "was_outdated",
+                "removal_reason",
+                "well_known_names",
+            ),
+        )
+        .annotate(
+            DOC_ANNOTATION,
+            "The watcher watcher_id, a unique bus name or <bus name>/<sub_id> for a \
+             sub-watcher, stopped being tracked for removal_reason, while outdated or not.",
+        );
+        b.signal::<(), _>(SERVICE_SHUTTING_DOWN_SIGNAL, ())
+            .annotate(
+                DOC_ANNOTATION,
+                "The service is stopping, no further generation changes are notified.",
+            );
+        b.signal::<(String,), _>(GROUP_READY_SIGNAL, ("group",))
+            .annotate(
+                DOC_ANNOTATION,
+                "No watcher of the watcher group named group is outdated anymore.",
+            );
+        b.signal::<(u32,), _>(PRE_BUMP_SIGNAL, ("proposed_counter",))
+            .annotate(
+                DOC_ANNOTATION,
+                "The generation is about to change to proposed_counter, after a grace period.",
+            );
+        b.signal::<(u32, u32), _>(CONVERGENCE_STALLED_SIGNAL, ("counter", "outstanding_count"))
+            .annotate(
+                DOC_ANNOTATION,
+                "Watchers are still outdated a while after the generation changed to counter, \
+                 outstanding_count of them.",
+            );
+        // Read-only property mirroring GetSysGenCounter, with change notifications.
+        b.property::<u32, _>(SYSGEN_COUNTER_PROPERTY)
+            .get(|_, data: &mut LSysgenid| Ok(lock(data).sysgen_counter()));
+        // Read-only property mirroring CountOutdatedWatchers, with change notifications.
+        b.property::<u32, _>(OUTDATED_WATCHER_COUNT_PROPERTY)
+            .get(|_, data: &mut LSysgenid| Ok(lock(data).outdated_count() as u32));
+        // Read-only property telling when the generation last changed, with change
+        // notifications.
+        b.property::<u64, _>(LAST_BUMP_TIMESTAMP_PROPERTY)
+            .get(|_, data: &mut LSysgenid| Ok(lock(data).last_bump_timestamp()));
+        // Read-only property telling whether new watchers are turned away, with change
+        // notifications.
+        b.property::<bool, _>(DRAINING_PROPERTY)
+            .get(|_, data: &mut LSysgenid| Ok(lock(data).is_draining()));
+        // Read-only property telling whether generation bumps are refused, with change
+        // notifications.
+        b.property::<bool, _>(BUMPS_PAUSED_PROPERTY)
+            .get(|_, data: &mut LSysgenid| Ok(lock(data).bumps_paused()));
+        // Constant properties telling clients what this service can do, rather than
+        // having them try out methods.
+        b.property::<String, _>(VERSION_PROPERTY)
+            .emits_changed_const()
+            .get(|_, _| Ok(env!("CARGO_PKG_VERSION").to_owned()));
+        b.property::<Vec<String>, _>(FEATURES_PROPERTY)
+            .emits_changed_const()
+            .get(move |_, _| Ok(features.clone()));
         // Let's add a method to the interface. We have the method name, followed by
         // names of input and output arguments (used for introspection). The closure then controls
         // the types of these arguments. The last argument to the closure is a tuple of the input arguments.
@@ -197,9 +978,43 @@ fn main() -> Result<(), Box<dyn Error>> {
             ("sysgen_counter",),
             |_: &mut Context, data: &mut LSysgenid, ()| {
                 debug!("handle method GetSysGenCounter");
-                let sysgenid = data.lock().unwrap();
-                Ok((sysgenid.generation_counter,))
+                let sysgenid = lock(data);
+                Ok((sysgenid.sysgen_counter(),))
+            },
+        )
+        .annotate(
+            DOC_ANNOTATION,
+            "Returns the current system generation counter.",
+        );
+        b.method(
+            "GetSysGenCounterWithEpoch",
+            (),
+            ("sysgen_counter", "epoch"),
+            |_: &mut Context, data: &mut LSysgenid, ()| {
+                debug!("handle method GetSysGenCounterWithEpoch");
+                let sysgenid = lock(data);
+                Ok((sysgenid.sysgen_counter(), sysgenid.epoch()))
+            },
+        )
+        .annotate(
+            DOC_ANNOTATION,
+            "Returns the current system generation counter and its epoch, which increases with \
+             every generation change and is carried by NewGeneration: signals with an epoch up to \
+             the returned one are covered by this read.",
+        );
+        // Liveness probe: proves the serve loop handles calls, without taking the lock.
+        b.method(
+            "Ping",
+            (),
+            ("status",),
+            |_: &mut Context, _: &mut LSysgenid, ()| {
+                debug!("handle method Ping");
+                Ok(("pong".to_owned(),))
             },
+        )
+        .annotate(
+            DOC_ANNOTATION,
+            "Returns pong, without side effects, for liveness probes.",
         );
         b.method(
             "CountOutdatedWatchers",
@@ -207,53 +1022,824 @@ fn main() -> Result<(), Box<dyn Error>> {
             ("outdated_watchers",),
             |_: &mut Context, data: &mut LSysgenid, ()| {
                 debug!("handle method CountOutdatedWatchers");
-                let sysgenid = data.lock().unwrap();
-                let ret = sysgenid.outdated_watchers.len() as u32;
+                let sysgenid = lock(data);
+                let ret = sysgenid.outdated_count() as u32;
+                Ok((ret,))
+            },
+        )
+        .annotate(
+            DOC_ANNOTATION,
+            "Returns the number of tracked watchers yet to ack the current generation.",
+        );
+        b.method(
+            "GetOutdatedWatchers",
+            (),
+            ("outdated_watchers", "well_known_names"),
+            |_: &mut Context, data: &mut LSysgenid, ()| {
+                debug!("handle method GetOutdatedWatchers");
+                let outdated = lock(data).outdated_watcher_ids();
+                let names: Vec<Vec<String>> = outdated
+                    .iter()
+                    .map(|id| names::well_known_names(watcher_bus_name(id)))
+                    .collect();
+                Ok((outdated, names))
+            },
+        )
+        .annotate(
+            DOC_ANNOTATION,
+            "Returns the ids of the tracked watchers yet to ack the current generation, unique \
+             bus names or <bus name>/<sub_id> for sub-watchers, and the well-known names their \
+             connections own with --resolve-names.",
+        );
+        b.method(
+            "GetOutdatedWatcherBudgets",
+            (),
+            ("outdated_watchers",),
+            |_: &mut Context, data: &mut LSysgenid, ()| {
+                debug!("handle method GetOutdatedWatcherBudgets");
+                let sysgenid = lock(data);
+                // Watchers which didn't tell their expected adjustment time report zero.
+                let ret: Vec<(String, u64, u64, bool)> = sysgenid
+                    .outdated_watchers()
+                    .map(|(watcher_id, watcher)| {
+                        (
+                            watcher_id.to_owned(),
+                            watcher.outdated_for().unwrap_or_default().as_millis() as u64,
+                            watcher.expected_adjust().unwrap_or_default().as_millis() as u64,
+                            watcher.over_budget(),
+                        )
+                    })
+                    .collect();
                 Ok((ret,))
             },
+        )
+        .annotate(
+            DOC_ANNOTATION,
+            "Returns, for every outdated watcher, how long it has been outdated, the adjustment \
+             time it declared and whether it is over it, in milliseconds.",
         );
+        b.method(
+            "GetStaleWatchers",
+            (),
+            ("stale_watchers",),
+            |_: &mut Context, data: &mut LSysgenid, ()| {
+                debug!("handle method GetStaleWatchers");
+                let sysgenid = lock(data);
+                let ret: Vec<(String, u64)> = sysgenid
+                    .stale_watchers()
+                    .into_iter()
+                    .map(|(watcher_id, since)| (watcher_id.to_owned(), since.as_millis() as u64))
+                    .collect();
+                Ok((ret,))
+            },
+        )
+        .annotate(
+            DOC_ANNOTATION,
+            "Returns the tracked watchers which stopped sending heartbeats, with the milliseconds \
+             since their last one.",
+        );
+        b.method(
+            "GetTrackedWatchers",
+            (),
+            ("tracked_watchers",),
+            |_: &mut Context, data: &mut LSysgenid, ()| {
+                debug!("handle method GetTrackedWatchers");
+                let sysgenid = lock(data);
+                let ret = sysgenid.tracked_watcher_ids();
+                Ok((ret,))
+            },
+        )
+        .annotate(
+            DOC_ANNOTATION,
+            "Returns the ids of the up-to-date tracked watchers, unique bus names or \
+             <bus name>/<sub_id> for sub-watchers.",
+        );
+        // Handlers needing more than the state of their domain own a handle on the service.
+        let shared = service.clone();
         b.method(
             "AckWatcherCounter",
             ("watcher_counter",),
             ("sysgen_counter",),
-            |ctx: &mut Context, data: &mut LSysgenid, (watcher_counter,): (u32,)| {
+            move |ctx: &mut Context, data: &mut LSysgenid, (watcher_counter,): (u32,)| {
                 debug!("handle method AckWatcherCounter");
-                let watcher_id = ctx
-                    .message()
-                    .sender()
-                    .ok_or(MethodErr::failed("could not identify sender"))?
-                    .to_string();
-                let mut sysgenid = data.lock().unwrap();
-                sysgenid.ack_watcher_gen_counter(&watcher_id, watcher_counter, |name| {
-                    debug!("send signal: {}", name);
-                    let signal_msg = ctx.make_signal(name, ());
-                    ctx.push_msg(signal_msg);
-                })?;
-                Ok((sysgenid.generation_counter,))
+                ack_watcher_counter(ctx, data, &shared.query_conn, watcher_counter, None, None)
             },
+        )
+        .annotate(
+            DOC_ANNOTATION,
+            "Acks watcher_counter, the generation the caller adjusted to, starting to track the \
+             caller if needed. Fails with StaleCounter for an older counter and InvalidCounter \
+             for a newer one. Returns the current counter.",
         );
+        let shared = service.clone();
         b.method(
-            "TriggerSysGenUpdate",
-            ("min_gen",),
+            "AckWatcherCounterInGroup",
+            ("watcher_counter", "group"),
+            ("sysgen_counter",),
+            move |ctx: &mut Context,
+                  data: &mut LSysgenid,
+                  (watcher_counter, group): (u32, String)| {
+                debug!("handle method AckWatcherCounterInGroup");
+                ack_watcher_counter(
+                    ctx,
+                    data,
+                    &shared.query_conn,
+                    watcher_counter,
+                    Some(&group),
+                    None,
+                )
+            },
+        )
+        .annotate(
+            DOC_ANNOTATION,
+            "Same as AckWatcherCounter, also making the caller part of the watcher group named \
+             group.",
+        );
+        let shared = service.clone();
+        b.method(
+            "AckSubWatcherCounter",
+            ("sub_id", "watcher_counter"),
+            ("sysgen_counter",),
+            move |ctx: &mut Context,
+                  data: &mut LSysgenid,
+                  (sub_id, watcher_counter): (String, u32)| {
+                debug!("handle method AckSubWatcherCounter");
+                ack_watcher_counter(
+                    ctx,
+                    data,
+                    &shared.query_conn,
+                    watcher_counter,
+                    None,
+                    Some(&sub_id),
+                )
+            },
+        )
+        .annotate(
+            DOC_ANNOTATION,
+            "Same as AckWatcherCounter, on behalf of sub_id, one of several watchers hosted by \
+             the caller. Sub-watchers are tracked independently, as <caller bus name>/<sub_id>, \
+             and all stop being tracked when the caller leaves the bus.",
+        );
+        let shared = service.clone();
+        b.method(
+            "RegisterWatcher",
             (),
-            |ctx: &mut Context, data: &mut LSysgenid, (min_gen,): (u32,)| {
-                debug!("handle method TriggerSysGenUpdate");
-                let mut sysgenid = data.lock().unwrap();
-                sysgenid.bump_generation(min_gen, |name, counter| {
-                    debug!("send signal: {}", name);
-                    let signal_msg = ctx.make_signal(name, (counter,));
-                    ctx.push_msg(signal_msg);
+            ("sysgen_counter",),
+            move |ctx: &mut Context, data: &mut LSysgenid, ()| {
+                debug!("handle method RegisterWatcher");
+                register_watcher(ctx, data, &shared.query_conn, None, None)
+            },
+        )
+        .annotate(
+            DOC_ANNOTATION,
+            "Starts tracking the caller at the current generation, atomically, and returns its \
+             counter.",
+        );
+        let shared = service.clone();
+        b.method(
+            "RegisterWatcherWithBudget",
+            ("expected_adjust_ms",),
+            ("sysgen_counter",),
+            move |ctx: &mut Context, data: &mut LSysgenid, (expected_adjust_ms,): (u32,)| {
+                debug!("handle method RegisterWatcherWithBudget");
+                let expected_adjust = Duration::from_millis(expected_adjust_ms.into());
+                let budget = Some(expected_adjust);
+                register_watcher(ctx, data, &shared.query_conn, budget, None)
+            },
+        )
+        .annotate(
+            DOC_ANNOTATION,
+            "Same as RegisterWatcher, declaring expected_adjust_ms, how long the caller expects \
+             to take adjusting to a new generation.",
+        );
+        let shared = service.clone();
+        b.method(
+            "RegisterCallbackWatcher",
+            ("callback_path",),
+            ("sysgen_counter",),
+            move |ctx: &mut Context,
+                  data: &mut LSysgenid,
+                  (callback_path,): (dbus::Path<'static>,)| {
+                debug!("handle method RegisterCallbackWatcher");
+                let callback = Some(callback_path);
+                register_watcher(ctx, data, &shared.query_conn, None, callback)
+            },
+        )
+        .annotate(
+            DOC_ANNOTATION,
+            "Starts tracking the caller at the current generation, calling OnNewGeneration on its \
+             object at callback_path on every change instead of waiting for acks. Returns the \
+             current counter.",
+        );
+        b.method(
+            "Heartbeat",
+            (),
+            (),
+            move |ctx: &mut Context, data: &mut LSysgenid, (): ()| {
+                debug!("handle method Heartbeat");
+                let watcher_id = sender_id(ctx)?;
+                Ok(lock(data).heartbeat(&watcher_id)?)
+            },
+        )
+        .annotate(
+            DOC_ANNOTATION,
+            "Tells that the calling tracked watcher is still responsive.",
+        );
+        b.method(
+            "SetNotifyThreshold",
+            ("counter",),
+            (),
+            move |ctx: &mut Context, data: &mut LSysgenid, (counter,): (u32,)| {
+                debug!("handle method SetNotifyThreshold");
+                let watcher_id = sender_id(ctx)?;
+                Ok(lock(data).set_notify_threshold(&watcher_id, counter)?)
+            },
+        )
+        .annotate(
+            DOC_ANNOTATION,
+            "Only sends the calling tracked watcher NewGeneration signals for counters from \
+             counter on, in targeted signals mode.",
+        );
+        b.method(
+            "UnregisterWatcher",
+            (),
+            (),
+            move |ctx: &mut Context, data: &mut LSysgenid, (): ()| {
+                debug!("handle method UnregisterWatcher");
+                let watcher_id = sender_id(ctx)?;
+                Ok(
+                    lock(data)
+                        .unregister_watcher(&watcher_id, |signal| push_signal(ctx, signal))?,
+                )
+            },
+        )
+        .annotate(DOC_ANNOTATION, "Stops tracking the caller.");
+        b.method(
+            "UnregisterSubWatcher",
+            ("sub_id",),
+            (),
+            |ctx: &mut Context, data: &mut LSysgenid, (sub_id,): (String,)| {
+                debug!("handle method UnregisterSubWatcher");
+                let watcher_id = sub_watcher_id(&sender_id(ctx)?, &sub_id)?;
+                let mut sysgenid = lock(data);
+                Ok(sysgenid.unregister_watcher(&watcher_id, |signal| push_signal(ctx, signal))?)
+            },
+        )
+        .annotate(
+            DOC_ANNOTATION,
+            "Stops tracking the sub-watcher sub_id of the caller.",
+        );
+        b.method(
+            "CountOutdatedWatchersInGroup",
+            ("group",),
+            ("outdated_watchers",),
+            |_: &mut Context, data: &mut LSysgenid, (group,): (String,)| {
+                debug!("handle method CountOutdatedWatchersInGroup");
+                let sysgenid = lock(data);
+                Ok((sysgenid.outdated_count_in_group(&group) as u32,))
+            },
+        )
+        .annotate(
+            DOC_ANNOTATION,
+            "Returns the number of outdated watchers in the watcher group named group.",
+        );
+        b.method(
+            "GetWatcherInfo",
+            ("bus_name",),
+            ("watcher_info",),
+            |_: &mut Context, data: &mut LSysgenid, (bus_name,): (String,)| {
+                debug!("handle method GetWatcherInfo");
+                let sysgenid = lock(data);
+                let (watcher, outdated) = sysgenid
+                    .find_watcher(&bus_name)
+                    .ok_or_else(|| SysgenidError::UnknownWatcher(bus_name.clone()))?;
+                let mut info = arg::PropMap::new();
+                if let Some(pid) = watcher.pid() {
+                    info.insert("pid".to_owned(), arg::Variant(Box::new(pid)));
+                }
+                info.insert(
+                    "tracked_ms".to_owned(),
+                    arg::Variant(Box::new(watcher.registered().elapsed().as_millis() as u64)),
+                );
+                info.insert(
+                    "acked_generation".to_owned(),
+                    arg::Variant(Box::new(watcher.acked_generation())),
+                );
+                if let Some(group) = watcher.group() {
+                    info.insert("group".to_owned(), arg::Variant(Box::new(group.to_owned())));
+                }
+                if let Some(expected_adjust) = watcher.expected_adjust() {
+                    info.insert(
+                        "expected_adjust_ms".to_owned(),
+                        arg::Variant(Box::new(expected_adjust.as_millis() as u64)),
+                    );
+                }
+                if let Some(since_heartbeat) = watcher.since_heartbeat() {
+                    info.insert(
+                        "since_heartbeat_ms".to_owned(),
+                        arg::Variant(Box::new(since_heartbeat.as_millis() as u64)),
+                    );
+                }
+                if let Some(notify_threshold) = watcher.notify_threshold() {
+                    info.insert(
+                        "notify_threshold".to_owned(),
+                        arg::Variant(Box::new(notify_threshold)),
+                    );
+                }
+                info.insert("outdated".to_owned(), arg::Variant(Box::new(outdated)));
+                Ok((info,))
+            },
+        )
+        .annotate(
+            DOC_ANNOTATION,
+            "Returns details about the tracked watcher with the unique name bus_name.",
+        );
+        let shared = service.clone();
+        b.method(
+            "GetStats",
+            (),
+            ("stats",),
+            move |_: &mut Context, data: &mut LSysgenid, ()| {
+                debug!("handle method GetStats");
+                // Single lock, so that all stats are mutually consistent.
+                let sysgenid = lock(data);
+                let mut stats = arg::PropMap::new();
+                stats.insert(
+                    "generation_counter".to_owned(),
+                    arg::Variant(Box::new(sysgenid.sysgen_counter())),
+                );
+                stats.insert(
+                    "tracked_watchers".to_owned(),
+                    arg::Variant(Box::new(sysgenid.tracked_count() as u32)),
+                );
+                stats.insert(
+                    "outdated_watchers".to_owned(),
+                    arg::Variant(Box::new(sysgenid.outdated_count() as u32)),
+                );
+                stats.insert(
+                    "total_bumps".to_owned(),
+                    arg::Variant(Box::new(sysgenid.total_bumps())),
+                );
+                stats.insert(
+                    "peak_concurrent_acks".to_owned(),
+                    arg::Variant(Box::new(sysgenid.peak_concurrent_acks())),
+                );
+                stats.insert(
+                    "uptime_secs".to_owned(),
+                    arg::Variant(Box::new(sysgenid.uptime().as_secs())),
+                );
+                // The serve loop is shared by all domains.
+                stats.insert(
+                    "total_messages".to_owned(),
+                    arg::Variant(Box::new(shared.serve_stats.total())),
+                );
+                stats.insert(
+                    "messages_per_sec".to_owned(),
+                    arg::Variant(Box::new(shared.serve_stats.rate())),
+                );
+                stats.insert(
+                    "peak_messages_per_sec".to_owned(),
+                    arg::Variant(Box::new(shared.serve_stats.peak_rate())),
+                );
+                let max_latency_us: HashMap<String, u64> = shared
+                    .serve_stats
+                    .max_latencies()
+                    .into_iter()
+                    .map(|(method, latency)| (method, latency.as_micros() as u64))
+                    .collect();
+                stats.insert(
+                    "max_latency_us".to_owned(),
+                    arg::Variant(Box::new(max_latency_us)),
+                );
+                Ok((stats,))
+            },
+        )
+        .annotate(
+            DOC_ANNOTATION,
+            "Returns a consistent snapshot of the service statistics.",
+        );
+        b.method(
+            "IsWatcherOutdated",
+            ("bus_name",),
+            ("outdated",),
+            |_: &mut Context, data: &mut LSysgenid, (bus_name,): (String,)| {
+                debug!("handle method IsWatcherOutdated");
+                let sysgenid = lock(data);
+                Ok((sysgenid.is_watcher_outdated(&bus_name)?,))
+            },
+        )
+        .annotate(
+            DOC_ANNOTATION,
+            "Returns whether the tracked watcher with the unique name bus_name is outdated.",
+        );
+        let shared = service.clone();
+        b.method(
+            "GetAndResetMissedGenerations",
+            ("bus_name",),
+            ("missed_generations",),
+            move |ctx: &mut Context, data: &mut LSysgenid, (bus_name,): (String,)| {
+                debug!("handle method GetAndResetMissedGenerations");
+                // Watchers may audit themselves, resetting anyone else's count is
+                // privileged.
+                if watcher_bus_name(&bus_name) != sender_id(ctx)? {
+                    authorize(&shared.polkit, ctx)?;
+                }
+                Ok((lock(data).take_missed_generations(&bus_name)?,))
+            },
+        )
+        .annotate(
+            DOC_ANNOTATION,
+            "Returns the number of generations the tracked watcher bus_name missed since it \
+             last caught up or since the previous call, and resets it to zero. Privileged, \
+             unless bus_name is the caller or one of its sub-watchers.",
+        );
+        b.method(
+            "GetWatcherLagMillis",
+            ("bus_name",),
+            ("lag_ms",),
+            |_: &mut Context, data: &mut LSysgenid, (bus_name,): (String,)| {
+                debug!("handle method GetWatcherLagMillis");
+                let sysgenid = lock(data);
+                Ok((sysgenid.watcher_lag(&bus_name)?.as_millis() as u64,))
+            },
+        )
+        .annotate(
+            DOC_ANNOTATION,
+            "Returns for how many milliseconds the tracked watcher with the unique name bus_name \
+             has been outdated. Fails with WatcherNotOutdated if it is up to date.",
+        );
+        let shared = service.clone();
+        b.method_with_cr_custom::<(u32,), (bool,), _, _>(
+            "WaitSystemReady",
+            ("timeout_ms",),
+            ("ready",),
+            move |mut ctx, cr, (timeout_ms,)| {
+                debug!("handle method WaitSystemReady");
+                let sysgenid = match cr.data_mut::<LSysgenid>(ctx.path()) {
+                    Some(sysgenid) => sysgenid.clone(),
+                    None => {
+                        ctx.reply::<(bool,)>(Err(MethodErr::no_path(ctx.path())));
+                        return Some(ctx);
+                    }
+                };
+                if lock(&sysgenid).is_ready() {
+                    ctx.reply(Ok((true,)));
+                    return Some(ctx);
+                }
+                // Defer the reply until the system is ready or the timeout expires.
+                lock(&shared.pending_waits).push(PendingWait {
+                    ctx,
+                    sysgenid,
+                    deadline: Instant::now() + Duration::from_millis(u64::from(timeout_ms)),
+                    counter: None,
                 });
+                None
+            },
+        )
+        .annotate(
+            DOC_ANNOTATION,
+            "Replies once no tracked watcher is outdated (true), or after timeout_ms milliseconds \
+             (false).",
+        );
+        let shared = service.clone();
+        b.method_with_cr_custom::<(u32, String, u32), (u32, bool), _, _>(
+            "BumpAndWait",
+            ("min_gen", "reason", "timeout_ms"),
+            ("sysgen_counter", "ready"),
+            move |mut ctx, cr, (min_gen, reason, timeout_ms)| {
+                debug!("handle method BumpAndWait");
+                let sysgenid = match cr.data_mut::<LSysgenid>(ctx.path()) {
+                    Some(sysgenid) => sysgenid.clone(),
+                    None => {
+                        ctx.reply::<(u32, bool)>(Err(MethodErr::no_path(ctx.path())));
+                        return Some(ctx);
+                    }
+                };
+                if let Err(e) = authorize(&shared.polkit, &ctx) {
+                    ctx.reply::<(u32, bool)>(Err(e));
+                    return Some(ctx);
+                }
+                // Same lock for the bump and the readiness check, so that no ack can
+                // slip in between.
+                let mut guard = lock(&sysgenid);
+                let counter = match guard
+                    .bump_generation(min_gen, &reason, |signal| push_signal(&mut ctx, signal))
+                {
+                    Ok((counter, _)) => counter,
+                    Err(e) => {
+                        ctx.reply::<(u32, bool)>(Err(e.into()));
+                        return Some(ctx);
+                    }
+                };
+                let ready = guard.is_ready();
+                drop(guard);
+                if ready {
+                    ctx.reply(Ok((counter, true)));
+                    return Some(ctx);
+                }
+                // Defer the reply until the system is ready or the timeout expires.
+                lock(&shared.pending_waits).push(PendingWait {
+                    ctx,
+                    sysgenid,
+                    deadline: Instant::now() + Duration::from_millis(u64::from(timeout_ms)),
+                    counter: Some(counter),
+                });
+                None
+            },
+        )
+        .annotate(
+            DOC_ANNOTATION,
+            "Moves to a new generation, at least min_gen, for the given reason, then replies once \
+             no tracked watcher is outdated or after timeout_ms milliseconds. Returns the new \
+             counter and whether the system converged. Privileged.",
+        );
+        b.method(
+            "GetLastBumpReason",
+            (),
+            ("reason",),
+            |_: &mut Context, data: &mut LSysgenid, ()| {
+                debug!("handle method GetLastBumpReason");
+                let sysgenid = lock(data);
+                Ok((sysgenid.last_bump_reason().to_owned(),))
+            },
+        )
+        .annotate(
+            DOC_ANNOTATION,
+            "Returns the reason of the latest generation change.",
+        );
+        b.method(
+            "GetBumpHistory",
+            (),
+            ("history",),
+            |_: &mut Context, data: &mut LSysgenid, ()| {
+                debug!("handle method GetBumpHistory");
+                let sysgenid = lock(data);
+                let history: Vec<(u32, String, u64)> = sysgenid.bump_history().cloned().collect();
+                Ok((history,))
+            },
+        )
+        .annotate(
+            DOC_ANNOTATION,
+            "Returns the recent generation changes: counter, reason and Unix timestamp in \
+             seconds.",
+        );
+        b.method(
+            "GetConvergenceHistogram",
+            (),
+            ("buckets", "incomplete"),
+            |_: &mut Context, data: &mut LSysgenid, ()| {
+                debug!("handle method GetConvergenceHistogram");
+                let sysgenid = lock(data);
+                Ok((
+                    sysgenid.convergence_histogram(),
+                    sysgenid.incomplete_convergences(),
+                ))
+            },
+        )
+        .annotate(
+            DOC_ANNOTATION,
+            "Returns how long the system took to become ready after generation changes, as (upper \
+             bound in ms, count) buckets, and the number of generations superseded before it did.",
+        );
+        b.method(
+            "GetEventFd",
+            (),
+            ("event_fd",),
+            |ctx: &mut Context, data: &mut LSysgenid, ()| {
+                debug!("handle method GetEventFd");
+                let sender = sender_id(ctx)?;
+                let file = lock(data).event_fd(&sender).map_err(|e| {
+                    error!("failed to create eventfd for {}: {}", sender, e);
+                    MethodErr::failed(&format!("failed to create eventfd: {}", e))
+                })?;
+                // Safe because the fd was just taken out of the owning `File`.
+                Ok((unsafe { arg::OwnedFd::new(file.into_raw_fd()) },))
+            },
+        )
+        .annotate(
+            DOC_ANNOTATION,
+            "Returns an eventfd signaled on every generation change.",
+        );
+        let shared = service.clone();
+        b.method(
+            "ForceSystemReady",
+            (),
+            ("dropped_watchers",),
+            move |ctx: &mut Context, data: &mut LSysgenid, ()| {
+                debug!("handle method ForceSystemReady");
+                authorize(&shared.polkit, ctx)?;
+                let mut sysgenid = lock(data);
+                let dropped = sysgenid.force_system_ready(|signal| push_signal(ctx, signal));
+                Ok((dropped,))
+            },
+        )
+        .annotate(
+            DOC_ANNOTATION,
+            "Drops all outdated watchers and signals SystemReady. Returns the number of dropped \
+             watchers. Privileged.",
+        );
+        let shared = service.clone();
+        b.method(
+            "ResetWatchers",
+            (),
+            ("dropped_watchers",),
+            move |ctx: &mut Context, data: &mut LSysgenid, ()| {
+                debug!("handle method ResetWatchers");
+                authorize(&shared.polkit, ctx)?;
+                let mut sysgenid = lock(data);
+                let dropped = sysgenid.reset_watchers(|signal| push_signal(ctx, signal));
+                Ok((dropped,))
+            },
+        )
+        .annotate(
+            DOC_ANNOTATION,
+            "Drops all tracked watchers. Returns their number. Privileged.",
+        );
+        let shared = service.clone();
+        b.method(
+            "ResetCounter",
+            ("counter",),
+            ("sysgen_counter",),
+            move |ctx: &mut Context, data: &mut LSysgenid, (counter,): (u32,)| {
+                debug!("handle method ResetCounter");
+                authorize(&shared.polkit, ctx)?;
+                let mut sysgenid = lock(data);
+                let counter = sysgenid.reset_counter(counter, |signal| push_signal(ctx, signal))?;
+                Ok((counter,))
+            },
+        )
+        .annotate(
+            DOC_ANNOTATION,
+            "Sets the counter to counter, even a lower one, breaking monotonicity: for testing \
+             and recovery only. Privileged.",
+        );
+        let shared = service.clone();
+        b.method(
+            "PauseBumps",
+            (),
+            (),
+            move |ctx: &mut Context, data: &mut LSysgenid, ()| {
+                debug!("handle method PauseBumps");
+                authorize(&shared.polkit, ctx)?;
+                lock(data).set_bumps_paused(true, |signal| push_signal(ctx, signal));
                 Ok(())
             },
+        )
+        .annotate(
+            DOC_ANNOTATION,
+            "Refuses generation changes with BumpsPaused until ResumeBumps. Privileged.",
+        );
+        let shared = service.clone();
+        b.method(
+            "ResumeBumps",
+            (),
+            (),
+            move |ctx: &mut Context, data: &mut LSysgenid, ()| {
+                debug!("handle method ResumeBumps");
+                authorize(&shared.polkit, ctx)?;
+                lock(data).set_bumps_paused(false, |signal| push_signal(ctx, signal));
+                Ok(())
+            },
+        )
+        .annotate(
+            DOC_ANNOTATION,
+            "Allows generation changes again after PauseBumps. Privileged.",
+        );
+        let shared = service.clone();
+        b.method(
+            "SetDrainMode",
+            ("draining",),
+            (),
+            move |ctx: &mut Context, data: &mut LSysgenid, (draining,): (bool,)| {
+                debug!("handle method SetDrainMode");
+                authorize(&shared.polkit, ctx)?;
+                lock(data).set_draining(draining, |signal| push_signal(ctx, signal));
+                Ok(())
+            },
+        )
+        .annotate(
+            DOC_ANNOTATION,
+            "Starts (true) or stops (false) refusing new watchers with Draining, tracked ones \
+             keep acking. Privileged.",
+        );
+        let shared = service.clone();
+        b.method(
+            "SetGeneration",
+            ("exact_counter",),
+            ("sysgen_counter",),
+            move |ctx: &mut Context, data: &mut LSysgenid, (counter,): (u32,)| {
+                debug!("handle method SetGeneration");
+                authorize(&shared.polkit, ctx)?;
+                let mut sysgenid = lock(data);
+                let counter =
+                    sysgenid.set_generation(counter, |signal| push_signal(ctx, signal))?;
+                Ok((counter,))
+            },
+        )
+        .annotate(
+            DOC_ANNOTATION,
+            "Moves to generation exact_counter, which must be greater than the current counter. \
+             Privileged.",
+        );
+        b.method(
+            "PreviewBump",
+            (),
+            ("affected_watchers",),
+            |_: &mut Context, data: &mut LSysgenid, ()| {
+                debug!("handle method PreviewBump");
+                let sysgenid = lock(data);
+                Ok((sysgenid.preview_bump() as u32,))
+            },
+        )
+        .annotate(
+            DOC_ANNOTATION,
+            "Returns the number of watchers a generation change would make outdated.",
+        );
+        let shared = service.clone();
+        b.method(
+            "TriggerSysGenUpdate",
+            ("min_gen", "reason"),
+            ("sysgen_counter", "outdated_watchers"),
+            move |ctx: &mut Context, data: &mut LSysgenid, (min_gen, reason): (u32, String)| {
+                debug!("handle method TriggerSysGenUpdate");
+                authorize(&shared.polkit, ctx)?;
+                let mut sysgenid = lock(data);
+                // The watchers to wait for, snapshotted under the same lock as the bump.
+                let (counter, outdated) = sysgenid
+                    .bump_generation(min_gen, &reason, |signal| push_signal(ctx, signal))?;
+                Ok((counter, outdated))
+            },
+        )
+        .annotate(
+            DOC_ANNOTATION,
+            "Moves to a new generation, at least min_gen and at least the current counter plus \
+             one, for the given reason. Returns the new counter and the watchers it made \
+             outdated. Privileged.",
         );
     });
 
-    // Let's add the /com/RFC/sysgenid path, which implements the com.RFC.sysgenid interface.
-    cr.insert(SYGENID_PATH, &[iface_token], sysgenid);
+    // Let's add the object paths (/com/RFC/sysgenid by default), which implement the
+    // sysgenid interface. Each generation domain is backed by its own state.
+    for (path, sysgenid) in domains.iter() {
+        info!("serving generation domain at {}", path);
+        cr.insert(path.clone(), &[iface_token], sysgenid.clone());
+    }
+    cr
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let args = Args::parse(std::env::args().skip(1))?;
 
-    debug!("SysGenID DBus service started");
-    // Serve clients forever.
-    cr.serve(&c)?;
-    unreachable!()
+    env_logger::Builder::new()
+        .filter_level(args.log_level)
+        .init();
+
+    let config = args.config;
+    if let Some(command) = args.command {
+        return cli::run(command, &config);
+    }
+    if args.json_events {
+        json_events::enable();
+    }
+
+    // Resume each generation domain from its last persisted generation, if any.
+    let mut domains = Vec::new();
+    for domain_config in config.domain_configs() {
+        let path = dbus::Path::new(domain_config.object_path.clone())?;
+        let sysgenid = Arc::new(Mutex::new(Sysgenid::from_config(&domain_config)?));
+        domains.push((path, sysgenid));
+    }
+    let domains: Domains = Arc::new(domains);
+
+    let query_conn = Arc::new(SyncConnection::from(config.open_bus()?));
+
+    // Privileged methods are open to anyone on the bus, unless polkit is enabled.
+    let polkit = if config.enable_polkit {
+        info!(
+            "polkit authorization enabled, action {}",
+            config.polkit_action
+        );
+        Some(Polkit::new(config.polkit_action.clone())?)
+    } else {
+        None
+    };
+
+    let service = Arc::new(Service {
+        iface: dbus::strings::Interface::new(SYGENID_INTERFACE)?,
+        config,
+        domains,
+        query_conn,
+        pending_waits: Arc::new(Mutex::new(Vec::new())),
+        callbacks_in_flight: Mutex::new(HashMap::new()),
+        owns_name: AtomicBool::new(false),
+        serve_stats: Arc::new(ServeStats::new()),
+        chardev_signals: Arc::new(Mutex::new(Vec::new())),
+        polkit,
+    });
+    let cr = build_crossroads(&service);
+    if service.config.async_server {
+        async_server::serve(service, cr)
+    } else {
+        serve_blocking(service, cr)
+    }
 }
@@ -0,0 +1,94 @@
+use std::fs::{self, OpenOptions, Permissions};
+use std::io;
+use std::mem::size_of;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::ptr;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+pub const DEFAULT_COUNTER_FILE: &str = "/run/sysgenid/counter";
+pub const DEFAULT_COUNTER_FILE_MODE: u32 = 0o644;
+
+/// Shared memory mapping of the exported sys gen counter file.
+///
+/// The file holds exactly 4 bytes at offset 0: the native-endian u32 value of the
+/// system generation counter. Other software in the system can map the file
+/// read-only and probe the counter without a DBus round-trip.
+///
+/// Memory ordering: the counter is stored with `Release` ordering before any
+/// `NewGeneration` signal goes out. A reader that loads the mapped value with
+/// `Acquire` ordering (or a plain aligned 32-bit read on platforms where that is
+/// atomic) after receiving the signal is guaranteed to observe the new value.
+/// Readers never observe a torn value since the store is a single aligned u32.
+pub struct MappedCounter {
+    counter: *const AtomicU32,
+}
+
+// The mapping is owned exclusively by this struct and only ever accessed atomically.
+unsafe impl Send for MappedCounter {}
+
+impl MappedCounter {
+    /// Creates (or truncates) the file at `path`, with permissions `mode`, and maps its
+    /// first 4 bytes. The counter reads zero until the first `store()`.
+    pub fn new(path: &Path, mode: u32) -> io::Result<Self> {
+        let context = |e: io::Error| {
+            io::Error::new(
+                e.kind(),
+                format!("failed to create counter file {}: {}", path.display(), e),
+            )
+        };
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(context)?;
+        }
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(mode)
+            .open(path)
+            .map_err(context)?;
+        // The creation mode is subject to the umask, and ignored for existing files. Set
+        // the permissions explicitly before anyone can map the file.
+        file.set_permissions(Permissions::from_mode(mode))
+            .map_err(context)?;
+        file.set_len(size_of::<u32>() as u64).map_err(context)?;
+
+        // Safe because we map a valid fd, with a length matching the file size, and check
+        // the result. The mapping outlives the fd, which can be closed after this call.
+        let addr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                size_of::<u32>(),
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if addr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(MappedCounter {
+            counter: addr as *const AtomicU32,
+        })
+    }
+
+    /// Atomically publishes `value` to the mapped file.
+    pub fn store(&self, value: u32) {
+        // Safe because `counter` points to a live, page-aligned mapping for the whole
+        // lifetime of `self`.
+        unsafe { (*self.counter).store(value, Ordering::Release) };
+    }
+}
+
+impl Drop for MappedCounter {
+    fn drop(&mut self) {
+        // Safe because the mapping was created in `new()` with the same length.
+        unsafe {
+            libc::munmap(self.counter as *mut libc::c_void, size_of::<u32>());
+        }
+    }
+}
@@ -0,0 +1,203 @@
+use log::{debug, info, warn};
+use std::fmt::Write as _;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::thread;
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use crate::serve_stats::ServeStats;
+use crate::{lock, Domains};
+use sysgenid_dbus::Sysgenid;
+
+/// Serves the service metrics in Prometheus text format at `http://<addr>/metrics`,
+/// from a dedicated thread.
+pub fn spawn_server(
+    addr: SocketAddr,
+    domains: Domains,
+    serve_stats: Arc<ServeStats>,
+) -> io::Result<()> {
+    let server = Server::http(addr).map_err(io::Error::other)?;
+    info!("serving metrics at http://{}/metrics", addr);
+    thread::spawn(move || {
+        for request in server.incoming_requests() {
+            if let Err(e) = handle_request(request, &domains, &serve_stats) {
+                warn!("failed to serve metrics request: {}", e);
+            }
+        }
+    });
+    Ok(())
+}
+
+fn handle_request(request: Request, domains: &Domains, serve_stats: &ServeStats) -> io::Result<()> {
+    debug!("metrics request: {} {}", request.method(), request.url());
+    let response = match (request.method(), request.url()) {
+        (Method::Get, "/metrics") => Response::from_string(render(domains, serve_stats))
+            .with_header(
+                Header::from_bytes("Content-Type", "text/plain; version=0.0.4")
+                    .expect("valid header"),
+            ),
+        _ => Response::from_string("").with_status_code(404),
+    };
+    request.respond(response)
+}
+
+// Name, type, help and value of every exported metric.
+type Metric = (
+    &'static str,
+    &'static str,
+    &'static str,
+    fn(&Sysgenid) -> u64,
+);
+
+const METRICS: [Metric; 8] = [
+    (
+        "sysgenid_generation",
+        "gauge",
+        "Current system generation counter.",
+        |s| u64::from(s.sysgen_counter()),
+    ),
+    (
+        "sysgenid_tracked_watchers",
+        "gauge",
+        "Number of tracked watchers, outdated ones included.",
+        |s| s.tracked_count() as u64,
+    ),
+    (
+        "sysgenid_outdated_watchers",
+        "gauge",
+        "Number of outdated tracked watchers.",
+        |s| s.outdated_count() as u64,
+    ),
+    (
+        "sysgenid_bumps_total",
+        "counter",
+        "Generation bumps since the service started.",
+        |s| s.total_bumps(),
+    ),
+    (
+        "sysgenid_acks_total",
+        "counter",
+        "Watcher acks since the service started.",
+        |s| s.total_acks(),
+    ),
+    (
+        "sysgenid_peak_concurrent_acks",
+        "gauge",
+        "Largest number of outdated watcher acks received within 100 ms of each other.",
+        |s| u64::from(s.peak_concurrent_acks()),
+    ),
+    (
+        "sysgenid_watcher_disconnects_total",
+        "counter",
+        "Tracked watchers that left the bus since the service started.",
+        |s| s.total_disconnects(),
+    ),
+    (
+        "sysgenid_convergence_incomplete_total",
+        "counter",
+        "Generations superseded by a newer one before the system was ready.",
+        |s| s.incomplete_convergences(),
+    ),
+];
+
+const CONVERGENCE_HISTOGRAM: &str = "sysgenid_convergence_duration_ms";
+
+// Name, type, help and value of every serve loop metric, shared by all domains.
+type ServeMetric = (
+    &'static str,
+    &'static str,
+    &'static str,
+    fn(&ServeStats) -> u64,
+);
+
+const SERVE_METRICS: [ServeMetric; 3] = [
+    (
+        "sysgenid_messages_total",
+        "counter",
+        "Method calls handled since the service started.",
+        |s| s.total(),
+    ),
+    (
+        "sysgenid_messages_per_sec",
+        "gauge",
+        "Method calls handled within the last complete second.",
+        |s| s.rate(),
+    ),
+    (
+        "sysgenid_peak_messages_per_sec",
+        "gauge",
+        "Largest number of method calls handled within a single second.",
+        |s| s.peak_rate(),
+    ),
+];
+
+const MAX_LATENCY: &str = "sysgenid_method_max_latency_us";
+
+fn render(domains: &Domains, serve_stats: &ServeStats) -> String {
+    let mut out = String::new();
+    for (name, kind, help, value_fn) in METRICS.iter() {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} {}", name, kind);
+        for (path, sysgenid) in domains.iter() {
+            let value = value_fn(&lock(sysgenid));
+            let _ = writeln!(out, "{}{{path=\"{}\"}} {}", name, path, value);
+        }
+    }
+    let _ = writeln!(
+        out,
+        "# HELP {} Time the system took to become ready after a generation bump.",
+        CONVERGENCE_HISTOGRAM
+    );
+    let _ = writeln!(out, "# TYPE {} histogram", CONVERGENCE_HISTOGRAM);
+    for (path, sysgenid) in domains.iter() {
+        let sysgenid = lock(sysgenid);
+        // Prometheus buckets are cumulative.
+        let mut count = 0;
+        for (bound, bucket_count) in sysgenid.convergence_histogram() {
+            count += bucket_count;
+            let le = match bound {
+                u64::MAX => "+Inf".to_owned(),
+                bound => bound.to_string(),
+            };
+            let _ = writeln!(
+                out,
+                "{}_bucket{{path=\"{}\",le=\"{}\"}} {}",
+                CONVERGENCE_HISTOGRAM, path, le, count
+            );
+        }
+        let _ = writeln!(
+            out,
+            "{}_sum{{path=\"{}\"}} {}",
+            CONVERGENCE_HISTOGRAM,
+            path,
+            sysgenid.convergence_sum_ms()
+        );
+        let _ = writeln!(
+            out,
+            "{}_count{{path=\"{}\"}} {}",
+            CONVERGENCE_HISTOGRAM, path, count
+        );
+    }
+    for (name, kind, help, value_fn) in SERVE_METRICS.iter() {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} {}", name, kind);
+        let _ = writeln!(out, "{} {}", name, value_fn(serve_stats));
+    }
+    let _ = writeln!(
+        out,
+        "# HELP {} Longest time handling a method call took, in microseconds.",
+        MAX_LATENCY
+    );
+    let _ = writeln!(out, "# TYPE {} gauge", MAX_LATENCY);
+    for (method, latency) in serve_stats.max_latencies() {
+        let _ = writeln!(
+            out,
+            "{}{{method=\"{}\"}} {}",
+            MAX_LATENCY,
+            method,
+            latency.as_micros()
+        );
+    }
+    out
+}
@@ -0,0 +1,135 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::sync::Mutex;
+
+use crate::lock;
+
+// Well-known names by the unique name of the connection owning them, `None` unless
+// resolution is enabled.
+static OWNED_NAMES: Mutex<Option<HashMap<String, BTreeSet<String>>>> = Mutex::new(None);
+
+// Connections excluded from tracking, `None` unless some are.
+static TRACK_DENY: Mutex<Option<TrackDeny>> = Mutex::new(None);
+
+struct TrackDeny {
+    // Unique names excluded as such.
+    unique_names: Vec<String>,
+    // Current owners of the excluded well-known names, `None` for names nobody owns.
+    owners: HashMap<String, Option<String>>,
+    // Unique names of all the excluded connections, what callers are checked against.
+    denied: HashSet<String>,
+}
+
+impl TrackDeny {
+    fn update_denied(&mut self) {
+        self.denied = self
+            .unique_names
+            .iter()
+            .chain(self.owners.values().flatten())
+            .cloned()
+            .collect();
+    }
+}
+
+/// Excludes the connections with the unique or well-known `names` from tracking. Owners
+/// of well-known names are looked up once by `resolve`, then kept current by
+/// `name_owner_changed()`.
+pub fn deny_tracking<R>(names: &[String], mut resolve: R)
+where
+    R: FnMut(&str) -> Option<String>,
+{
+    let (unique_names, well_known_names): (Vec<String>, Vec<String>) = names
+        .iter()
+        .cloned()
+        .partition(|name| name.starts_with(':'));
+    let owners = well_known_names
+        .into_iter()
+        .map(|name| {
+            let owner = resolve(&name);
+            (name, owner)
+        })
+        .collect();
+    let mut track_deny = TrackDeny {
+        unique_names,
+        owners,
+        denied: HashSet::new(),
+    };
+    track_deny.update_denied();
+    *lock(&TRACK_DENY) = Some(track_deny);
+}
+
+/// Whether the connection `unique_name` is excluded from tracking.
+pub fn is_track_denied(unique_name: &str) -> bool {
+    lock(&TRACK_DENY)
+        .as_ref()
+        .is_some_and(|track_deny| track_deny.denied.contains(unique_name))
+}
+
+/// Turns on resolving unique names to the well-known names they own, starting from the
+/// `(name, owner)` pairs of the well-known names currently on the bus. The names are
+/// then kept current by `name_owner_changed()`.
+pub fn enable<I: IntoIterator<Item = (String, String)>>(owners: I) {
+    let mut owned_names: HashMap<String, BTreeSet<String>> = HashMap::new();
+    for (name, owner) in owners {
+        owned_names.entry(owner).or_default().insert(name);
+    }
+    *lock(&OWNED_NAMES) = Some(owned_names);
+}
+
+/// Well-known names owned by the connection `unique_name`, sorted. Empty if it owns
+/// none, or if resolution isn't enabled.
+pub fn well_known_names(unique_name: &str) -> Vec<String> {
+    lock(&OWNED_NAMES)
+        .as_ref()
+        .and_then(|owned_names| owned_names.get(unique_name))
+        .map(|names| names.iter().cloned().collect())
+        .unwrap_or_default()
+}
+
+/// Follows the NameOwnerChanged signal of `name` moving from `old_owner` to
+/// `new_owner`, either of them empty for none. `old_owner_alive` tells whether the
+/// previous owner of a well-known name is still on the bus.
+///
+/// The bus releases the well-known names of a disconnecting connection right before
+/// its unique name: those are kept until the unique name goes too, for the signals
+/// about the departure to still mention them.
+pub fn name_owner_changed<A>(name: &str, old_owner: &str, new_owner: &str, old_owner_alive: A)
+where
+    A: FnOnce() -> bool,
+{
+    if let Some(track_deny) = lock(&TRACK_DENY).as_mut() {
+        if let Some(owner) = track_deny.owners.get_mut(name) {
+            *owner = Some(new_owner.to_owned()).filter(|owner| !owner.is_empty());
+            track_deny.update_denied();
+        }
+    }
+    if lock(&OWNED_NAMES).is_none() {
+        return;
+    }
+    // Don't hold the lock across the bus round-trip.
+    let released = !name.starts_with(':') && !old_owner.is_empty() && old_owner_alive();
+    let mut owned_names = lock(&OWNED_NAMES);
+    let owned_names = match owned_names.as_mut() {
+        Some(owned_names) => owned_names,
+        None => return,
+    };
+    if name.starts_with(':') {
+        if new_owner.is_empty() {
+            owned_names.remove(name);
+        }
+        return;
+    }
+    if released {
+        if let Some(names) = owned_names.get_mut(old_owner) {
+            names.remove(name);
+            if names.is_empty() {
+                owned_names.remove(old_owner);
+            }
+        }
+    }
+    if !new_owner.is_empty() {
+        owned_names
+            .entry(new_owner.to_owned())
+            .or_default()
+            .insert(name.to_owned());
+    }
+}
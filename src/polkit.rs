@@ -0,0 +1,71 @@
+use dbus::arg::{PropMap, Variant};
+use dbus::blocking::SyncConnection;
+use dbus_crossroads::MethodErr;
+use log::{debug, error, warn};
+use std::collections::HashMap;
+use std::time::Duration;
+
+pub const DEFAULT_POLKIT_ACTION: &str = "com.RFC.sysgenid.trigger";
+
+const ACCESS_DENIED_ERROR: &str = "org.freedesktop.DBus.Error.AccessDenied";
+
+// CheckAuthorization result: is_authorized, is_challenge and details.
+type AuthorizationResult = (bool, bool, HashMap<String, String>);
+
+/// Authorization of privileged method calls through polkit.
+///
+/// Polkit always lives on the system bus, regardless of the bus the service is
+/// serving on.
+pub struct Polkit {
+    conn: SyncConnection,
+    action_id: String,
+}
+
+impl Polkit {
+    pub fn new(action_id: String) -> Result<Self, dbus::Error> {
+        Ok(Polkit {
+            conn: SyncConnection::new_system()?,
+            action_id,
+        })
+    }
+
+    /// Checks whether the caller owning `bus_name` is authorized for the configured
+    /// action, returning an `AccessDenied` error if not.
+    pub fn check_authorization(&self, bus_name: &str) -> Result<(), MethodErr> {
+        let proxy = self.conn.with_proxy(
+            "org.freedesktop.PolicyKit1",
+            "/org/freedesktop/PolicyKit1/Authority",
+            Duration::from_millis(5000),
+        );
+        let mut subject_details = PropMap::new();
+        subject_details.insert("name".to_owned(), Variant(Box::new(bus_name.to_owned())));
+        let subject = ("system-bus-name", subject_details);
+        let details: HashMap<&str, &str> = HashMap::new();
+        // No flags: never block the service on interactive authentication.
+        let res: Result<(AuthorizationResult,), dbus::Error> = proxy.method_call(
+            "org.freedesktop.PolicyKit1.Authority",
+            "CheckAuthorization",
+            (subject, self.action_id.as_str(), details, 0u32, ""),
+        );
+        match res {
+            Ok(((true, _, _),)) => {
+                debug!("{} authorized for {}", bus_name, self.action_id);
+                Ok(())
+            }
+            Ok(_) => {
+                warn!("{} not authorized for {}", bus_name, self.action_id);
+                Err(MethodErr::from((
+                    ACCESS_DENIED_ERROR,
+                    format!("not authorized for {}", self.action_id),
+                )))
+            }
+            Err(e) => {
+                error!("polkit authorization check failed: {}", e);
+                Err(MethodErr::from((
+                    ACCESS_DENIED_ERROR,
+                    format!("authorization check failed: {}", e),
+                )))
+            }
+        }
+    }
+}
@@ -0,0 +1,46 @@
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::PathBuf;
+
+/// External source of the sys gen counter, such as the Linux SysGenID chardev.
+///
+/// The source holds the counter either as a decimal string, optionally followed by
+/// whitespace, or as the raw 4 bytes of a native-endian u32 like `/dev/sysgenid`.
+pub struct SeedSource {
+    path: PathBuf,
+}
+
+impl SeedSource {
+    pub fn new(path: PathBuf) -> Self {
+        SeedSource { path }
+    }
+
+    /// Returns the counter currently held by the source.
+    pub fn read(&self) -> io::Result<u32> {
+        // A single read: character devices hand out the whole counter at once and may
+        // never report end of file.
+        let mut buf = [0u8; 32];
+        let len = File::open(&self.path)
+            .and_then(|mut file| file.read(&mut buf))
+            .map_err(|e| {
+                io::Error::new(
+                    e.kind(),
+                    format!("failed to read seed source {}: {}", self.path.display(), e),
+                )
+            })?;
+        let buf = &buf[..len];
+        if let Some(counter) = std::str::from_utf8(buf)
+            .ok()
+            .and_then(|text| text.trim().parse().ok())
+        {
+            return Ok(counter);
+        }
+        if len == 4 {
+            return Ok(u32::from_ne_bytes([buf[0], buf[1], buf[2], buf[3]]));
+        }
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid counter in seed source {}", self.path.display()),
+        ))
+    }
+}
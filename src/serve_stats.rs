@@ -0,0 +1,107 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::lock;
+
+// Cap on the methods tracked by name: callers can send any member name, unknown ones
+// included, and must not make the service grow its tables without bound.
+const MAX_TRACKED_METHODS: usize = 64;
+// Name latencies of methods past the cap are tracked under.
+const OTHER_METHODS: &str = "other";
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+/// Load of the serve loop: how many method calls it processes and how long handling
+/// them takes, to spot it falling behind during ack storms.
+pub struct ServeStats {
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    total: u64,
+    // Calls handled within the current rate window, and within the previous one.
+    window_start: Instant,
+    window_count: u64,
+    last_window_count: u64,
+    peak_rate: u64,
+    max_latency: HashMap<String, Duration>,
+}
+
+impl ServeStats {
+    pub fn new() -> Self {
+        ServeStats {
+            inner: Mutex::new(Inner {
+                total: 0,
+                window_start: Instant::now(),
+                window_count: 0,
+                last_window_count: 0,
+                peak_rate: 0,
+                max_latency: HashMap::new(),
+            }),
+        }
+    }
+
+    /// Records a call of `method` handled in `latency`.
+    pub fn record(&self, method: &str, latency: Duration) {
+        let mut inner = lock(&self.inner);
+        inner.roll_window(Instant::now());
+        inner.total += 1;
+        inner.window_count += 1;
+        inner.peak_rate = inner.peak_rate.max(inner.window_count);
+        let tracked =
+            inner.max_latency.len() < MAX_TRACKED_METHODS || inner.max_latency.contains_key(method);
+        let method = if tracked { method } else { OTHER_METHODS };
+        match inner.max_latency.get_mut(method) {
+            Some(max) => *max = (*max).max(latency),
+            None => {
+                inner.max_latency.insert(method.to_owned(), latency);
+            }
+        }
+    }
+
+    /// Method calls handled since the service started.
+    pub fn total(&self) -> u64 {
+        lock(&self.inner).total
+    }
+
+    /// Method calls handled within the last complete second.
+    pub fn rate(&self) -> u64 {
+        let mut inner = lock(&self.inner);
+        inner.roll_window(Instant::now());
+        inner.last_window_count
+    }
+
+    /// Largest number of method calls handled within a single second.
+    pub fn peak_rate(&self) -> u64 {
+        lock(&self.inner).peak_rate
+    }
+
+    /// Longest time handling a call took, by method name.
+    pub fn max_latencies(&self) -> Vec<(String, Duration)> {
+        let inner = lock(&self.inner);
+        let mut max_latencies: Vec<_> = inner
+            .max_latency
+            .iter()
+            .map(|(method, latency)| (method.clone(), *latency))
+            .collect();
+        max_latencies.sort();
+        max_latencies
+    }
+}
+
+impl Inner {
+    fn roll_window(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.window_start);
+        if elapsed < RATE_WINDOW {
+            return;
+        }
+        // A window ended without any call in between counts as idle.
+        self.last_window_count = if elapsed < 2 * RATE_WINDOW {
+            self.window_count
+        } else {
+            0
+        };
+        self.window_start = now;
+        self.window_count = 0;
+    }
+}
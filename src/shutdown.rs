@@ -0,0 +1,38 @@
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static SHUTDOWN_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn request_shutdown(_signum: libc::c_int) {
+    // Only async-signal-safe work here: the serve loop does the actual shutdown.
+    SHUTDOWN_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Installs SIGTERM and SIGINT handlers which request a graceful shutdown.
+pub fn install_handlers() -> io::Result<()> {
+    for signum in [libc::SIGTERM, libc::SIGINT].iter() {
+        // Safe because the handler only touches an atomic and the sigaction struct
+        // is fully initialized before use.
+        let ret = unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction =
+                request_shutdown as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            libc::sigemptyset(&mut action.sa_mask);
+            libc::sigaction(*signum, &action, std::ptr::null_mut())
+        };
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Requests a graceful shutdown from within the service.
+pub fn request() {
+    SHUTDOWN_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Whether a graceful shutdown was requested.
+pub fn requested() -> bool {
+    SHUTDOWN_REQUESTED.load(Ordering::SeqCst)
+}
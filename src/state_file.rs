@@ -0,0 +1,57 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+pub const DEFAULT_STATE_FILE: &str = "/var/lib/sysgenid/state";
+
+/// On-disk persistence of the sys gen counter across service restarts.
+///
+/// The file holds the counter as a decimal string followed by a newline.
+pub struct StateFile {
+    path: PathBuf,
+}
+
+impl StateFile {
+    pub fn new(path: PathBuf) -> Self {
+        StateFile { path }
+    }
+
+    /// Returns the persisted counter, or `None` if nothing was persisted yet. Fails with
+    /// `InvalidData` if the file is corrupt, e.g. truncated or overwritten.
+    pub fn load(&self) -> io::Result<Option<u32>> {
+        let contents = match fs::read(&self.path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let invalid = |problem: String| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid state file {}: {}", self.path.display(), problem),
+            )
+        };
+        String::from_utf8(contents)
+            .map_err(|e| invalid(e.to_string()))?
+            .trim()
+            .parse::<u32>()
+            .map(Some)
+            .map_err(|e| invalid(e.to_string()))
+    }
+
+    /// Atomically persists `counter`: the new state is written to a temporary file
+    /// which is then renamed over the old one, so a crash never leaves a partial state.
+    pub fn store(&self, counter: u32) -> io::Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // Appended rather than replacing the extension: the state files of domains only
+        // differ by theirs, e.g. state.net and state.storage.
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+        let mut tmp = fs::File::create(&tmp_path)?;
+        writeln!(tmp, "{}", counter)?;
+        tmp.sync_all()?;
+        fs::rename(&tmp_path, &self.path)
+    }
+}
@@ -0,0 +1,42 @@
+//! Fixtures shared by the tests and benchmarks.
+
+// Each test crate builds its own copy, using only part of it.
+#![allow(dead_code)]
+
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use sysgenid_dbus::SysgenidConfig;
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Scratch directory of a test, removed when dropped.
+pub struct TempDir(pub PathBuf);
+
+impl TempDir {
+    pub fn new() -> Self {
+        let path = std::env::temp_dir().join(format!(
+            "sysgenid-test-{}-{}",
+            std::process::id(),
+            NEXT_ID.fetch_add(1, Ordering::SeqCst)
+        ));
+        fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+
+    /// Default options, with the state and counter files in the directory.
+    pub fn config(&self) -> SysgenidConfig {
+        SysgenidConfig {
+            state_file: self.0.join("state"),
+            counter_file: self.0.join("counter"),
+            ..SysgenidConfig::default()
+        }
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
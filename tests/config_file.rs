@@ -0,0 +1,140 @@
+//! Tests of loading service options from configuration files, through
+//! `SysgenidConfig::load_file()`.
+
+mod common;
+
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use common::TempDir;
+use sysgenid_dbus::{BusType, CorruptStatePolicy, SysgenidConfig};
+
+// Sets every supported key.
+const SAMPLE_CONFIG: &str = r#"
+# Sample configuration, with every option set.
+bus = "system"
+bus_address = "unix:path=/run/test-bus"
+allow_tcp = true
+bus_name = "org.example.Sysgenid"
+replace = true
+allow_replacement = true
+do_not_queue = true
+object_path = "/org/example/Sysgenid"
+state_file = "/srv/sysgenid/state"
+on_corrupt_state = "fail"
+seed_from = "/dev/sysgenid"
+counter_file = "/dev/shm/sysgenid"
+counter_file_mode = "0640"
+ack_timeout_secs = 30
+slow_convergence_warn_secs = 10
+max_bumps_per_sec = 5
+max_watchers = 1_024
+heartbeat_timeout_secs = 60
+reap_interval_secs = 5
+targeted_signals = true
+track_deny = ["org.example.Noisy", ":1.42"]
+resolve_names = true
+bump_history = 8
+max_reason_len = 64
+coalesce_ms = 250
+pre_bump_ms = 100
+auto_bump_interval_secs = 3600
+enable_polkit = true
+polkit_action = "org.example.sysgenid.manage"
+domains = ["net", "storage"]
+metrics_addr = "127.0.0.1:9100"
+chardev = "/dev/sysgenid"
+async = true
+"#;
+
+/// Loads the configuration file holding `text` over the default options.
+fn load(text: &str) -> io::Result<SysgenidConfig> {
+    let dir = TempDir::new();
+    let path = dir.0.join("sysgenid.toml");
+    fs::write(&path, text).unwrap();
+    let mut config = SysgenidConfig::default();
+    config.load_file(&path).map(|()| config)
+}
+
+#[test]
+fn sample_config_sets_every_option() {
+    let config = load(SAMPLE_CONFIG).unwrap();
+    assert_eq!(config.bus, BusType::System);
+    assert_eq!(
+        config.bus_address.as_deref(),
+        Some("unix:path=/run/test-bus")
+    );
+    assert!(config.allow_tcp);
+    assert_eq!(config.bus_name, "org.example.Sysgenid");
+    assert!(config.replace);
+    assert!(config.allow_replacement);
+    assert!(config.do_not_queue);
+    assert_eq!(config.object_path, "/org/example/Sysgenid");
+    assert_eq!(config.state_file, PathBuf::from("/srv/sysgenid/state"));
+    assert_eq!(config.on_corrupt_state, CorruptStatePolicy::Fail);
+    assert_eq!(config.seed_from, Some(PathBuf::from("/dev/sysgenid")));
+    assert_eq!(config.counter_file, PathBuf::from("/dev/shm/sysgenid"));
+    assert_eq!(config.counter_file_mode, 0o640);
+    assert_eq!(config.ack_timeout, Some(Duration::from_secs(30)));
+    assert_eq!(config.slow_convergence_warn, Some(Duration::from_secs(10)));
+    assert_eq!(config.max_bumps_per_sec, Some(5));
+    assert_eq!(config.max_watchers, Some(1024));
+    assert_eq!(config.heartbeat_timeout, Some(Duration::from_secs(60)));
+    assert_eq!(config.reap_interval, Duration::from_secs(5));
+    assert!(config.targeted_signals);
+    assert_eq!(config.track_deny, ["org.example.Noisy", ":1.42"]);
+    assert!(config.resolve_names);
+    assert_eq!(config.bump_history, 8);
+    assert_eq!(config.max_reason_len, 64);
+    assert_eq!(config.coalesce_window, Some(Duration::from_millis(250)));
+    assert_eq!(config.pre_bump_grace, Some(Duration::from_millis(100)));
+    assert_eq!(config.auto_bump_interval, Some(Duration::from_secs(3600)));
+    assert!(config.enable_polkit);
+    assert_eq!(config.polkit_action, "org.example.sysgenid.manage");
+    assert_eq!(config.domains, ["net", "storage"]);
+    assert_eq!(config.metrics_addr, Some("127.0.0.1:9100".parse().unwrap()));
+    assert_eq!(config.chardev, Some(PathBuf::from("/dev/sysgenid")));
+    assert!(config.async_server);
+}
+
+#[test]
+fn unset_options_keep_their_defaults() {
+    let config = load("# Nothing but comments.\n").unwrap();
+    let default = SysgenidConfig::default();
+    assert_eq!(config.bus, default.bus);
+    assert_eq!(config.state_file, default.state_file);
+    assert_eq!(config.ack_timeout, None);
+    assert!(config.domains.is_empty());
+}
+
+#[test]
+fn invalid_config_files_are_rejected() {
+    for (text, problem) in [
+        ("no_such_option = 1", "unknown field `no_such_option`"),
+        ("[section]\nbus = \"system\"", "unknown field `section`"),
+        ("bus = \"bogus\"", "unknown variant `bogus`"),
+        ("ack_timeout_secs = \"30\"", "invalid type"),
+        ("ack_timeout_secs = -1", "invalid value"),
+        ("allow_tcp = true\nallow_tcp = false", "duplicate key"),
+        ("domains = [\"net\", 42]", "invalid type"),
+        ("bus = \"system", "invalid basic string"),
+        // Validated as on the command line.
+        (
+            "counter_file_mode = \"0999\"",
+            "invalid counter file mode '0999'",
+        ),
+        ("max_bumps_per_sec = 0", "invalid max bumps per sec '0'"),
+        ("domains = [\"net\", \"net\"]", "duplicate domain 'net'"),
+        ("bus_address = \"nowhere\"", "invalid bus address 'nowhere'"),
+    ]
+    .iter()
+    {
+        let err = load(text).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{}", text);
+        let err = err.to_string();
+        assert!(err.starts_with("invalid config file "), "{}", err);
+        assert!(err.contains(problem), "{:?}: {}", text, err);
+    }
+}
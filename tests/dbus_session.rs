@@ -0,0 +1,2554 @@
+//! End to end tests of the sysgenid D-Bus interface, against the service running on a
+//! private `dbus-daemon` session bus. Skipped when `dbus-daemon` isn't installed.
+//!
+//! Every test of the service runs against the blocking server, then again against the
+//! async one as `async_server::<test>`.
+
+mod common;
+
+use std::cell::Cell;
+use std::fs;
+use std::io::{BufRead, BufReader};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::Ordering;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use common::TempDir;
+use dbus::arg::{PropMap, RefArg};
+use dbus::blocking::stdintf::org_freedesktop_dbus::{
+    Introspectable, Properties, PropertiesPropertiesChanged,
+};
+use dbus::blocking::Connection;
+use dbus::channel::{Channel, MatchingReceiver};
+use dbus::message::{MatchRule, MessageType, SignalArgs};
+use dbus::{Message, MethodErr};
+use dbus_crossroads::Crossroads;
+
+const SYSGENID_INTERFACE: &str = "com.RFC.sysgenid";
+const SYSGENID_PATH: &str = "/com/RFC/sysgenid";
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+// Bus on an ephemeral loopback port. Clients can't authenticate with their unix
+// credentials over TCP, let them in anonymously.
+const TCP_BUS_CONFIG: &str = r#"<busconfig>
+  <type>session</type>
+  <listen>tcp:host=127.0.0.1,bind=127.0.0.1,port=0</listen>
+  <auth>ANONYMOUS</auth>
+  <allow_anonymous/>
+  <policy context="default">
+    <allow send_destination="*" eavesdrop="true"/>
+    <allow eavesdrop="true"/>
+    <allow own="*"/>
+  </policy>
+</busconfig>
+"#;
+
+thread_local! {
+    // Whether the services the current test spawns serve with --async.
+    static ASYNC_SERVER: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Child process killed when dropped.
+struct Process(Child);
+
+impl Drop for Process {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+/// A private session bus running the sysgenid service. Processes are stopped before
+/// their scratch directory goes away.
+struct TestBus {
+    address: String,
+    service: Process,
+    _bus: Process,
+    dir: TempDir,
+}
+
+impl TestBus {
+    /// Starts a private bus and the service on it, passing the service `args`. Returns
+    /// `None` if `dbus-daemon` isn't available.
+    fn start(args: &[&str]) -> Option<Self> {
+        let dir = TempDir::new();
+        let bus_args = [
+            "--session".to_owned(),
+            format!("--address=unix:path={}", dir.0.join("bus").display()),
+        ];
+        Self::start_with(dir, &bus_args, args)
+    }
+
+    /// Same as `start()`, with the bus listening on loopback TCP instead.
+    fn start_tcp(args: &[&str]) -> Option<Self> {
+        let dir = TempDir::new();
+        let config = dir.0.join("bus.conf");
+        fs::write(&config, TCP_BUS_CONFIG).unwrap();
+        let bus_args = [format!("--config-file={}", config.display())];
+        let args: Vec<&str> = ["--allow-tcp"].iter().chain(args).copied().collect();
+        Self::start_with(dir, &bus_args, &args)
+    }
+
+    fn start_with(dir: TempDir, bus_args: &[String], args: &[&str]) -> Option<Self> {
+        let bus = Command::new("dbus-daemon")
+            .args(["--nofork", "--print-address"])
+            .args(bus_args)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn();
+        let mut bus = match bus {
+            Ok(bus) => Process(bus),
+            Err(e) => {
+                eprintln!("skipping, could not run dbus-daemon: {}", e);
+                return None;
+            }
+        };
+        // The bus prints its address once it accepts connections.
+        let mut line = String::new();
+        BufReader::new(bus.0.stdout.as_mut().unwrap())
+            .read_line(&mut line)
+            .unwrap();
+        if line.is_empty() {
+            eprintln!("skipping, dbus-daemon failed to start");
+            return None;
+        }
+        let address = line.trim_end().to_owned();
+
+        let service = spawn_service(&address, &dir, args);
+        let test_bus = TestBus {
+            address,
+            service,
+            _bus: bus,
+            dir,
+        };
+        test_bus.wait_for_service();
+        Some(test_bus)
+    }
+
+    /// Stops the service and starts a new instance with `args`, on the same bus and
+    /// with the same state and counter files.
+    fn restart_service(&mut self, args: &[&str]) {
+        // Killed outright, the bus name has to be free before the new instance starts.
+        let _ = self.service.0.kill();
+        let _ = self.service.0.wait();
+        self.service = spawn_service(&self.address, &self.dir, args);
+        self.wait_for_service();
+    }
+
+    /// Lines the service prints to stdout, received as they come.
+    fn stdout_lines(&mut self) -> mpsc::Receiver<String> {
+        let stdout = self.service.0.stdout.take().unwrap();
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            for line in BufReader::new(stdout).lines() {
+                if tx.send(line.unwrap()).is_err() {
+                    break;
+                }
+            }
+        });
+        rx
+    }
+
+    /// Opens a new client connection to the bus.
+    fn connect(&self) -> Connection {
+        let mut channel = Channel::open_private(&self.address).unwrap();
+        channel.register().unwrap();
+        Connection::from(channel)
+    }
+
+    fn wait_for_service(&self) {
+        let conn = self.connect();
+        let deadline = Instant::now() + TIMEOUT;
+        while call::<(u32,), _>(&conn, "GetSysGenCounter", ()).is_err() {
+            assert!(Instant::now() < deadline, "service did not come up");
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+}
+
+/// Runs the service on the bus at `address`, with its files in `dir`.
+fn spawn_service(address: &str, dir: &TempDir, args: &[&str]) -> Process {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_sysgenid-dbus"));
+    // Point the service at the bus through its options only.
+    command
+        .arg("--bus-address")
+        .arg(address)
+        .arg("--state-file")
+        .arg(dir.0.join("state"))
+        .arg("--counter-file")
+        .arg(dir.0.join("counter"))
+        .args(["--log-level", "warn"])
+        .env_remove("DBUS_SESSION_BUS_ADDRESS")
+        .stdout(Stdio::piped());
+    if ASYNC_SERVER.with(Cell::get) {
+        command.arg("--async");
+    }
+    Process(command.args(args).spawn().unwrap())
+}
+
+/// Runs the CLI with `args` against the bus at `address`, returning its exit status
+/// and what it printed to stdout.
+fn run_cli(address: &str, args: &[&str]) -> (bool, String) {
+    let output = Command::new(env!("CARGO_BIN_EXE_sysgenid-dbus"))
+        .args(["--bus-address", address])
+        .args(args)
+        .env_remove("DBUS_SESSION_BUS_ADDRESS")
+        .stderr(Stdio::null())
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout).trim().to_owned();
+    (output.status.success(), stdout)
+}
+
+/// Declares the async server twins of the listed tests, in the `async_server` module.
+macro_rules! async_server_tests {
+    ($($(#[$attr:meta])* $test:ident,)*) => {
+        mod async_server {
+            $(
+                $(#[$attr])*
+                #[test]
+                fn $test() {
+                    super::ASYNC_SERVER.with(|async_server| async_server.set(true));
+                    super::$test();
+                }
+            )*
+        }
+    };
+}
+
+fn call<R: dbus::arg::ReadAll, A: dbus::arg::AppendAll>(
+    conn: &Connection,
+    method: &str,
+    args: A,
+) -> Result<R, dbus::Error> {
+    conn.with_proxy(SYSGENID_INTERFACE, SYSGENID_PATH, TIMEOUT)
+        .method_call(SYSGENID_INTERFACE, method, args)
+}
+
+fn get_property<R: for<'b> dbus::arg::Get<'b> + 'static>(conn: &Connection, name: &str) -> R {
+    conn.with_proxy(SYSGENID_INTERFACE, SYSGENID_PATH, TIMEOUT)
+        .get(SYSGENID_INTERFACE, name)
+        .unwrap()
+}
+
+/// Collects the sysgenid `signal`s received by `conn`.
+fn collect_signals(conn: &Connection, signal: &'static str) -> Arc<Mutex<Vec<Message>>> {
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let received2 = received.clone();
+    let rule = MatchRule::new_signal(SYSGENID_INTERFACE, signal);
+    conn.add_match(rule, move |(): (), _: &Connection, msg: &Message| {
+        received2.lock().unwrap().push(msg.duplicate().unwrap());
+        true
+    })
+    .unwrap();
+    received
+}
+
+/// Collects the properties the sysgenid interface reports changed to `conn`, one map
+/// per `PropertiesChanged` signal.
+fn collect_properties_changed(conn: &Connection) -> Arc<Mutex<Vec<PropMap>>> {
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let received2 = received.clone();
+    let path = dbus::Path::from(SYSGENID_PATH);
+    let rule = PropertiesPropertiesChanged::match_rule(None, Some(&path));
+    conn.add_match(
+        rule.static_clone(),
+        move |h: PropertiesPropertiesChanged, _: &Connection, _: &Message| {
+            if h.interface_name == SYSGENID_INTERFACE {
+                received2.lock().unwrap().push(h.changed_properties);
+            }
+            true
+        },
+    )
+    .unwrap();
+    received
+}
+
+/// Processes incoming messages of `conn` until `received` holds a message.
+fn next_signal(conn: &Connection, received: &Mutex<Vec<Message>>) -> Message {
+    let deadline = Instant::now() + TIMEOUT;
+    loop {
+        if let Some(msg) = received.lock().unwrap().pop() {
+            return msg;
+        }
+        assert!(Instant::now() < deadline, "no signal received");
+        conn.process(Duration::from_millis(100)).unwrap();
+    }
+}
+
+/// Processes incoming messages of `conn` for `duration`.
+fn process_for(conn: &Connection, duration: Duration) {
+    let deadline = Instant::now() + duration;
+    while Instant::now() < deadline {
+        conn.process(Duration::from_millis(50)).unwrap();
+    }
+}
+
+#[test]
+fn bump_updates_counter() {
+    let bus = match TestBus::start(&[]) {
+        Some(bus) => bus,
+        None => return,
+    };
+    let conn = bus.connect();
+
+    let (counter,): (u32,) = call(&conn, "GetSysGenCounter", ()).unwrap();
+    assert_eq!(counter, 0);
+    let (counter,): (u32,) = call(&conn, "TriggerSysGenUpdate", (0u32, "test")).unwrap();
+    assert_eq!(counter, 1);
+    let (counter,): (u32,) = call(&conn, "TriggerSysGenUpdate", (5u32, "test")).unwrap();
+    assert_eq!(counter, 5);
+    let (counter,): (u32,) = call(&conn, "GetSysGenCounter", ()).unwrap();
+    assert_eq!(counter, 5);
+}
+
+#[test]
+fn counter_file_maps_the_current_counter() {
+    use std::os::unix::io::AsRawFd;
+    use std::sync::atomic::AtomicU32;
+
+    let bus = match TestBus::start(&[]) {
+        Some(bus) => bus,
+        None => return,
+    };
+    let conn = bus.connect();
+    let received = collect_signals(&conn, "NewGeneration");
+
+    // Mapped read-only, the way readers probe the counter without a round-trip.
+    let file = fs::File::open(bus.dir.0.join("counter")).unwrap();
+    assert_eq!(file.metadata().unwrap().len(), 4);
+    let addr = unsafe {
+        libc::mmap(
+            std::ptr::null_mut(),
+            4,
+            libc::PROT_READ,
+            libc::MAP_SHARED,
+            file.as_raw_fd(),
+            0,
+        )
+    };
+    assert_ne!(addr, libc::MAP_FAILED);
+    let mapped = unsafe { &*(addr as *const AtomicU32) };
+    assert_eq!(mapped.load(Ordering::Acquire), 0);
+
+    let (counter,): (u32,) = call(&conn, "TriggerSysGenUpdate", (7u32, "mmap")).unwrap();
+    assert_eq!(counter, 7);
+    // Stored before the signal went out.
+    let signal = next_signal(&conn, &received);
+    assert_eq!(signal.read1::<u32>().unwrap(), 7);
+    assert_eq!(mapped.load(Ordering::Acquire), 7);
+    unsafe { libc::munmap(addr, 4) };
+}
+
+#[test]
+fn counter_file_is_created_with_the_given_mode() {
+    use std::convert::TryInto;
+    use std::os::unix::fs::PermissionsExt;
+
+    let bus = match TestBus::start(&["--counter-file-mode", "0604"]) {
+        Some(bus) => bus,
+        None => return,
+    };
+    let conn = bus.connect();
+    let counter_file = bus.dir.0.join("counter");
+    let mode = fs::metadata(&counter_file).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o604);
+    call::<(u32,), _>(&conn, "TriggerSysGenUpdate", (5u32, "")).unwrap();
+    let contents: [u8; 4] = fs::read(&counter_file).unwrap().try_into().unwrap();
+    assert_eq!(u32::from_ne_bytes(contents), 5);
+
+    // Startup fails when the file can't be created.
+    let bad_path = bus.dir.0.join("state").join("counter");
+    let second_dir = TempDir::new();
+    let args = [
+        "--bus-name",
+        "org.example.Second",
+        "--counter-file",
+        bad_path.to_str().unwrap(),
+    ];
+    let mut second = spawn_service(&bus.address, &second_dir, &args);
+    assert!(!wait_for_exit(&mut second));
+}
+
+#[test]
+fn counter_is_restored_across_restarts() {
+    let mut bus = match TestBus::start(&[]) {
+        Some(bus) => bus,
+        None => return,
+    };
+    let conn = bus.connect();
+    call::<(u32,), _>(&conn, "TriggerSysGenUpdate", (41u32, "before")).unwrap();
+    let (counter,): (u32,) = call(&conn, "TriggerSysGenUpdate", (0u32, "before")).unwrap();
+    assert_eq!(counter, 42);
+
+    bus.restart_service(&[]);
+    let conn = bus.connect();
+    let (counter,): (u32,) = call(&conn, "GetSysGenCounter", ()).unwrap();
+    assert_eq!(counter, 42);
+    // And keeps increasing from there.
+    let (counter,): (u32,) = call(&conn, "TriggerSysGenUpdate", (0u32, "after")).unwrap();
+    assert_eq!(counter, 43);
+}
+
+#[test]
+fn json_events_are_printed_to_stdout() {
+    let mut bus = match TestBus::start(&["--json-events"]) {
+        Some(bus) => bus,
+        None => return,
+    };
+    let lines = bus.stdout_lines();
+    let next_event = || -> serde_json::Value {
+        let line = lines.recv_timeout(TIMEOUT).expect("no event printed");
+        serde_json::from_str(&line).unwrap()
+    };
+    let watcher = bus.connect();
+    let watcher_name = watcher.unique_name().to_string();
+    let overseer = bus.connect();
+
+    call::<(u32,), _>(&watcher, "AckWatcherCounter", (0u32,)).unwrap();
+    let event = next_event();
+    assert_eq!(event["event"], "ack");
+    assert_eq!(event["path"], SYSGENID_PATH);
+    assert_eq!(event["watcher"], watcher_name.as_str());
+    assert_eq!(event["counter"], 0);
+    assert!(event["ts"].as_u64().unwrap() > 0);
+
+    // Reasons are escaped, whatever printable characters they hold.
+    let reason = "say \"hi\" to C:\\ and {\u{e9}t\u{e9}}";
+    call::<(u32,), _>(&overseer, "TriggerSysGenUpdate", (0u32, reason)).unwrap();
+    let event = next_event();
+    assert_eq!(event["event"], "bump");
+    assert_eq!(event["counter"], 1);
+    assert_eq!(event["reason"], reason);
+    assert!(event["epoch"].is_u64());
+
+    drop(watcher);
+    let event = next_event();
+    assert_eq!(event["event"], "watcher_lost");
+    assert_eq!(event["watcher"], watcher_name.as_str());
+    assert_eq!(event["was_outdated"], true);
+    assert_eq!(event["reason"], "disconnected");
+    let event = next_event();
+    assert_eq!(event["event"], "system_ready");
+    assert_eq!(event["counter"], 1);
+    assert!(event["adjust_duration_ms"].is_u64());
+}
+
+/// Sends an HTTP GET request for `path` to `addr`, returning the response.
+fn http_get(addr: &str, path: &str) -> String {
+    use std::io::{Read, Write};
+
+    let mut stream = std::net::TcpStream::connect(addr).unwrap();
+    write!(stream, "GET {} HTTP/1.0\r\nHost: {}\r\n\r\n", path, addr).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+}
+
+#[test]
+fn metrics_are_scraped_over_http() {
+    // Grab a free port, the service binds it again right away.
+    let addr = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .to_string();
+    let bus = match TestBus::start(&["--metrics-addr", &addr]) {
+        Some(bus) => bus,
+        None => return,
+    };
+    let watcher = bus.connect();
+    let overseer = bus.connect();
+    call::<(u32,), _>(&watcher, "AckWatcherCounter", (0u32,)).unwrap();
+    call::<(u32, Vec<String>), _>(&overseer, "TriggerSysGenUpdate", (3u32, "")).unwrap();
+
+    let response = http_get(&addr, "/metrics");
+    let (head, body) = response.split_at(response.find("\r\n\r\n").unwrap());
+    assert!(head.starts_with("HTTP/1.0 200"), "{}", head);
+    assert!(
+        head.contains("Content-Type: text/plain; version=0.0.4"),
+        "{}",
+        head
+    );
+    for sample in [
+        "sysgenid_generation{path=\"/com/RFC/sysgenid\"} 3",
+        "sysgenid_tracked_watchers{path=\"/com/RFC/sysgenid\"} 1",
+        "sysgenid_outdated_watchers{path=\"/com/RFC/sysgenid\"} 1",
+        "sysgenid_bumps_total{path=\"/com/RFC/sysgenid\"} 1",
+        "# TYPE sysgenid_convergence_duration_ms histogram",
+    ]
+    .iter()
+    {
+        assert!(body.lines().any(|line| line == *sample), "{}", body);
+    }
+
+    let response = http_get(&addr, "/elsewhere");
+    assert!(response.starts_with("HTTP/1.0 404"), "{}", response);
+}
+
+#[test]
+fn instances_with_their_own_names_dont_cross_signal() {
+    const SECOND_NAME: &str = "org.example.Second";
+    const SECOND_PATH: &str = "/org/example/Second";
+
+    let bus = match TestBus::start(&[]) {
+        Some(bus) => bus,
+        None => return,
+    };
+    let second_dir = TempDir::new();
+    let args = ["--bus-name", SECOND_NAME, "--object-path", SECOND_PATH];
+    let _second = spawn_service(&bus.address, &second_dir, &args);
+    let conn = bus.connect();
+    // Same interface, told apart by bus name and object path.
+    let call_second = |conn: &Connection, method: &str, args: (u32, &str)| {
+        conn.with_proxy(SECOND_NAME, SECOND_PATH, TIMEOUT)
+            .method_call::<(u32,), _, _, _>(SYSGENID_INTERFACE, method, args)
+            .map(|(counter,)| counter)
+    };
+    // Waited for without bumping it: the signal could come after the match is added.
+    let deadline = Instant::now() + TIMEOUT;
+    while conn
+        .with_proxy(SECOND_NAME, SECOND_PATH, TIMEOUT)
+        .method_call::<(u32,), _, _, _>(SYSGENID_INTERFACE, "GetSysGenCounter", ())
+        .is_err()
+    {
+        assert!(Instant::now() < deadline, "second instance did not come up");
+        thread::sleep(Duration::from_millis(50));
+    }
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let received2 = received.clone();
+    let rule = MatchRule::new_signal(SYSGENID_INTERFACE, "NewGeneration");
+    conn.add_match(rule, move |(): (), _: &Connection, msg: &Message| {
+        let path = msg.path().unwrap().to_string();
+        received2
+            .lock()
+            .unwrap()
+            .push((path, msg.read1::<u32>().unwrap()));
+        true
+    })
+    .unwrap();
+
+    let (counter,): (u32,) = call(&conn, "TriggerSysGenUpdate", (5u32, "first")).unwrap();
+    assert_eq!(counter, 5);
+    let counter = call_second(&conn, "TriggerSysGenUpdate", (0, "second")).unwrap();
+    assert_eq!(counter, 1);
+    process_for(&conn, Duration::from_millis(200));
+    assert_eq!(
+        *received.lock().unwrap(),
+        vec![(SYSGENID_PATH.to_owned(), 5), (SECOND_PATH.to_owned(), 1)]
+    );
+    // Each keeps its own counter.
+    let (counter,): (u32,) = call(&conn, "GetSysGenCounter", ()).unwrap();
+    assert_eq!(counter, 5);
+}
+
+/// Waits for `process` to exit, returning whether it exited successfully.
+fn wait_for_exit(process: &mut Process) -> bool {
+    let deadline = Instant::now() + TIMEOUT;
+    loop {
+        if let Some(status) = process.0.try_wait().unwrap() {
+            return status.success();
+        }
+        assert!(Instant::now() < deadline, "service did not exit");
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+#[test]
+fn replace_takes_the_bus_name_over() {
+    let mut bus = match TestBus::start(&["--allow-replacement"]) {
+        Some(bus) => bus,
+        None => return,
+    };
+    let conn = bus.connect();
+    call::<(u32,), _>(&conn, "TriggerSysGenUpdate", (3u32, "first")).unwrap();
+
+    let second_dir = TempDir::new();
+    let _second = spawn_service(&bus.address, &second_dir, &["--replace-existing"]);
+    // The first instance shuts down gracefully once it lost the name.
+    assert!(wait_for_exit(&mut bus.service));
+    let (counter,): (u32,) = call(&conn, "GetSysGenCounter", ()).unwrap();
+    assert_eq!(counter, 0);
+}
+
+#[test]
+fn taken_bus_name_is_queued_for_unless_asked_not_to() {
+    let mut bus = match TestBus::start(&[]) {
+        Some(bus) => bus,
+        None => return,
+    };
+    let conn = bus.connect();
+    call::<(u32,), _>(&conn, "TriggerSysGenUpdate", (3u32, "first")).unwrap();
+
+    let second_dir = TempDir::new();
+    let mut second = spawn_service(&bus.address, &second_dir, &["--do-not-queue"]);
+    assert!(!wait_for_exit(&mut second));
+    // Without replacement allowed, --replace alone doesn't get the name either.
+    let mut second = spawn_service(&bus.address, &second_dir, &["--replace", "--do-not-queue"]);
+    assert!(!wait_for_exit(&mut second));
+
+    // By default, a standby instance waits in line and serves once the name is free.
+    let _standby = spawn_service(&bus.address, &second_dir, &[]);
+    thread::sleep(Duration::from_millis(200));
+    let (counter,): (u32,) = call(&conn, "GetSysGenCounter", ()).unwrap();
+    assert_eq!(counter, 3);
+    let _ = bus.service.0.kill();
+    let _ = bus.service.0.wait();
+    bus.wait_for_service();
+    let (counter,): (u32,) = call(&conn, "GetSysGenCounter", ()).unwrap();
+    assert_eq!(counter, 0);
+}
+
+#[test]
+fn bump_honors_min_gen_only_above_next_counter() {
+    let bus = match TestBus::start(&[]) {
+        Some(bus) => bus,
+        None => return,
+    };
+    let conn = bus.connect();
+    let (counter,): (u32,) = call(&conn, "TriggerSysGenUpdate", (10u32, "")).unwrap();
+    assert_eq!(counter, 10);
+
+    // Below and equal to the current counter, the natural increment wins.
+    let (counter,): (u32,) = call(&conn, "TriggerSysGenUpdate", (3u32, "below")).unwrap();
+    assert_eq!(counter, 11);
+    let (counter,): (u32,) = call(&conn, "TriggerSysGenUpdate", (11u32, "equal")).unwrap();
+    assert_eq!(counter, 12);
+    // Above it, the counter jumps to min_gen.
+    let (counter,): (u32,) = call(&conn, "TriggerSysGenUpdate", (20u32, "above")).unwrap();
+    assert_eq!(counter, 20);
+    let (counter,): (u32,) = call(&conn, "GetSysGenCounter", ()).unwrap();
+    assert_eq!(counter, 20);
+}
+
+#[test]
+fn rapid_bumps_are_rate_limited() {
+    let bus = match TestBus::start(&["--max-bumps-per-sec", "2"]) {
+        Some(bus) => bus,
+        None => return,
+    };
+    let conn = bus.connect();
+    let (counter,): (u32,) = call(&conn, "TriggerSysGenUpdate", (0u32, "first")).unwrap();
+    assert_eq!(counter, 1);
+    let err = call::<(u32,), _>(&conn, "TriggerSysGenUpdate", (0u32, "second")).unwrap_err();
+    assert_eq!(err.name(), Some("com.RFC.sysgenid.Error.RateLimited"));
+    let (counter,): (u32,) = call(&conn, "GetSysGenCounter", ()).unwrap();
+    assert_eq!(counter, 1);
+
+    // Accepted again once the interval elapsed.
+    thread::sleep(Duration::from_millis(600));
+    let (counter,): (u32,) = call(&conn, "TriggerSysGenUpdate", (0u32, "third")).unwrap();
+    assert_eq!(counter, 2);
+}
+
+#[test]
+fn bumps_within_coalescing_window_are_notified_once() {
+    let bus = match TestBus::start(&["--coalesce-ms", "300"]) {
+        Some(bus) => bus,
+        None => return,
+    };
+    let watcher = bus.connect();
+    let received = collect_signals(&watcher, "NewGeneration");
+    call::<(u32,), _>(&watcher, "RegisterWatcher", ()).unwrap();
+
+    // Every bump advances the counter, only the last one gets notified.
+    for expected in 1..=3u32 {
+        let (counter,): (u32,) = call(&watcher, "TriggerSysGenUpdate", (0u32, "")).unwrap();
+        assert_eq!(counter, expected);
+    }
+    process_for(&watcher, Duration::from_millis(600));
+    let counters: Vec<u32> = received
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|msg| msg.read1().unwrap())
+        .collect();
+    assert_eq!(counters, vec![3]);
+}
+
+#[test]
+fn ack_of_stale_counter_fails() {
+    let bus = match TestBus::start(&[]) {
+        Some(bus) => bus,
+        None => return,
+    };
+    let conn = bus.connect();
+
+    call::<(u32,), _>(&conn, "TriggerSysGenUpdate", (0u32, "")).unwrap();
+    let err = call::<(u32,), _>(&conn, "AckWatcherCounter", (0u32,)).unwrap_err();
+    assert_eq!(err.name(), Some("com.RFC.sysgenid.Error.StaleCounter"));
+    let err = call::<(u32,), _>(&conn, "AckWatcherCounter", (2u32,)).unwrap_err();
+    assert_eq!(err.name(), Some("com.RFC.sysgenid.Error.InvalidCounter"));
+}
+
+#[test]
+fn watcher_ack_makes_system_ready() {
+    let bus = match TestBus::start(&[]) {
+        Some(bus) => bus,
+        None => return,
+    };
+    let watcher = bus.connect();
+    let overseer = bus.connect();
+    let new_generations = collect_signals(&watcher, "NewGeneration");
+    let system_ready = collect_signals(&overseer, "SystemReady");
+
+    // The first ack starts tracking the watcher.
+    let (counter,): (u32,) = call(&watcher, "AckWatcherCounter", (0u32,)).unwrap();
+    assert_eq!(counter, 0);
+    let (counter,): (u32,) = call(&overseer, "TriggerSysGenUpdate", (0u32, "restore")).unwrap();
+    assert_eq!(counter, 1);
+    let (outdated,): (u32,) = call(&overseer, "CountOutdatedWatchers", ()).unwrap();
+    assert_eq!(outdated, 1);
+
+    let msg = next_signal(&watcher, &new_generations);
+    let (counter, reason): (u32, String) = msg.read2().unwrap();
+    assert_eq!((counter, reason.as_str()), (1, "restore"));
+    call::<(u32,), _>(&watcher, "AckWatcherCounter", (counter,)).unwrap();
+
+    let msg = next_signal(&overseer, &system_ready);
+    let (_, for_counter): (u64, u32) = msg.read2().unwrap();
+    assert_eq!(for_counter, 1);
+    let (outdated,): (u32,) = call(&overseer, "CountOutdatedWatchers", ()).unwrap();
+    assert_eq!(outdated, 0);
+}
+
+#[test]
+fn outdated_and_tracked_watchers_are_listed() {
+    let bus = match TestBus::start(&[]) {
+        Some(bus) => bus,
+        None => return,
+    };
+    let acking = bus.connect();
+    let lagging = bus.connect();
+    let overseer = bus.connect();
+    call::<(u32,), _>(&acking, "RegisterWatcher", ()).unwrap();
+    call::<(u32,), _>(&lagging, "RegisterWatcher", ()).unwrap();
+
+    call::<(u32,), _>(&overseer, "TriggerSysGenUpdate", (0u32, "")).unwrap();
+    call::<(u32,), _>(&acking, "AckWatcherCounter", (1u32,)).unwrap();
+    let (outdated,): (Vec<String>,) = call(&overseer, "GetOutdatedWatchers", ()).unwrap();
+    assert_eq!(outdated, vec![lagging.unique_name().to_string()]);
+    let (tracked,): (Vec<String>,) = call(&overseer, "GetTrackedWatchers", ()).unwrap();
+    assert_eq!(tracked, vec![acking.unique_name().to_string()]);
+}
+
+#[test]
+fn force_system_ready_drops_outdated_watchers() {
+    let bus = match TestBus::start(&[]) {
+        Some(bus) => bus,
+        None => return,
+    };
+    let watcher = bus.connect();
+    let overseer = bus.connect();
+    let system_ready = collect_signals(&overseer, "SystemReady");
+    call::<(u32,), _>(&watcher, "RegisterWatcher", ()).unwrap();
+    call::<(u32,), _>(&overseer, "TriggerSysGenUpdate", (0u32, "")).unwrap();
+
+    // The watcher never acks.
+    let (dropped,): (u32,) = call(&overseer, "ForceSystemReady", ()).unwrap();
+    assert_eq!(dropped, 1);
+    let msg = next_signal(&overseer, &system_ready);
+    let (_, for_counter): (u64, u32) = msg.read2().unwrap();
+    assert_eq!(for_counter, 1);
+    let (outdated,): (Vec<String>,) = call(&overseer, "GetOutdatedWatchers", ()).unwrap();
+    assert!(outdated.is_empty());
+}
+
+#[test]
+fn unacked_watchers_are_dropped_after_ack_timeout() {
+    let bus = match TestBus::start(&["--ack-timeout-secs", "1"]) {
+        Some(bus) => bus,
+        None => return,
+    };
+    let watcher = bus.connect();
+    let overseer = bus.connect();
+    let system_ready = collect_signals(&overseer, "SystemReady");
+    call::<(u32,), _>(&watcher, "RegisterWatcher", ()).unwrap();
+    let bumped = Instant::now();
+    call::<(u32,), _>(&overseer, "TriggerSysGenUpdate", (0u32, "")).unwrap();
+
+    // The watcher never acks, the timeout gives up on it.
+    let msg = next_signal(&overseer, &system_ready);
+    assert!(bumped.elapsed() >= Duration::from_secs(1));
+    let (_, for_counter): (u64, u32) = msg.read2().unwrap();
+    assert_eq!(for_counter, 1);
+    let (outdated,): (u32,) = call(&overseer, "CountOutdatedWatchers", ()).unwrap();
+    assert_eq!(outdated, 0);
+    let (tracked,): (Vec<String>,) = call(&overseer, "GetTrackedWatchers", ()).unwrap();
+    assert!(tracked.is_empty());
+}
+
+#[test]
+fn readiness_of_superseded_generation_is_told_apart() {
+    let bus = match TestBus::start(&[]) {
+        Some(bus) => bus,
+        None => return,
+    };
+    let watcher = bus.connect();
+    let overseer = bus.connect();
+    let system_ready = collect_signals(&overseer, "SystemReady");
+    call::<(u32,), _>(&watcher, "RegisterWatcher", ()).unwrap();
+
+    // The last watcher acks generation 1, and the overseer bumps again before it got
+    // to see the readiness.
+    call::<(u32,), _>(&overseer, "TriggerSysGenUpdate", (0u32, "")).unwrap();
+    call::<(u32,), _>(&watcher, "AckWatcherCounter", (1u32,)).unwrap();
+    let (triggered,): (u32,) = call(&overseer, "TriggerSysGenUpdate", (0u32, "")).unwrap();
+    assert_eq!(triggered, 2);
+    let (outdated,): (u32,) = call(&overseer, "CountOutdatedWatchers", ()).unwrap();
+    assert_eq!(outdated, 1);
+
+    call::<(u32,), _>(&watcher, "AckWatcherCounter", (2u32,)).unwrap();
+    process_for(&overseer, Duration::from_millis(200));
+    let ready_for: Vec<u32> = system_ready
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|msg| msg.read2::<u64, u32>().unwrap().1)
+        .collect();
+    assert_eq!(ready_for, vec![1, 2]);
+    // Only the readiness of the triggered generation counts, the stale one is ignored.
+    let current: Vec<u32> = ready_for
+        .into_iter()
+        .filter(|&for_counter| for_counter >= triggered)
+        .collect();
+    assert_eq!(current, vec![2]);
+}
+
+#[test]
+fn version_and_features_are_advertised() {
+    let bus = match TestBus::start(&["--pre-bump-ms", "10"]) {
+        Some(bus) => bus,
+        None => return,
+    };
+    let conn = bus.connect();
+
+    let version: String = get_property(&conn, "Version");
+    assert_eq!(version, env!("CARGO_PKG_VERSION"));
+    let features: Vec<String> = get_property(&conn, "Features");
+    for feature in ["bump-and-wait", "register-watcher", "pre-bump"].iter() {
+        assert!(
+            features.iter().any(|f| f == feature),
+            "{} not listed",
+            feature
+        );
+    }
+    assert!(!features.iter().any(|f| f == "polkit"));
+}
+
+#[test]
+fn ack_before_any_bump_does_not_signal_ready() {
+    let bus = match TestBus::start(&[]) {
+        Some(bus) => bus,
+        None => return,
+    };
+    let watcher = bus.connect();
+    let overseer = bus.connect();
+    let registered = collect_signals(&overseer, "WatcherRegistered");
+    let system_ready = collect_signals(&overseer, "SystemReady");
+
+    // Track the watcher at the initial generation, then ack it again.
+    for _ in 0..2 {
+        let (counter,): (u32,) = call(&watcher, "AckWatcherCounter", (0u32,)).unwrap();
+        assert_eq!(counter, 0);
+    }
+    next_signal(&overseer, &registered);
+    process_for(&overseer, Duration::from_millis(300));
+    assert!(system_ready.lock().unwrap().is_empty());
+    let (outdated,): (u32,) = call(&overseer, "CountOutdatedWatchers", ()).unwrap();
+    assert_eq!(outdated, 0);
+
+    // The first actual adjustment is signaled, once.
+    call::<(u32,), _>(&overseer, "TriggerSysGenUpdate", (0u32, "")).unwrap();
+    call::<(u32,), _>(&watcher, "AckWatcherCounter", (1u32,)).unwrap();
+    next_signal(&overseer, &system_ready);
+    process_for(&overseer, Duration::from_millis(300));
+    assert!(system_ready.lock().unwrap().is_empty());
+}
+
+#[test]
+fn malformed_bus_address_is_rejected() {
+    let output = Command::new(env!("CARGO_BIN_EXE_sysgenid-dbus"))
+        .args(["--bus-address", "/tmp/not-an-address", "get"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("invalid bus address"), "{}", stderr);
+}
+
+#[test]
+fn outdated_watcher_lag_increases() {
+    let bus = match TestBus::start(&[]) {
+        Some(bus) => bus,
+        None => return,
+    };
+    let watcher = bus.connect();
+    let overseer = bus.connect();
+    let name = watcher.unique_name().to_string();
+
+    call::<(u32,), _>(&watcher, "AckWatcherCounter", (0u32,)).unwrap();
+    let err = call::<(u64,), _>(&overseer, "GetWatcherLagMillis", (name.as_str(),)).unwrap_err();
+    assert_eq!(
+        err.name(),
+        Some("com.RFC.sysgenid.Error.WatcherNotOutdated")
+    );
+
+    call::<(u32,), _>(&overseer, "TriggerSysGenUpdate", (0u32, "")).unwrap();
+    let (first,): (u64,) = call(&overseer, "GetWatcherLagMillis", (name.as_str(),)).unwrap();
+    thread::sleep(Duration::from_millis(200));
+    let (second,): (u64,) = call(&overseer, "GetWatcherLagMillis", (name.as_str(),)).unwrap();
+    assert!(
+        second >= first + 200,
+        "lag went from {} to {} ms",
+        first,
+        second
+    );
+
+    call::<(u32,), _>(&watcher, "AckWatcherCounter", (1u32,)).unwrap();
+    let err = call::<(u64,), _>(&overseer, "GetWatcherLagMillis", (name.as_str(),)).unwrap_err();
+    assert_eq!(
+        err.name(),
+        Some("com.RFC.sysgenid.Error.WatcherNotOutdated")
+    );
+}
+
+#[test]
+fn lag_of_unknown_watcher_fails() {
+    let bus = match TestBus::start(&[]) {
+        Some(bus) => bus,
+        None => return,
+    };
+    let conn = bus.connect();
+
+    let err = call::<(u64,), _>(&conn, "GetWatcherLagMillis", (":1.999",)).unwrap_err();
+    assert_eq!(err.name(), Some("com.RFC.sysgenid.Error.UnknownWatcher"));
+}
+
+#[test]
+fn set_generation_to_greater_counter() {
+    let bus = match TestBus::start(&[]) {
+        Some(bus) => bus,
+        None => return,
+    };
+    let watcher = bus.connect();
+    let overseer = bus.connect();
+    let new_generations = collect_signals(&watcher, "NewGeneration");
+
+    call::<(u32,), _>(&watcher, "AckWatcherCounter", (0u32,)).unwrap();
+    let (counter,): (u32,) = call(&overseer, "SetGeneration", (42u32,)).unwrap();
+    assert_eq!(counter, 42);
+    let (counter,): (u32,) = call(&overseer, "GetSysGenCounter", ()).unwrap();
+    assert_eq!(counter, 42);
+    let (outdated,): (u32,) = call(&overseer, "CountOutdatedWatchers", ()).unwrap();
+    assert_eq!(outdated, 1);
+
+    let msg = next_signal(&watcher, &new_generations);
+    let (counter, _): (u32, String) = msg.read2().unwrap();
+    assert_eq!(counter, 42);
+}
+
+#[test]
+fn set_generation_to_same_or_lower_counter_fails() {
+    let bus = match TestBus::start(&[]) {
+        Some(bus) => bus,
+        None => return,
+    };
+    let conn = bus.connect();
+
+    call::<(u32,), _>(&conn, "SetGeneration", (7u32,)).unwrap();
+    for counter in [7u32, 3, 0].iter() {
+        let err = call::<(u32,), _>(&conn, "SetGeneration", (*counter,)).unwrap_err();
+        assert_eq!(err.name(), Some("com.RFC.sysgenid.Error.InvalidCounter"));
+    }
+    let (counter,): (u32,) = call(&conn, "GetSysGenCounter", ()).unwrap();
+    assert_eq!(counter, 7);
+}
+
+#[test]
+fn serve_stats_count_calls_under_load() {
+    let bus = match TestBus::start(&[]) {
+        Some(bus) => bus,
+        None => return,
+    };
+    let conn = bus.connect();
+
+    for _ in 0..200 {
+        call::<(u32,), _>(&conn, "GetSysGenCounter", ()).unwrap();
+    }
+    let (stats,): (dbus::arg::PropMap,) = call(&conn, "GetStats", ()).unwrap();
+    let total_messages = dbus::arg::prop_cast::<u64>(&stats, "total_messages").unwrap();
+    assert!(*total_messages >= 200, "{} messages", total_messages);
+    let peak = dbus::arg::prop_cast::<u64>(&stats, "peak_messages_per_sec").unwrap();
+    assert!(*peak > 0);
+    // Dictionaries iterate over their keys and values in turn.
+    let mut max_latency_us = stats["max_latency_us"].0.as_iter().unwrap();
+    assert!(max_latency_us.any(|arg| arg.as_str() == Some("GetSysGenCounter")));
+}
+
+#[test]
+fn tcp_bus_requires_opt_in() {
+    let bus = match TestBus::start_tcp(&[]) {
+        Some(bus) => bus,
+        None => return,
+    };
+
+    let get = |args: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_sysgenid-dbus"))
+            .arg("--bus-address")
+            .arg(&bus.address)
+            .args(args)
+            .arg("get")
+            .output()
+            .unwrap()
+    };
+    let output = get(&[]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("--allow-tcp"), "{}", stderr);
+    let output = get(&["--allow-tcp"]);
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), "0");
+}
+
+#[test]
+fn watchers_are_tracked_over_tcp_bus() {
+    let bus = match TestBus::start_tcp(&[]) {
+        Some(bus) => bus,
+        None => return,
+    };
+    let watcher = bus.connect();
+    let overseer = bus.connect();
+    let system_ready = collect_signals(&overseer, "SystemReady");
+
+    call::<(u32,), _>(&watcher, "AckWatcherCounter", (0u32,)).unwrap();
+    call::<(u32,), _>(&overseer, "TriggerSysGenUpdate", (0u32, "")).unwrap();
+    let (outdated,): (u32,) = call(&overseer, "CountOutdatedWatchers", ()).unwrap();
+    assert_eq!(outdated, 1);
+
+    // The service learns about the watcher leaving through NameOwnerChanged.
+    drop(watcher);
+    next_signal(&overseer, &system_ready);
+    let (outdated,): (u32,) = call(&overseer, "CountOutdatedWatchers", ()).unwrap();
+    assert_eq!(outdated, 0);
+}
+
+#[test]
+fn draining_rejects_only_new_watchers() {
+    let bus = match TestBus::start(&[]) {
+        Some(bus) => bus,
+        None => return,
+    };
+    let existing = bus.connect();
+    let newcomer = bus.connect();
+    let overseer = bus.connect();
+    let system_ready = collect_signals(&overseer, "SystemReady");
+
+    call::<(u32,), _>(&existing, "AckWatcherCounter", (0u32,)).unwrap();
+    call::<(), _>(&overseer, "SetDrainMode", (true,)).unwrap();
+    assert!(get_property::<bool>(&overseer, "Draining"));
+    call::<(u32,), _>(&overseer, "TriggerSysGenUpdate", (0u32, "")).unwrap();
+
+    let err = call::<(u32,), _>(&newcomer, "AckWatcherCounter", (1u32,)).unwrap_err();
+    assert_eq!(err.name(), Some("com.RFC.sysgenid.Error.Draining"));
+    let err = call::<(u32,), _>(&newcomer, "RegisterWatcher", ()).unwrap_err();
+    assert_eq!(err.name(), Some("com.RFC.sysgenid.Error.Draining"));
+
+    // The tracked watcher still readjusts, and the system converges.
+    call::<(u32,), _>(&existing, "AckWatcherCounter", (1u32,)).unwrap();
+    next_signal(&overseer, &system_ready);
+    let (tracked,): (Vec<String>,) = call(&overseer, "GetTrackedWatchers", ()).unwrap();
+    assert_eq!(tracked, vec![existing.unique_name().to_string()]);
+
+    call::<(), _>(&overseer, "SetDrainMode", (false,)).unwrap();
+    assert!(!get_property::<bool>(&overseer, "Draining"));
+    call::<(u32,), _>(&newcomer, "RegisterWatcher", ()).unwrap();
+}
+
+#[test]
+fn stalled_convergence_is_signaled_once() {
+    let bus = match TestBus::start(&["--slow-convergence-warn-secs", "1"]) {
+        Some(bus) => bus,
+        None => return,
+    };
+    let watcher = bus.connect();
+    let overseer = bus.connect();
+    let stalled = collect_signals(&overseer, "ConvergenceStalled");
+
+    call::<(u32,), _>(&watcher, "AckWatcherCounter", (0u32,)).unwrap();
+    call::<(u32,), _>(&overseer, "TriggerSysGenUpdate", (0u32, "")).unwrap();
+    process_for(&overseer, Duration::from_millis(500));
+    assert!(stalled.lock().unwrap().is_empty());
+
+    let msg = next_signal(&overseer, &stalled);
+    let (counter, outstanding_count): (u32, u32) = msg.read2().unwrap();
+    assert_eq!((counter, outstanding_count), (1, 1));
+    process_for(&overseer, Duration::from_millis(1500));
+    assert!(stalled.lock().unwrap().is_empty());
+}
+
+#[test]
+fn paused_bumps_are_rejected_until_resumed() {
+    let bus = match TestBus::start(&[]) {
+        Some(bus) => bus,
+        None => return,
+    };
+    let conn = bus.connect();
+
+    call::<(), _>(&conn, "PauseBumps", ()).unwrap();
+    assert!(get_property::<bool>(&conn, "BumpsPaused"));
+    let err = call::<(u32,), _>(&conn, "TriggerSysGenUpdate", (0u32, "")).unwrap_err();
+    assert_eq!(err.name(), Some("com.RFC.sysgenid.Error.BumpsPaused"));
+    let err = call::<(u32,), _>(&conn, "SetGeneration", (5u32,)).unwrap_err();
+    assert_eq!(err.name(), Some("com.RFC.sysgenid.Error.BumpsPaused"));
+    let (counter,): (u32,) = call(&conn, "GetSysGenCounter", ()).unwrap();
+    assert_eq!(counter, 0);
+
+    call::<(), _>(&conn, "ResumeBumps", ()).unwrap();
+    assert!(!get_property::<bool>(&conn, "BumpsPaused"));
+    let (counter,): (u32,) = call(&conn, "TriggerSysGenUpdate", (0u32, "")).unwrap();
+    assert_eq!(counter, 1);
+    let (counter,): (u32,) = call(&conn, "SetGeneration", (5u32,)).unwrap();
+    assert_eq!(counter, 5);
+}
+
+#[test]
+fn epoch_of_read_matches_signals() {
+    let bus = match TestBus::start(&[]) {
+        Some(bus) => bus,
+        None => return,
+    };
+    let conn = bus.connect();
+    let overseer = bus.connect();
+    let new_generations = collect_signals(&conn, "NewGeneration");
+
+    let (counter, epoch): (u32, u64) = call(&conn, "GetSysGenCounterWithEpoch", ()).unwrap();
+    assert_eq!((counter, epoch), (0, 0));
+
+    // Changes notified before the read carry an epoch it covers.
+    call::<(u32,), _>(&overseer, "TriggerSysGenUpdate", (0u32, "")).unwrap();
+    call::<(u32,), _>(&overseer, "TriggerSysGenUpdate", (0u32, "")).unwrap();
+    let (counter, epoch): (u32, u64) = call(&conn, "GetSysGenCounterWithEpoch", ()).unwrap();
+    assert_eq!((counter, epoch), (2, 2));
+    let mut signal_epochs: Vec<u64> = (0..2)
+        .map(|_| {
+            let msg = next_signal(&conn, &new_generations);
+            let (counter, _, signal_epoch): (u32, String, u64) = msg.read3().unwrap();
+            assert_eq!(u64::from(counter), signal_epoch);
+            signal_epoch
+        })
+        .collect();
+    signal_epochs.sort_unstable();
+    assert_eq!(signal_epochs, vec![1, 2]);
+    assert!(signal_epochs
+        .iter()
+        .all(|signal_epoch| *signal_epoch <= epoch));
+
+    // The epoch keeps increasing even when the counter goes back.
+    call::<(u32,), _>(&overseer, "ResetCounter", (1u32,)).unwrap();
+    let msg = next_signal(&conn, &new_generations);
+    let (counter, _, signal_epoch): (u32, String, u64) = msg.read3().unwrap();
+    assert_eq!((counter, signal_epoch), (1, 3));
+    let (counter, epoch): (u32, u64) = call(&conn, "GetSysGenCounterWithEpoch", ()).unwrap();
+    assert_eq!((counter, epoch), (1, 3));
+}
+
+/// Value of the doc string annotation of the `kind` member `name` in the introspection
+/// `xml`, if any.
+fn doc_string<'a>(xml: &'a str, kind: &str, name: &str) -> Option<&'a str> {
+    let start = xml.find(&format!("<{} name=\"{}\"", kind, name))?;
+    let member = &xml[start..];
+    let member = &member[..member.find(&format!("</{}>", kind))?];
+    let annotation = "<annotation name=\"org.gtk.GDBus.DocString\" value=\"";
+    let doc = &member[member.find(annotation)? + annotation.len()..];
+    Some(&doc[..doc.find('"')?])
+}
+
+#[test]
+fn introspection_documents_methods_and_signals() {
+    let bus = match TestBus::start(&[]) {
+        Some(bus) => bus,
+        None => return,
+    };
+    let conn = bus.connect();
+
+    let xml = conn
+        .with_proxy(SYSGENID_INTERFACE, SYSGENID_PATH, TIMEOUT)
+        .introspect()
+        .unwrap();
+    for (kind, name, mentions) in [
+        ("method", "TriggerSysGenUpdate", "min_gen"),
+        ("method", "AckWatcherCounter", "watcher_counter"),
+        ("method", "BumpAndWait", "timeout_ms"),
+        ("signal", "NewGeneration", "epoch"),
+    ]
+    .iter()
+    {
+        let doc = doc_string(&xml, kind, name)
+            .unwrap_or_else(|| panic!("{} {} is not documented", kind, name));
+        assert!(doc.contains(mentions), "{}: {}", name, doc);
+    }
+}
+
+#[test]
+fn sub_watchers_are_tracked_independently() {
+    let bus = match TestBus::start(&[]) {
+        Some(bus) => bus,
+        None => return,
+    };
+    let host = bus.connect();
+    let overseer = bus.connect();
+    let system_ready = collect_signals(&overseer, "SystemReady");
+    let plugin_a = format!("{}/plugin-a", host.unique_name());
+    let plugin_b = format!("{}/plugin-b", host.unique_name());
+
+    for sub_id in ["plugin-a", "plugin-b"].iter() {
+        call::<(u32,), _>(&host, "AckSubWatcherCounter", (*sub_id, 0u32)).unwrap();
+    }
+    call::<(u32,), _>(&overseer, "TriggerSysGenUpdate", (0u32, "")).unwrap();
+    let (mut outdated,): (Vec<String>,) = call(&overseer, "GetOutdatedWatchers", ()).unwrap();
+    outdated.sort();
+    assert_eq!(outdated, vec![plugin_a.clone(), plugin_b.clone()]);
+
+    // Each sub-watcher acks on its own.
+    call::<(u32,), _>(&host, "AckSubWatcherCounter", ("plugin-a", 1u32)).unwrap();
+    let (outdated,): (Vec<String>,) = call(&overseer, "GetOutdatedWatchers", ()).unwrap();
+    assert_eq!(outdated, vec![plugin_b]);
+    let (tracked,): (Vec<String>,) = call(&overseer, "GetTrackedWatchers", ()).unwrap();
+    assert_eq!(tracked, vec![plugin_a]);
+    let err = call::<(u32,), _>(&host, "AckSubWatcherCounter", ("", 1u32)).unwrap_err();
+    assert_eq!(err.name(), Some("com.RFC.sysgenid.Error.InvalidSubId"));
+
+    // Both go away along with their connection.
+    drop(host);
+    next_signal(&overseer, &system_ready);
+    let (tracked,): (Vec<String>,) = call(&overseer, "GetTrackedWatchers", ()).unwrap();
+    assert!(tracked.is_empty());
+    let (outdated,): (u32,) = call(&overseer, "CountOutdatedWatchers", ()).unwrap();
+    assert_eq!(outdated, 0);
+}
+
+#[test]
+fn ping_answers_promptly() {
+    let bus = match TestBus::start(&[]) {
+        Some(bus) => bus,
+        None => return,
+    };
+    let conn = bus.connect();
+
+    let start = Instant::now();
+    let (status,): (String,) = call(&conn, "Ping", ()).unwrap();
+    assert_eq!(status, "pong");
+    assert!(start.elapsed() < Duration::from_secs(1));
+
+    // The CLI exits successfully only when the service answers.
+    let ping = |bus_name: &str| {
+        run_cli(
+            &bus.address,
+            &["--bus-name", bus_name, "ping", "--timeout-secs", "2"],
+        )
+    };
+    assert_eq!(ping(SYSGENID_INTERFACE), (true, "pong".to_owned()));
+    assert!(!ping("com.RFC.nobody").0);
+}
+
+#[test]
+fn cli_commands_call_the_running_service() {
+    let bus = match TestBus::start(&[]) {
+        Some(bus) => bus,
+        None => return,
+    };
+    let watcher = bus.connect();
+    call::<(u32,), _>(&watcher, "RegisterWatcher", ()).unwrap();
+    let cli = |args: &[&str]| run_cli(&bus.address, args);
+
+    assert_eq!(cli(&["get"]), (true, "0".to_owned()));
+    let bump = cli(&["bump", "--min", "5", "--reason", "cli"]);
+    assert_eq!(bump, (true, "5".to_owned()));
+    assert_eq!(cli(&["count-outdated"]), (true, "1".to_owned()));
+    let (counter,): (u32,) = call(&watcher, "GetSysGenCounter", ()).unwrap();
+    assert_eq!(counter, 5);
+
+    // The CLI acks on behalf of its own connection, errors fail the command.
+    assert_eq!(cli(&["ack", "5"]), (true, "5".to_owned()));
+    assert!(!cli(&["ack", "4"]).0);
+    assert_eq!(cli(&["get"]), (true, "5".to_owned()));
+}
+
+#[test]
+fn oneshot_waits_for_the_system_to_converge() {
+    let bus = match TestBus::start(&[]) {
+        Some(bus) => bus,
+        None => return,
+    };
+    let watcher = bus.connect();
+    let new_generations = collect_signals(&watcher, "NewGeneration");
+    call::<(u32,), _>(&watcher, "RegisterWatcher", ()).unwrap();
+    let oneshot = |timeout_secs: &'static str| {
+        let address = bus.address.clone();
+        thread::spawn(move || {
+            let args = ["oneshot", "--reason", "cli", "--timeout-secs", timeout_secs];
+            run_cli(&address, &args)
+        })
+    };
+
+    // Succeeds once the watcher readjusted.
+    let waiting = oneshot("5");
+    let msg = next_signal(&watcher, &new_generations);
+    let counter: u32 = msg.read1().unwrap();
+    assert_eq!(counter, 1);
+    call::<(u32,), _>(&watcher, "AckWatcherCounter", (counter,)).unwrap();
+    assert_eq!(waiting.join().unwrap(), (true, "1".to_owned()));
+
+    // Fails when it doesn't in time, still printing the new counter.
+    let started = Instant::now();
+    let waiting = oneshot("1");
+    assert_eq!(waiting.join().unwrap(), (false, "2".to_owned()));
+    assert!(started.elapsed() >= Duration::from_secs(1));
+    let (outdated,): (u32,) = call(&watcher, "CountOutdatedWatchers", ()).unwrap();
+    assert_eq!(outdated, 1);
+}
+
+#[test]
+fn bump_and_wait_replies_with_convergence() {
+    let bus = match TestBus::start(&[]) {
+        Some(bus) => bus,
+        None => return,
+    };
+    let watcher = bus.connect();
+    let new_generations = collect_signals(&watcher, "NewGeneration");
+    call::<(u32,), _>(&watcher, "RegisterWatcher", ()).unwrap();
+    let bump_and_wait = |timeout_ms: u32| {
+        let overseer = bus.connect();
+        thread::spawn(move || {
+            call::<(u32, bool), _>(&overseer, "BumpAndWait", (0u32, "wait", timeout_ms)).unwrap()
+        })
+    };
+
+    // Replies once the watcher readjusted.
+    let waiting = bump_and_wait(5000);
+    let msg = next_signal(&watcher, &new_generations);
+    let counter: u32 = msg.read1().unwrap();
+    assert_eq!(counter, 1);
+    call::<(u32,), _>(&watcher, "AckWatcherCounter", (counter,)).unwrap();
+    assert_eq!(waiting.join().unwrap(), (1, true));
+
+    // Replies when it doesn't in time, still with the new counter.
+    let started = Instant::now();
+    let waiting = bump_and_wait(500);
+    assert_eq!(waiting.join().unwrap(), (2, false));
+    assert!(started.elapsed() >= Duration::from_millis(500));
+    let (outdated,): (u32,) = call(&watcher, "CountOutdatedWatchers", ()).unwrap();
+    assert_eq!(outdated, 1);
+}
+
+#[test]
+fn departures_during_bump_keep_service_responsive() {
+    let bus = match TestBus::start(&[]) {
+        Some(bus) => bus,
+        None => return,
+    };
+    let overseer = bus.connect();
+    let system_ready = collect_signals(&overseer, "SystemReady");
+    // A subscriber never reading its signals, for the bus to queue them up.
+    let stalled = bus.connect();
+    let _stalled_signals = collect_signals(&stalled, "WatcherLost");
+    let watchers: Vec<_> = (0..32).map(|_| bus.connect()).collect();
+    for watcher in watchers.iter() {
+        call::<(u32,), _>(watcher, "AckWatcherCounter", (0u32,)).unwrap();
+    }
+    call::<(u32,), _>(&overseer, "TriggerSysGenUpdate", (0u32, "")).unwrap();
+
+    // Removals send their signals from the bus name tracking, each one used to do so
+    // while holding the state lock method calls wait for.
+    drop(watchers);
+    for _ in 0..20 {
+        let start = Instant::now();
+        let (status,): (String,) = call(&overseer, "Ping", ()).unwrap();
+        assert_eq!(status, "pong");
+        let (_,): (u32,) = call(&overseer, "CountOutdatedWatchers", ()).unwrap();
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+    next_signal(&overseer, &system_ready);
+    let (outdated,): (u32,) = call(&overseer, "CountOutdatedWatchers", ()).unwrap();
+    assert_eq!(outdated, 0);
+}
+
+#[test]
+fn bump_returns_newly_outdated_watchers() {
+    let bus = match TestBus::start(&[]) {
+        Some(bus) => bus,
+        None => return,
+    };
+    let first = bus.connect();
+    let second = bus.connect();
+    let overseer = bus.connect();
+
+    call::<(u32,), _>(&first, "AckWatcherCounter", (0u32,)).unwrap();
+    call::<(u32,), _>(&second, "AckWatcherCounter", (0u32,)).unwrap();
+    let (counter, newly_outdated): (u32, Vec<String>) =
+        call(&overseer, "TriggerSysGenUpdate", (0u32, "")).unwrap();
+    assert_eq!(counter, 1);
+    let mut expected = vec![
+        first.unique_name().to_string(),
+        second.unique_name().to_string(),
+    ];
+    expected.sort();
+    assert_eq!(newly_outdated, expected);
+    let (mut outdated,): (Vec<String>,) = call(&overseer, "GetOutdatedWatchers", ()).unwrap();
+    outdated.sort();
+    assert_eq!(outdated, expected);
+
+    // Watchers already outdated aren't newly outdated by the next bump.
+    let (_, newly_outdated): (u32, Vec<String>) =
+        call(&overseer, "TriggerSysGenUpdate", (0u32, "")).unwrap();
+    assert!(newly_outdated.is_empty());
+}
+
+#[test]
+fn auto_bump_advances_counter_over_time() {
+    let bus = match TestBus::start(&["--auto-bump-interval-secs", "1"]) {
+        Some(bus) => bus,
+        None => return,
+    };
+    let watcher = bus.connect();
+    let overseer = bus.connect();
+    let new_generation = collect_signals(&watcher, "NewGeneration");
+    let system_ready = collect_signals(&overseer, "SystemReady");
+    let features: Vec<String> = get_property(&overseer, "Features");
+    assert!(features.iter().any(|f| f == "auto-bump"));
+
+    // A watcher tracked along the way is notified of auto-bumps, with their reason.
+    let (counter,): (u32,) = call(&watcher, "GetSysGenCounter", ()).unwrap();
+    call::<(u32,), _>(&watcher, "AckWatcherCounter", (counter,)).unwrap();
+    let msg = next_signal(&watcher, &new_generation);
+    let (auto_counter, reason): (u32, String) = msg.read2().unwrap();
+    assert!(auto_counter > counter);
+    assert_eq!(reason, "auto");
+    call::<(u32,), _>(&watcher, "AckWatcherCounter", (auto_counter,)).unwrap();
+    next_signal(&overseer, &system_ready);
+
+    // Manual bumps keep working in between, and the timer keeps going.
+    let (manual,): (u32,) = call(&overseer, "TriggerSysGenUpdate", (0u32, "")).unwrap();
+    assert!(manual > auto_counter);
+    process_for(&overseer, Duration::from_millis(2500));
+    let (counter,): (u32,) = call(&overseer, "GetSysGenCounter", ()).unwrap();
+    assert!(
+        counter >= manual + 2,
+        "counter {} after {}",
+        counter,
+        manual
+    );
+}
+
+#[test]
+fn denied_names_ack_without_being_tracked() {
+    let bus = match TestBus::start(&["--track-deny", "org.example.Noisy"]) {
+        Some(bus) => bus,
+        None => return,
+    };
+    let noisy = bus.connect();
+    noisy
+        .request_name("org.example.Noisy", false, false, true)
+        .unwrap();
+    let watcher = bus.connect();
+    let overseer = bus.connect();
+
+    // The denied name is resolved to its owner, whose acks succeed but aren't tracked.
+    let (counter,): (u32,) = call(&noisy, "AckWatcherCounter", (0u32,)).unwrap();
+    assert_eq!(counter, 0);
+    let (counter,): (u32,) = call(&noisy, "RegisterWatcher", ()).unwrap();
+    assert_eq!(counter, 0);
+    call::<(u32,), _>(&watcher, "AckWatcherCounter", (0u32,)).unwrap();
+    let (tracked,): (Vec<String>,) = call(&overseer, "GetTrackedWatchers", ()).unwrap();
+    assert_eq!(tracked, vec![watcher.unique_name().to_string()]);
+
+    let (_, newly_outdated): (u32, Vec<String>) =
+        call(&overseer, "TriggerSysGenUpdate", (0u32, "")).unwrap();
+    assert_eq!(newly_outdated, vec![watcher.unique_name().to_string()]);
+    // Even acking a counter it shouldn't know about just returns the current one.
+    let (counter,): (u32,) = call(&noisy, "AckWatcherCounter", (7u32,)).unwrap();
+    assert_eq!(counter, 1);
+    let (outdated,): (Vec<String>,) = call(&overseer, "GetOutdatedWatchers", ()).unwrap();
+    assert_eq!(outdated, vec![watcher.unique_name().to_string()]);
+    call::<(u32,), _>(&watcher, "AckWatcherCounter", (1u32,)).unwrap();
+    let (tracked,): (Vec<String>,) = call(&overseer, "GetTrackedWatchers", ()).unwrap();
+    assert_eq!(tracked, vec![watcher.unique_name().to_string()]);
+
+    // The exclusion follows the name to its new owner.
+    noisy.release_name("org.example.Noisy").unwrap();
+    let newcomer = bus.connect();
+    newcomer
+        .request_name("org.example.Noisy", false, false, true)
+        .unwrap();
+    call::<(u32,), _>(&newcomer, "RegisterWatcher", ()).unwrap();
+    call::<(u32,), _>(&noisy, "RegisterWatcher", ()).unwrap();
+    let (mut tracked,): (Vec<String>,) = call(&overseer, "GetTrackedWatchers", ()).unwrap();
+    tracked.sort();
+    let mut expected = vec![
+        watcher.unique_name().to_string(),
+        noisy.unique_name().to_string(),
+    ];
+    expected.sort();
+    assert_eq!(tracked, expected);
+}
+
+// Ioctls of the SysGenID kernel driver, as emulated by `--chardev`.
+#[cfg(target_os = "linux")]
+const SYSGENID_SET_WATCHER_TRACKING: libc::c_ulong = 0xE401;
+#[cfg(target_os = "linux")]
+const SYSGENID_TRIGGER_GEN_UPDATE: libc::c_ulong = 0xE403;
+#[cfg(target_os = "linux")]
+const SYSGENID_GET_OUTDATED_WATCHERS: libc::c_ulong = 0xE404;
+#[cfg(target_os = "linux")]
+const SYSGENID_GET_GENERATION: libc::c_ulong = 0xE405;
+
+/// Issues the SysGenID ioctl `cmd` with `arg` on `file`.
+#[cfg(target_os = "linux")]
+fn sysgenid_ioctl(file: &fs::File, cmd: libc::c_ulong, arg: libc::c_ulong) -> i32 {
+    use std::os::unix::io::AsRawFd;
+    let res = unsafe { libc::ioctl(file.as_raw_fd(), cmd as _, arg) };
+    assert!(
+        res >= 0,
+        "ioctl {:#x}: {}",
+        cmd,
+        std::io::Error::last_os_error()
+    );
+    res
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn chardev_mirrors_the_dbus_interface() {
+    use std::io::{Read, Write};
+    if !std::path::Path::new("/dev/cuse").exists() {
+        eprintln!("skipping, CUSE is not available");
+        return;
+    }
+    let dev_path = format!("/dev/sysgenid-test-{}", std::process::id());
+    let bus = match TestBus::start(&["--chardev", &dev_path]) {
+        Some(bus) => bus,
+        None => return,
+    };
+    let overseer = bus.connect();
+    let system_ready = collect_signals(&overseer, "SystemReady");
+    let deadline = Instant::now() + TIMEOUT;
+    let mut dev = loop {
+        match fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&dev_path)
+        {
+            Ok(dev) => break dev,
+            Err(e) => assert!(Instant::now() < deadline, "{}: {}", dev_path, e),
+        }
+        thread::sleep(Duration::from_millis(50));
+    };
+
+    // Open files become watchers once tracking is turned on.
+    sysgenid_ioctl(&dev, SYSGENID_SET_WATCHER_TRACKING, 1);
+    let (tracked,): (Vec<String>,) = call(&overseer, "GetTrackedWatchers", ()).unwrap();
+    assert_eq!(tracked.len(), 1);
+    assert!(tracked[0].starts_with("chardev:"));
+
+    // Bumps from either side are seen on the other.
+    sysgenid_ioctl(&dev, SYSGENID_TRIGGER_GEN_UPDATE, 0);
+    let (counter,): (u32,) = call(&overseer, "GetSysGenCounter", ()).unwrap();
+    assert_eq!(counter, 1);
+    let (counter, _): (u32, Vec<String>) =
+        call(&overseer, "TriggerSysGenUpdate", (0u32, "")).unwrap();
+    assert_eq!(
+        sysgenid_ioctl(&dev, SYSGENID_GET_GENERATION, 0),
+        counter as i32
+    );
+    let mut buf = [0u8; 4];
+    dev.read_exact(&mut buf).unwrap();
+    assert_eq!(u32::from_ne_bytes(buf), counter);
+    assert_eq!(sysgenid_ioctl(&dev, SYSGENID_GET_OUTDATED_WATCHERS, 0), 1);
+
+    // Writing the counter acks it, over D-Bus the system turns ready.
+    dev.write_all(&counter.to_ne_bytes()).unwrap();
+    assert_eq!(sysgenid_ioctl(&dev, SYSGENID_GET_OUTDATED_WATCHERS, 0), 0);
+    let (_, for_counter): (u64, u32) = next_signal(&overseer, &system_ready).read2().unwrap();
+    assert_eq!(for_counter, counter);
+
+    // Closing the file stops tracking it.
+    drop(dev);
+    let deadline = Instant::now() + TIMEOUT;
+    loop {
+        let (tracked,): (Vec<String>,) = call(&overseer, "GetTrackedWatchers", ()).unwrap();
+        if tracked.is_empty() {
+            break;
+        }
+        assert!(Instant::now() < deadline, "watcher still tracked");
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+#[test]
+fn missed_generations_count_bumps_until_ack() {
+    let bus = match TestBus::start(&[]) {
+        Some(bus) => bus,
+        None => return,
+    };
+    let watcher = bus.connect();
+    let overseer = bus.connect();
+    let watcher_name = watcher.unique_name().to_string();
+    call::<(u32,), _>(&watcher, "AckWatcherCounter", (0u32,)).unwrap();
+    let (missed,): (u32,) =
+        call(&watcher, "GetAndResetMissedGenerations", (&watcher_name,)).unwrap();
+    assert_eq!(missed, 0);
+
+    // Every bump counts, whether it makes the watcher outdated or finds it so already.
+    for _ in 0..3 {
+        call::<(u32, Vec<String>), _>(&overseer, "TriggerSysGenUpdate", (0u32, "")).unwrap();
+    }
+    let (missed,): (u32,) =
+        call(&overseer, "GetAndResetMissedGenerations", (&watcher_name,)).unwrap();
+    assert_eq!(missed, 3);
+    let (missed,): (u32,) =
+        call(&watcher, "GetAndResetMissedGenerations", (&watcher_name,)).unwrap();
+    assert_eq!(missed, 0);
+
+    // Catching up resets the count.
+    let (counter, _): (u32, Vec<String>) =
+        call(&overseer, "TriggerSysGenUpdate", (0u32, "")).unwrap();
+    call::<(u32,), _>(&watcher, "AckWatcherCounter", (counter,)).unwrap();
+    let (missed,): (u32,) =
+        call(&watcher, "GetAndResetMissedGenerations", (&watcher_name,)).unwrap();
+    assert_eq!(missed, 0);
+    let (_, outdated): (u32, Vec<String>) =
+        call(&overseer, "TriggerSysGenUpdate", (0u32, "")).unwrap();
+    assert_eq!(outdated, vec![watcher_name.clone()]);
+    let (missed,): (u32,) =
+        call(&watcher, "GetAndResetMissedGenerations", (&watcher_name,)).unwrap();
+    assert_eq!(missed, 1);
+
+    let err =
+        call::<(u32,), _>(&overseer, "GetAndResetMissedGenerations", (":1.999",)).unwrap_err();
+    assert_eq!(err.name(), Some("com.RFC.sysgenid.Error.UnknownWatcher"));
+}
+
+#[test]
+fn notify_threshold_holds_back_targeted_notifications() {
+    let bus = match TestBus::start(&["--targeted-signals"]) {
+        Some(bus) => bus,
+        None => return,
+    };
+    let coasting = bus.connect();
+    let watcher = bus.connect();
+    let overseer = bus.connect();
+    let coasting_generations = collect_signals(&coasting, "NewGeneration");
+    let new_generation = collect_signals(&watcher, "NewGeneration");
+    call::<(u32,), _>(&coasting, "AckWatcherCounter", (0u32,)).unwrap();
+    call::<(), _>(&coasting, "SetNotifyThreshold", (3u32,)).unwrap();
+    call::<(u32,), _>(&watcher, "AckWatcherCounter", (0u32,)).unwrap();
+
+    // Below the threshold, only the other watcher is woken up, though both are outdated.
+    for counter in 1..3u32 {
+        call::<(u32, Vec<String>), _>(&overseer, "TriggerSysGenUpdate", (0u32, "")).unwrap();
+        let notified: u32 = next_signal(&watcher, &new_generation).read1().unwrap();
+        assert_eq!(notified, counter);
+        call::<(u32,), _>(&watcher, "AckWatcherCounter", (counter,)).unwrap();
+    }
+    let (outdated,): (Vec<String>,) = call(&overseer, "GetOutdatedWatchers", ()).unwrap();
+    assert_eq!(outdated, vec![coasting.unique_name().to_string()]);
+    process_for(&coasting, Duration::from_millis(300));
+    assert!(coasting_generations.lock().unwrap().is_empty());
+
+    // Reaching it notifies the coasting watcher again.
+    call::<(u32, Vec<String>), _>(&overseer, "TriggerSysGenUpdate", (0u32, "")).unwrap();
+    let notified: u32 = next_signal(&coasting, &coasting_generations)
+        .read1()
+        .unwrap();
+    assert_eq!(notified, 3);
+
+    let err = call::<(), _>(&overseer, "SetNotifyThreshold", (1u32,)).unwrap_err();
+    assert_eq!(err.name(), Some("com.RFC.sysgenid.Error.UnknownWatcher"));
+}
+
+#[test]
+fn watchers_are_reported_with_their_well_known_names() {
+    let bus = match TestBus::start(&["--resolve-names"]) {
+        Some(bus) => bus,
+        None => return,
+    };
+    let friendly = bus.connect();
+    friendly
+        .request_name("org.example.Friendly", false, false, true)
+        .unwrap();
+    let anonymous = bus.connect();
+    let overseer = bus.connect();
+    let registered = collect_signals(&overseer, "WatcherRegistered");
+    let lost = collect_signals(&overseer, "WatcherLost");
+    let friendly_name = friendly.unique_name().to_string();
+    let anonymous_name = anonymous.unique_name().to_string();
+
+    call::<(u32,), _>(&friendly, "AckWatcherCounter", (0u32,)).unwrap();
+    let (bus_name, names): (String, Vec<String>) =
+        next_signal(&overseer, &registered).read2().unwrap();
+    assert_eq!(bus_name, friendly_name);
+    assert_eq!(names, vec!["org.example.Friendly".to_owned()]);
+    // Connections without a well-known name have none to report.
+    call::<(u32,), _>(&anonymous, "AckWatcherCounter", (0u32,)).unwrap();
+    let (bus_name, names): (String, Vec<String>) =
+        next_signal(&overseer, &registered).read2().unwrap();
+    assert_eq!(bus_name, anonymous_name);
+    assert!(names.is_empty());
+
+    call::<(u32, Vec<String>), _>(&overseer, "TriggerSysGenUpdate", (0u32, "")).unwrap();
+    let (outdated, names): (Vec<String>, Vec<Vec<String>>) =
+        call(&overseer, "GetOutdatedWatchers", ()).unwrap();
+    let mut expected = vec![
+        (
+            friendly_name.clone(),
+            vec!["org.example.Friendly".to_owned()],
+        ),
+        (anonymous_name, Vec::new()),
+    ];
+    expected.sort();
+    let mut reported: Vec<_> = outdated.into_iter().zip(names).collect();
+    reported.sort();
+    assert_eq!(reported, expected);
+
+    // The name is still reported once its owner left the bus, along with it.
+    drop(friendly);
+    let msg = next_signal(&overseer, &lost);
+    let (bus_name, was_outdated, _, names): (String, bool, String, Vec<String>) =
+        msg.read4().unwrap();
+    assert_eq!(bus_name, friendly_name);
+    assert!(was_outdated);
+    assert_eq!(names, vec!["org.example.Friendly".to_owned()]);
+}
+
+#[test]
+fn silent_watchers_are_reported_stale_until_heartbeat() {
+    let bus = match TestBus::start(&["--heartbeat-timeout-secs", "1"]) {
+        Some(bus) => bus,
+        None => return,
+    };
+    let heartbeating = bus.connect();
+    let quiet = bus.connect();
+    let overseer = bus.connect();
+    call::<(u32,), _>(&heartbeating, "RegisterWatcher", ()).unwrap();
+    call::<(u32,), _>(&quiet, "RegisterWatcher", ()).unwrap();
+    call::<(), _>(&heartbeating, "Heartbeat", ()).unwrap();
+    let (stale,): (Vec<(String, u64)>,) = call(&overseer, "GetStaleWatchers", ()).unwrap();
+    assert!(stale.is_empty());
+
+    // Going silent past the timeout makes it stale, never having sent one doesn't.
+    thread::sleep(Duration::from_millis(1200));
+    let (stale,): (Vec<(String, u64)>,) = call(&overseer, "GetStaleWatchers", ()).unwrap();
+    assert_eq!(stale.len(), 1);
+    assert_eq!(stale[0].0, heartbeating.unique_name().to_string());
+    assert!(stale[0].1 > 1000);
+
+    // A new heartbeat clears it.
+    call::<(), _>(&heartbeating, "Heartbeat", ()).unwrap();
+    let (stale,): (Vec<(String, u64)>,) = call(&overseer, "GetStaleWatchers", ()).unwrap();
+    assert!(stale.is_empty());
+}
+
+// Bus hiding bus name changes from everyone, for departures to go unnoticed.
+const DEAF_BUS_CONFIG: &str = r#"<busconfig>
+  <type>session</type>
+  <listen>unix:path=@BUS@</listen>
+  <auth>EXTERNAL</auth>
+  <policy context="default">
+    <allow send_destination="*" eavesdrop="true"/>
+    <allow eavesdrop="true"/>
+    <allow own="*"/>
+    <deny receive_interface="org.freedesktop.DBus" receive_member="NameOwnerChanged"/>
+  </policy>
+</busconfig>
+"#;
+
+#[test]
+fn departed_watchers_are_reaped_when_unnoticed() {
+    let dir = TempDir::new();
+    let config = dir.0.join("bus.conf");
+    let bus_path = dir.0.join("bus");
+    fs::write(
+        &config,
+        DEAF_BUS_CONFIG.replace("@BUS@", &bus_path.display().to_string()),
+    )
+    .unwrap();
+    let bus_args = [format!("--config-file={}", config.display())];
+    let bus = match TestBus::start_with(dir, &bus_args, &["--reap-interval-secs", "1"]) {
+        Some(bus) => bus,
+        None => return,
+    };
+    let staying = bus.connect();
+    let leaving = bus.connect();
+    let overseer = bus.connect();
+    let lost = collect_signals(&overseer, "WatcherLost");
+    let system_ready = collect_signals(&overseer, "SystemReady");
+    call::<(u32,), _>(&staying, "RegisterWatcher", ()).unwrap();
+    call::<(u32,), _>(&leaving, "RegisterWatcher", ()).unwrap();
+    call::<(u32,), _>(&overseer, "TriggerSysGenUpdate", (0u32, "")).unwrap();
+    call::<(u32,), _>(&staying, "AckWatcherCounter", (1u32,)).unwrap();
+    let leaving_name = leaving.unique_name().to_string();
+
+    // Only the periodic check finds out it left, as the last outdated watcher.
+    drop(leaving);
+    let msg = next_signal(&overseer, &lost);
+    let (bus_name, was_outdated, removal_reason): (String, bool, String) = msg.read3().unwrap();
+    assert_eq!(bus_name, leaving_name);
+    assert!(was_outdated);
+    assert_eq!(removal_reason, "disconnected");
+    let msg = next_signal(&overseer, &system_ready);
+    let (_, for_counter): (u64, u32) = msg.read2().unwrap();
+    assert_eq!(for_counter, 1);
+    let (tracked,): (Vec<String>,) = call(&overseer, "GetTrackedWatchers", ()).unwrap();
+    assert_eq!(tracked, vec![staying.unique_name().to_string()]);
+}
+
+#[test]
+fn forged_name_owner_changed_keeps_watchers() {
+    let bus = match TestBus::start(&[]) {
+        Some(bus) => bus,
+        None => return,
+    };
+    let victim = bus.connect();
+    let attacker = bus.connect();
+    let lost = collect_signals(&attacker, "WatcherLost");
+    call::<(u32,), _>(&victim, "RegisterWatcher", ()).unwrap();
+    let victim_name = victim.unique_name().to_string();
+
+    // Claims the victim left the bus, broadcast as well as sent to the service.
+    for destination in [None, Some(SYSGENID_INTERFACE)].iter() {
+        let mut msg = Message::signal(
+            &"/org/freedesktop/DBus".into(),
+            &"org.freedesktop.DBus".into(),
+            &"NameOwnerChanged".into(),
+        )
+        .append3(victim_name.as_str(), victim_name.as_str(), "");
+        if let Some(destination) = destination {
+            msg.set_destination(Some((*destination).into()));
+        }
+        attacker.channel().send(msg).unwrap();
+    }
+    // Handled in order, after the forged signals.
+    call::<(String,), _>(&attacker, "Ping", ()).unwrap();
+    process_for(&attacker, Duration::from_millis(200));
+    assert!(lost.lock().unwrap().is_empty());
+    let (tracked,): (Vec<String>,) = call(&attacker, "GetTrackedWatchers", ()).unwrap();
+    assert_eq!(tracked, vec![victim_name]);
+}
+
+#[test]
+fn wait_system_ready_replies_when_ready_or_timed_out() {
+    let bus = match TestBus::start(&[]) {
+        Some(bus) => bus,
+        None => return,
+    };
+    let watcher = bus.connect();
+    let overseer = bus.connect();
+    call::<(u32,), _>(&watcher, "RegisterWatcher", ()).unwrap();
+
+    // Ready right away with no outdated watcher, without waiting out the timeout.
+    let started = Instant::now();
+    let (ready,): (bool,) = call(&overseer, "WaitSystemReady", (3000u32,)).unwrap();
+    assert!(ready);
+    assert!(started.elapsed() < Duration::from_secs(3));
+
+    // Not ready once the timeout expires with the watcher still outdated.
+    call::<(u32,), _>(&overseer, "TriggerSysGenUpdate", (0u32, "")).unwrap();
+    let started = Instant::now();
+    let (ready,): (bool,) = call(&overseer, "WaitSystemReady", (500u32,)).unwrap();
+    assert!(!ready);
+    assert!(started.elapsed() >= Duration::from_millis(500));
+    let (outdated,): (u32,) = call(&overseer, "CountOutdatedWatchers", ()).unwrap();
+    assert_eq!(outdated, 1);
+}
+
+#[test]
+fn shutdown_is_signaled_on_sigterm() {
+    let mut bus = match TestBus::start(&[]) {
+        Some(bus) => bus,
+        None => return,
+    };
+    let watcher = bus.connect();
+    let shutting_down = collect_signals(&watcher, "ServiceShuttingDown");
+
+    let status = Command::new("kill")
+        .args(["-TERM", &bus.service.0.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    next_signal(&watcher, &shutting_down);
+    assert!(wait_for_exit(&mut bus.service));
+}
+
+#[test]
+fn reset_watchers_drops_everyone() {
+    let bus = match TestBus::start(&[]) {
+        Some(bus) => bus,
+        None => return,
+    };
+    let acking = bus.connect();
+    let lagging = bus.connect();
+    let overseer = bus.connect();
+    let system_ready = collect_signals(&overseer, "SystemReady");
+    let reset = || {
+        let (dropped,): (u32,) = call(&overseer, "ResetWatchers", ()).unwrap();
+        let (outdated,): (Vec<String>,) = call(&overseer, "GetOutdatedWatchers", ()).unwrap();
+        let (tracked,): (Vec<String>,) = call(&overseer, "GetTrackedWatchers", ()).unwrap();
+        assert!(outdated.is_empty());
+        assert!(tracked.is_empty());
+        dropped
+    };
+
+    // No one was outdated, the system was already ready.
+    call::<(u32,), _>(&acking, "RegisterWatcher", ()).unwrap();
+    assert_eq!(reset(), 1);
+    process_for(&overseer, Duration::from_millis(200));
+    assert!(system_ready.lock().unwrap().is_empty());
+
+    // Dropping outdated watchers makes it ready, once.
+    call::<(u32,), _>(&acking, "RegisterWatcher", ()).unwrap();
+    call::<(u32,), _>(&lagging, "RegisterWatcher", ()).unwrap();
+    call::<(u32,), _>(&overseer, "TriggerSysGenUpdate", (0u32, "")).unwrap();
+    call::<(u32,), _>(&acking, "AckWatcherCounter", (1u32,)).unwrap();
+    assert_eq!(reset(), 2);
+    process_for(&overseer, Duration::from_millis(200));
+    let signals = system_ready.lock().unwrap();
+    assert_eq!(signals.len(), 1);
+    let (_, for_counter): (u64, u32) = signals[0].read2().unwrap();
+    assert_eq!(for_counter, 1);
+}
+
+#[test]
+fn pre_bump_announces_the_next_generation() {
+    let bus = match TestBus::start(&["--pre-bump-ms", "300"]) {
+        Some(bus) => bus,
+        None => return,
+    };
+    let conn = bus.connect();
+    // Both signals, in the order they arrive.
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let received2 = received.clone();
+    let mut rule = MatchRule::new();
+    rule.msg_type = Some(MessageType::Signal);
+    rule.interface = Some(SYSGENID_INTERFACE.into());
+    conn.add_match(rule, move |(): (), _: &Connection, msg: &Message| {
+        let member = msg.member().unwrap().to_string();
+        received2
+            .lock()
+            .unwrap()
+            .push((member, msg.read1::<u32>().unwrap(), Instant::now()));
+        true
+    })
+    .unwrap();
+
+    let triggered = Instant::now();
+    let (counter,): (u32,) = call(&conn, "TriggerSysGenUpdate", (5u32, "")).unwrap();
+    assert_eq!(counter, 5);
+    // Not entered before the grace period is over.
+    let (counter,): (u32,) = call(&conn, "GetSysGenCounter", ()).unwrap();
+    assert_eq!(counter, 0);
+    process_for(&conn, Duration::from_millis(800));
+
+    let received = received.lock().unwrap();
+    let signals: Vec<_> = received
+        .iter()
+        .map(|(member, counter, _)| (member.as_str(), *counter))
+        .collect();
+    assert_eq!(signals, vec![("PreBump", 5), ("NewGeneration", 5)]);
+    assert!(received[1].2 - triggered >= Duration::from_millis(300));
+}
+
+#[test]
+fn groups_become_ready_independently() {
+    let bus = match TestBus::start(&[]) {
+        Some(bus) => bus,
+        None => return,
+    };
+    let net = bus.connect();
+    let storage = bus.connect();
+    let overseer = bus.connect();
+    let group_ready = collect_signals(&overseer, "GroupReady");
+    call::<(u32,), _>(&net, "AckWatcherCounterInGroup", (0u32, "net")).unwrap();
+    call::<(u32,), _>(&storage, "AckWatcherCounterInGroup", (0u32, "storage")).unwrap();
+    call::<(u32,), _>(&overseer, "TriggerSysGenUpdate", (0u32, "")).unwrap();
+    let count_outdated = |group: &str| {
+        let (outdated,): (u32,) =
+            call(&overseer, "CountOutdatedWatchersInGroup", (group,)).unwrap();
+        outdated
+    };
+    assert_eq!((count_outdated("net"), count_outdated("storage")), (1, 1));
+
+    // Watchers keep their group when acking without one.
+    call::<(u32,), _>(&net, "AckWatcherCounter", (1u32,)).unwrap();
+    let msg = next_signal(&overseer, &group_ready);
+    assert_eq!(msg.read1::<&str>().unwrap(), "net");
+    assert_eq!((count_outdated("net"), count_outdated("storage")), (0, 1));
+    process_for(&overseer, Duration::from_millis(200));
+    assert!(group_ready.lock().unwrap().is_empty());
+
+    call::<(u32,), _>(&storage, "AckWatcherCounterInGroup", (1u32, "storage")).unwrap();
+    let msg = next_signal(&overseer, &group_ready);
+    assert_eq!(msg.read1::<&str>().unwrap(), "storage");
+    assert_eq!((count_outdated("net"), count_outdated("storage")), (0, 0));
+}
+
+#[test]
+fn bump_history_keeps_the_latest_bumps() {
+    let bus = match TestBus::start(&["--bump-history", "3"]) {
+        Some(bus) => bus,
+        None => return,
+    };
+    let conn = bus.connect();
+    for reason in ["first", "second", "third", "fourth", "fifth"].iter() {
+        call::<(u32,), _>(&conn, "TriggerSysGenUpdate", (0u32, *reason)).unwrap();
+    }
+
+    // Oldest first.
+    let (history,): (Vec<(u32, String, u64)>,) = call(&conn, "GetBumpHistory", ()).unwrap();
+    let bumps: Vec<_> = history
+        .iter()
+        .map(|(counter, reason, _)| (*counter, reason.as_str()))
+        .collect();
+    assert_eq!(bumps, vec![(3, "third"), (4, "fourth"), (5, "fifth")]);
+    assert!(history.windows(2).all(|pair| pair[0].2 <= pair[1].2));
+}
+
+#[test]
+fn invalid_reasons_are_rejected() {
+    let bus = match TestBus::start(&["--max-reason-len", "8"]) {
+        Some(bus) => bus,
+        None => return,
+    };
+    let conn = bus.connect();
+
+    for reason in ["too long a reason", "new\nline", "esc\u{1b}[2J"].iter() {
+        let err = call::<(u32,), _>(&conn, "TriggerSysGenUpdate", (0u32, *reason)).unwrap_err();
+        assert_eq!(err.name(), Some("com.RFC.sysgenid.Error.InvalidReason"));
+    }
+    // Nothing got bumped, reasons within bounds still go through.
+    let (counter,): (u32,) = call(&conn, "GetSysGenCounter", ()).unwrap();
+    assert_eq!(counter, 0);
+    let (counter,): (u32,) = call(&conn, "TriggerSysGenUpdate", (0u32, "restore")).unwrap();
+    assert_eq!(counter, 1);
+}
+
+#[test]
+fn convergence_durations_are_bucketed() {
+    let bus = match TestBus::start(&[]) {
+        Some(bus) => bus,
+        None => return,
+    };
+    let watcher = bus.connect();
+    let overseer = bus.connect();
+    call::<(u32,), _>(&watcher, "RegisterWatcher", ()).unwrap();
+    let converge_after = |bumps: u32, delay: Duration| {
+        let mut counter = 0;
+        for _ in 0..bumps {
+            let (bumped,): (u32,) = call(&overseer, "TriggerSysGenUpdate", (0u32, "")).unwrap();
+            counter = bumped;
+        }
+        thread::sleep(delay);
+        call::<(u32,), _>(&watcher, "AckWatcherCounter", (counter,)).unwrap();
+    };
+
+    converge_after(1, Duration::from_millis(120));
+    // The first of these is superseded before converging, only the second one counts.
+    converge_after(2, Duration::from_millis(600));
+
+    let (buckets, incomplete): (Vec<(u64, u64)>, u64) =
+        call(&overseer, "GetConvergenceHistogram", ()).unwrap();
+    assert_eq!(incomplete, 1);
+    let filled: Vec<_> = buckets
+        .into_iter()
+        .filter(|(_, count)| *count > 0)
+        .collect();
+    assert_eq!(filled, vec![(250, 1), (1000, 1)]);
+}
+
+#[test]
+fn callback_returns_ack_the_new_generation() {
+    let bus = match TestBus::start(&[]) {
+        Some(bus) => bus,
+        None => return,
+    };
+    let watcher = bus.connect();
+    let overseer = bus.connect();
+    let system_ready = collect_signals(&overseer, "SystemReady");
+    // Serves the callback like examples/callback_client.rs, failing on generation 1.
+    let called = Arc::new(Mutex::new(Vec::new()));
+    let called2 = called.clone();
+    let mut cr = Crossroads::new();
+    let iface_token = cr.register("com.RFC.sysgenid.Callback", move |b| {
+        let called = called2.clone();
+        b.method(
+            "OnNewGeneration",
+            ("sysgen_counter",),
+            (),
+            move |_, _: &mut (), (counter,): (u32,)| {
+                called.lock().unwrap().push(counter);
+                if counter == 1 {
+                    return Err(MethodErr::failed("not adjusted"));
+                }
+                Ok(())
+            },
+        );
+    });
+    cr.insert("/callback", &[iface_token], ());
+    watcher.start_receive(
+        MatchRule::new_method_call(),
+        Box::new(move |msg, conn| {
+            cr.handle_message(msg, conn).unwrap();
+            true
+        }),
+    );
+    let (counter,): (u32,) = call(
+        &watcher,
+        "RegisterCallbackWatcher",
+        (dbus::Path::from("/callback"),),
+    )
+    .unwrap();
+    assert_eq!(counter, 0);
+
+    // A failed callback doesn't ack.
+    call::<(u32,), _>(&overseer, "TriggerSysGenUpdate", (0u32, "")).unwrap();
+    process_for(&watcher, Duration::from_millis(300));
+    assert_eq!(*called.lock().unwrap(), vec![1]);
+    let (outdated,): (u32,) = call(&overseer, "CountOutdatedWatchers", ()).unwrap();
+    assert_eq!(outdated, 1);
+
+    // A successful one does, without the watcher calling back.
+    call::<(u32,), _>(&overseer, "TriggerSysGenUpdate", (0u32, "")).unwrap();
+    process_for(&watcher, Duration::from_millis(300));
+    assert_eq!(*called.lock().unwrap(), vec![1, 2]);
+    let msg = next_signal(&overseer, &system_ready);
+    let (_, for_counter): (u64, u32) = msg.read2().unwrap();
+    assert_eq!(for_counter, 2);
+    let (tracked,): (Vec<String>,) = call(&overseer, "GetTrackedWatchers", ()).unwrap();
+    assert_eq!(tracked, vec![watcher.unique_name().to_string()]);
+}
+
+#[test]
+fn watcher_lost_tells_unregistering_from_disconnecting() {
+    let bus = match TestBus::start(&[]) {
+        Some(bus) => bus,
+        None => return,
+    };
+    let unregistering = bus.connect();
+    let disconnecting = bus.connect();
+    let overseer = bus.connect();
+    let lost = collect_signals(&overseer, "WatcherLost");
+    call::<(u32,), _>(&unregistering, "RegisterWatcher", ()).unwrap();
+    call::<(u32,), _>(&disconnecting, "RegisterWatcher", ()).unwrap();
+    let next_lost = || {
+        let msg = next_signal(&overseer, &lost);
+        let (bus_name, _, removal_reason): (String, bool, String) = msg.read3().unwrap();
+        (bus_name, removal_reason)
+    };
+
+    call::<(), _>(&unregistering, "UnregisterWatcher", ()).unwrap();
+    let expected = (
+        unregistering.unique_name().to_string(),
+        "unregistered".to_owned(),
+    );
+    assert_eq!(next_lost(), expected);
+    // Only tracked watchers can unregister.
+    let err = call::<(), _>(&unregistering, "UnregisterWatcher", ()).unwrap_err();
+    assert_eq!(err.name(), Some("com.RFC.sysgenid.Error.UnknownWatcher"));
+
+    let expected = (
+        disconnecting.unique_name().to_string(),
+        "disconnected".to_owned(),
+    );
+    drop(disconnecting);
+    assert_eq!(next_lost(), expected);
+}
+
+#[test]
+fn watchers_over_their_budget_are_flagged() {
+    let bus = match TestBus::start(&[]) {
+        Some(bus) => bus,
+        None => return,
+    };
+    let budgeted = bus.connect();
+    let unbudgeted = bus.connect();
+    let overseer = bus.connect();
+    call::<(u32,), _>(&budgeted, "RegisterWatcherWithBudget", (300u32,)).unwrap();
+    call::<(u32,), _>(&unbudgeted, "RegisterWatcher", ()).unwrap();
+    let budgeted_name = budgeted.unique_name().to_string();
+    let budgets = || {
+        let (mut budgets,): (Vec<(String, u64, u64, bool)>,) =
+            call(&overseer, "GetOutdatedWatcherBudgets", ()).unwrap();
+        budgets.sort_by_key(|(watcher, ..)| *watcher != budgeted_name);
+        budgets
+    };
+    call::<(u32,), _>(&overseer, "TriggerSysGenUpdate", (0u32, "")).unwrap();
+
+    let within = budgets();
+    assert_eq!(within.len(), 2);
+    assert_eq!((within[0].2, within[0].3), (300, false));
+    // Watchers without a budget are never over it.
+    assert_eq!((within[1].2, within[1].3), (0, false));
+
+    thread::sleep(Duration::from_millis(400));
+    let over = budgets();
+    assert!(over[0].1 > 300);
+    assert_eq!((over[0].2, over[0].3), (300, true));
+    assert_eq!((over[1].2, over[1].3), (0, false));
+}
+
+#[test]
+fn preview_bump_counts_without_bumping() {
+    let bus = match TestBus::start(&[]) {
+        Some(bus) => bus,
+        None => return,
+    };
+    let acking = bus.connect();
+    let lagging = bus.connect();
+    let overseer = bus.connect();
+    call::<(u32,), _>(&acking, "RegisterWatcher", ()).unwrap();
+    call::<(u32,), _>(&lagging, "RegisterWatcher", ()).unwrap();
+    call::<(u32,), _>(&overseer, "TriggerSysGenUpdate", (0u32, "")).unwrap();
+    call::<(u32,), _>(&acking, "AckWatcherCounter", (1u32,)).unwrap();
+    let watchers = || {
+        let (tracked,): (Vec<String>,) = call(&overseer, "GetTrackedWatchers", ()).unwrap();
+        let (outdated,): (Vec<String>,) = call(&overseer, "GetOutdatedWatchers", ()).unwrap();
+        (tracked, outdated)
+    };
+    let before = watchers();
+
+    // Only up to date watchers would be made outdated.
+    let (affected,): (u32,) = call(&overseer, "PreviewBump", ()).unwrap();
+    assert_eq!(affected, 1);
+    assert_eq!(watchers(), before);
+    let (counter,): (u32,) = call(&overseer, "GetSysGenCounter", ()).unwrap();
+    assert_eq!(counter, 1);
+}
+
+#[test]
+fn watchers_are_told_outdated_or_unknown() {
+    let bus = match TestBus::start(&[]) {
+        Some(bus) => bus,
+        None => return,
+    };
+    let acking = bus.connect();
+    let lagging = bus.connect();
+    let overseer = bus.connect();
+    call::<(u32,), _>(&acking, "RegisterWatcher", ()).unwrap();
+    call::<(u32,), _>(&lagging, "RegisterWatcher", ()).unwrap();
+    call::<(u32,), _>(&overseer, "TriggerSysGenUpdate", (0u32, "")).unwrap();
+    call::<(u32,), _>(&acking, "AckWatcherCounter", (1u32,)).unwrap();
+    let is_outdated = |conn: &Connection| {
+        let bus_name = conn.unique_name().to_string();
+        call::<(bool,), _>(&overseer, "IsWatcherOutdated", (bus_name,)).map(|(outdated,)| outdated)
+    };
+
+    assert!(!is_outdated(&acking).unwrap());
+    assert!(is_outdated(&lagging).unwrap());
+    let err = is_outdated(&overseer).unwrap_err();
+    assert_eq!(err.name(), Some("com.RFC.sysgenid.Error.UnknownWatcher"));
+}
+
+#[test]
+fn outdated_watcher_count_changes_are_notified() {
+    let bus = match TestBus::start(&[]) {
+        Some(bus) => bus,
+        None => return,
+    };
+    let first = bus.connect();
+    let second = bus.connect();
+    let overseer = bus.connect();
+    let changes = collect_properties_changed(&overseer);
+    call::<(u32,), _>(&first, "RegisterWatcher", ()).unwrap();
+    call::<(u32,), _>(&second, "RegisterWatcher", ()).unwrap();
+    call::<(u32,), _>(&overseer, "TriggerSysGenUpdate", (0u32, "")).unwrap();
+    call::<(u32,), _>(&first, "AckWatcherCounter", (1u32,)).unwrap();
+    call::<(u32,), _>(&second, "AckWatcherCounter", (1u32,)).unwrap();
+    process_for(&overseer, Duration::from_millis(200));
+
+    // Down to zero, one watcher at a time.
+    let counts: Vec<u64> = changes
+        .lock()
+        .unwrap()
+        .iter()
+        .filter_map(|changed| changed.get("OutdatedWatcherCount"))
+        .map(|count| count.0.as_u64().unwrap())
+        .collect();
+    assert_eq!(counts, vec![2, 1, 0]);
+    let count: u32 = get_property(&overseer, "OutdatedWatcherCount");
+    assert_eq!(count, 0);
+}
+
+#[test]
+fn bump_notifies_its_property_changes_at_once() {
+    let bus = match TestBus::start(&[]) {
+        Some(bus) => bus,
+        None => return,
+    };
+    let conn = bus.connect();
+    let changes = collect_properties_changed(&conn);
+    call::<(u32,), _>(&conn, "TriggerSysGenUpdate", (0u32, "")).unwrap();
+    process_for(&conn, Duration::from_millis(200));
+
+    let changes = changes.lock().unwrap();
+    assert_eq!(changes.len(), 1);
+    let mut keys: Vec<&str> = changes[0].keys().map(String::as_str).collect();
+    keys.sort_unstable();
+    assert_eq!(keys, vec!["LastBumpTimestamp", "SysGenCounter"]);
+    assert_eq!(changes[0]["SysGenCounter"].0.as_u64(), Some(1));
+    let timestamp: u64 = get_property(&conn, "LastBumpTimestamp");
+    assert_eq!(changes[0]["LastBumpTimestamp"].0.as_u64(), Some(timestamp));
+}
+
+#[test]
+fn last_bump_timestamp_advances_with_bumps() {
+    let bus = match TestBus::start(&[]) {
+        Some(bus) => bus,
+        None => return,
+    };
+    let conn = bus.connect();
+    let unix_now = || {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    };
+    call::<(u32,), _>(&conn, "TriggerSysGenUpdate", (0u32, "")).unwrap();
+    let first: u64 = get_property(&conn, "LastBumpTimestamp");
+    assert!(first.abs_diff(unix_now()) <= 1);
+
+    // Timestamps are in seconds.
+    thread::sleep(Duration::from_millis(1100));
+    call::<(u32,), _>(&conn, "TriggerSysGenUpdate", (0u32, "")).unwrap();
+    let second: u64 = get_property(&conn, "LastBumpTimestamp");
+    assert!(second > first);
+    assert!(second.abs_diff(unix_now()) <= 1);
+}
+
+#[test]
+fn watchers_past_the_limit_are_refused() {
+    let bus = match TestBus::start(&["--max-watchers", "2"]) {
+        Some(bus) => bus,
+        None => return,
+    };
+    let first = bus.connect();
+    let second = bus.connect();
+    let third = bus.connect();
+    call::<(u32,), _>(&first, "RegisterWatcher", ()).unwrap();
+    call::<(u32,), _>(&second, "AckWatcherCounter", (0u32,)).unwrap();
+
+    let err = call::<(u32,), _>(&third, "RegisterWatcher", ()).unwrap_err();
+    assert_eq!(err.name(), Some("com.RFC.sysgenid.Error.TooManyWatchers"));
+    let err = call::<(u32,), _>(&third, "AckWatcherCounter", (0u32,)).unwrap_err();
+    assert_eq!(err.name(), Some("com.RFC.sysgenid.Error.TooManyWatchers"));
+
+    // Outdated watchers still count, and can still ack.
+    call::<(u32,), _>(&third, "TriggerSysGenUpdate", (0u32, "")).unwrap();
+    let err = call::<(u32,), _>(&third, "AckWatcherCounter", (1u32,)).unwrap_err();
+    assert_eq!(err.name(), Some("com.RFC.sysgenid.Error.TooManyWatchers"));
+    call::<(u32,), _>(&first, "AckWatcherCounter", (1u32,)).unwrap();
+    call::<(u32,), _>(&first, "AckWatcherCounter", (1u32,)).unwrap();
+    let (outdated,): (Vec<String>,) = call(&third, "GetOutdatedWatchers", ()).unwrap();
+    assert_eq!(outdated, vec![second.unique_name().to_string()]);
+}
+
+/// Number of `NameOwnerChanged` signals handled by a service started with `args` on
+/// `bus`, while a client takes and releases `count` well-known names.
+fn name_changes_handled(bus: &TestBus, args: &[&str], count: usize) -> usize {
+    const NAME: &str = "org.example.Counted";
+    let dir = TempDir::new();
+    let mut command = Command::new(env!("CARGO_BIN_EXE_sysgenid-dbus"));
+    command
+        .args(["--bus-address", &bus.address, "--bus-name", NAME])
+        .arg("--state-file")
+        .arg(dir.0.join("state"))
+        .arg("--counter-file")
+        .arg(dir.0.join("counter"))
+        .args(["--log-level", "debug"])
+        .args(args)
+        .env_remove("DBUS_SESSION_BUS_ADDRESS")
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped());
+    if ASYNC_SERVER.with(Cell::get) {
+        command.arg("--async");
+    }
+    let mut service = Process(command.spawn().unwrap());
+    let stderr = service.0.stderr.take().unwrap();
+    let logs = thread::spawn(move || {
+        BufReader::new(stderr)
+            .lines()
+            .filter(|line| line.as_ref().unwrap().contains("handle NameOwnerChanged"))
+            .count()
+    });
+
+    let conn = bus.connect();
+    let proxy = conn.with_proxy(NAME, SYSGENID_PATH, TIMEOUT);
+    let ping = || proxy.method_call::<(String,), _, _, _>(SYSGENID_INTERFACE, "Ping", ());
+    let deadline = Instant::now() + TIMEOUT;
+    while ping().is_err() {
+        assert!(Instant::now() < deadline, "service did not come up");
+        thread::sleep(Duration::from_millis(50));
+    }
+    for i in 0..count {
+        let name = format!("org.example.Unrelated{}", i);
+        conn.request_name(name.as_str(), false, false, true)
+            .unwrap();
+        conn.release_name(name.as_str()).unwrap();
+    }
+    // Handled in order, after the signals about the names.
+    ping().unwrap();
+    drop(service);
+    logs.join().unwrap()
+}
+
+#[test]
+fn unrelated_name_changes_are_filtered_by_the_bus() {
+    let bus = match TestBus::start(&[]) {
+        Some(bus) => bus,
+        None => return,
+    };
+
+    // Names getting an owner never wake up the service, only losing it does, unless it
+    // resolves names.
+    assert_eq!(name_changes_handled(&bus, &[], 20), 20);
+    assert_eq!(name_changes_handled(&bus, &["--resolve-names"], 20), 40);
+}
+
+#[test]
+fn system_ready_tells_how_long_watchers_took() {
+    let bus = match TestBus::start(&[]) {
+        Some(bus) => bus,
+        None => return,
+    };
+    let watcher = bus.connect();
+    let overseer = bus.connect();
+    let system_ready = collect_signals(&overseer, "SystemReady");
+    call::<(u32,), _>(&watcher, "RegisterWatcher", ()).unwrap();
+    let bumped = Instant::now();
+    call::<(u32,), _>(&overseer, "TriggerSysGenUpdate", (0u32, "")).unwrap();
+    thread::sleep(Duration::from_millis(300));
+    call::<(u32,), _>(&watcher, "AckWatcherCounter", (1u32,)).unwrap();
+    let acked = bumped.elapsed();
+
+    let msg = next_signal(&overseer, &system_ready);
+    let (adjust_duration_ms, for_counter): (u64, u32) = msg.read2().unwrap();
+    assert_eq!(for_counter, 1);
+    assert!(adjust_duration_ms >= 300, "{} ms", adjust_duration_ms);
+    assert!(adjust_duration_ms <= acked.as_millis() as u64);
+}
+
+#[test]
+fn stats_reflect_a_partially_acked_bump() {
+    let bus = match TestBus::start(&[]) {
+        Some(bus) => bus,
+        None => return,
+    };
+    let watchers: Vec<_> = (0..3).map(|_| bus.connect()).collect();
+    let overseer = bus.connect();
+    for watcher in watchers.iter() {
+        call::<(u32,), _>(watcher, "RegisterWatcher", ()).unwrap();
+    }
+    call::<(u32,), _>(&overseer, "TriggerSysGenUpdate", (0u32, "")).unwrap();
+    // Two of them ack right away, in a single burst.
+    for watcher in watchers[..2].iter() {
+        call::<(u32,), _>(watcher, "AckWatcherCounter", (1u32,)).unwrap();
+    }
+
+    let (stats,): (PropMap,) = call(&overseer, "GetStats", ()).unwrap();
+    let stat = |name: &str| stats[name].0.as_u64().unwrap();
+    assert_eq!(stat("generation_counter"), 1);
+    // Outdated watchers are still tracked.
+    assert_eq!(stat("tracked_watchers"), 3);
+    assert_eq!(stat("outdated_watchers"), 1);
+    assert_eq!(stat("total_bumps"), 1);
+    assert_eq!(stat("peak_concurrent_acks"), 2);
+    assert!(stat("uptime_secs") < 60);
+    // Registrations, the bump and the acks, at least.
+    assert!(stat("total_messages") >= 6);
+    assert!(stat("messages_per_sec") <= stat("peak_messages_per_sec"));
+    assert!(stat("peak_messages_per_sec") > 0);
+    let max_latency_us = stats["max_latency_us"].0.as_iter().unwrap();
+    let methods: Vec<_> = max_latency_us.filter_map(|arg| arg.as_str()).collect();
+    for method in [
+        "RegisterWatcher",
+        "TriggerSysGenUpdate",
+        "AckWatcherCounter",
+    ]
+    .iter()
+    {
+        assert!(methods.contains(method), "{:?}", methods);
+    }
+}
+
+async_server_tests!(
+    bump_updates_counter,
+    counter_file_maps_the_current_counter,
+    counter_file_is_created_with_the_given_mode,
+    counter_is_restored_across_restarts,
+    json_events_are_printed_to_stdout,
+    metrics_are_scraped_over_http,
+    instances_with_their_own_names_dont_cross_signal,
+    replace_takes_the_bus_name_over,
+    taken_bus_name_is_queued_for_unless_asked_not_to,
+    bump_honors_min_gen_only_above_next_counter,
+    rapid_bumps_are_rate_limited,
+    bumps_within_coalescing_window_are_notified_once,
+    ack_of_stale_counter_fails,
+    watcher_ack_makes_system_ready,
+    outdated_and_tracked_watchers_are_listed,
+    force_system_ready_drops_outdated_watchers,
+    unacked_watchers_are_dropped_after_ack_timeout,
+    readiness_of_superseded_generation_is_told_apart,
+    version_and_features_are_advertised,
+    ack_before_any_bump_does_not_signal_ready,
+    outdated_watcher_lag_increases,
+    lag_of_unknown_watcher_fails,
+    set_generation_to_greater_counter,
+    set_generation_to_same_or_lower_counter_fails,
+    serve_stats_count_calls_under_load,
+    tcp_bus_requires_opt_in,
+    watchers_are_tracked_over_tcp_bus,
+    draining_rejects_only_new_watchers,
+    stalled_convergence_is_signaled_once,
+    paused_bumps_are_rejected_until_resumed,
+    epoch_of_read_matches_signals,
+    introspection_documents_methods_and_signals,
+    sub_watchers_are_tracked_independently,
+    ping_answers_promptly,
+    cli_commands_call_the_running_service,
+    oneshot_waits_for_the_system_to_converge,
+    bump_and_wait_replies_with_convergence,
+    departures_during_bump_keep_service_responsive,
+    bump_returns_newly_outdated_watchers,
+    auto_bump_advances_counter_over_time,
+    denied_names_ack_without_being_tracked,
+    #[cfg(target_os = "linux")]
+    chardev_mirrors_the_dbus_interface,
+    missed_generations_count_bumps_until_ack,
+    notify_threshold_holds_back_targeted_notifications,
+    watchers_are_reported_with_their_well_known_names,
+    silent_watchers_are_reported_stale_until_heartbeat,
+    departed_watchers_are_reaped_when_unnoticed,
+    forged_name_owner_changed_keeps_watchers,
+    wait_system_ready_replies_when_ready_or_timed_out,
+    shutdown_is_signaled_on_sigterm,
+    reset_watchers_drops_everyone,
+    pre_bump_announces_the_next_generation,
+    groups_become_ready_independently,
+    bump_history_keeps_the_latest_bumps,
+    invalid_reasons_are_rejected,
+    convergence_durations_are_bucketed,
+    callback_returns_ack_the_new_generation,
+    watcher_lost_tells_unregistering_from_disconnecting,
+    watchers_over_their_budget_are_flagged,
+    preview_bump_counts_without_bumping,
+    watchers_are_told_outdated_or_unknown,
+    outdated_watcher_count_changes_are_notified,
+    bump_notifies_its_property_changes_at_once,
+    last_bump_timestamp_advances_with_bumps,
+    watchers_past_the_limit_are_refused,
+    unrelated_name_changes_are_filtered_by_the_bus,
+    system_ready_tells_how_long_watchers_took,
+    stats_reflect_a_partially_acked_bump,
+);
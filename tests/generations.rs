@@ -0,0 +1,67 @@
+//! Tests of the sys gen counter of the `Sysgenid` state machine, driven directly: how
+//! it starts, orders and behaves at its upper bound.
+
+mod common;
+
+use std::cmp::Ordering;
+use std::fs;
+
+use common::TempDir;
+use sysgenid_dbus::{generation_cmp, Signal, Sysgenid, SysgenidConfig, SysgenidError};
+
+#[test]
+fn counter_starts_from_the_seed_source() {
+    let dir = TempDir::new();
+    let seed = dir.0.join("seed");
+    fs::write(&seed, "41\n").unwrap();
+    let config = SysgenidConfig {
+        seed_from: Some(seed.clone()),
+        ..dir.config()
+    };
+    let mut sysgenid = Sysgenid::from_config(&config).unwrap();
+    assert_eq!(sysgenid.sysgen_counter(), 41);
+
+    // Bumps never fall behind it either.
+    fs::write(&seed, 50u32.to_ne_bytes()).unwrap();
+    let (counter, _) = sysgenid.bump_generation(0, "", |_| ()).unwrap();
+    assert_eq!(counter, 50);
+    sysgenid.persist().unwrap();
+    drop(sysgenid);
+
+    // The persisted counter wins over a seed behind it.
+    fs::write(&seed, "7").unwrap();
+    let sysgenid = Sysgenid::from_config(&config).unwrap();
+    assert_eq!(sysgenid.sysgen_counter(), 50);
+}
+
+#[test]
+fn counter_saturates_at_its_maximum() {
+    let dir = TempDir::new();
+    let mut sysgenid = Sysgenid::from_config(&dir.config()).unwrap();
+    sysgenid.set_generation(u32::MAX - 1, |_| ()).unwrap();
+
+    // The last generation is still reachable.
+    let (counter, _) = sysgenid.bump_generation(0, "", |_| ()).unwrap();
+    assert_eq!(counter, u32::MAX);
+
+    // Past it, bumps fail without wrapping around, letting listeners know.
+    let mut signals = Vec::new();
+    let result = sysgenid.bump_generation(0, "", |signal| signals.push(signal));
+    assert!(matches!(result, Err(SysgenidError::CounterExhausted)));
+    assert_eq!(sysgenid.sysgen_counter(), u32::MAX);
+    assert_eq!(signals.len(), 1);
+    assert!(matches!(
+        signals[0],
+        Signal::CounterExhausted { counter: u32::MAX }
+    ));
+}
+
+#[test]
+fn generations_order_up_to_the_boundary() {
+    assert_eq!(generation_cmp(u32::MAX, u32::MAX - 1), Ordering::Greater);
+    assert_eq!(generation_cmp(u32::MAX - 1, u32::MAX), Ordering::Less);
+    assert_eq!(generation_cmp(u32::MAX, u32::MAX), Ordering::Equal);
+    // The counter never wraps, the first generation is never newer than the last.
+    assert_eq!(generation_cmp(0, u32::MAX), Ordering::Less);
+    assert_eq!(generation_cmp(u32::MAX, 0), Ordering::Greater);
+}
@@ -0,0 +1,51 @@
+//! Tests of the state shared by the method handlers staying usable after a handler
+//! panicked while holding its lock.
+
+mod common;
+
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use common::TempDir;
+use sysgenid_dbus::{lock, Sysgenid, SysgenidError};
+
+#[test]
+fn poisoned_state_keeps_serving_calls() {
+    let dir = TempDir::new();
+    let sysgenid = Arc::new(Mutex::new(Sysgenid::from_config(&dir.config()).unwrap()));
+    lock(&sysgenid)
+        .register_watcher(":1.10", None, || true, || None, |_| ())
+        .unwrap();
+
+    // A handler panicking halfway through a call, e.g. on an overflow bug.
+    let sysgenid2 = sysgenid.clone();
+    let handler = thread::spawn(move || {
+        let mut sysgenid = lock(&sysgenid2);
+        sysgenid.bump_generation(0, "poison", |_| ()).unwrap();
+        panic!("handler bug");
+    });
+    assert!(handler.join().is_err());
+    assert!(sysgenid.is_poisoned());
+
+    // Later calls get their errors as usual, rather than panicking in turn.
+    let mut sysgenid = lock(&sysgenid);
+    assert_eq!(sysgenid.sysgen_counter(), 1);
+    let err = sysgenid
+        .ack_watcher_gen_counter(":1.10", 0, None, || true, || None, |_| ())
+        .unwrap_err();
+    assert!(
+        matches!(
+            err,
+            SysgenidError::StaleCounter {
+                counter: 0,
+                current: 1
+            }
+        ),
+        "{:?}",
+        err
+    );
+    sysgenid
+        .ack_watcher_gen_counter(":1.10", 1, None, || true, || None, |_| ())
+        .unwrap();
+    assert!(sysgenid.is_ready());
+}
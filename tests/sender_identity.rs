@@ -0,0 +1,43 @@
+//! Tests of the validation of method call senders, which watchers are tracked and
+//! callers authorized by. The bus always sets a valid one, so missing and malformed
+//! senders are checked directly rather than over a session bus.
+
+use sysgenid_dbus::unique_sender;
+
+#[test]
+fn unique_names_are_accepted() {
+    for sender in [":1.42", ":1.0", ":abc.def-1"].iter() {
+        assert_eq!(unique_sender(Some(sender)), Ok(*sender));
+    }
+}
+
+#[test]
+fn missing_sender_is_rejected() {
+    let err = unique_sender(None).unwrap_err();
+    assert_eq!(err, "could not identify sender");
+}
+
+#[test]
+fn malformed_senders_are_rejected() {
+    // Well-known names, empty ones and names the bus would never hand out.
+    for sender in [
+        "",
+        "org.example.Watcher",
+        ":",
+        ":1",
+        ":1.42 ",
+        ":1..42",
+        ":1.4/2",
+        "1.42",
+    ]
+    .iter()
+    {
+        let err = unique_sender(Some(sender)).unwrap_err();
+        assert!(
+            err.contains("not a unique bus name"),
+            "{:?}: {}",
+            sender,
+            err
+        );
+    }
+}
@@ -0,0 +1,137 @@
+//! Tests of resuming the sys gen counter from the state file at startup, driving
+//! `Sysgenid::from_config()` directly against state files in a scratch directory, and of
+//! where the state file goes by default.
+
+mod common;
+
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use common::TempDir;
+use sysgenid_dbus::{
+    BusType, CorruptStatePolicy, Sysgenid, SysgenidConfig, DEFAULT_COUNTER_FILE, DEFAULT_STATE_FILE,
+};
+
+// Contents of state files a crash or a stray write could leave behind.
+const TRUNCATED: &[u8] = b"";
+const GARBAGE: &[u8] = b"\xff\xfe{not a counter}\n";
+
+/// Options of a state machine with its files in `dir`, handling a corrupt state file
+/// according to `on_corrupt_state`.
+fn config(dir: &TempDir, on_corrupt_state: CorruptStatePolicy) -> SysgenidConfig {
+    SysgenidConfig {
+        on_corrupt_state,
+        ..dir.config()
+    }
+}
+
+/// Starts from a state file holding `contents`, returning the outcome along with the
+/// state file contents afterwards.
+fn start_from(contents: &[u8], policy: CorruptStatePolicy) -> (io::Result<u32>, Vec<u8>) {
+    let dir = TempDir::new();
+    let config = config(&dir, policy);
+    fs::write(&config.state_file, contents).unwrap();
+    let counter = Sysgenid::from_config(&config).map(|sysgenid| sysgenid.sysgen_counter());
+    (counter, fs::read(&config.state_file).unwrap())
+}
+
+#[test]
+fn corrupt_state_is_reset_by_default() {
+    assert_eq!(
+        SysgenidConfig::default().on_corrupt_state,
+        CorruptStatePolicy::Reset
+    );
+    for contents in [TRUNCATED, GARBAGE].iter() {
+        let (counter, state) = start_from(contents, CorruptStatePolicy::Reset);
+        assert_eq!(counter.unwrap(), 0, "{:?}", contents);
+        // The next start finds a valid state again.
+        assert_eq!(state, b"0\n");
+    }
+}
+
+#[test]
+fn corrupt_state_fails_startup_when_asked_to() {
+    for contents in [TRUNCATED, GARBAGE].iter() {
+        let (counter, state) = start_from(contents, CorruptStatePolicy::Fail);
+        let err = counter.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{:?}", contents);
+        assert!(err.to_string().contains("invalid state file"), "{}", err);
+        // Left as is, for the counter to be recovered by hand.
+        assert_eq!(state, *contents);
+    }
+}
+
+#[test]
+fn valid_state_is_resumed_under_both_policies() {
+    for policy in [CorruptStatePolicy::Reset, CorruptStatePolicy::Fail].iter() {
+        let (counter, state) = start_from(b"42\n", *policy);
+        assert_eq!(counter.unwrap(), 42, "{:?}", policy);
+        assert_eq!(state, b"42\n");
+    }
+}
+
+#[test]
+fn missing_state_starts_from_zero() {
+    let dir = TempDir::new();
+    let config = config(&dir, CorruptStatePolicy::Fail);
+    let sysgenid = Sysgenid::from_config(&config).unwrap();
+    assert_eq!(sysgenid.sysgen_counter(), 0);
+}
+
+#[test]
+fn domain_state_files_stay_isolated() {
+    let dir = TempDir::new();
+    let mut config = config(&dir, CorruptStatePolicy::Fail);
+    // The temporary file of one domain mustn't be the state file of another.
+    for domain in ["net", "tmp"].iter() {
+        config.add_domain(domain.to_string()).unwrap();
+    }
+    let configs = config.domain_configs();
+    let mut net = Sysgenid::from_config(&configs[0]).unwrap();
+    let mut tmp = Sysgenid::from_config(&configs[1]).unwrap();
+    net.bump_generation(5, "", |_| ()).unwrap();
+    tmp.bump_generation(9, "", |_| ()).unwrap();
+    net.bump_generation(0, "", |_| ()).unwrap();
+    drop((net, tmp));
+
+    let mut files: Vec<_> = fs::read_dir(&dir.0)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect();
+    files.sort();
+    assert_eq!(
+        files,
+        ["counter.net", "counter.tmp", "state.net", "state.tmp"]
+    );
+    let net = Sysgenid::from_config(&configs[0]).unwrap();
+    let tmp = Sysgenid::from_config(&configs[1]).unwrap();
+    assert_eq!(net.sysgen_counter(), 6);
+    assert_eq!(tmp.sysgen_counter(), 9);
+}
+
+#[test]
+fn session_instances_default_to_user_dirs() {
+    let runtime_dir = PathBuf::from("/run/user/1000");
+    let state_dir = PathBuf::from("/home/user/.local/state");
+
+    let mut config = SysgenidConfig::default();
+    config.use_user_dirs(&runtime_dir, &state_dir);
+    assert_eq!(config.state_file, state_dir.join("sysgenid/state"));
+    assert_eq!(config.counter_file, runtime_dir.join("sysgenid/counter"));
+
+    // Files set explicitly, or those of system bus instances, are left alone.
+    let mut config = SysgenidConfig {
+        state_file: PathBuf::from("/srv/state"),
+        ..SysgenidConfig::default()
+    };
+    config.use_user_dirs(&runtime_dir, &state_dir);
+    assert_eq!(config.state_file, PathBuf::from("/srv/state"));
+    let mut config = SysgenidConfig {
+        bus: BusType::System,
+        ..SysgenidConfig::default()
+    };
+    config.use_user_dirs(&runtime_dir, &state_dir);
+    assert_eq!(config.state_file, PathBuf::from(DEFAULT_STATE_FILE));
+    assert_eq!(config.counter_file, PathBuf::from(DEFAULT_COUNTER_FILE));
+}
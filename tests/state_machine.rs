@@ -0,0 +1,216 @@
+//! Property-based tests of the `Sysgenid` state machine, driven directly through random
+//! sequences of bumps, acks and removals checked against a model of the watcher sets,
+//! along with the races of watchers with the bus such sequences can't express.
+//!
+//! Failing sequences are shrunk by proptest and printed as `Op` lists: paste them into
+//! `MINIMIZED_CASES` to replay them as a plain test while fixing the bug.
+
+mod common;
+
+use std::collections::BTreeSet;
+
+use common::TempDir;
+use proptest::prelude::*;
+use sysgenid_dbus::{RemovalReason, Signal, Sysgenid, SysgenidError};
+
+// Bus names of the watchers operations pick from, few enough for them to collide.
+const WATCHERS: [&str; 4] = [":1.10", ":1.11", ":1.12", ":1.13"];
+const MAX_OPS: usize = 48;
+
+// Sequences which once broke an invariant, replayed on every run.
+const MINIMIZED_CASES: &[&[Op]] = &[
+    // The last outdated watcher leaving makes the system ready, like its ack would.
+    &[
+        Op::Ack {
+            watcher: 0,
+            counter_offset: 0,
+        },
+        Op::Bump { jump: 0 },
+        Op::Remove { watcher: 0 },
+    ],
+    // Stale and future acks of an outdated watcher leave it outdated.
+    &[
+        Op::Ack {
+            watcher: 1,
+            counter_offset: 0,
+        },
+        Op::Bump { jump: 3 },
+        Op::Ack {
+            watcher: 1,
+            counter_offset: -1,
+        },
+        Op::Ack {
+            watcher: 1,
+            counter_offset: 1,
+        },
+        Op::Ack {
+            watcher: 1,
+            counter_offset: 0,
+        },
+    ],
+    // Bumping with no one tracked, or everyone already outdated, isn't a transition.
+    &[
+        Op::Bump { jump: 0 },
+        Op::Ack {
+            watcher: 2,
+            counter_offset: 0,
+        },
+        Op::Bump { jump: 0 },
+        Op::Bump { jump: 2 },
+        Op::Ack {
+            watcher: 2,
+            counter_offset: 0,
+        },
+    ],
+];
+
+/// An operation on the state machine.
+#[derive(Clone, Debug)]
+enum Op {
+    /// Bump with `min_gen` set `jump` generations ahead of the current one.
+    Bump { jump: u32 },
+    /// Ack of `WATCHERS[watcher]` for the current counter plus `counter_offset`.
+    Ack { watcher: usize, counter_offset: i64 },
+    /// `WATCHERS[watcher]` leaving the bus.
+    Remove { watcher: usize },
+}
+
+fn op() -> impl Strategy<Value = Op> {
+    prop_oneof![
+        1 => (0u32..4).prop_map(|jump| Op::Bump { jump }),
+        // Mostly acks of the current counter, which are the ones changing state.
+        3 => (0..WATCHERS.len(), prop_oneof![3 => Just(0i64), 1 => Just(-1i64), 1 => Just(1i64)])
+            .prop_map(|(watcher, counter_offset)| Op::Ack { watcher, counter_offset }),
+        1 => (0..WATCHERS.len()).prop_map(|watcher| Op::Remove { watcher }),
+    ]
+}
+
+/// Expected watcher sets: who acked the current generation and who has yet to.
+#[derive(Default)]
+struct Model {
+    up_to_date: BTreeSet<String>,
+    outdated: BTreeSet<String>,
+}
+
+/// Runs `ops` against a fresh state machine, checking the invariants after each one.
+fn run_ops(ops: &[Op]) -> Result<(), TestCaseError> {
+    let dir = TempDir::new();
+    let mut sysgenid = Sysgenid::from_config(&dir.config()).unwrap();
+    let mut model = Model::default();
+
+    for op in ops {
+        let counter = sysgenid.sysgen_counter();
+        let was_outdated = !model.outdated.is_empty();
+        let mut signals = Vec::new();
+        match *op {
+            Op::Bump { jump } => {
+                let min_gen = counter.saturating_add(jump);
+                let (new_gen, newly_outdated) = sysgenid
+                    .bump_generation(min_gen, "", |signal| signals.push(signal))
+                    .unwrap();
+                prop_assert_eq!(new_gen, (counter + 1).max(min_gen));
+                let up_to_date = std::mem::take(&mut model.up_to_date);
+                // Exactly the watchers which were up to date are reported outdated.
+                prop_assert!(newly_outdated.iter().eq(up_to_date.iter()));
+                model.outdated.extend(up_to_date);
+            }
+            Op::Ack {
+                watcher,
+                counter_offset,
+            } => {
+                let watcher = WATCHERS[watcher];
+                let acked = (i64::from(counter) + counter_offset).max(0) as u32;
+                let result = sysgenid.ack_watcher_gen_counter(
+                    watcher,
+                    acked,
+                    None,
+                    || true,
+                    || None,
+                    |signal| signals.push(signal),
+                );
+                if acked < counter {
+                    let is_stale = matches!(result, Err(SysgenidError::StaleCounter { .. }));
+                    prop_assert!(is_stale, "ack of {} at {}", acked, counter);
+                } else if acked > counter {
+                    let is_invalid = matches!(result, Err(SysgenidError::InvalidCounter { .. }));
+                    prop_assert!(is_invalid, "ack of {} at {}", acked, counter);
+                } else {
+                    prop_assert!(result.is_ok(), "ack of {} at {}", acked, counter);
+                    model.outdated.remove(watcher);
+                    model.up_to_date.insert(watcher.to_owned());
+                }
+            }
+            Op::Remove { watcher } => {
+                let watcher = WATCHERS[watcher];
+                let was_tracked =
+                    sysgenid.remove_watcher(watcher, RemovalReason::Disconnected, |signal| {
+                        signals.push(signal)
+                    });
+                let expected = model.up_to_date.remove(watcher) | model.outdated.remove(watcher);
+                prop_assert_eq!(was_tracked, expected);
+            }
+        }
+
+        // The counter never goes back.
+        prop_assert!(sysgenid.sysgen_counter() >= counter);
+        // Watchers are either up to date or outdated, never both, as modeled.
+        let up_to_date: BTreeSet<_> = sysgenid.tracked_watcher_ids().into_iter().collect();
+        let outdated: BTreeSet<_> = sysgenid.outdated_watcher_ids().into_iter().collect();
+        prop_assert!(up_to_date.is_disjoint(&outdated));
+        prop_assert_eq!(&up_to_date, &model.up_to_date);
+        prop_assert_eq!(&outdated, &model.outdated);
+        prop_assert_eq!(sysgenid.is_ready(), outdated.is_empty());
+        // SystemReady is sent exactly when the last outdated watcher goes away.
+        let ready_signals = signals
+            .iter()
+            .filter(|signal| matches!(signal, Signal::SystemReady { .. }))
+            .count();
+        let became_ready = was_outdated && outdated.is_empty();
+        prop_assert_eq!(ready_signals, became_ready as usize, "after {:?}", op);
+    }
+    Ok(())
+}
+
+proptest! {
+    #[test]
+    fn random_operations_keep_invariants(ops in proptest::collection::vec(op(), 1..MAX_OPS)) {
+        run_ops(&ops)?;
+    }
+}
+
+#[test]
+fn minimized_cases_keep_invariants() {
+    for ops in MINIMIZED_CASES {
+        if let Err(e) = run_ops(ops) {
+            panic!("{:?}: {}", ops, e);
+        }
+    }
+}
+
+#[test]
+fn acks_of_vanished_connections_are_not_tracked() {
+    let dir = TempDir::new();
+    let mut sysgenid = Sysgenid::from_config(&dir.config()).unwrap();
+    let mut signals = Vec::new();
+
+    // The connection left the bus before its ack got handled.
+    let result = sysgenid.ack_watcher_gen_counter(
+        WATCHERS[0],
+        0,
+        None,
+        || false,
+        || None,
+        |signal| signals.push(signal),
+    );
+    assert!(matches!(result, Err(SysgenidError::WatcherGone(ref id)) if id == WATCHERS[0]));
+    let result = sysgenid.register_watcher(
+        WATCHERS[1],
+        None,
+        || false,
+        || None,
+        |signal| signals.push(signal),
+    );
+    assert!(matches!(result, Err(SysgenidError::WatcherGone(ref id)) if id == WATCHERS[1]));
+    assert!(signals.is_empty());
+    assert_eq!(sysgenid.tracked_count(), 0);
+}